@@ -1,7 +1,44 @@
+use std::path::Path;
+
 mod vmm;
 
 fn main() {
-    let vm = vmm::Vm::new(512);
+    vmm::Vm::set_log_level(log::LevelFilter::Warn);
+
+    // an optional Firecracker style config file, otherwise the default layout
+    let mut vm = match std::env::args().nth(1) {
+        Some(path) => {
+            let config = match vmm::config::VmConfig::from_json_file(Path::new(&path)) {
+                Ok(value) => value,
+                Err(error) => panic!("{:?}", error),
+            };
+            match vmm::Vm::from_config(config) {
+                Ok(value) => value,
+                Err(error) => panic!("{:?}", error),
+            }
+        }
+        None => vmm::Vm::new(512),
+    };
+
+    if let Err(error) = vm.configure() {
+        panic!("{:?}", error);
+    }
+
+    // an optional unix socket to control the VM from
+    if let Some(path) = std::env::args().nth(2) {
+        if let Err(error) = vm.start_api_server(Path::new(&path)) {
+            panic!("{:?}", error);
+        }
+    }
+
+    if let Err(error) = vm.spawn_device_thread() {
+        panic!("{:?}", error);
+    }
+    let exit_reason = match vm.run() {
+        Ok(value) => value,
+        Err(error) => panic!("{:?}", error),
+    };
+    vm.shutdown();
 
-    vm.configure();
+    log::info!("vm exited: {:?}", exit_reason);
 }
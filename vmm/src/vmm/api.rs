@@ -0,0 +1,290 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
+
+use crate::vmm::metrics::VmMetrics;
+
+// How long the server waits for a connection before checking whether it should stop.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long a read from an idle connection blocks before the server checks whether it has to
+// stop.
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// A kick that arrives right before the vcpu enters the guest is missed, so it's repeated until
+// the VM answers.
+const KICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A command sent over the API socket, one JSON object per line, e.g.
+/// `{"action":"BalloonSet","mb":64}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "action")]
+pub enum ApiRequest {
+    /// Stops the vcpu, `Vm::run` returns `VmExitReason::Stopped`.
+    Shutdown,
+    BalloonSet {
+        mb: u64,
+    },
+    GetMetrics,
+}
+
+/// Answer to an `ApiRequest`, written back as one JSON line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ApiResponse {
+    Ok,
+    Metrics(VmMetrics),
+    Error(String),
+}
+
+/// A request handed to the VM together with where to send the answer.
+pub struct ApiCall {
+    pub request: ApiRequest,
+    pub response: Sender<ApiResponse>,
+}
+
+/// Thread running `Vm::run`, if any. Requests interrupt it with a signal so they're handled
+/// even while the guest doesn't exit on its own.
+pub type VcpuThread = Arc<Mutex<Option<libc::pthread_t>>>;
+
+/// Pauses and resumes `Vm::run` from other threads. The vcpu stops between two guest exits,
+/// where its state is consistent, e.g. for a snapshot. Clones control the same VM.
+#[derive(Clone)]
+pub struct PauseHandle {
+    state: Arc<(Mutex<PauseState>, Condvar)>,
+    vcpu_thread: VcpuThread,
+}
+
+#[derive(Default)]
+struct PauseState {
+    requested: bool,
+    // the vcpu thread is waiting in `wait_while_paused`
+    paused: bool,
+}
+
+impl PauseHandle {
+    pub fn new(vcpu_thread: VcpuThread) -> PauseHandle {
+        PauseHandle {
+            state: Arc::new((Mutex::new(PauseState::default()), Condvar::new())),
+            vcpu_thread,
+        }
+    }
+
+    /// Stops the vcpu and returns once it's stopped. A vcpu that isn't running doesn't start
+    /// until `resume` is called.
+    pub fn pause(&self) -> io::Result<()> {
+        register_kick_handler()?;
+
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().expect("Poisoned lock");
+        state.requested = true;
+        while !state.paused && self.vcpu_thread.lock().expect("Poisoned lock").is_some() {
+            kick_vcpu(&self.vcpu_thread);
+            state = condvar
+                .wait_timeout(state, KICK_INTERVAL)
+                .expect("Poisoned lock")
+                .0;
+        }
+
+        Ok(())
+    }
+
+    /// Lets a paused vcpu run again.
+    pub fn resume(&self) {
+        let (lock, condvar) = &*self.state;
+        lock.lock().expect("Poisoned lock").requested = false;
+        condvar.notify_all();
+    }
+
+    /// Whether the vcpu is stopped in `wait_while_paused`.
+    pub fn is_paused(&self) -> bool {
+        self.state.0.lock().expect("Poisoned lock").paused
+    }
+
+    /// Called by the vcpu thread between two guest exits, waits there for at most `timeout`
+    /// while a pause is requested. Returns whether the vcpu is still meant to be paused.
+    pub fn wait_while_paused(&self, timeout: Duration) -> bool {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().expect("Poisoned lock");
+        if !state.requested {
+            return false;
+        }
+
+        state.paused = true;
+        condvar.notify_all();
+        state = condvar
+            .wait_timeout_while(state, timeout, |state| state.requested)
+            .expect("Poisoned lock")
+            .0;
+        state.paused = state.requested;
+
+        state.requested
+    }
+}
+
+/// Unix socket control plane of a VM.
+///
+/// Connections are handled one at a time on the server's thread. Requests are passed to the
+/// VM over a channel and handled by `Vm::run` between two guest exits.
+pub struct ApiServer {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ApiServer {
+    pub fn start(
+        path: &Path,
+        calls: Sender<ApiCall>,
+        vcpu_thread: VcpuThread,
+    ) -> io::Result<ApiServer> {
+        register_kick_handler()?;
+
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("api".to_string())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Acquire) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let result =
+                                handle_connection(stream, &calls, &vcpu_thread, &thread_stop);
+                            if let Err(err) = result {
+                                warn!("api connection failed: {:?}", err);
+                            }
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            thread::sleep(ACCEPT_POLL_INTERVAL)
+                        }
+                        // e.g. out of fds, which may clear up later
+                        Err(err) => {
+                            error!("failed to accept api connection: {:?}", err);
+                            thread::sleep(ACCEPT_POLL_INTERVAL)
+                        }
+                    }
+                }
+            })?;
+
+        Ok(ApiServer {
+            path: path.to_path_buf(),
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("api thread panicked");
+            }
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Installs the handler of the signal `kick_vcpu` sends. Without one the signal would kill
+/// the process.
+pub fn register_kick_handler() -> io::Result<()> {
+    register_signal_handler(SIGRTMIN(), handle_kick)
+        .map_err(|err| io::Error::from_raw_os_error(err.errno()))
+}
+
+extern "C" fn handle_kick(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+// Answers the requests of a connection, one per line, until the client closes it or the
+// server is stopped.
+fn handle_connection(
+    stream: UnixStream,
+    calls: &Sender<ApiCall>,
+    vcpu_thread: &VcpuThread,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    // an idle client must not keep the server from stopping
+    stream.set_read_timeout(Some(READ_POLL_INTERVAL))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    let mut line = Vec::new();
+    loop {
+        // a timed out read keeps what it got so far in `line`
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                if stop.load(Ordering::Acquire) {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+
+        let request = line.strip_suffix(b"\n").unwrap_or(&line);
+        let response = match serde_json::from_slice(request) {
+            Ok(request) => call(request, calls, vcpu_thread),
+            Err(err) => ApiResponse::Error(format!("invalid request: {}", err)),
+        };
+        line.clear();
+
+        let mut json = serde_json::to_string(&response).map_err(io::Error::from)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes())?;
+    }
+}
+
+fn call(request: ApiRequest, calls: &Sender<ApiCall>, vcpu_thread: &VcpuThread) -> ApiResponse {
+    let (response, answer) = mpsc::channel();
+    if calls.send(ApiCall { request, response }).is_err() {
+        return ApiResponse::Error("the VM is gone".to_string());
+    }
+
+    loop {
+        kick_vcpu(vcpu_thread);
+        match answer.recv_timeout(KICK_INTERVAL) {
+            Ok(response) => return response,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return ApiResponse::Error("the VM is gone".to_string())
+            }
+        }
+    }
+}
+
+/// Makes KVM_RUN return with EINTR so `Vm::run` picks up pending calls. A kick right before
+/// the vcpu enters the guest is missed, callers repeat it until they get an answer.
+pub fn kick_vcpu(vcpu_thread: &VcpuThread) {
+    if let Some(thread) = *vcpu_thread.lock().expect("Poisoned lock") {
+        // SAFETY: The thread is still running `Vm::run`, which clears it before returning.
+        unsafe { libc::pthread_kill(thread, SIGRTMIN()) };
+    }
+}
+
+/// Drains the calls queued by the server, handing each request to `handle`.
+pub fn handle_calls(calls: &Receiver<ApiCall>, mut handle: impl FnMut(ApiRequest) -> ApiResponse) {
+    while let Ok(call) = calls.try_recv() {
+        let response = handle(call.request);
+        // the connection may have been closed in the meantime
+        let _ = call.response.send(response);
+    }
+}
@@ -0,0 +1,89 @@
+use linux_loader::loader::Cmdline;
+
+#[derive(Debug)]
+pub enum CmdlineError {
+    Cmdline(linux_loader::cmdline::Error),
+    /// The key is empty or has a space, `=` or `"` in it.
+    InvalidKey(String),
+    /// The value has a `"` in it, which the kernel has no way to escape.
+    QuoteInValue(String),
+    /// The args leave a `"` open, which would swallow every arg appended after them.
+    UnbalancedQuotes(String),
+}
+
+/// Inserts `key=value`, putting the value in double quotes when it has spaces, the way the
+/// kernel's parser expects them.
+pub fn insert_quoted(cmdline: &mut Cmdline, key: &str, value: &str) -> Result<(), CmdlineError> {
+    if key.is_empty() || key.contains([' ', '=', '"']) {
+        return Err(CmdlineError::InvalidKey(key.to_string()));
+    }
+    if value.contains('"') {
+        return Err(CmdlineError::QuoteInValue(value.to_string()));
+    }
+
+    let arg = if value.contains(' ') {
+        format!("{}=\"{}\"", key, value)
+    } else {
+        format!("{}={}", key, value)
+    };
+    cmdline.insert_str(arg).map_err(CmdlineError::Cmdline)
+}
+
+/// Appends args given as one string, like the user supplied part of the cmdline.
+pub fn insert_args(cmdline: &mut Cmdline, args: &str) -> Result<(), CmdlineError> {
+    if !args.matches('"').count().is_multiple_of(2) {
+        return Err(CmdlineError::UnbalancedQuotes(args.to_string()));
+    }
+
+    cmdline.insert_str(args).map_err(CmdlineError::Cmdline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmdline_string(cmdline: &Cmdline) -> String {
+        cmdline.as_cstring().unwrap().into_string().unwrap()
+    }
+
+    #[test]
+    fn test_insert_quoted() {
+        let mut cmdline = Cmdline::new(256).unwrap();
+        insert_quoted(&mut cmdline, "earlycon", "uart,mmio,0x40000000").unwrap();
+        insert_quoted(&mut cmdline, "label", "root disk").unwrap();
+
+        assert_eq!(
+            cmdline_string(&cmdline),
+            "earlycon=uart,mmio,0x40000000 label=\"root disk\""
+        );
+    }
+
+    #[test]
+    fn test_insert_quoted_invalid() {
+        let mut cmdline = Cmdline::new(256).unwrap();
+
+        for key in ["", "root disk", "a=b", "\"key"] {
+            assert!(matches!(
+                insert_quoted(&mut cmdline, key, "value"),
+                Err(CmdlineError::InvalidKey(_))
+            ));
+        }
+        assert!(matches!(
+            insert_quoted(&mut cmdline, "label", "say \"hi\""),
+            Err(CmdlineError::QuoteInValue(_))
+        ));
+        assert_eq!(cmdline_string(&cmdline), "");
+    }
+
+    #[test]
+    fn test_insert_args() {
+        let mut cmdline = Cmdline::new(256).unwrap();
+        insert_args(&mut cmdline, "quiet label=\"root disk\"").unwrap();
+
+        assert!(matches!(
+            insert_args(&mut cmdline, "label=\"root disk"),
+            Err(CmdlineError::UnbalancedQuotes(_))
+        ));
+        assert_eq!(cmdline_string(&cmdline), "quiet label=\"root disk\"");
+    }
+}
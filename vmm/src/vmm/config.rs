@@ -0,0 +1,510 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::vmm::cpu::CpuFeatures;
+use crate::vmm::device::block::backend::IoEngine;
+use crate::vmm::device::block::QUEUE_SIZE as BLOCK_QUEUE_SIZE;
+use crate::vmm::device::net::QUEUE_SIZE as NET_QUEUE_SIZE;
+use crate::vmm::device::serial::ConsoleBackend;
+use crate::vmm::memory::HugePages;
+use crate::vmm::rate_limiter::RateLimiterConfig;
+use crate::vmm::{Vm, VmError};
+
+/// A virtio block device backed by a file on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockConfig {
+    pub id: String,
+    pub path: PathBuf,
+    pub rate_limiter: RateLimiterConfig,
+    pub io_engine: IoEngine,
+    /// Maximum size of the request queue, a power of two up to `MAX_QUEUE_SIZE`.
+    pub queue_size: u16,
+    /// Once the guest driver activated the device, holds the vcpu until the device thread
+    /// set it up as well, for at most this long. Meant for the root device, so the guest
+    /// doesn't mount it before the host is ready to serve it.
+    pub activation_timeout: Option<Duration>,
+}
+
+/// A virtio net device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetConfig {
+    pub id: String,
+    pub mac: Option<[u8; 6]>,
+    /// Tap interface the device is attached to, created if it doesn't exist. Without one the
+    /// device drops what the guest sends and never receives anything.
+    pub host_dev_name: Option<String>,
+    /// Limits traffic towards the guest.
+    pub rx_rate_limiter: RateLimiterConfig,
+    /// Limits traffic from the guest.
+    pub tx_rate_limiter: RateLimiterConfig,
+    /// Maximum size of the rx and tx queues, a power of two up to `MAX_QUEUE_SIZE`.
+    pub queue_size: u16,
+}
+
+/// Everything `Vm::from_config` needs to create a VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmConfig {
+    /// Guest memory size in MiB.
+    pub memory_size: usize,
+    pub huge_pages: HugePages,
+    /// Faults in all of guest memory when creating the VM, so the guest doesn't pay for it
+    /// on first access.
+    pub prefault_memory: bool,
+    /// Binds guest memory to this host NUMA node.
+    pub numa_node: Option<u32>,
+    /// Only a single vcpu is supported for now.
+    pub vcpu_count: u8,
+    /// Kernel image in the arm64 PE format.
+    pub kernel_path: PathBuf,
+    pub initrd_path: Option<PathBuf>,
+    /// Appended to `DEFAULT_KERNEL_CMDLINE`.
+    pub cmdline_extra: Option<String>,
+    /// Attached in order, so the first one is the guest's `/dev/vda`.
+    pub block_devices: Vec<BlockConfig>,
+    pub net_devices: Vec<NetConfig>,
+    pub balloon: bool,
+    pub console: ConsoleBackend,
+    pub cpu_features: CpuFeatures,
+    pub max_reboots: Option<u32>,
+    /// Adds a PCIe host bridge with a single, empty bus. Devices are still attached over
+    /// virtio-mmio.
+    pub pci: bool,
+    /// Puts the process' stdout in non-blocking mode when the console is on stdio, so a
+    /// stalled reader can't block the vcpu. This affects everything else writing to stdout.
+    pub nonblocking_stdout: bool,
+    /// Adds a watchdog that reboots the guest once it enabled it and then didn't ping it for
+    /// this long.
+    pub watchdog_timeout: Option<Duration>,
+    /// Adds a virtio console on this backend, next to the serial one. The guest sees it as
+    /// `/dev/hvc0`, `console=hvc0` in the cmdline makes it the kernel's console. It can't
+    /// share stdio with the serial console.
+    pub virtio_console: Option<ConsoleBackend>,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            memory_size: 128,
+            huge_pages: HugePages::default(),
+            prefault_memory: false,
+            numa_node: None,
+            vcpu_count: 1,
+            kernel_path: PathBuf::from("./kernel"),
+            initrd_path: None,
+            cmdline_extra: None,
+            block_devices: Vec::new(),
+            net_devices: Vec::new(),
+            balloon: false,
+            console: ConsoleBackend::default(),
+            cpu_features: CpuFeatures::default(),
+            max_reboots: None,
+            pci: false,
+            nonblocking_stdout: false,
+            watchdog_timeout: None,
+            virtio_console: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// The file isn't valid JSON or misses one of the required sections or fields.
+    Json(serde_json::Error),
+    /// `boot-source.kernel_image_path` is empty.
+    MissingKernelPath,
+    /// `machine-config.mem_size_mib` is zero.
+    InvalidMemorySize,
+    /// `drives` has a drive with an empty `path_on_host`.
+    MissingDrivePath(String),
+    /// `guest_mac` isn't six colon separated hex bytes.
+    InvalidMac(String),
+}
+
+// Sections of a Firecracker style config file. Fields that don't apply to this VMM, like
+// `is_read_only`, are accepted and ignored.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigFile {
+    boot_source: BootSource,
+    machine_config: MachineConfig,
+    #[serde(default)]
+    drives: Vec<Drive>,
+    #[serde(default)]
+    network_interfaces: Vec<NetworkInterface>,
+}
+
+#[derive(Deserialize)]
+struct BootSource {
+    kernel_image_path: PathBuf,
+    initrd_path: Option<PathBuf>,
+    boot_args: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MachineConfig {
+    vcpu_count: u8,
+    mem_size_mib: usize,
+    #[serde(default)]
+    huge_pages: HugePages,
+    numa_node: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Drive {
+    drive_id: String,
+    path_on_host: PathBuf,
+    #[serde(default)]
+    rate_limiter: RateLimiterConfig,
+    #[serde(default)]
+    io_engine: IoEngine,
+    #[serde(default = "default_block_queue_size")]
+    queue_size: u16,
+}
+
+#[derive(Deserialize)]
+struct NetworkInterface {
+    iface_id: String,
+    guest_mac: Option<String>,
+    host_dev_name: Option<String>,
+    #[serde(default)]
+    rx_rate_limiter: RateLimiterConfig,
+    #[serde(default)]
+    tx_rate_limiter: RateLimiterConfig,
+    #[serde(default = "default_net_queue_size")]
+    queue_size: u16,
+}
+
+fn default_block_queue_size() -> u16 {
+    BLOCK_QUEUE_SIZE
+}
+
+fn default_net_queue_size() -> u16 {
+    NET_QUEUE_SIZE
+}
+
+/// Parses a MAC address written as `06:00:ac:10:00:02`.
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut bytes = [0; 6];
+    let mut parts = mac.split(':');
+    for byte in &mut bytes {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(bytes)
+}
+
+impl VmConfig {
+    /// Reads a Firecracker style JSON config, with the `boot-source`, `machine-config`,
+    /// `drives` and `network-interfaces` sections. Anything the file doesn't mention keeps
+    /// its `VmConfig::default()` value.
+    pub fn from_json_file(path: &Path) -> Result<VmConfig, ConfigError> {
+        let file = File::open(path).map_err(ConfigError::Io)?;
+        VmConfig::from_json_reader(file)
+    }
+
+    pub fn from_json_reader(reader: impl io::Read) -> Result<VmConfig, ConfigError> {
+        let file: ConfigFile = serde_json::from_reader(reader).map_err(ConfigError::Json)?;
+
+        if file.boot_source.kernel_image_path.as_os_str().is_empty() {
+            return Err(ConfigError::MissingKernelPath);
+        }
+        if file.machine_config.mem_size_mib == 0 {
+            return Err(ConfigError::InvalidMemorySize);
+        }
+
+        let mut block_devices = Vec::new();
+        for drive in file.drives {
+            if drive.path_on_host.as_os_str().is_empty() {
+                return Err(ConfigError::MissingDrivePath(drive.drive_id));
+            }
+            block_devices.push(BlockConfig {
+                id: drive.drive_id,
+                path: drive.path_on_host,
+                rate_limiter: drive.rate_limiter,
+                io_engine: drive.io_engine,
+                queue_size: drive.queue_size,
+                activation_timeout: None,
+            });
+        }
+
+        let mut net_devices = Vec::new();
+        for iface in file.network_interfaces {
+            let mac = match iface.guest_mac {
+                Some(mac) => Some(parse_mac(&mac).ok_or(ConfigError::InvalidMac(mac))?),
+                None => None,
+            };
+            net_devices.push(NetConfig {
+                id: iface.iface_id,
+                mac,
+                host_dev_name: iface.host_dev_name,
+                rx_rate_limiter: iface.rx_rate_limiter,
+                tx_rate_limiter: iface.tx_rate_limiter,
+                queue_size: iface.queue_size,
+            });
+        }
+
+        Ok(VmConfig {
+            memory_size: file.machine_config.mem_size_mib,
+            huge_pages: file.machine_config.huge_pages,
+            numa_node: file.machine_config.numa_node,
+            vcpu_count: file.machine_config.vcpu_count,
+            kernel_path: file.boot_source.kernel_image_path,
+            initrd_path: file.boot_source.initrd_path,
+            cmdline_extra: file.boot_source.boot_args,
+            block_devices,
+            net_devices,
+            ..VmConfig::default()
+        })
+    }
+}
+
+/// Fluent way of filling in a `VmConfig`, starting from `VmConfig::default()`.
+#[derive(Debug, Default)]
+pub struct VmBuilder {
+    config: VmConfig,
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        VmBuilder::default()
+    }
+
+    /// Guest memory is only created by `build`, so the size can be changed until then.
+    pub fn memory_size(&mut self, memory_size: usize) -> &mut Self {
+        self.config.memory_size = memory_size;
+        self
+    }
+
+    pub fn huge_pages(&mut self, huge_pages: HugePages) -> &mut Self {
+        self.config.huge_pages = huge_pages;
+        self
+    }
+
+    pub fn prefault_memory(&mut self, prefault: bool) -> &mut Self {
+        self.config.prefault_memory = prefault;
+        self
+    }
+
+    pub fn numa_node(&mut self, node: u32) -> &mut Self {
+        self.config.numa_node = Some(node);
+        self
+    }
+
+    pub fn vcpu_count(&mut self, vcpu_count: u8) -> &mut Self {
+        self.config.vcpu_count = vcpu_count;
+        self
+    }
+
+    pub fn kernel(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.config.kernel_path = path.into();
+        self
+    }
+
+    pub fn initrd(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.config.initrd_path = Some(path.into());
+        self
+    }
+
+    pub fn cmdline_extra(&mut self, cmdline: impl Into<String>) -> &mut Self {
+        self.config.cmdline_extra = Some(cmdline.into());
+        self
+    }
+
+    pub fn add_block(&mut self, id: impl Into<String>, path: impl Into<PathBuf>) -> &mut Self {
+        self.add_block_config(BlockConfig {
+            id: id.into(),
+            path: path.into(),
+            rate_limiter: RateLimiterConfig::default(),
+            io_engine: IoEngine::default(),
+            queue_size: BLOCK_QUEUE_SIZE,
+            activation_timeout: None,
+        })
+    }
+
+    pub fn add_block_config(&mut self, block: BlockConfig) -> &mut Self {
+        self.config.block_devices.push(block);
+        self
+    }
+
+    pub fn add_net(&mut self, id: impl Into<String>, mac: Option<[u8; 6]>) -> &mut Self {
+        self.add_net_config(NetConfig {
+            id: id.into(),
+            mac,
+            host_dev_name: None,
+            rx_rate_limiter: RateLimiterConfig::default(),
+            tx_rate_limiter: RateLimiterConfig::default(),
+            queue_size: NET_QUEUE_SIZE,
+        })
+    }
+
+    pub fn add_net_config(&mut self, net: NetConfig) -> &mut Self {
+        self.config.net_devices.push(net);
+        self
+    }
+
+    pub fn balloon(&mut self, balloon: bool) -> &mut Self {
+        self.config.balloon = balloon;
+        self
+    }
+
+    pub fn console(&mut self, console: ConsoleBackend) -> &mut Self {
+        self.config.console = console;
+        self
+    }
+
+    pub fn virtio_console(&mut self, console: Option<ConsoleBackend>) -> &mut Self {
+        self.config.virtio_console = console;
+        self
+    }
+
+    pub fn cpu_features(&mut self, features: CpuFeatures) -> &mut Self {
+        self.config.cpu_features = features;
+        self
+    }
+
+    pub fn max_reboots(&mut self, max_reboots: Option<u32>) -> &mut Self {
+        self.config.max_reboots = max_reboots;
+        self
+    }
+
+    pub fn pci(&mut self, pci: bool) -> &mut Self {
+        self.config.pci = pci;
+        self
+    }
+
+    pub fn nonblocking_stdout(&mut self, nonblocking_stdout: bool) -> &mut Self {
+        self.config.nonblocking_stdout = nonblocking_stdout;
+        self
+    }
+
+    pub fn watchdog(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.config.watchdog_timeout = timeout;
+        self
+    }
+
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    pub fn build(&self) -> Result<Vm, VmError> {
+        Vm::from_config(self.config.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_two_drives() {
+        let mut builder = VmBuilder::new();
+        builder
+            .memory_size(256)
+            .kernel("/boot/Image")
+            .add_block("rootfs", "/images/rootfs.ext4")
+            .add_block("data", "/images/data.ext4");
+
+        let config = builder.config();
+        assert_eq!(config.memory_size, 256);
+        assert_eq!(config.kernel_path, PathBuf::from("/boot/Image"));
+        let drives: Vec<_> = config
+            .block_devices
+            .iter()
+            .map(|block| (block.id.as_str(), block.path.as_path()))
+            .collect();
+        assert_eq!(
+            drives,
+            [
+                ("rootfs", Path::new("/images/rootfs.ext4")),
+                ("data", Path::new("/images/data.ext4")),
+            ]
+        );
+        assert!(config
+            .block_devices
+            .iter()
+            .all(|block| block.queue_size == BLOCK_QUEUE_SIZE));
+    }
+
+    #[test]
+    fn test_from_json() {
+        let json = r#"{
+            "boot-source": {
+                "kernel_image_path": "/boot/Image",
+                "boot_args": "quiet"
+            },
+            "machine-config": {
+                "vcpu_count": 1,
+                "mem_size_mib": 512
+            },
+            "drives": [{
+                "drive_id": "rootfs",
+                "path_on_host": "/images/rootfs.ext4",
+                "is_root_device": true,
+                "is_read_only": false
+            }],
+            "network-interfaces": [{
+                "iface_id": "eth0",
+                "guest_mac": "06:00:ac:10:00:02",
+                "host_dev_name": "tap0"
+            }]
+        }"#;
+
+        let config = VmConfig::from_json_reader(json.as_bytes()).unwrap();
+
+        let mut builder = VmBuilder::new();
+        builder
+            .memory_size(512)
+            .vcpu_count(1)
+            .kernel("/boot/Image")
+            .cmdline_extra("quiet")
+            .add_block("rootfs", "/images/rootfs.ext4")
+            .add_net_config(NetConfig {
+                id: "eth0".to_string(),
+                mac: Some([0x06, 0x00, 0xac, 0x10, 0x00, 0x02]),
+                host_dev_name: Some("tap0".to_string()),
+                rx_rate_limiter: RateLimiterConfig::default(),
+                tx_rate_limiter: RateLimiterConfig::default(),
+                queue_size: NET_QUEUE_SIZE,
+            });
+        assert_eq!(&config, builder.config());
+    }
+
+    #[test]
+    fn test_from_json_invalid() {
+        let missing_kernel = r#"{
+            "boot-source": { "kernel_image_path": "" },
+            "machine-config": { "vcpu_count": 1, "mem_size_mib": 512 }
+        }"#;
+        assert!(matches!(
+            VmConfig::from_json_reader(missing_kernel.as_bytes()),
+            Err(ConfigError::MissingKernelPath)
+        ));
+
+        let bad_mac = r#"{
+            "boot-source": { "kernel_image_path": "/boot/Image" },
+            "machine-config": { "vcpu_count": 1, "mem_size_mib": 512 },
+            "network-interfaces": [{ "iface_id": "eth0", "guest_mac": "06:00:ac" }]
+        }"#;
+        assert!(matches!(
+            VmConfig::from_json_reader(bad_mac.as_bytes()),
+            Err(ConfigError::InvalidMac(mac)) if mac == "06:00:ac"
+        ));
+
+        assert!(matches!(
+            VmConfig::from_json_reader(r#"{ "drives": [] }"#.as_bytes()),
+            Err(ConfigError::Json(_))
+        ));
+    }
+}
@@ -1,16 +1,178 @@
-use kvm_bindings::kvm_vcpu_init;
+use kvm_bindings::{kvm_device_attr, kvm_guest_debug, kvm_vcpu_init};
 use kvm_bindings::{PSR_MODE_EL1h, PSR_A_BIT, PSR_D_BIT, PSR_F_BIT, PSR_I_BIT};
-use kvm_bindings::{KVM_REG_ARM64, KVM_REG_ARM_CORE, KVM_REG_SIZE_U64};
-use kvm_ioctls::{VcpuFd, VmFd};
+use kvm_bindings::{RegList, KVM_REG_ARM64, KVM_REG_ARM_CORE, KVM_REG_SIZE_U64};
+use kvm_bindings::{
+    KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW, KVM_GUESTDBG_USE_SW_BP,
+};
+use kvm_ioctls::{Cap, VcpuExit, VcpuFd, VmFd};
+use log::{debug, error, warn};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
 use vmm_sys_util::eventfd::EventFd;
 
+use crate::vmm::device::bus::Bus;
+use crate::vmm::device::eventfd_write_retry;
+use crate::vmm::fdt::AARCH64_PMU_IRQ;
 use crate::vmm::memory::*;
 
-pub const AARCH64_FDT_MAX_SIZE: u64 = 0x200000;
-
 #[macro_use]
 mod regs;
 
+use self::regs::{reg_size, MPIDR_EL1, SVE_VLS};
+pub use self::regs::{SCTLR_EL1, TCR_EL1, TTBR0_EL1, TTBR1_EL1};
+
+// MPIDR_EL1 affinity fields Aff3, Aff2, Aff1 and Aff0, without the RES1, U and MT bits.
+const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+// PPIs are numbered after the 16 SGIs in the GIC interrupt id space.
+const GIC_PPI_BASE: u32 = 16;
+
+/// Optional vcpu features. PSCI 0.2 is always enabled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Versionize)]
+pub struct CpuFeatures {
+    /// Expose the PMUv3 to the guest, with its overflow interrupt on `AARCH64_PMU_IRQ`.
+    pub pmu: bool,
+    /// Expose SVE to the guest, if the host supports it.
+    pub sve: bool,
+}
+
+impl CpuFeatures {
+    /// Bits to set in `kvm_vcpu_init::features[0]`. SVE is left out when KVM doesn't support it.
+    fn init_bits(&self, vm_fd: &VmFd) -> u32 {
+        let mut bits = 1 << kvm_bindings::KVM_ARM_VCPU_PSCI_0_2;
+        if self.pmu {
+            bits |= 1 << kvm_bindings::KVM_ARM_VCPU_PMU_V3;
+        }
+        if self.sve && vm_fd.check_extension(Cap::ArmSve) {
+            bits |= 1 << kvm_bindings::KVM_ARM_VCPU_SVE;
+        }
+        bits
+    }
+}
+
+/// Why `Cpu::run` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuExit {
+    /// The guest powered off (PSCI SYSTEM_OFF) or crashed.
+    Shutdown,
+    /// The guest asked to be restarted (PSCI SYSTEM_RESET).
+    Reboot,
+    /// A signal interrupted the vcpu before the guest exited, see `Vm::start_api_server`.
+    Interrupted,
+    /// The guest hit a breakpoint or finished a single step, see `Vm::start_gdb_server`.
+    Debug,
+    /// The vcpu exited for a reason the VMM doesn't handle, running it again would most
+    /// likely exit the same way.
+    Unhandled,
+}
+
+/// A way `Cpu::set_guest_debug` can make the guest exit to the VMM, with `CpuExit::Debug`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestDebug {
+    /// Exit after every instruction.
+    SingleStep,
+    /// Exit before executing the instruction at this address, using one of the hardware
+    /// breakpoint registers.
+    Breakpoint(u64),
+    /// Exit on `BRK` instructions instead of delivering them to the guest, for debuggers
+    /// that patch their breakpoints into guest memory.
+    SoftwareBreakpoints,
+}
+
+// Hardware breakpoints in `kvm_guest_debug_arch`, hosts may have less.
+const MAX_HW_BREAKPOINTS: usize = 16;
+// DBGBCR_EL1 of an enabled breakpoint matching an A64 instruction at EL1 and EL0.
+const DBGBCR_ENABLED_EL1_EL0: u64 = 1 | (0b11 << 1) | (0b1111 << 5);
+
+// More than KVM lists for a vcpu with SVE and the PMU.
+const MAX_REGS: usize = 1024;
+
+/// Saved vcpu state: the MPIDR and the `(id, value)` pairs of every register KVM lists for
+/// the vcpu, core, FP/SIMD, system and timer registers alike. Values are little endian and
+/// as wide as the register.
+#[derive(Debug, Default, Versionize)]
+pub struct CpuState {
+    pub mpidr: u64,
+    pub regs: Vec<(u64, Vec<u8>)>,
+}
+
+/// The core registers making up the vcpu's architectural state: x0-x30, sp, pc and pstate.
+pub fn core_reg_ids() -> Vec<u64> {
+    // Register ids are offsets in 32 bit units, so each x register is 2 ids apart.
+    let mut ids: Vec<u64> = (0..31).map(|i| arm64_core_reg!(regs) + i * 2).collect();
+    ids.push(arm64_core_reg!(sp));
+    ids.push(arm64_core_reg!(pc));
+    ids.push(arm64_core_reg!(pstate));
+    ids
+}
+
+// The affinity bits of an MPIDR_EL1 value, what the FDT cpu nodes use as their `reg`.
+fn mpidr_affinity(mpidr: u64) -> u64 {
+    mpidr & MPIDR_AFFINITY_MASK
+}
+
+// The `KVM_SET_GUEST_DEBUG` argument enabling `controls`.
+fn guest_debug(controls: &[GuestDebug]) -> Result<kvm_guest_debug, kvm_ioctls::Error> {
+    let mut debug = kvm_guest_debug::default();
+    let mut breakpoints = 0;
+    for control in controls {
+        match control {
+            GuestDebug::SingleStep => debug.control |= KVM_GUESTDBG_SINGLESTEP,
+            GuestDebug::SoftwareBreakpoints => debug.control |= KVM_GUESTDBG_USE_SW_BP,
+            GuestDebug::Breakpoint(addr) => {
+                if breakpoints == MAX_HW_BREAKPOINTS {
+                    return Err(kvm_ioctls::Error::new(libc::EINVAL));
+                }
+                debug.control |= KVM_GUESTDBG_USE_HW;
+                debug.arch.dbg_bcr[breakpoints] = DBGBCR_ENABLED_EL1_EL0;
+                // instructions are 4 byte aligned
+                debug.arch.dbg_bvr[breakpoints] = addr & !0x3;
+                breakpoints += 1;
+            }
+        }
+    }
+    if !controls.is_empty() {
+        debug.control |= KVM_GUESTDBG_ENABLE;
+    }
+
+    Ok(debug)
+}
+
+// Handles a single vcpu exit, returning why `Cpu::run` has to return, if it does.
+fn handle_exit(exit: VcpuExit, bus: &Bus, exit_evt: &EventFd) -> Option<CpuExit> {
+    match exit {
+        VcpuExit::MmioRead(addr, data) => {
+            if !bus.read(addr, data) {
+                debug!("mmio read from unmapped address: {:#x}", addr);
+            }
+        }
+        VcpuExit::MmioWrite(addr, data) => {
+            if !bus.write(addr, data) {
+                debug!("mmio write to unmapped address: {:#x}", addr);
+            }
+        }
+        VcpuExit::Debug(_) => return Some(CpuExit::Debug),
+        VcpuExit::SystemEvent(event_type, _) => match event_type {
+            kvm_bindings::KVM_SYSTEM_EVENT_RESET => return Some(CpuExit::Reboot),
+            kvm_bindings::KVM_SYSTEM_EVENT_SHUTDOWN | kvm_bindings::KVM_SYSTEM_EVENT_CRASH => {
+                if let Err(err) = eventfd_write_retry(exit_evt, 1) {
+                    error!("failed to signal the vcpu exit: {:?}", err);
+                }
+                return Some(CpuExit::Shutdown);
+            }
+            event_type => {
+                warn!("unexpected system event: {}", event_type);
+            }
+        },
+        exit => {
+            error!("unhandled vcpu exit: {:?}", exit);
+            return Some(CpuExit::Unhandled);
+        }
+    }
+
+    None
+}
+
 pub struct Cpu {
     pub index: u8,
     pub fd: VcpuFd,
@@ -37,13 +199,88 @@ impl Cpu {
         }
     }
 
-    pub fn init(&self, vm_fd: &VmFd) {
+    pub fn init(&mut self, vm_fd: &VmFd, features: &CpuFeatures) {
         let mut kvi: kvm_vcpu_init = kvm_vcpu_init::default();
         vm_fd.get_preferred_target(&mut kvi).unwrap();
 
-        kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PSCI_0_2;
+        kvi.features[0] |= features.init_bits(vm_fd);
 
         self.fd.vcpu_init(&kvi).unwrap();
+
+        // SVE vector lengths are configurable until the feature is finalized, the defaults
+        // (all lengths the host supports) are fine.
+        if kvi.features[0] & (1 << kvm_bindings::KVM_ARM_VCPU_SVE) != 0 {
+            self.fd
+                .vcpu_finalize(&(kvm_bindings::KVM_ARM_VCPU_SVE as i32))
+                .unwrap();
+        }
+
+        if features.pmu {
+            self.init_pmu();
+        }
+
+        self.kvi = Some(kvi);
+    }
+
+    /// Puts the vcpu back in its reset state, keeping the features chosen by `init`.
+    pub fn reset(&self) -> Result<(), kvm_ioctls::Error> {
+        match &self.kvi {
+            Some(kvi) => self.fd.vcpu_init(kvi),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the vcpu until the guest shuts down or reboots, a signal interrupts it or it exits
+    /// for a reason the VMM doesn't handle, handling MMIO accesses with the devices on `bus`.
+    /// On shutdown the exit eventfd is signalled as well.
+    pub fn run(&mut self, bus: &Bus) -> Result<CpuExit, kvm_ioctls::Error> {
+        loop {
+            let exit = match self.fd.run() {
+                Ok(exit) => exit,
+                Err(err) if err.errno() == libc::EINTR => return Ok(CpuExit::Interrupted),
+                Err(err) if err.errno() == libc::EAGAIN => continue,
+                Err(err) => return Err(err),
+            };
+
+            if let Some(exit) = handle_exit(exit, bus, &self.exit_evt) {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Reads the MPIDR KVM assigned to the vcpu. Secondary vcpus are started by PSCI CPU_ON
+    /// using this value, so the FDT cpu nodes must use it as their `reg`.
+    pub fn configure_mpidr(&mut self) -> Result<u64, kvm_ioctls::Error> {
+        let mut data = [0u8; 8];
+        self.fd.get_one_reg(MPIDR_EL1, &mut data)?;
+        self.mpidr = mpidr_affinity(u64::from_le_bytes(data));
+
+        Ok(self.mpidr)
+    }
+
+    /// The vcpu's MPIDR affinity bits, 0 until `configure_mpidr` is called.
+    pub fn mpidr(&self) -> u64 {
+        self.mpidr
+    }
+
+    // Needs the vgic to be created already.
+    fn init_pmu(&self) {
+        let irq = GIC_PPI_BASE + AARCH64_PMU_IRQ;
+        let irq_attr = kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_IRQ),
+            addr: &irq as *const u32 as u64,
+            flags: 0,
+        };
+        self.fd.set_device_attr(&irq_attr).unwrap();
+
+        let init_attr = kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_INIT),
+            addr: 0,
+            flags: 0,
+        };
+        self.fd.set_device_attr(&init_attr).unwrap();
     }
 
     pub fn configure_regs(&self, guest_memory: &GuestMemoryMmap) {
@@ -56,11 +293,203 @@ impl Cpu {
 
         self.fd.set_one_reg(reg_id, &data.to_le_bytes()).unwrap();
 
-        let mut fdt_offset: u64 = guest_memory.iter().map(|region| region.len()).sum();
-        fdt_offset = fdt_offset - AARCH64_FDT_MAX_SIZE - 0x10000;
-        data = (0x80000000 + fdt_offset) as u64;
+        data = get_fdt_addr(guest_memory);
         reg_id = arm64_core_reg!(regs);
 
         self.fd.set_one_reg(reg_id, &data.to_le_bytes()).unwrap();
     }
+
+    /// Reads the 64 bit register `reg_id`, a core register id built with `arm64_core_reg!` or
+    /// a system register id like `MPIDR_EL1`.
+    pub fn get_reg(&self, reg_id: u64) -> Result<u64, kvm_ioctls::Error> {
+        let mut data = [0u8; 8];
+        self.fd.get_one_reg(reg_id, &mut data)?;
+
+        Ok(u64::from_le_bytes(data))
+    }
+
+    pub fn set_reg(&self, reg_id: u64, value: u64) -> Result<(), kvm_ioctls::Error> {
+        self.fd.set_one_reg(reg_id, &value.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn pc(&self) -> Result<u64, kvm_ioctls::Error> {
+        self.get_reg(arm64_core_reg!(pc))
+    }
+
+    pub fn set_pc(&self, pc: u64) -> Result<(), kvm_ioctls::Error> {
+        self.set_reg(arm64_core_reg!(pc), pc)
+    }
+
+    /// The stack pointer of EL0, the guest kernel's one is `SP_EL1`.
+    pub fn sp(&self) -> Result<u64, kvm_ioctls::Error> {
+        self.get_reg(arm64_core_reg!(sp))
+    }
+
+    pub fn set_sp(&self, sp: u64) -> Result<(), kvm_ioctls::Error> {
+        self.set_reg(arm64_core_reg!(sp), sp)
+    }
+
+    pub fn pstate(&self) -> Result<u64, kvm_ioctls::Error> {
+        self.get_reg(arm64_core_reg!(pstate))
+    }
+
+    pub fn set_pstate(&self, pstate: u64) -> Result<(), kvm_ioctls::Error> {
+        self.set_reg(arm64_core_reg!(pstate), pstate)
+    }
+
+    /// Enables the given debug exits, replacing the ones set before. An empty `controls`
+    /// turns debugging off.
+    pub fn set_guest_debug(&self, controls: &[GuestDebug]) -> Result<(), kvm_ioctls::Error> {
+        self.fd.set_guest_debug(&guest_debug(controls)?)
+    }
+
+    /// Reads the registers KVM lists for the vcpu so it can be recreated later.
+    pub fn save_state(&self) -> Result<CpuState, kvm_ioctls::Error> {
+        let mut reg_list =
+            RegList::new(MAX_REGS).map_err(|_| kvm_ioctls::Error::new(libc::ENOMEM))?;
+        self.fd.get_reg_list(&mut reg_list)?;
+
+        let mut regs = Vec::new();
+        for &reg_id in reg_list.as_slice() {
+            // `init` sets up SVE again, it can't be written once finalized
+            if reg_id == SVE_VLS {
+                continue;
+            }
+            let mut data = vec![0u8; reg_size(reg_id)];
+            self.fd.get_one_reg(reg_id, &mut data)?;
+            regs.push((reg_id, data));
+        }
+
+        Ok(CpuState {
+            mpidr: self.mpidr,
+            regs,
+        })
+    }
+
+    /// Restores the registers saved by `save_state`. The vcpu must be initialized first.
+    pub fn restore_state(&mut self, state: &CpuState) -> Result<(), kvm_ioctls::Error> {
+        for (reg_id, data) in &state.regs {
+            self.fd.set_one_reg(*reg_id, data)?;
+        }
+        self.mpidr = state.mpidr;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kvm_ioctls::Kvm;
+
+    use super::*;
+
+    #[test]
+    fn test_mpidr_read_after_init() {
+        // needs /dev/kvm
+        let kvm = match Kvm::new() {
+            Ok(kvm) => kvm,
+            Err(_) => return,
+        };
+        let vm_fd = kvm.create_vm().unwrap();
+
+        for index in 0..2 {
+            let exit_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+            let mut cpu = Cpu::new(index, &vm_fd, exit_evt);
+            cpu.init(&vm_fd, &CpuFeatures::default());
+
+            // KVM numbers vcpus in Aff0 from their id
+            assert_eq!(cpu.configure_mpidr().unwrap(), u64::from(index));
+            assert_eq!(cpu.mpidr(), u64::from(index));
+        }
+    }
+
+    // An initialized vcpu of a VM without memory, `None` when the host has no KVM.
+    fn test_cpu() -> Option<(VmFd, Cpu)> {
+        let vm_fd = Kvm::new().ok()?.create_vm().unwrap();
+        let exit_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut cpu = Cpu::new(0, &vm_fd, exit_evt);
+        cpu.init(&vm_fd, &CpuFeatures::default());
+        Some((vm_fd, cpu))
+    }
+
+    #[test]
+    fn test_core_regs_round_trip() {
+        let (_vm_fd, cpu) = match test_cpu() {
+            Some(cpu) => cpu,
+            None => return,
+        };
+
+        cpu.set_pc(0x8008_0000).unwrap();
+        assert_eq!(cpu.pc().unwrap(), 0x8008_0000);
+        cpu.set_sp(0x8100_0000).unwrap();
+        assert_eq!(cpu.sp().unwrap(), 0x8100_0000);
+        let pstate = u64::from(PSR_MODE_EL1h | PSR_D_BIT | PSR_A_BIT | PSR_I_BIT | PSR_F_BIT);
+        cpu.set_pstate(pstate).unwrap();
+        assert_eq!(cpu.pstate().unwrap(), pstate);
+        // x0 is the first core register
+        cpu.set_reg(arm64_core_reg!(regs), 0x1234).unwrap();
+        assert_eq!(cpu.get_reg(arm64_core_reg!(regs)).unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn test_guest_debug_flags() {
+        assert_eq!(guest_debug(&[]).unwrap().control, 0);
+
+        let debug = guest_debug(&[GuestDebug::SingleStep]).unwrap();
+        assert_eq!(debug.control, KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_SINGLESTEP);
+
+        let debug = guest_debug(&[
+            GuestDebug::Breakpoint(0x8008_0002),
+            GuestDebug::SoftwareBreakpoints,
+        ])
+        .unwrap();
+        assert_eq!(
+            debug.control,
+            KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW | KVM_GUESTDBG_USE_SW_BP
+        );
+        assert_eq!(debug.arch.dbg_bcr[0], DBGBCR_ENABLED_EL1_EL0);
+        assert_eq!(debug.arch.dbg_bvr[0], 0x8008_0000);
+        assert_eq!(debug.arch.dbg_bcr[1], 0);
+
+        let too_many = vec![GuestDebug::Breakpoint(0); MAX_HW_BREAKPOINTS + 1];
+        assert_eq!(guest_debug(&too_many).unwrap_err().errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_mpidr_affinity() {
+        // RES1 bit 31, U bit 30 and MT bit 24 are dropped
+        assert_eq!(mpidr_affinity(0x8000_0000), 0);
+        assert_eq!(mpidr_affinity(0xc100_0001), 1);
+        // Aff3 is in bits 39:32, past the RES1 bit
+        assert_eq!(mpidr_affinity(0xff_8003_0201), 0xff_0003_0201);
+        assert_eq!(mpidr_affinity(u64::MAX), MPIDR_AFFINITY_MASK);
+    }
+
+    #[test]
+    fn test_system_off_stops_vcpu() {
+        let exit_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        let exit = VcpuExit::SystemEvent(kvm_bindings::KVM_SYSTEM_EVENT_SHUTDOWN, 0);
+        assert_eq!(
+            handle_exit(exit, &Bus::new(), &exit_evt),
+            Some(CpuExit::Shutdown)
+        );
+        assert_eq!(exit_evt.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_unhandled_exit_stops_vcpu() {
+        let exit_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        assert_eq!(
+            handle_exit(VcpuExit::Unknown, &Bus::new(), &exit_evt),
+            Some(CpuExit::Unhandled)
+        );
+        // mmio accesses keep the vcpu running, even when nothing is mapped there
+        assert_eq!(
+            handle_exit(VcpuExit::MmioWrite(0x1000, &[0]), &Bus::new(), &exit_evt),
+            None
+        );
+    }
 }
@@ -41,4 +41,18 @@ const fn arm64_sys_reg(op0: u64, op1: u64, crn: u64, crm: u64, op2: u64) -> u64
 
 // The MPIDR_EL1 register ID is defined in the kernel:
 // https://elixir.bootlin.com/linux/v4.20.17/source/arch/arm64/include/asm/sysreg.h#L135
-const MPIDR_EL1: u64 = arm64_sys_reg(3, 0, 0, 0, 5);
+pub const MPIDR_EL1: u64 = arm64_sys_reg(3, 0, 0, 0, 5);
+
+// Translation control registers, used to walk the guest's page tables.
+pub const SCTLR_EL1: u64 = arm64_sys_reg(3, 0, 1, 0, 0);
+pub const TTBR0_EL1: u64 = arm64_sys_reg(3, 0, 2, 0, 0);
+pub const TTBR1_EL1: u64 = arm64_sys_reg(3, 0, 2, 0, 1);
+pub const TCR_EL1: u64 = arm64_sys_reg(3, 0, 2, 0, 2);
+
+// SVE vector lengths, only writable before the vcpu's SVE is finalized.
+pub const SVE_VLS: u64 = KVM_REG_ARM64 | KVM_REG_ARM64_SVE as u64 | KVM_REG_SIZE_U512 | 0xffff;
+
+/// Size in bytes of the register `reg_id`.
+pub fn reg_size(reg_id: u64) -> usize {
+    1 << ((reg_id & KVM_REG_SIZE_MASK) >> KVM_REG_SIZE_SHIFT)
+}
@@ -0,0 +1,495 @@
+use std::os::unix::io::AsRawFd;
+use std::sync::{atomic::AtomicU32, Arc};
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::{debug, error, warn};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::vmm::memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use super::queue::{Queue, QueueError};
+use super::{
+    eventfd_write_retry, read_config_bytes, ActivateError, DeviceState, IrqTrigger, IrqType,
+    QuiesceError, VirtioDevice, TYPE_BALLOON,
+};
+
+pub const QUEUE_SIZE: u16 = 256;
+
+const INFLATE_INDEX: usize = 0;
+const DEFLATE_INDEX: usize = 1;
+const STATS_INDEX: usize = 2;
+
+// The stats queue exists, the driver reports memory statistics through it.
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
+
+// Size of a `struct virtio_balloon_stat`, a 16 bit tag followed by a 64 bit value, packed.
+const STAT_ENTRY_SIZE: u64 = 10;
+
+// Page frame numbers in the balloon queues always refer to 4K pages, whatever the guest's
+// page size is.
+const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+const VIRTIO_BALLOON_PAGE_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+
+/// Device specific configuration, as laid out in `struct virtio_balloon_config`.
+#[derive(Clone, Copy, Debug, Default, Versionize)]
+pub struct BalloonConfig {
+    /// Number of pages the host wants the balloon to hold.
+    pub num_pages: u32,
+    /// Number of pages the guest has actually handed over.
+    pub actual: u32,
+}
+
+/// Memory statistics the guest reported on the stats queue, in bytes for the memory amounts.
+/// Statistics the guest didn't report are `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BalloonStats {
+    pub swap_in: Option<u64>,
+    pub swap_out: Option<u64>,
+    pub major_faults: Option<u64>,
+    pub minor_faults: Option<u64>,
+    pub free_memory: Option<u64>,
+    pub total_memory: Option<u64>,
+    pub available_memory: Option<u64>,
+    pub disk_caches: Option<u64>,
+    pub hugetlb_allocations: Option<u64>,
+    pub hugetlb_failures: Option<u64>,
+}
+
+impl BalloonStats {
+    // Stores the value of one `virtio_balloon_stat`, unknown tags are ignored.
+    fn update(&mut self, tag: u16, value: u64) {
+        let stat = match tag {
+            0 => &mut self.swap_in,
+            1 => &mut self.swap_out,
+            2 => &mut self.major_faults,
+            3 => &mut self.minor_faults,
+            4 => &mut self.free_memory,
+            5 => &mut self.total_memory,
+            6 => &mut self.available_memory,
+            7 => &mut self.disk_caches,
+            8 => &mut self.hugetlb_allocations,
+            9 => &mut self.hugetlb_failures,
+            _ => return,
+        };
+        *stat = Some(value);
+    }
+}
+
+#[derive(Debug)]
+pub struct Balloon {
+    pub config: BalloonConfig,
+    pub queues: Vec<Queue>,
+    pub queue_events: [EventFd; 3],
+    pub irq_trigger: IrqTrigger,
+    pub activate_event: EventFd,
+    pub device_state: DeviceState,
+    pub acked_features: u64,
+    stats: BalloonStats,
+    // The driver keeps a single buffer on the stats queue, which the device holds on to until
+    // it wants new statistics. Handing it back makes the driver refill and resubmit it.
+    stats_desc_index: Option<u16>,
+}
+
+impl BalloonConfig {
+    fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&self.num_pages.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.actual.to_le_bytes());
+        bytes
+    }
+}
+
+impl Balloon {
+    pub fn new() -> Balloon {
+        let queues = vec![
+            Queue::new(QUEUE_SIZE),
+            Queue::new(QUEUE_SIZE),
+            Queue::new(QUEUE_SIZE),
+        ];
+        let queue_events = [
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        ];
+        let irq_trigger = IrqTrigger::new().unwrap();
+        let activate_event = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        Balloon {
+            config: BalloonConfig::default(),
+            queues,
+            queue_events,
+            irq_trigger,
+            activate_event,
+            device_state: DeviceState::Inactive,
+            acked_features: 0,
+            stats: BalloonStats::default(),
+            stats_desc_index: None,
+        }
+    }
+
+    /// The statistics the guest reported last.
+    pub fn stats(&self) -> BalloonStats {
+        self.stats
+    }
+
+    /// Hands the stats buffer back to the driver, which then reports fresh statistics.
+    /// Nothing happens while the driver hasn't submitted a buffer yet.
+    pub fn request_stats(&mut self) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let index = match self.stats_desc_index.take() {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        self.queues[STATS_INDEX].add_used(mem, index, 0)?;
+        if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+            error!("failed to trigger balloon irq: {:?}", err);
+        }
+
+        Ok(())
+    }
+
+    /// Parses the statistics the driver put on the stats queue, keeping its buffer for the
+    /// next `request_stats`.
+    pub fn process_stats_queue(&mut self) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let queue = &mut self.queues[STATS_INDEX];
+        // the driver only sets the queue up when it accepted VIRTIO_BALLOON_F_STATS_VQ
+        if !queue.ready {
+            return Ok(());
+        }
+
+        while let Some(head) = queue.pop(mem) {
+            // a driver only ever has one buffer out, return an older one it gave up on
+            if let Some(index) = self.stats_desc_index.replace(head.index) {
+                warn!("balloon driver submitted a stats buffer while one is held");
+                queue.add_used(mem, index, 0)?;
+            }
+
+            let mut stats = BalloonStats::default();
+            for desc in head {
+                if desc.is_write_only() {
+                    continue;
+                }
+
+                for index in 0..u64::from(desc.len) / STAT_ENTRY_SIZE {
+                    let entry = desc.addr.unchecked_add(index * STAT_ENTRY_SIZE);
+                    let tag = mem.read_obj::<u16>(entry);
+                    let value = mem.read_obj::<u64>(entry.unchecked_add(2));
+                    match (tag, value) {
+                        (Ok(tag), Ok(value)) => {
+                            stats.update(u16::from_le(tag), u64::from_le(value))
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            self.stats = stats;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the balloon size the guest should converge to and notifies the driver.
+    pub fn update_target(&mut self, num_pages: u32) -> std::io::Result<()> {
+        self.config.num_pages = num_pages;
+        self.irq_trigger.notify_config_change()
+    }
+
+    /// Releases the pages the guest put in the inflate queue and returns how many were
+    /// advised away.
+    pub fn process_inflate_queue(&mut self) -> Result<u32, QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(0),
+        };
+        let queue = &mut self.queues[INFLATE_INDEX];
+
+        let mut advised = 0;
+        let mut used_any = false;
+        while let Some(head) = queue.pop(mem) {
+            let index = head.index;
+
+            for desc in head {
+                if desc.is_write_only() {
+                    continue;
+                }
+
+                for offset in (0..u64::from(desc.len)).step_by(4) {
+                    let pfn = match mem.read_obj::<u32>(desc.addr.unchecked_add(offset)) {
+                        Ok(pfn) => pfn,
+                        Err(_) => break,
+                    };
+
+                    let addr = GuestAddress(u64::from(pfn) << VIRTIO_BALLOON_PFN_SHIFT);
+                    if remove_range(mem, addr, VIRTIO_BALLOON_PAGE_SIZE).is_ok() {
+                        advised += 1;
+                    }
+                }
+            }
+
+            queue.add_used(mem, index, 0)?;
+            used_any = true;
+        }
+
+        if used_any {
+            if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+                error!("failed to trigger balloon irq: {:?}", err);
+            }
+        }
+
+        Ok(advised)
+    }
+
+    /// Returns the deflated descriptors to the guest. The host doesn't need to do anything
+    /// for the pages themselves, they get faulted back in on the next guest access.
+    pub fn process_deflate_queue(&mut self) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let queue = &mut self.queues[DEFLATE_INDEX];
+
+        let mut used_any = false;
+        while let Some(head) = queue.pop(mem) {
+            queue.add_used(mem, head.index, 0)?;
+            used_any = true;
+        }
+
+        if used_any {
+            if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+                error!("failed to trigger balloon irq: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_activate_event(&self, ops: &mut EventOps) {
+        if let Err(err) = self.activate_event.read() {
+            panic!("Failed to consume balloon activate event: {:?}", err);
+        }
+
+        for queue_event in &self.queue_events {
+            if let Err(err) = ops.add(Events::new(queue_event, EventSet::IN)) {
+                panic!("Failed to register balloon queue event: {}", err);
+            }
+        }
+
+        if let Err(err) = ops.remove(Events::new(&self.activate_event, EventSet::IN)) {
+            panic!("Failed to unregister balloon activate event: {}", err);
+        }
+    }
+}
+
+/// Gives the host pages backing `[addr, addr + len)` back to the host.
+///
+/// Guest memory is a shared memfd mapping, where `MADV_DONTNEED` only drops the page tables
+/// and leaves the pages allocated in the file, so the range is punched out with `MADV_REMOVE`.
+/// Private mappings (a restored snapshot) don't support that and fall back to
+/// `MADV_DONTNEED`, which drops the private copies.
+fn remove_range(mem: &GuestMemoryMmap, addr: GuestAddress, len: u64) -> std::io::Result<()> {
+    // Rejects ranges that are out of bounds or span more than one region.
+    if mem.get_slice(addr, len as usize).is_err() {
+        return Err(std::io::Error::from_raw_os_error(libc::EFAULT));
+    }
+    let host_addr = mem
+        .get_host_address(addr)
+        .map_err(|_| std::io::Error::from_raw_os_error(libc::EFAULT))?;
+
+    // SAFETY: The range was checked to be inside a single region of guest memory, which
+    // stays mapped for the lifetime of `mem`.
+    let ret = unsafe { libc::madvise(host_addr.cast(), len as usize, libc::MADV_REMOVE) };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    // SAFETY: Same as above.
+    let ret = unsafe { libc::madvise(host_addr.cast(), len as usize, libc::MADV_DONTNEED) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+impl VirtioDevice for Balloon {
+    fn device_type(&self) -> u32 {
+        TYPE_BALLOON
+    }
+
+    fn avail_features(&self) -> u64 {
+        1 << VIRTIO_BALLOON_F_STATS_VQ
+    }
+
+    fn ack_features_by_page(&mut self, page: u32, value: u32) {
+        let features = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => return,
+        };
+        // the driver can't accept anything that wasn't offered
+        self.acked_features |= features & self.avail_features();
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_events
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicU32> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn config_generation(&self) -> Arc<AtomicU32> {
+        self.irq_trigger.config_generation.clone()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> Result<(), ActivateError> {
+        eventfd_write_retry(&self.activate_event, 1).map_err(ActivateError::EventFd)?;
+        self.device_state = DeviceState::Activated(mem);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_bytes(&self.config.to_bytes(), offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // The driver only writes `actual`, `num_pages` is owned by the host.
+        if let (4, Ok(bytes)) = (offset, data.try_into()) {
+            self.config.actual = u32::from_le_bytes(bytes);
+        }
+    }
+
+    fn quiesce(&mut self) -> Result<(), QuiesceError> {
+        self.process_inflate_queue().map_err(QuiesceError::Queue)?;
+        self.process_deflate_queue().map_err(QuiesceError::Queue)?;
+        // A held stats buffer isn't part of the saved state, returning it lets the driver
+        // resubmit it after a restore.
+        self.request_stats().map_err(QuiesceError::Queue)
+    }
+}
+
+impl MutEventSubscriber for Balloon {
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+
+        if source == self.activate_event.as_raw_fd() {
+            self.process_activate_event(ops);
+        } else if source == self.queue_events[INFLATE_INDEX].as_raw_fd() {
+            let _ = self.queue_events[INFLATE_INDEX].read();
+            if let Err(err) = self.process_inflate_queue() {
+                panic!("Failed to process balloon inflate queue: {:?}", err);
+            }
+        } else if source == self.queue_events[DEFLATE_INDEX].as_raw_fd() {
+            let _ = self.queue_events[DEFLATE_INDEX].read();
+            if let Err(err) = self.process_deflate_queue() {
+                panic!("Failed to process balloon deflate queue: {:?}", err);
+            }
+        } else if source == self.queue_events[STATS_INDEX].as_raw_fd() {
+            let _ = self.queue_events[STATS_INDEX].read();
+            if let Err(err) = self.process_stats_queue() {
+                panic!("Failed to process balloon stats queue: {:?}", err);
+            }
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        debug!("balloon device init called");
+        if let Err(err) = ops.add(Events::new(&self.activate_event, EventSet::IN)) {
+            panic!("Failed to register activate event: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vmm::device::queue::TestQueue;
+    use crate::vmm::layout::DRAM_MEM_START;
+    use crate::vmm::memory::test_guest_memory;
+
+    use super::*;
+
+    #[test]
+    fn test_inflate_advises_pages() {
+        let mem = test_guest_memory(0x10000);
+        let mut balloon = Balloon::new();
+        let mut inflate = TestQueue::new(&mem, QUEUE_SIZE);
+        balloon.queues[INFLATE_INDEX] = inflate.queue();
+        balloon.activate(mem.clone()).unwrap();
+
+        // three pages at the end of guest memory, and one past it that can't be advised
+        let first_pfn = ((DRAM_MEM_START + 0xd000) >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
+        let pfns: Vec<u8> = (first_pfn..first_pfn + 4)
+            .flat_map(|pfn| pfn.to_le_bytes())
+            .collect();
+        let pfns_addr = GuestAddress(DRAM_MEM_START + 0x8000);
+        mem.write_slice(&pfns, pfns_addr).unwrap();
+        inflate.add_chain(&[(pfns_addr, pfns.len() as u32, 0)]);
+
+        assert_eq!(balloon.process_inflate_queue().unwrap(), 3);
+        assert_eq!(inflate.used_idx(), 1);
+    }
+
+    #[test]
+    fn test_stats_parsed() {
+        let mem = test_guest_memory(0x10000);
+        let mut balloon = Balloon::new();
+        let mut stats_queue = TestQueue::new(&mem, QUEUE_SIZE);
+        balloon.queues[STATS_INDEX] = stats_queue.queue();
+        balloon.activate(mem.clone()).unwrap();
+
+        // free and total memory, swap out, and a tag the device doesn't know
+        let entries: Vec<u8> = [(4u16, 1 << 20), (5, 4 << 20), (1, 7), (42, 1)]
+            .iter()
+            .flat_map(|(tag, value): &(u16, u64)| {
+                tag.to_le_bytes().into_iter().chain(value.to_le_bytes())
+            })
+            .collect();
+        let entries_addr = GuestAddress(DRAM_MEM_START + 0x8000);
+        mem.write_slice(&entries, entries_addr).unwrap();
+        let head = stats_queue.add_chain(&[(entries_addr, entries.len() as u32, 0)]);
+
+        balloon.process_stats_queue().unwrap();
+
+        assert_eq!(
+            balloon.stats(),
+            BalloonStats {
+                free_memory: Some(1 << 20),
+                total_memory: Some(4 << 20),
+                swap_out: Some(7),
+                ..Default::default()
+            }
+        );
+        // the buffer is held until the next request
+        assert_eq!(stats_queue.used_idx(), 0);
+        balloon.request_stats().unwrap();
+        assert_eq!(stats_queue.used_idx(), 1);
+        assert_eq!(stats_queue.used_elem(0), (u32::from(head), 0));
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use serde::Deserialize;
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
+use versionize_derive::Versionize;
+use vmm_sys_util::eventfd::EventFd;
+
+// Bytes written at once by the default `write_zeroes`.
+const ZERO_CHUNK: usize = 64 << 10;
+
+/// Storage behind a block device, addressed in bytes.
+pub trait DiskBackend: Debug {
+    /// Fills `buf` with the bytes starting at `offset`.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Writes all of `buf` starting at `offset`.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Makes the writes done so far durable.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Tells the backend `len` bytes at `offset` aren't needed anymore, their content is
+    /// undefined afterwards. Discarding is only a hint, so by default nothing happens.
+    fn discard(&mut self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Zeroes `len` bytes at `offset`. With `unmap` the backend may deallocate them as
+    /// well, as long as they read back as zeroes.
+    fn write_zeroes(&mut self, offset: u64, len: u64, _unmap: bool) -> io::Result<()> {
+        write_zero_chunks(self, offset, len)
+    }
+
+    /// Size of the disk in bytes.
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Backends able to run reads and writes in the background return themselves here, the
+    /// block device then submits its reads and writes without waiting for them.
+    fn as_async(&mut self) -> Option<&mut dyn AsyncDisk> {
+        None
+    }
+}
+
+/// A backend that queues reads and writes and reports their completion later.
+///
+/// Operations are identified by the `user_data` passed when preparing them.
+pub trait AsyncDisk {
+    /// Signalled whenever some operations complete.
+    fn completion_event(&self) -> &EventFd;
+
+    /// Queues a read of `len` bytes at `offset` into `buf`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid for writes of `len` bytes until the operation completes.
+    unsafe fn prepare_read(
+        &mut self,
+        offset: u64,
+        buf: *mut u8,
+        len: u32,
+        user_data: u64,
+    ) -> io::Result<()>;
+
+    /// Queues a write of `len` bytes from `buf` at `offset`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid for reads of `len` bytes until the operation completes.
+    unsafe fn prepare_write(
+        &mut self,
+        offset: u64,
+        buf: *const u8,
+        len: u32,
+        user_data: u64,
+    ) -> io::Result<()>;
+
+    /// Starts the prepared operations, then waits until at least `min_complete` operations
+    /// have completed.
+    fn submit(&mut self, min_complete: usize) -> io::Result<()>;
+
+    /// Takes the next completed operation, with the number of bytes it transferred.
+    fn pop_completion(&mut self) -> Option<(u64, io::Result<u32>)>;
+
+    /// Number of operations prepared or submitted that didn't complete yet.
+    fn in_flight(&self) -> usize;
+}
+
+/// How a block device accesses its backing file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Versionize)]
+pub enum IoEngine {
+    /// Requests are executed one after the other with blocking reads and writes.
+    #[default]
+    Sync,
+    /// Reads and writes go through an io_uring and complete in the background.
+    Async,
+}
+
+impl DiskBackend for File {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write_all_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        fallocate(self, libc::FALLOC_FL_PUNCH_HOLE, offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        // a punched hole reads back as zeroes, not every filesystem supports either mode
+        let mode = if unmap {
+            libc::FALLOC_FL_PUNCH_HOLE
+        } else {
+            libc::FALLOC_FL_ZERO_RANGE
+        };
+        match fallocate(self, mode, offset, len) {
+            Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zero_chunks(self, offset, len)
+            }
+            result => result,
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+fn write_zero_chunks<D: DiskBackend + ?Sized>(
+    disk: &mut D,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    let zeroes = vec![0; ZERO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let chunk = (len - done).min(ZERO_CHUNK as u64) as usize;
+        disk.write_at(&zeroes[..chunk], offset + done)?;
+        done += chunk as u64;
+    }
+
+    Ok(())
+}
+
+// The file keeps its size, the range is deallocated or zeroed in place.
+fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+    // SAFETY: Plain syscall on a valid fd, the result is checked below.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Disk kept in host memory, its content is lost when the device is dropped.
+#[derive(Debug, Default)]
+pub struct MemDisk {
+    pub data: Vec<u8>,
+}
+
+impl MemDisk {
+    pub fn new(size: usize) -> MemDisk {
+        MemDisk {
+            data: vec![0; size],
+        }
+    }
+
+    fn range(&self, offset: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+        usize::try_from(offset)
+            .ok()
+            .and_then(|start| Some(start..start.checked_add(len)?))
+            .filter(|range| range.end <= self.data.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+}
+
+impl DiskBackend for MemDisk {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let range = self.range(offset, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+}
@@ -1,34 +1,695 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::os::unix::io::AsRawFd;
 use std::sync::{atomic::AtomicU32, Arc};
 
-use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use event_manager::{Error as EventManagerError, EventOps, EventSet, Events, MutEventSubscriber};
+use log::{debug, error, warn};
+use vm_memory::bitmap::Bitmap;
+use vm_memory::GuestMemoryError;
 use vmm_sys_util::eventfd::EventFd;
 
-use super::{IrqTrigger, VirtioDevice};
+use crate::vmm::memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use crate::vmm::metrics::DeviceMetrics;
+use crate::vmm::rate_limiter::{RateLimiter, RateLimiterConfig};
+
+use super::descriptor::DescriptorChain;
+use super::queue::{Queue, QueueError};
+use super::{
+    eventfd_write_retry, read_config_bytes, ActivateError, DeviceState, IrqTrigger, IrqType,
+    QuiesceError, VirtioDevice, TYPE_BLOCK, VIRTIO_F_IN_ORDER, VIRTIO_F_VERSION_1,
+};
+
+use self::backend::{AsyncDisk, DiskBackend};
+
+pub mod backend;
+pub mod uring;
+
+/// Queue size of devices that don't configure one.
+pub const QUEUE_SIZE: u16 = 256;
+
+const SECTOR_SHIFT: u8 = 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+/// Length of the id returned by `VIRTIO_BLK_T_GET_ID`, shorter ids are padded with zeroes.
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// The device supports `VIRTIO_BLK_T_FLUSH`, without it the driver assumes a write-through cache.
+const VIRTIO_BLK_F_FLUSH: u32 = 9;
+const VIRTIO_BLK_F_DISCARD: u32 = 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+
+/// Segments accepted in a single discard or write zeroes request.
+const MAX_DISCARD_SEGMENTS: u32 = 1;
+/// Set in a write zeroes segment when the range may be deallocated too.
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+
+// Offsets in `struct virtio_blk_config`, which is laid out for the fields up to the write
+// zeroes ones.
+const CONFIG_SPACE_SIZE: usize = 60;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+const CONFIG_MAX_DISCARD_SEG: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT: usize = 44;
+const CONFIG_MAX_WRITE_ZEROES_SECTORS: usize = 48;
+const CONFIG_MAX_WRITE_ZEROES_SEG: usize = 52;
+const CONFIG_WRITE_ZEROES_MAY_UNMAP: usize = 56;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Header every block request starts with, as laid out in `struct virtio_blk_outhdr`.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// SAFETY: `RequestHeader` is a POD and contains no padding.
+unsafe impl ByteValued for RequestHeader {}
+
+/// Range of a discard or write zeroes request, as laid out in
+/// `struct virtio_blk_discard_write_zeroes`.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// SAFETY: `DiscardSegment` is a POD and contains no padding.
+unsafe impl ByteValued for DiscardSegment {}
+
+/// A request whose reads or writes were handed to an `AsyncDisk` and didn't all complete yet.
+#[derive(Debug)]
+struct PendingRequest {
+    request_type: u32,
+    /// Operations of the request still in flight.
+    in_flight: usize,
+    status_addr: GuestAddress,
+    /// Bytes written to guest memory once the request succeeds.
+    len: u32,
+    failed: bool,
+}
+
+#[derive(Debug)]
+enum RequestError {
+    /// The descriptor chain doesn't have the header, data, status layout.
+    InvalidChain,
+    GuestMemory(GuestMemoryError),
+    Io(io::Error),
+    Unsupported(u32),
+    /// More discard or write zeroes segments than `MAX_DISCARD_SEGMENTS`.
+    TooManySegments(usize),
+    /// A segment reaches past the end of the disk.
+    OutOfRange,
+}
 
 #[derive(Debug)]
 pub struct Block {
+    pub disk: Box<dyn DiskBackend + Send>,
+    pub queues: Vec<Queue>,
     pub queue_events: [EventFd; 1],
     pub irq_trigger: IrqTrigger,
     pub activate_event: EventFd,
+    /// Signalled once the device thread handled `activate_event`.
+    pub ready_event: EventFd,
+    pub device_state: DeviceState,
+    pub metrics: Arc<DeviceMetrics>,
+    /// Size of the disk in 512 byte sectors.
+    pub capacity: u64,
+    pub rate_limiter: RateLimiter,
+    /// The drive id, truncated or zero padded, returned to `VIRTIO_BLK_T_GET_ID` requests.
+    pub device_id: [u8; VIRTIO_BLK_ID_BYTES],
+    pub acked_features: u64,
+    /// Whether requests complete in the order they were made available, which is the case
+    /// unless the disk is asynchronous.
+    in_order: bool,
+    /// Requests submitted to an asynchronous disk, by descriptor head index.
+    pending: HashMap<u16, PendingRequest>,
 }
 
 impl Block {
-    pub fn new() -> Block {
+    pub fn new(
+        id: &str,
+        mut disk: Box<dyn DiskBackend + Send>,
+        rate_limiter: RateLimiterConfig,
+        queue_size: u16,
+    ) -> Block {
         let irq_trigger = IrqTrigger::new().unwrap();
+        let queues = vec![Queue::new(queue_size)];
         let queue_events = [EventFd::new(libc::EFD_NONBLOCK).unwrap()];
         let activate_event = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let ready_event = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let metrics = irq_trigger.metrics.clone();
+
+        let len = match disk.len() {
+            Ok(len) => len,
+            Err(err) => panic!("Failed to get the disk size: {:?}", err),
+        };
+        if len % (1 << SECTOR_SHIFT) != 0 {
+            warn!("disk size is not a multiple of the sector size, ignoring the last bytes");
+        }
+        let capacity = len >> SECTOR_SHIFT;
+        let in_order = disk.as_async().is_none();
+        let rate_limiter = RateLimiter::new(rate_limiter).unwrap();
+
+        let mut device_id = [0; VIRTIO_BLK_ID_BYTES];
+        let id_len = id.len().min(VIRTIO_BLK_ID_BYTES);
+        device_id[..id_len].copy_from_slice(&id.as_bytes()[..id_len]);
 
         Block {
+            disk,
+            queues,
             queue_events,
             irq_trigger,
             activate_event,
+            ready_event,
+            device_state: DeviceState::Inactive,
+            metrics,
+            capacity,
+            rate_limiter,
+            device_id,
+            acked_features: 0,
+            in_order,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Besides the capacity only the discard and write zeroes limits are filled in, the other
+    // fields belong to features the device doesn't offer.
+    fn config_space(&self) -> [u8; CONFIG_SPACE_SIZE] {
+        let mut config = [0; CONFIG_SPACE_SIZE];
+        let mut put_u32 = |offset: usize, value: u32| {
+            config[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        put_u32(CONFIG_MAX_DISCARD_SECTORS, u32::MAX);
+        put_u32(CONFIG_MAX_DISCARD_SEG, MAX_DISCARD_SEGMENTS);
+        put_u32(CONFIG_DISCARD_SECTOR_ALIGNMENT, 1);
+        put_u32(CONFIG_MAX_WRITE_ZEROES_SECTORS, u32::MAX);
+        put_u32(CONFIG_MAX_WRITE_ZEROES_SEG, MAX_DISCARD_SEGMENTS);
+        config[CONFIG_WRITE_ZEROES_MAY_UNMAP] = 1;
+        config[..8].copy_from_slice(&self.capacity.to_le_bytes());
+
+        config
+    }
+
+    /// Executes every request the driver made available. Requests are handled synchronously,
+    /// so once this returns no descriptor is left popped without being added to the used ring,
+    /// unless the disk is asynchronous: its reads and writes are only submitted here and
+    /// completed by `complete_async`.
+    /// Stops early when the rate limiter runs out of budget; the remaining requests are
+    /// picked up again once its timer fires.
+    pub fn process_queue(&mut self) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let queue = &mut self.queues[0];
+
+        let mut completed = 0;
+        // requests executed here, written to the used ring at once
+        let mut used = Vec::new();
+        while let Some(head) = queue.pop(mem) {
+            let index = head.index;
+            let descs: Vec<DescriptorChain> = head.into_iter().collect();
+
+            if !self.rate_limiter.consume(data_len(&descs)) {
+                queue.undo_pop();
+                break;
+            }
+
+            if let Some(disk) = self.disk.as_async() {
+                if let Some(request) = submit_request(disk, mem, index, &descs) {
+                    self.pending.insert(index, request);
+                    continue;
+                }
+                // other requests run synchronously, after the reads and writes before them
+                completed +=
+                    complete_requests(disk, &mut self.pending, mem, queue, &self.metrics, true)?;
+            }
+
+            let len = execute_request(
+                self.disk.as_mut(),
+                &self.device_id,
+                mem,
+                descs,
+                &self.metrics,
+            );
+
+            used.push((index, len));
+            self.metrics.requests_completed.inc();
+        }
+        queue.add_used_batch(mem, &used)?;
+        completed += used.len();
+
+        // operations that couldn't be submitted stay queued for the next submission
+        if let Some(disk) = self.disk.as_async() {
+            if let Err(err) = disk.submit(0) {
+                error!("failed to submit block io: {:?}", err);
+            }
+        }
+
+        if completed > 0 {
+            if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+                error!("failed to trigger block irq: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the requests an asynchronous disk finished to the used ring. With `wait` it first
+    /// waits for every read and write in flight.
+    pub fn complete_async(&mut self, wait: bool) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let disk = match self.disk.as_async() {
+            Some(disk) => disk,
+            None => return Ok(()),
+        };
+
+        let completed = complete_requests(
+            disk,
+            &mut self.pending,
+            mem,
+            &mut self.queues[0],
+            &self.metrics,
+            wait,
+        )?;
+        if completed > 0 {
+            if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+                error!("failed to trigger block irq: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops servicing the queue after a failure the driver can only recover from with a
+    /// reset, and tells it through a config change.
+    fn mark_broken(&mut self, err: &dyn Debug) {
+        error!("block device failed: {:?}", err);
+        // operations in flight still write to guest memory, a reset won't wait for them once
+        // the device is broken
+        if let Err(err) = self.complete_async(true) {
+            error!("failed to complete block requests: {:?}", err);
+        }
+        self.device_state = DeviceState::Broken;
+        if let Err(err) = self.irq_trigger.notify_config_change() {
+            error!("failed to trigger block irq: {:?}", err);
+        }
+    }
+
+    fn process_activate_event(&mut self, ops: &mut EventOps) {
+        if let Err(err) = self.activate_event.read() {
+            panic!("Failed to consume block activate event: {:?}", err);
+        }
+
+        // After a reset the device is activated again with these still registered, the
+        // activate event stays registered for the same reason.
+        match ops.add(Events::new(&self.queue_events[0], EventSet::IN)) {
+            Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+            Err(err) => panic!("Failed to register block queue event: {}", err),
+        }
+
+        match ops.add(Events::new(&self.rate_limiter, EventSet::IN)) {
+            Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+            Err(err) => panic!("Failed to register block rate limiter event: {}", err),
+        }
+
+        let result = match self.disk.as_async() {
+            Some(disk) => ops.add(Events::new(disk.completion_event(), EventSet::IN)),
+            None => Ok(()),
+        };
+        match result {
+            Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+            Err(err) => self.mark_broken(&err),
+        }
+
+        if let Err(err) = eventfd_write_retry(&self.ready_event, 1) {
+            error!("failed to signal block device ready: {:?}", err);
         }
     }
 }
 
+/// Queues the reads or writes of an `IN` or `OUT` request on `disk`. Returns `None`, without
+/// queuing anything, for other requests, for malformed ones and when the disk can't queue
+/// any of the operations, `execute_request` handles those.
+fn submit_request(
+    disk: &mut dyn AsyncDisk,
+    mem: &GuestMemoryMmap,
+    index: u16,
+    descs: &[DescriptorChain],
+) -> Option<PendingRequest> {
+    let (header_desc, data_descs, status_desc) = match descs {
+        [header, data @ .., status]
+            if !data.is_empty()
+                && is_header(header)
+                && status.is_write_only()
+                && status.len >= 1 =>
+        {
+            (header, data, status)
+        }
+        _ => return None,
+    };
+    let header: RequestHeader = mem.read_obj(header_desc.addr).ok()?;
+    let is_read = match header.request_type {
+        VIRTIO_BLK_T_IN => true,
+        VIRTIO_BLK_T_OUT => false,
+        _ => return None,
+    };
+
+    // resolve every buffer first so a bad descriptor doesn't leave the request half queued
+    let mut bufs = Vec::with_capacity(data_descs.len());
+    for desc in data_descs {
+        if desc.is_write_only() != is_read {
+            return None;
+        }
+        let slice = mem.get_slice(desc.addr, desc.len as usize).ok()?;
+        if is_read {
+            slice.bitmap().mark_dirty(0, desc.len as usize);
+        }
+        bufs.push((slice.ptr_guard_mut().as_ptr(), desc.len));
+    }
+
+    // the head index and length of each operation are packed in its user data
+    let mut offset = header.sector << SECTOR_SHIFT;
+    let mut len = 0;
+    let mut in_flight = 0;
+    let mut failed = false;
+    for (buf, buf_len) in bufs {
+        let user_data = (u64::from(index) << 32) | u64::from(buf_len);
+        // SAFETY: Guest memory stays mapped while the device is active, and the device waits
+        // for the operations in flight before it is reset.
+        let result = unsafe {
+            if is_read {
+                disk.prepare_read(offset, buf, buf_len, user_data)
+            } else {
+                disk.prepare_write(offset, buf, buf_len, user_data)
+            }
+        };
+        if let Err(err) = result {
+            warn!("failed to queue block io: {:?}", err);
+            // nothing in flight yet, it runs synchronously instead
+            if in_flight == 0 {
+                return None;
+            }
+            // fails once the operations already queued complete
+            failed = true;
+            break;
+        }
+        in_flight += 1;
+
+        offset += u64::from(buf_len);
+        if is_read {
+            len += buf_len;
+        }
+    }
+
+    Some(PendingRequest {
+        request_type: header.request_type,
+        in_flight,
+        status_addr: status_desc.addr,
+        len,
+        failed,
+    })
+}
+
+/// Adds the requests whose operations all completed to the used ring, after writing their
+/// status. Returns how many were added.
+fn complete_requests(
+    disk: &mut dyn AsyncDisk,
+    pending: &mut HashMap<u16, PendingRequest>,
+    mem: &GuestMemoryMmap,
+    queue: &mut Queue,
+    metrics: &DeviceMetrics,
+    wait: bool,
+) -> Result<usize, QueueError> {
+    if wait && disk.in_flight() > 0 {
+        if let Err(err) = disk.submit(disk.in_flight()) {
+            error!("failed to wait for block io: {:?}", err);
+        }
+    }
+
+    let mut completed = 0;
+    while let Some((user_data, result)) = disk.pop_completion() {
+        let index = (user_data >> 32) as u16;
+        let expected = user_data as u32;
+        let request = match pending.get_mut(&index) {
+            Some(request) => request,
+            None => {
+                warn!("completion of an unknown block request: {}", index);
+                continue;
+            }
+        };
+
+        request.in_flight -= 1;
+        match result {
+            Ok(len) if len == expected => match request.request_type {
+                VIRTIO_BLK_T_IN => metrics.read_bytes.add(u64::from(len)),
+                _ => metrics.write_bytes.add(u64::from(len)),
+            },
+            Ok(len) => {
+                warn!("short block transfer: {} of {} bytes", len, expected);
+                request.failed = true;
+            }
+            Err(err) => {
+                warn!("block request failed: {:?}", err);
+                request.failed = true;
+            }
+        }
+        if request.in_flight > 0 {
+            continue;
+        }
+
+        let request = pending.remove(&index).unwrap();
+        let (status, len) = match request.failed {
+            false => (VIRTIO_BLK_S_OK, request.len),
+            true => (VIRTIO_BLK_S_IOERR, 0),
+        };
+        let len = match mem.write_obj(status, request.status_addr) {
+            Ok(()) => len + 1,
+            Err(_) => len,
+        };
+
+        queue.add_used(mem, index, len)?;
+        metrics.requests_completed.inc();
+        completed += 1;
+    }
+
+    Ok(completed)
+}
+
+/// Whether `desc` can hold a request header: the driver has to make all of it readable.
+fn is_header(desc: &DescriptorChain) -> bool {
+    !desc.is_write_only() && desc.len as usize >= std::mem::size_of::<RequestHeader>()
+}
+
+/// Number of bytes in the data descriptors of a request, between the header and the status.
+fn data_len(descs: &[DescriptorChain]) -> u64 {
+    match descs {
+        [_, data @ .., _] => data.iter().map(|desc| u64::from(desc.len)).sum(),
+        _ => 0,
+    }
+}
+
+/// Executes the request made of the descriptors in `descs` and writes its status back to
+/// the guest. Returns the number of bytes written to guest memory.
+fn execute_request(
+    disk: &mut dyn DiskBackend,
+    device_id: &[u8; VIRTIO_BLK_ID_BYTES],
+    mem: &GuestMemoryMmap,
+    mut descs: Vec<DescriptorChain>,
+    metrics: &DeviceMetrics,
+) -> u32 {
+    // A request is a read only header, the data descriptors and a write only status byte.
+    // Flush requests have no data descriptors. Any other chain fails with an IOERR status,
+    // unless it doesn't even end in a place to write that status to.
+    let status_desc = match descs.pop() {
+        Some(desc) if desc.is_write_only() && desc.len >= 1 => desc,
+        _ => return 0,
+    };
+
+    let (status, len) = match execute(disk, device_id, mem, &descs, metrics) {
+        Ok(len) => (VIRTIO_BLK_S_OK, len),
+        Err(RequestError::Unsupported(_)) => (VIRTIO_BLK_S_UNSUPP, 0),
+        Err(err) => {
+            warn!("block request failed: {:?}", err);
+            (VIRTIO_BLK_S_IOERR, 0)
+        }
+    };
+
+    match mem.write_obj(status, status_desc.addr) {
+        Ok(()) => len + 1,
+        Err(_) => len,
+    }
+}
+
+fn execute(
+    disk: &mut dyn DiskBackend,
+    device_id: &[u8; VIRTIO_BLK_ID_BYTES],
+    mem: &GuestMemoryMmap,
+    descs: &[DescriptorChain],
+    metrics: &DeviceMetrics,
+) -> Result<u32, RequestError> {
+    let (header_desc, data_descs) = descs.split_first().ok_or(RequestError::InvalidChain)?;
+    if !is_header(header_desc) {
+        return Err(RequestError::InvalidChain);
+    }
+    let header: RequestHeader = mem
+        .read_obj(header_desc.addr)
+        .map_err(RequestError::GuestMemory)?;
+
+    let mut offset = header.sector << SECTOR_SHIFT;
+    let mut len = 0;
+    match header.request_type {
+        VIRTIO_BLK_T_IN => {
+            for desc in data_descs {
+                if !desc.is_write_only() {
+                    return Err(RequestError::InvalidChain);
+                }
+                let mut buf = vec![0; desc.len as usize];
+                disk.read_at(&mut buf, offset).map_err(RequestError::Io)?;
+                mem.write_slice(&buf, desc.addr)
+                    .map_err(RequestError::GuestMemory)?;
+                metrics.read_bytes.add(u64::from(desc.len));
+
+                offset += u64::from(desc.len);
+                len += desc.len;
+            }
+        }
+        VIRTIO_BLK_T_OUT => {
+            for desc in data_descs {
+                if desc.is_write_only() {
+                    return Err(RequestError::InvalidChain);
+                }
+                let mut buf = vec![0; desc.len as usize];
+                mem.read_slice(&mut buf, desc.addr)
+                    .map_err(RequestError::GuestMemory)?;
+                disk.write_at(&buf, offset).map_err(RequestError::Io)?;
+                metrics.write_bytes.add(u64::from(desc.len));
+
+                offset += u64::from(desc.len);
+            }
+        }
+        VIRTIO_BLK_T_FLUSH => disk.flush().map_err(RequestError::Io)?,
+        request_type @ (VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES) => {
+            let segments = read_segments(mem, data_descs)?;
+            if segments.len() > MAX_DISCARD_SEGMENTS as usize {
+                return Err(RequestError::TooManySegments(segments.len()));
+            }
+
+            let disk_len = disk.len().map_err(RequestError::Io)?;
+            for segment in segments {
+                let offset = segment.sector << SECTOR_SHIFT;
+                let len = u64::from(segment.num_sectors) << SECTOR_SHIFT;
+                if offset.checked_add(len).is_none_or(|end| end > disk_len) {
+                    return Err(RequestError::OutOfRange);
+                }
+
+                if request_type == VIRTIO_BLK_T_DISCARD {
+                    if segment.flags != 0 {
+                        return Err(RequestError::Unsupported(request_type));
+                    }
+                    disk.discard(offset, len).map_err(RequestError::Io)?;
+                } else {
+                    if segment.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+                        return Err(RequestError::Unsupported(request_type));
+                    }
+                    let unmap = segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                    disk.write_zeroes(offset, len, unmap)
+                        .map_err(RequestError::Io)?;
+                    metrics.write_bytes.add(len);
+                }
+            }
+        }
+        VIRTIO_BLK_T_GET_ID => {
+            let mut id = &device_id[..];
+            for desc in data_descs {
+                if !desc.is_write_only() {
+                    return Err(RequestError::InvalidChain);
+                }
+                let chunk = id.len().min(desc.len as usize);
+                mem.write_slice(&id[..chunk], desc.addr)
+                    .map_err(RequestError::GuestMemory)?;
+
+                id = &id[chunk..];
+                len += chunk as u32;
+            }
+        }
+        request_type => return Err(RequestError::Unsupported(request_type)),
+    }
+
+    Ok(len)
+}
+
+/// Reads the segments of a discard or write zeroes request from its read only data
+/// descriptors.
+fn read_segments(
+    mem: &GuestMemoryMmap,
+    data_descs: &[DescriptorChain],
+) -> Result<Vec<DiscardSegment>, RequestError> {
+    let segment_size = std::mem::size_of::<DiscardSegment>();
+
+    let mut segments = Vec::new();
+    for desc in data_descs {
+        if desc.is_write_only() || !(desc.len as usize).is_multiple_of(segment_size) {
+            return Err(RequestError::InvalidChain);
+        }
+        for i in 0..desc.len as usize / segment_size {
+            let addr = desc.addr.unchecked_add((i * segment_size) as u64);
+            segments.push(mem.read_obj(addr).map_err(RequestError::GuestMemory)?);
+        }
+    }
+
+    Ok(segments)
+}
+
 impl VirtioDevice for Block {
     fn device_type(&self) -> u32 {
-        2
+        TYPE_BLOCK
+    }
+
+    fn avail_features(&self) -> u64 {
+        let mut features = (1 << VIRTIO_F_VERSION_1)
+            | (1 << VIRTIO_BLK_F_FLUSH)
+            | (1 << VIRTIO_BLK_F_DISCARD)
+            | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+        if self.in_order {
+            features |= 1 << VIRTIO_F_IN_ORDER;
+        }
+        features
+    }
+
+    fn ack_features_by_page(&mut self, page: u32, value: u32) {
+        let features = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => return,
+        };
+        // the driver can't accept anything that wasn't offered
+        self.acked_features |= features & self.avail_features();
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
     }
 
     fn queue_events(&self) -> &[EventFd] {
@@ -42,17 +703,645 @@ impl VirtioDevice for Block {
     fn interrupt_status(&self) -> Arc<AtomicU32> {
         self.irq_trigger.irq_status.clone()
     }
+
+    fn config_generation(&self) -> Arc<AtomicU32> {
+        self.irq_trigger.config_generation.clone()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> Result<(), ActivateError> {
+        if self.acked_features & (1 << VIRTIO_F_IN_ORDER) != 0 {
+            self.queues[0].enable_in_order();
+        }
+        eventfd_write_retry(&self.activate_event, 1).map_err(ActivateError::EventFd)?;
+        self.device_state = DeviceState::Activated(mem);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn ready_event(&self) -> Option<&EventFd> {
+        Some(&self.ready_event)
+    }
+
+    fn is_broken(&self) -> bool {
+        self.device_state.is_broken()
+    }
+
+    fn reset(&mut self) -> bool {
+        // operations in flight still write to guest memory, wait for them
+        if let Err(err) = self.complete_async(true) {
+            error!("failed to complete block requests before reset: {:?}", err);
+        }
+        self.pending.clear();
+        self.queues = vec![Queue::new(self.queues[0].get_max_size())];
+        self.acked_features = 0;
+        self.device_state = DeviceState::Inactive;
+        // drop kicks the driver made before the reset
+        let _ = self.queue_events[0].read();
+
+        true
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_bytes(&self.config_space(), offset, data);
+    }
+
+    fn quiesce(&mut self) -> Result<(), QuiesceError> {
+        self.process_queue().map_err(QuiesceError::Queue)?;
+        self.complete_async(true).map_err(QuiesceError::Queue)?;
+        self.disk.flush().map_err(QuiesceError::Io)
+    }
+}
+
+// A VM dropped without being quiesced would otherwise leave the guest's last writes in the
+// host's page cache.
+impl Drop for Block {
+    fn drop(&mut self) {
+        // operations in flight still write to the disk
+        if let Err(err) = self.complete_async(true) {
+            error!("failed to complete block requests: {:?}", err);
+        }
+        if let Err(err) = self.disk.flush() {
+            error!("failed to flush block device: {:?}", err);
+        }
+    }
 }
 
 impl MutEventSubscriber for Block {
     fn process(&mut self, event: Events, ops: &mut EventOps) {
-        todo!();
+        let source = event.fd();
+
+        if source == self.activate_event.as_raw_fd() {
+            self.process_activate_event(ops);
+        } else if source == self.queue_events[0].as_raw_fd() {
+            let _ = self.queue_events[0].read();
+            if let Err(err) = self.process_queue() {
+                self.mark_broken(&err);
+            }
+        } else if self
+            .disk
+            .as_async()
+            .is_some_and(|disk| source == disk.completion_event().as_raw_fd())
+        {
+            if let Some(disk) = self.disk.as_async() {
+                let _ = disk.completion_event().read();
+            }
+            if let Err(err) = self.complete_async(false) {
+                self.mark_broken(&err);
+            }
+        } else if source == self.rate_limiter.as_raw_fd() {
+            if let Err(err) = self.rate_limiter.event_handler() {
+                panic!("Failed to handle block rate limiter event: {:?}", err);
+            }
+            if let Err(err) = self.process_queue() {
+                self.mark_broken(&err);
+            }
+        }
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        dbg!("block device init called");
+        debug!("block device init called");
         if let Err(err) = ops.add(Events::new(&self.activate_event, EventSet::IN)) {
             panic!("Failed to register activate event: {}", err);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::vmm::device::descriptor::VIRTQ_DESC_F_WRITE;
+    use crate::vmm::device::queue::TestQueue;
+    use crate::vmm::layout::DRAM_MEM_START;
+    use crate::vmm::memory::test_guest_memory;
+    use crate::vmm::mmio::mmio_transport::{MmioTransport, QUEUE_NOTIFY};
+    use crate::vmm::rate_limiter::TokenBucketConfig;
+
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::backend::MemDisk;
+    use super::uring::IoUringDisk;
+    use super::*;
+
+    // Requests are laid out past the rings, each in its own slot.
+    const REQUEST_SLOTS: u64 = DRAM_MEM_START + 0x4000;
+    const DATA: GuestAddress = GuestAddress(DRAM_MEM_START + 0x8000);
+
+    fn header_addr(slot: u64) -> GuestAddress {
+        GuestAddress(REQUEST_SLOTS + slot * 0x20)
+    }
+
+    fn status_addr(slot: u64) -> GuestAddress {
+        header_addr(slot).unchecked_add(0x10)
+    }
+
+    fn activated_block(
+        disk: Box<dyn DiskBackend + Send>,
+        rate_limiter: RateLimiterConfig,
+        mem: &GuestMemoryMmap,
+        queue: &TestQueue,
+    ) -> Block {
+        let mut block = Block::new("block", disk, rate_limiter, QUEUE_SIZE);
+        block.queues[0] = queue.queue();
+        block.activate(mem.clone()).unwrap();
+        block
+    }
+
+    // Makes a request with the header and status in `slot` and the given data descriptors
+    // available.
+    fn add_request(
+        queue: &mut TestQueue,
+        mem: &GuestMemoryMmap,
+        slot: u64,
+        request_type: u32,
+        sector: u64,
+        data: &[(GuestAddress, u32, u16)],
+    ) -> u16 {
+        let header = RequestHeader {
+            request_type,
+            reserved: 0,
+            sector,
+        };
+        mem.write_obj(header, header_addr(slot)).unwrap();
+        // anything but a valid status
+        mem.write_obj(0xffu8, status_addr(slot)).unwrap();
+
+        let mut chain = vec![(header_addr(slot), 16, 0)];
+        chain.extend_from_slice(data);
+        chain.push((status_addr(slot), 1, VIRTQ_DESC_F_WRITE));
+        queue.add_chain(&chain)
+    }
+
+    // Counts the flushes of the disk it wraps.
+    #[derive(Debug)]
+    struct FlushCountingDisk {
+        disk: MemDisk,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl DiskBackend for FlushCountingDisk {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            self.disk.read_at(buf, offset)
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+            self.disk.write_at(buf, offset)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            self.disk.flush()
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            self.disk.len()
+        }
+    }
+
+    fn status(mem: &GuestMemoryMmap, slot: u64) -> u8 {
+        mem.read_obj(status_addr(slot)).unwrap()
+    }
+
+    #[test]
+    fn test_three_descriptor_read() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut disk = MemDisk::new(1 << 20);
+        disk.write_at(&[0xab; 512], 512).unwrap();
+        let mut block = activated_block(Box::new(disk), RateLimiterConfig::default(), &mem, &queue);
+        let head = add_request(
+            &mut queue,
+            &mem,
+            0,
+            VIRTIO_BLK_T_IN,
+            1,
+            &[(DATA, 512, VIRTQ_DESC_F_WRITE)],
+        );
+
+        block.process_queue().unwrap();
+
+        assert_eq!(status(&mem, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(queue.used_elem(0), (u32::from(head), 513));
+        let mut data = [0; 512];
+        mem.read_slice(&mut data, DATA).unwrap();
+        assert_eq!(data, [0xab; 512]);
+    }
+
+    #[test]
+    fn test_in_order_completions() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = Block::new(
+            "block",
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        );
+        assert_ne!(block.avail_features() & (1 << VIRTIO_F_IN_ORDER), 0);
+        block.ack_features_by_page(1, 1 << (VIRTIO_F_IN_ORDER - 32));
+        block.queues[0] = queue.queue();
+        block.activate(mem.clone()).unwrap();
+        let heads: Vec<_> = (0..3)
+            .map(|slot| add_request(&mut queue, &mem, slot, VIRTIO_BLK_T_FLUSH, 0, &[]))
+            .collect();
+
+        block.process_queue().unwrap();
+
+        // only the last request of the batch is written out
+        assert_eq!(queue.used_idx(), 3);
+        assert_eq!(queue.used_elem(2), (u32::from(heads[2]), 1));
+        for slot in 0..3 {
+            assert_eq!(status(&mem, slot), VIRTIO_BLK_S_OK);
+        }
+    }
+
+    #[test]
+    fn test_single_descriptor_request() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = activated_block(
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            &mem,
+            &queue,
+        );
+        // only a status, no header
+        mem.write_obj(0xffu8, status_addr(0)).unwrap();
+        let status_only = queue.add_chain(&[(status_addr(0), 1, VIRTQ_DESC_F_WRITE)]);
+        // only a header, nowhere to write the status to
+        mem.write_obj(0xffu8, status_addr(1)).unwrap();
+        let header_only = queue.add_chain(&[(header_addr(1), 16, 0)]);
+
+        block.process_queue().unwrap();
+
+        assert_eq!(status(&mem, 0), VIRTIO_BLK_S_IOERR);
+        assert_eq!(queue.used_elem(0), (u32::from(status_only), 1));
+        assert_eq!(status(&mem, 1), 0xff);
+        assert_eq!(queue.used_elem(1), (u32::from(header_only), 0));
+    }
+
+    #[test]
+    fn test_quiesce_uses_every_descriptor() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = activated_block(
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            &mem,
+            &queue,
+        );
+        add_request(
+            &mut queue,
+            &mem,
+            0,
+            VIRTIO_BLK_T_IN,
+            0,
+            &[(DATA, 512, VIRTQ_DESC_F_WRITE)],
+        );
+        add_request(&mut queue, &mem, 1, VIRTIO_BLK_T_FLUSH, 0, &[]);
+
+        block.quiesce().unwrap();
+
+        assert_eq!(queue.used_idx(), 2);
+        assert!(block.queues[0].is_empty(&mem));
+        assert_eq!(status(&mem, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(status(&mem, 1), VIRTIO_BLK_S_OK);
+    }
+
+    #[test]
+    fn test_read_counts_bytes() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = activated_block(
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            &mem,
+            &queue,
+        );
+        add_request(
+            &mut queue,
+            &mem,
+            0,
+            VIRTIO_BLK_T_IN,
+            0,
+            &[(DATA, 1024, VIRTQ_DESC_F_WRITE)],
+        );
+
+        block.process_queue().unwrap();
+
+        let metrics = block.metrics.snapshot();
+        assert_eq!(metrics.read_bytes, 1024);
+        assert_eq!(metrics.requests_completed, 1);
+    }
+
+    #[test]
+    fn test_broken_ignores_kicks() {
+        let mem = test_guest_memory(0x10000);
+        let queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = activated_block(
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            &mem,
+            &queue,
+        );
+
+        // what a failed activation does
+        block.mark_broken(&io::Error::from(io::ErrorKind::Other));
+        assert!(block.is_broken());
+        assert!(!block.is_activated());
+        assert_eq!(block.config_generation().load(Ordering::SeqCst), 1);
+
+        let mut transport = MmioTransport::new(mem, Arc::new(Mutex::new(block)), false);
+        transport.bus_write(QUEUE_NOTIFY, &0u32.to_le_bytes());
+        assert!(transport.locked_device().queue_events()[0].read().is_err());
+    }
+
+    #[test]
+    fn test_flush_syncs_disk() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let disk = FlushCountingDisk {
+            disk: MemDisk::new(1 << 20),
+            flushes: flushes.clone(),
+        };
+        let mut block = activated_block(Box::new(disk), RateLimiterConfig::default(), &mem, &queue);
+        add_request(&mut queue, &mem, 0, VIRTIO_BLK_T_FLUSH, 0, &[]);
+
+        block.process_queue().unwrap();
+
+        assert_eq!(status(&mem, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_write_then_read() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = activated_block(
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            &mem,
+            &queue,
+        );
+        let pattern: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        let read_buf = DATA.unchecked_add(0x1000);
+        mem.write_slice(&pattern, DATA).unwrap();
+
+        add_request(&mut queue, &mem, 0, VIRTIO_BLK_T_OUT, 8, &[(DATA, 1024, 0)]);
+        let read_head = add_request(
+            &mut queue,
+            &mem,
+            1,
+            VIRTIO_BLK_T_IN,
+            8,
+            &[(read_buf, 1024, VIRTQ_DESC_F_WRITE)],
+        );
+        block.process_queue().unwrap();
+
+        assert_eq!(status(&mem, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(status(&mem, 1), VIRTIO_BLK_S_OK);
+        let mut read = vec![0; 1024];
+        mem.read_slice(&mut read, read_buf).unwrap();
+        assert_eq!(read, pattern);
+        // the read wrote the data and the status byte
+        assert_eq!(queue.used_elem(1), (u32::from(read_head), 1025));
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let mem = test_guest_memory(0x10000);
+        let queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let disk = FlushCountingDisk {
+            disk: MemDisk::new(1 << 20),
+            flushes: flushes.clone(),
+        };
+        let block = activated_block(Box::new(disk), RateLimiterConfig::default(), &mem, &queue);
+
+        drop(block);
+
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_writes_persist_after_drop() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(1 << 20).unwrap();
+        let disk = File::options()
+            .read(true)
+            .write(true)
+            .open(file.as_path())
+            .unwrap();
+        let mut block = activated_block(Box::new(disk), RateLimiterConfig::default(), &mem, &queue);
+        let pattern: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        mem.write_slice(&pattern, DATA).unwrap();
+
+        add_request(&mut queue, &mem, 0, VIRTIO_BLK_T_OUT, 8, &[(DATA, 1024, 0)]);
+        block.process_queue().unwrap();
+        assert_eq!(status(&mem, 0), VIRTIO_BLK_S_OK);
+        drop(block);
+
+        let mut read = vec![0; 1024];
+        File::open(file.as_path())
+            .unwrap()
+            .read_exact_at(&mut read, 8 * 512)
+            .unwrap();
+        assert_eq!(read, pattern);
+    }
+
+    #[test]
+    fn test_capacity_in_sectors() {
+        let capacity = |len| {
+            let block = Block::new(
+                "block",
+                Box::new(MemDisk::new(len)),
+                RateLimiterConfig::default(),
+                QUEUE_SIZE,
+            );
+            let mut data = [0; 8];
+            block.read_config(0, &mut data);
+            u64::from_le_bytes(data)
+        };
+
+        assert_eq!(capacity(1 << 20), 2048);
+        // a partial last sector isn't part of the disk
+        assert_eq!(capacity((1 << 20) + 100), 2048);
+    }
+
+    #[test]
+    fn test_rate_limited_until_timer_fires() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        // room for a single 1KiB read, refilled by the time the limiter's timer fires
+        let rate_limiter = RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: 1024,
+                refill_time: 100,
+            }),
+            ops: None,
+        };
+        let mut block =
+            activated_block(Box::new(MemDisk::new(1 << 20)), rate_limiter, &mem, &queue);
+        for slot in 0..2 {
+            add_request(
+                &mut queue,
+                &mem,
+                slot,
+                VIRTIO_BLK_T_IN,
+                0,
+                &[(DATA, 1024, VIRTQ_DESC_F_WRITE)],
+            );
+        }
+
+        block.process_queue().unwrap();
+
+        assert_eq!(queue.used_idx(), 1);
+        assert_eq!(status(&mem, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(status(&mem, 1), 0xff);
+        assert!(block.rate_limiter.is_blocked());
+
+        // the limiter's timer is armed for 100ms
+        std::thread::sleep(Duration::from_millis(200));
+        block.rate_limiter.event_handler().unwrap();
+        block.process_queue().unwrap();
+
+        assert_eq!(queue.used_idx(), 2);
+        assert_eq!(status(&mem, 1), VIRTIO_BLK_S_OK);
+        assert!(!block.rate_limiter.is_blocked());
+    }
+
+    #[test]
+    fn test_get_id() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = activated_block(
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            &mem,
+            &queue,
+        );
+        mem.write_slice(&[0xff; VIRTIO_BLK_ID_BYTES], DATA).unwrap();
+        add_request(
+            &mut queue,
+            &mem,
+            0,
+            VIRTIO_BLK_T_GET_ID,
+            0,
+            &[(DATA, VIRTIO_BLK_ID_BYTES as u32, VIRTQ_DESC_F_WRITE)],
+        );
+
+        block.process_queue().unwrap();
+
+        assert_eq!(status(&mem, 0), VIRTIO_BLK_S_OK);
+        let mut id = [0; VIRTIO_BLK_ID_BYTES];
+        mem.read_slice(&mut id, DATA).unwrap();
+        assert_eq!(&id, b"block\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = activated_block(
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            &mem,
+            &queue,
+        );
+        let segment_addr = DATA.unchecked_add(0x1000);
+        let read_buf = DATA.unchecked_add(0x2000);
+        mem.write_slice(&[0xaa; 2048], DATA).unwrap();
+        let segment = DiscardSegment {
+            sector: 1,
+            num_sectors: 2,
+            flags: 0,
+        };
+        mem.write_obj(segment, segment_addr).unwrap();
+
+        add_request(&mut queue, &mem, 0, VIRTIO_BLK_T_OUT, 0, &[(DATA, 2048, 0)]);
+        add_request(
+            &mut queue,
+            &mem,
+            1,
+            VIRTIO_BLK_T_WRITE_ZEROES,
+            0,
+            &[(segment_addr, 16, 0)],
+        );
+        add_request(
+            &mut queue,
+            &mem,
+            2,
+            VIRTIO_BLK_T_IN,
+            0,
+            &[(read_buf, 2048, VIRTQ_DESC_F_WRITE)],
+        );
+        block.process_queue().unwrap();
+
+        for slot in 0..3 {
+            assert_eq!(status(&mem, slot), VIRTIO_BLK_S_OK);
+        }
+        let mut read = vec![0; 2048];
+        mem.read_slice(&mut read, read_buf).unwrap();
+        assert!(read[..512].iter().all(|&b| b == 0xaa));
+        assert!(read[512..1536].iter().all(|&b| b == 0));
+        assert!(read[1536..].iter().all(|&b| b == 0xaa));
+    }
+
+    #[test]
+    fn test_async_reads_complete() {
+        const REQUESTS: u64 = 8;
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(1 << 20).unwrap();
+        for sector in 0..REQUESTS {
+            file.write_all_at(&[sector as u8 + 1; 512], sector << SECTOR_SHIFT)
+                .unwrap();
+        }
+        // io_uring may be disabled on the host
+        let disk = match IoUringDisk::new(file, u32::from(QUEUE_SIZE)) {
+            Ok(disk) => disk,
+            Err(_) => return,
+        };
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, QUEUE_SIZE);
+        let mut block = activated_block(Box::new(disk), RateLimiterConfig::default(), &mem, &queue);
+        for sector in 0..REQUESTS {
+            let buf = DATA.unchecked_add(sector * 512);
+            add_request(
+                &mut queue,
+                &mem,
+                sector,
+                VIRTIO_BLK_T_IN,
+                sector,
+                &[(buf, 512, VIRTQ_DESC_F_WRITE)],
+            );
+        }
+
+        // the reads complete in any order
+        assert_eq!(block.avail_features() & (1 << VIRTIO_F_IN_ORDER), 0);
+
+        block.process_queue().unwrap();
+        block.complete_async(true).unwrap();
+
+        assert_eq!(queue.used_idx(), REQUESTS as u16);
+        assert_eq!(block.metrics.snapshot().requests_completed, REQUESTS);
+        for sector in 0..REQUESTS {
+            assert_eq!(status(&mem, sector), VIRTIO_BLK_S_OK);
+            let mut read = [0; 512];
+            mem.read_slice(&mut read, DATA.unchecked_add(sector * 512))
+                .unwrap();
+            assert!(read.iter().all(|&b| b == sector as u8 + 1));
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use vmm_sys_util::eventfd::EventFd;
+
+use super::backend::{AsyncDisk, DiskBackend};
+
+/// File backend that runs reads and writes through an io_uring.
+///
+/// The blocking `DiskBackend` methods go straight to the file, the block device only uses
+/// them once every queued operation has completed.
+pub struct IoUringDisk {
+    file: File,
+    ring: IoUring,
+    completion_event: EventFd,
+    in_flight: usize,
+}
+
+impl IoUringDisk {
+    /// `depth` is the number of operations the ring holds at once, the kernel rounds it up to
+    /// a power of two.
+    pub fn new(file: File, depth: u32) -> io::Result<IoUringDisk> {
+        let ring = IoUring::new(depth)?;
+        let completion_event = EventFd::new(libc::EFD_NONBLOCK)?;
+        ring.submitter()
+            .register_eventfd(completion_event.as_raw_fd())?;
+
+        Ok(IoUringDisk {
+            file,
+            ring,
+            completion_event,
+            in_flight: 0,
+        })
+    }
+
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        // make room by handing the queued entries to the kernel
+        if self.ring.submission().is_full() {
+            self.ring.submit()?;
+        }
+
+        // SAFETY: The caller of `prepare_read` or `prepare_write` keeps the buffer valid until
+        // the operation completes.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+        self.in_flight += 1;
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for IoUringDisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IoUringDisk")
+            .field("file", &self.file)
+            .field("in_flight", &self.in_flight)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DiskBackend for IoUringDisk {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.file.discard(offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        self.file.write_zeroes(offset, len, unmap)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        DiskBackend::len(&self.file)
+    }
+
+    fn as_async(&mut self) -> Option<&mut dyn AsyncDisk> {
+        Some(self)
+    }
+}
+
+impl AsyncDisk for IoUringDisk {
+    fn completion_event(&self) -> &EventFd {
+        &self.completion_event
+    }
+
+    unsafe fn prepare_read(
+        &mut self,
+        offset: u64,
+        buf: *mut u8,
+        len: u32,
+        user_data: u64,
+    ) -> io::Result<()> {
+        let entry = opcode::Read::new(types::Fd(self.file.as_raw_fd()), buf, len)
+            .offset(offset)
+            .build()
+            .user_data(user_data);
+        self.push(entry)
+    }
+
+    unsafe fn prepare_write(
+        &mut self,
+        offset: u64,
+        buf: *const u8,
+        len: u32,
+        user_data: u64,
+    ) -> io::Result<()> {
+        let entry = opcode::Write::new(types::Fd(self.file.as_raw_fd()), buf, len)
+            .offset(offset)
+            .build()
+            .user_data(user_data);
+        self.push(entry)
+    }
+
+    fn submit(&mut self, min_complete: usize) -> io::Result<()> {
+        self.ring.submit_and_wait(min_complete)?;
+        Ok(())
+    }
+
+    fn pop_completion(&mut self) -> Option<(u64, io::Result<u32>)> {
+        let entry = self.ring.completion().next()?;
+        self.in_flight -= 1;
+
+        let result = match entry.result() {
+            errno if errno < 0 => Err(io::Error::from_raw_os_error(-errno)),
+            len => Ok(len as u32),
+        };
+        Some((entry.user_data(), result))
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+}
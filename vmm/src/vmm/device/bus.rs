@@ -3,11 +3,14 @@ use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
+use log::warn;
 use vm_superio::rtc_pl031::{NoEvents, Rtc};
 
 use crate::vmm::device::i8042::I8042Device;
-use crate::vmm::device::serial::SerialDevice;
+use crate::vmm::device::serial::{SerialDevice, SerialInput};
+use crate::vmm::device::watchdog::Watchdog;
 use crate::vmm::mmio::mmio_transport::MmioTransport;
+use crate::vmm::pci::PciRoot;
 
 #[derive(Debug, Copy, Clone)]
 struct BusRange(u64, u64);
@@ -32,6 +35,18 @@ impl PartialOrd for BusRange {
     }
 }
 
+#[derive(Debug)]
+pub enum BusError {
+    /// The new device's range is empty or overlaps a device already on the bus.
+    Overlap,
+    /// A virtio device is given this many irqs instead of one.
+    InvalidIrqCount(usize),
+    /// A queue's eventfd couldn't be attached to QueueNotify.
+    Ioevent(kvm_ioctls::Error),
+    /// The device's interrupt eventfd couldn't be attached to its irq.
+    Irqfd(kvm_ioctls::Error),
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Bus {
     devices: BTreeMap<BusRange, Arc<Mutex<BusDevice>>>,
@@ -55,7 +70,7 @@ impl Bus {
         None
     }
 
-    fn get_device(&self, addr: u64) -> Option<(u64, &Mutex<BusDevice>)> {
+    pub(crate) fn get_device(&self, addr: u64) -> Option<(u64, &Mutex<BusDevice>)> {
         if let Some((BusRange(start, len), dev)) = self.first_before(addr) {
             let offset = addr - start;
             if offset < len {
@@ -65,15 +80,47 @@ impl Bus {
         None
     }
 
+    /// Reads `data.len()` bytes at `addr` from the device mapped there. Returns false when no
+    /// device is mapped at `addr`.
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        if let Some((offset, device)) = self.get_device(addr) {
+            device.lock().expect("Poisoned lock").read(offset, data);
+            return true;
+        }
+        false
+    }
+
+    /// Writes `data` at `addr` to the device mapped there. Returns false when no device is
+    /// mapped at `addr`.
+    pub fn write(&self, addr: u64, data: &[u8]) -> bool {
+        if let Some((offset, device)) = self.get_device(addr) {
+            device.lock().expect("Poisoned lock").write(offset, data);
+            return true;
+        }
+        false
+    }
+
+    /// Removes the device mapped at `base` and returns it. Returns None when no device starts
+    /// at `base`.
+    pub fn remove(&mut self, base: u64) -> Option<Arc<Mutex<BusDevice>>> {
+        // BusRange only compares the start, so the length doesn't matter for the lookup
+        self.devices.remove(&BusRange(base, 0))
+    }
+
     /// Puts the given device at the given address space.
-    pub fn insert(&mut self, device: Arc<Mutex<BusDevice>>, base: u64, len: u64) {
+    pub fn insert(
+        &mut self,
+        device: Arc<Mutex<BusDevice>>,
+        base: u64,
+        len: u64,
+    ) -> Result<(), BusError> {
         if len == 0 {
-            panic!("overlap");
+            return Err(BusError::Overlap);
         }
 
         // Reject all cases where the new device's base is within an old device's range.
         if self.get_device(base).is_some() {
-            panic!("overlap");
+            return Err(BusError::Overlap);
         }
 
         // The above check will miss an overlap in which the new device's base address is before the
@@ -84,13 +131,15 @@ impl Bus {
             // Such a device only conflicts with the new device if it also starts after the new
             // device because of our initial `get_device` check above.
             if start >= base {
-                panic!("overlap");
+                return Err(BusError::Overlap);
             }
         }
 
         if self.devices.insert(BusRange(base, len), device).is_some() {
-            panic!("overlap");
+            return Err(BusError::Overlap);
         }
+
+        Ok(())
     }
 }
 
@@ -99,27 +148,173 @@ pub enum BusDevice {
     I8042Device(I8042Device),
     RTCDevice(Rtc<NoEvents>),
     MmioTransport(MmioTransport),
-    Serial(SerialDevice<std::io::Stdin>),
+    Serial(SerialDevice<SerialInput>),
+    PciRoot(PciRoot),
+    Watchdog(Watchdog),
 }
 
 impl BusDevice {
-    pub fn serial_ref(&self) -> Option<&SerialDevice<std::io::Stdin>> {
+    pub fn serial_ref(&self) -> Option<&SerialDevice<SerialInput>> {
         match self {
             Self::Serial(x) => Some(x),
             _ => None,
         }
     }
+
+    pub fn rtc_ref(&self) -> Option<&Rtc<NoEvents>> {
+        match self {
+            Self::RTCDevice(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn mmio_transport_ref(&self) -> Option<&MmioTransport> {
+        match self {
+            Self::MmioTransport(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn mmio_transport_mut(&mut self) -> Option<&mut MmioTransport> {
+        match self {
+            Self::MmioTransport(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn watchdog_ref(&self) -> Option<&Watchdog> {
+        match self {
+            Self::Watchdog(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn watchdog_mut(&mut self) -> Option<&mut Watchdog> {
+        match self {
+            Self::Watchdog(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Handles a guest read at `offset` within the device's MMIO region.
+    pub fn read(&mut self, offset: u64, data: &mut [u8]) {
+        match self {
+            Self::RTCDevice(rtc) => {
+                // The PL031 registers are all 32 bits wide.
+                if let Ok(data) = <&mut [u8; 4]>::try_from(data) {
+                    rtc.read(offset as u16, data);
+                }
+            }
+            Self::Serial(serial) => {
+                if let Some(byte) = data.first_mut() {
+                    *byte = serial.serial.read(offset as u8);
+                }
+            }
+            Self::MmioTransport(transport) => transport.bus_read(offset, data),
+            Self::PciRoot(pci_root) => pci_root.read(offset, data),
+            Self::Watchdog(watchdog) => watchdog.read(offset, data),
+            _ => {}
+        }
+    }
+
+    /// Handles a guest write at `offset` within the device's MMIO region.
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        match self {
+            Self::RTCDevice(rtc) => {
+                if let Ok(data) = <&[u8; 4]>::try_from(data) {
+                    rtc.write(offset as u16, data);
+                }
+            }
+            Self::Serial(serial) => {
+                if let Some(byte) = data.first() {
+                    if let Err(err) = serial.serial.write(offset as u8, *byte) {
+                        warn!("failed to write to serial device: {:?}", err);
+                    }
+                }
+            }
+            Self::MmioTransport(transport) => transport.bus_write(offset, data),
+            Self::PciRoot(pci_root) => pci_root.write(offset, data),
+            Self::Watchdog(watchdog) => watchdog.write(offset, data),
+            _ => {}
+        }
+    }
 }
 
 impl MutEventSubscriber for BusDevice {
     fn process(&mut self, event: Events, ops: &mut EventOps) {
-        todo!();
+        match self {
+            Self::Serial(serial) => serial.process(event, ops),
+            Self::Watchdog(watchdog) => watchdog.process(event, ops),
+            _ => panic!(),
+        }
     }
 
     fn init(&mut self, ops: &mut EventOps) {
         match self {
             Self::Serial(serial) => serial.init(ops),
+            Self::Watchdog(watchdog) => watchdog.init(ops),
             _ => panic!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtc() -> Arc<Mutex<BusDevice>> {
+        Arc::new(Mutex::new(BusDevice::RTCDevice(Rtc::new())))
+    }
+
+    #[test]
+    fn test_rtc_read() {
+        let mut bus = Bus::new();
+        bus.insert(rtc(), 0x1000, 0x1000).unwrap();
+
+        // RTCDR, the current time in seconds
+        let mut data = [0; 4];
+        assert!(bus.read(0x1000, &mut data));
+        // 2020-01-01
+        assert!(u32::from_le_bytes(data) > 1_577_836_800);
+
+        assert!(!bus.read(0x3000, &mut data));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut bus = Bus::new();
+        bus.insert(rtc(), 0x1000, 0x1000).unwrap();
+
+        assert!(bus.remove(0x1000).is_some());
+        assert!(bus.get_device(0x1000).is_none());
+        assert!(bus.remove(0x1000).is_none());
+
+        // the range is free again
+        bus.insert(rtc(), 0x1000, 0x1000).unwrap();
+    }
+
+    #[test]
+    fn test_insert_overlap() {
+        let mut bus = Bus::new();
+        bus.insert(rtc(), 0x1000, 0x1000).unwrap();
+
+        assert!(matches!(
+            bus.insert(rtc(), 0x1000, 0x1000),
+            Err(BusError::Overlap)
+        ));
+        assert!(matches!(
+            bus.insert(rtc(), 0x1800, 0x1000),
+            Err(BusError::Overlap)
+        ));
+        assert!(matches!(
+            bus.insert(rtc(), 0x800, 0x1000),
+            Err(BusError::Overlap)
+        ));
+        assert!(matches!(
+            bus.insert(rtc(), 0x3000, 0),
+            Err(BusError::Overlap)
+        ));
+
+        bus.insert(rtc(), 0x2000, 0x1000).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::sync::{atomic::AtomicU32, Arc};
+
+use event_manager::{Error as EventManagerError, EventOps, EventSet, Events, MutEventSubscriber};
+use log::{debug, error, warn};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::vmm::memory::GuestMemoryMmap;
+
+use super::descriptor::{DescReader, DescWriter};
+use super::queue::{Queue, QueueError};
+use super::serial::out::BufferedOut;
+use super::serial::{is_pollable, SerialInput};
+use super::{
+    eventfd_write_retry, ActivateError, DeviceState, IrqTrigger, IrqType, QuiesceError,
+    VirtioDevice, TYPE_CONSOLE, VIRTIO_F_VERSION_1,
+};
+
+pub const QUEUE_SIZE: u16 = 256;
+
+const RECEIVE_INDEX: usize = 0;
+const TRANSMIT_INDEX: usize = 1;
+
+// How much host input is held while the driver has no receive buffers available. The input
+// isn't read any further until there's room again.
+const INPUT_CAPACITY: usize = 4096;
+
+/// A single port virtio console, the guest's `/dev/hvc0`.
+///
+/// What the guest puts on the transmit queue goes to `output`. What can be read from `input`
+/// is put on the receive queue, as far as the driver made buffers available for it.
+#[derive(Debug)]
+pub struct Console {
+    pub queues: Vec<Queue>,
+    pub queue_events: [EventFd; 2],
+    pub irq_trigger: IrqTrigger,
+    pub activate_event: EventFd,
+    pub device_state: DeviceState,
+    input: Option<SerialInput>,
+    // input read from the host and not yet handed to the guest
+    pending_input: VecDeque<u8>,
+    // input is left unread, and its fd unregistered, while `pending_input` is full
+    input_paused: bool,
+    output: BufferedOut,
+}
+
+impl Console {
+    pub fn new(input: Option<SerialInput>, output: BufferedOut) -> Console {
+        let queues = vec![Queue::new(QUEUE_SIZE), Queue::new(QUEUE_SIZE)];
+        let queue_events = [
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        ];
+        let irq_trigger = IrqTrigger::new().unwrap();
+        let activate_event = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        Console {
+            queues,
+            queue_events,
+            irq_trigger,
+            activate_event,
+            device_state: DeviceState::Inactive,
+            input,
+            pending_input: VecDeque::new(),
+            input_paused: false,
+            output,
+        }
+    }
+
+    /// Writes what the guest transmitted to the output.
+    pub fn process_transmit_queue(&mut self) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let queue = &mut self.queues[TRANSMIT_INDEX];
+
+        let mut used_any = false;
+        while let Some(head) = queue.pop(mem) {
+            let index = head.index;
+            // the output keeps what its fd doesn't take right away
+            if let Err(err) = io::copy(&mut DescReader::new(head), &mut self.output) {
+                warn!("failed to write console output: {:?}", err);
+            }
+
+            queue.add_used(mem, index, 0)?;
+            used_any = true;
+        }
+
+        if used_any {
+            if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+                error!("failed to trigger console irq: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the pending input to the buffers the driver made available on the receive queue.
+    pub fn process_receive_queue(&mut self) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let queue = &mut self.queues[RECEIVE_INDEX];
+
+        let mut used_any = false;
+        while !self.pending_input.is_empty() {
+            let head = match queue.pop(mem) {
+                Some(head) => head,
+                None => break,
+            };
+            let index = head.index;
+
+            let mut writer = DescWriter::new(head);
+            let mut written = 0;
+            while !self.pending_input.is_empty() {
+                let (front, _) = self.pending_input.as_slices();
+                match io::Write::write(&mut writer, front) {
+                    Ok(0) | Err(_) => break,
+                    Ok(count) => {
+                        self.pending_input.drain(..count);
+                        written += count;
+                    }
+                }
+            }
+
+            queue.add_used(mem, index, written as u32)?;
+            used_any = true;
+        }
+
+        if used_any {
+            if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+                error!("failed to trigger console irq: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_input(&mut self, ops: &mut EventOps) {
+        let input = match self.input.as_mut() {
+            Some(input) => input,
+            None => return,
+        };
+        let input_fd = input.as_raw_fd();
+
+        let mut buf = vec![0u8; INPUT_CAPACITY - self.pending_input.len()];
+        match input.read(&mut buf) {
+            // EOF, e.g. stdin was closed. Nothing more will ever be read from it.
+            Ok(0) => {
+                if let Err(err) = ops.remove(Events::new(&input_fd, EventSet::IN)) {
+                    panic!("Failed to unregister console input fd: {}", err);
+                }
+                self.input = None;
+            }
+            Ok(count) => self.pending_input.extend(&buf[..count]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => warn!("failed to read console input: {:?}", err),
+        }
+
+        if let Err(err) = self.process_receive_queue() {
+            error!("failed to process console receive queue: {:?}", err);
+        }
+
+        // the level triggered input fd would keep waking us up otherwise
+        if self.input.is_some() && self.pending_input.len() == INPUT_CAPACITY {
+            if let Err(err) = ops.remove(Events::new(&input_fd, EventSet::IN)) {
+                panic!("Failed to unregister console input fd: {}", err);
+            }
+            self.input_paused = true;
+        }
+    }
+
+    // Reads the input again once the driver took some of what's pending.
+    fn resume_input(&mut self, ops: &mut EventOps) {
+        if !self.input_paused || self.pending_input.len() == INPUT_CAPACITY {
+            return;
+        }
+        if let Some(input) = &self.input {
+            match ops.add(Events::new(input, EventSet::IN)) {
+                Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+                Err(err) => panic!("Failed to register console input fd: {}", err),
+            }
+        }
+        self.input_paused = false;
+    }
+
+    fn process_activate_event(&mut self, ops: &mut EventOps) {
+        if let Err(err) = self.activate_event.read() {
+            panic!("Failed to consume console activate event: {:?}", err);
+        }
+
+        // After a reset the device is activated again with these still registered, the
+        // activate event stays registered for the same reason.
+        for queue_event in &self.queue_events {
+            match ops.add(Events::new(queue_event, EventSet::IN)) {
+                Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+                Err(err) => panic!("Failed to register console queue event: {}", err),
+            }
+        }
+
+        // input that arrived before the driver was ready
+        if let Err(err) = self.process_receive_queue() {
+            error!("failed to process console receive queue: {:?}", err);
+        }
+    }
+}
+
+impl VirtioDevice for Console {
+    fn device_type(&self) -> u32 {
+        TYPE_CONSOLE
+    }
+
+    fn avail_features(&self) -> u64 {
+        1 << VIRTIO_F_VERSION_1
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_events
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicU32> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn config_generation(&self) -> Arc<AtomicU32> {
+        self.irq_trigger.config_generation.clone()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> Result<(), ActivateError> {
+        eventfd_write_retry(&self.activate_event, 1).map_err(ActivateError::EventFd)?;
+        self.device_state = DeviceState::Activated(mem);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn quiesce(&mut self) -> Result<(), QuiesceError> {
+        self.process_transmit_queue().map_err(QuiesceError::Queue)?;
+        self.output.drain().map_err(QuiesceError::Io)
+    }
+
+    fn reset(&mut self) -> bool {
+        self.queues = self
+            .queues
+            .iter()
+            .map(|queue| Queue::new(queue.get_max_size()))
+            .collect();
+        self.device_state = DeviceState::Inactive;
+        // drop kicks the driver made before the reset
+        for queue_event in &self.queue_events {
+            let _ = queue_event.read();
+        }
+
+        true
+    }
+}
+
+impl MutEventSubscriber for Console {
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let input_fd = self.input.as_ref().map_or(-1, |input| input.as_raw_fd());
+        let output_fd = self.output.as_raw_fd().unwrap_or(-1);
+
+        if source == self.activate_event.as_raw_fd() {
+            self.process_activate_event(ops);
+        } else if source == self.queue_events[TRANSMIT_INDEX].as_raw_fd() {
+            let _ = self.queue_events[TRANSMIT_INDEX].read();
+            if let Err(err) = self.process_transmit_queue() {
+                panic!("Failed to process console transmit queue: {:?}", err);
+            }
+        } else if source == self.queue_events[RECEIVE_INDEX].as_raw_fd() {
+            let _ = self.queue_events[RECEIVE_INDEX].read();
+            if let Err(err) = self.process_receive_queue() {
+                panic!("Failed to process console receive queue: {:?}", err);
+            }
+            self.resume_input(ops);
+        } else if source == input_fd {
+            self.process_input(ops);
+        } else if source == output_fd {
+            if let Err(err) = self.output.drain() {
+                warn!("failed to write console output: {:?}", err);
+            }
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        debug!("console device init called");
+        if let Err(err) = ops.add(Events::new(&self.activate_event, EventSet::IN)) {
+            panic!("Failed to register activate event: {}", err);
+        }
+        // edge triggered, a writable fd would wake us up all the time otherwise
+        if let Some(output_fd) = self.output.as_raw_fd().filter(|fd| is_pollable(*fd)) {
+            let events = EventSet::OUT | EventSet::EDGE_TRIGGERED;
+            if let Err(err) = ops.add(Events::new(&output_fd, events)) {
+                panic!("Failed to register console output fd: {}", err);
+            }
+        }
+        if let Some(input) = self
+            .input
+            .as_ref()
+            .filter(|input| is_pollable(input.as_raw_fd()))
+        {
+            if let Err(err) = ops.add(Events::new(input, EventSet::IN)) {
+                panic!("Failed to register console input fd: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::{Address, Bytes, GuestAddress};
+
+    use crate::vmm::device::queue::TestQueue;
+    use crate::vmm::device::serial::out::{ConsoleBuffer, SerialOut, PENDING_CAPACITY};
+    use crate::vmm::layout::DRAM_MEM_START;
+    use crate::vmm::memory::test_guest_memory;
+    use crate::vmm::metrics::Counter;
+
+    use super::*;
+
+    #[test]
+    fn test_transmit_to_output() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = TestQueue::new(&mem, 16);
+        let buffer = ConsoleBuffer::default();
+        let output = BufferedOut::new(
+            SerialOut::Buffer(buffer.clone(), 1024),
+            PENDING_CAPACITY,
+            Arc::new(Counter::default()),
+        );
+        let mut console = Console::new(None, output);
+        console.queues[TRANSMIT_INDEX] = queue.queue();
+        console.activate(mem.clone()).unwrap();
+
+        let data = GuestAddress(DRAM_MEM_START + 0x4000);
+        mem.write_slice(b"hello\n", data).unwrap();
+        let head = queue.add_chain(&[(data, 3, 0), (data.unchecked_add(3), 3, 0)]);
+        console.process_transmit_queue().unwrap();
+
+        assert!(buffer.lock().unwrap().iter().eq(b"hello\n"));
+        assert_eq!(queue.used_idx(), 1);
+        assert_eq!(queue.used_elem(0), (u32::from(head), 0));
+    }
+}
@@ -1,6 +1,10 @@
-use crate::vmm::memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use std::cmp::min;
+use std::io;
 
-/// A virtio descriptor constraints with C representative.
+use crate::vmm::memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+/// A virtio descriptor constraints with C representative. Fields are little endian, as the
+/// guest laid them out.
 #[repr(C)]
 #[derive(Default, Clone, Copy)]
 struct Descriptor {
@@ -71,10 +75,10 @@ impl<'a, M: GuestMemory> DescriptorChain<'a, M> {
             queue_size,
             ttl: queue_size,
             index,
-            addr: GuestAddress(desc.addr),
-            len: desc.len,
-            flags: desc.flags,
-            next: desc.next,
+            addr: GuestAddress(u64::from_le(desc.addr)),
+            len: u32::from_le(desc.len),
+            flags: u16::from_le(desc.flags),
+            next: u16::from_le(desc.next),
         };
 
         if chain.is_valid() {
@@ -117,6 +121,163 @@ impl<'a, M: GuestMemory> DescriptorChain<'a, M> {
             None
         }
     }
+
+    /// Copies the readable descriptors of the chain, starting with this one, to `buf` until
+    /// it's full. Write only descriptors are skipped. Returns the number of bytes copied,
+    /// which is less than `buf.len()` when the chain is shorter or a descriptor points
+    /// outside of guest memory.
+    pub fn read_to(&self, buf: &mut [u8]) -> usize {
+        let mut copied = 0;
+        self.walk(|desc| {
+            if !desc.is_write_only() {
+                let len = min(buf.len() - copied, desc.len as usize);
+                if desc
+                    .mem
+                    .read_slice(&mut buf[copied..copied + len], desc.addr)
+                    .is_err()
+                {
+                    return false;
+                }
+                copied += len;
+            }
+            copied < buf.len()
+        });
+
+        copied
+    }
+
+    /// Copies `buf` to the write only descriptors of the chain, starting with this one.
+    /// Read only descriptors are skipped. Returns the number of bytes copied, which is less
+    /// than `buf.len()` when the chain is shorter or a descriptor points outside of guest
+    /// memory.
+    pub fn write_from(&self, buf: &[u8]) -> usize {
+        let mut copied = 0;
+        self.walk(|desc| {
+            if desc.is_write_only() {
+                let len = min(buf.len() - copied, desc.len as usize);
+                if desc
+                    .mem
+                    .write_slice(&buf[copied..copied + len], desc.addr)
+                    .is_err()
+                {
+                    return false;
+                }
+                copied += len;
+            }
+            copied < buf.len()
+        });
+
+        copied
+    }
+
+    // Calls `f` with this descriptor and the ones after it until `f` returns false.
+    fn walk(&self, mut f: impl FnMut(&Self) -> bool) {
+        if !f(self) {
+            return;
+        }
+        let mut next = self.next_descriptor();
+        while let Some(desc) = next {
+            if !f(&desc) {
+                return;
+            }
+            next = desc.next_descriptor();
+        }
+    }
+}
+
+/// `io::Read` over the readable descriptors of a chain, as if they were one buffer. Write only
+/// descriptors are skipped, reads return 0 once the chain is exhausted.
+#[derive(Debug)]
+pub struct DescReader<'a, M: GuestMemory = GuestMemoryMmap> {
+    desc: Option<DescriptorChain<'a, M>>,
+    // bytes of `desc` already read
+    offset: u32,
+}
+
+impl<'a, M: GuestMemory> DescReader<'a, M> {
+    pub fn new(head: DescriptorChain<'a, M>) -> Self {
+        DescReader {
+            desc: Some(head),
+            offset: 0,
+        }
+    }
+}
+
+impl<M: GuestMemory> io::Read for DescReader<'_, M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        skip_to_usable(&mut self.desc, &mut self.offset, false);
+        let desc = match &self.desc {
+            Some(desc) => desc,
+            None => return Ok(0),
+        };
+
+        let len = min(buf.len(), (desc.len - self.offset) as usize);
+        let addr = desc.addr.unchecked_add(u64::from(self.offset));
+        desc.mem
+            .read_slice(&mut buf[..len], addr)
+            .map_err(io::Error::other)?;
+        self.offset += len as u32;
+
+        Ok(len)
+    }
+}
+
+/// `io::Write` over the write only descriptors of a chain, as if they were one buffer. Read
+/// only descriptors are skipped, writes return 0 once the chain is full, which `write_all`
+/// turns into a `WriteZero` error.
+#[derive(Debug)]
+pub struct DescWriter<'a, M: GuestMemory = GuestMemoryMmap> {
+    desc: Option<DescriptorChain<'a, M>>,
+    // bytes of `desc` already written
+    offset: u32,
+}
+
+impl<'a, M: GuestMemory> DescWriter<'a, M> {
+    pub fn new(head: DescriptorChain<'a, M>) -> Self {
+        DescWriter {
+            desc: Some(head),
+            offset: 0,
+        }
+    }
+}
+
+impl<M: GuestMemory> io::Write for DescWriter<'_, M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        skip_to_usable(&mut self.desc, &mut self.offset, true);
+        let desc = match &self.desc {
+            Some(desc) => desc,
+            None => return Ok(0),
+        };
+
+        let len = min(buf.len(), (desc.len - self.offset) as usize);
+        let addr = desc.addr.unchecked_add(u64::from(self.offset));
+        desc.mem
+            .write_slice(&buf[..len], addr)
+            .map_err(io::Error::other)?;
+        self.offset += len as u32;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Moves `desc` forward to the first descriptor with room left in the wanted direction, or to
+// None at the end of the chain.
+fn skip_to_usable<M: GuestMemory>(
+    desc: &mut Option<DescriptorChain<'_, M>>,
+    offset: &mut u32,
+    write_only: bool,
+) {
+    while let Some(current) = desc {
+        if current.is_write_only() == write_only && *offset < current.len {
+            return;
+        }
+        *desc = current.next_descriptor();
+        *offset = 0;
+    }
 }
 
 #[derive(Debug)]
@@ -141,3 +302,111 @@ impl<'a> Iterator for DescriptorIterator<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use crate::vmm::device::queue::TestQueue;
+    use crate::vmm::layout::DRAM_MEM_START;
+    use crate::vmm::memory::test_guest_memory;
+
+    use super::*;
+
+    fn addr(offset: u64) -> GuestAddress {
+        GuestAddress(DRAM_MEM_START + offset)
+    }
+
+    #[test]
+    fn test_little_endian_fields() {
+        let mem = test_guest_memory(0x10000);
+        // descriptor 1, as a little endian driver lays it out
+        let desc = [
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, // addr
+            0x44, 0x33, 0x22, 0x11, // len
+            0x03, 0x00, // flags: next | write
+            0x05, 0x00, // next
+        ];
+        mem.write_slice(&desc, addr(16)).unwrap();
+
+        let chain = DescriptorChain::checked_new(&mem, addr(0), 16, 1).unwrap();
+        assert_eq!(chain.addr, GuestAddress(0x0102_0304_0506_0708));
+        assert_eq!(chain.len, 0x1122_3344);
+        assert!(chain.has_next());
+        assert!(chain.is_write_only());
+        assert_eq!(chain.next, 5);
+    }
+
+    #[test]
+    fn test_write_from_two_descriptors() {
+        let mem = test_guest_memory(0x10000);
+        let mut test_queue = TestQueue::new(&mem, 16);
+        test_queue.add_chain(&[
+            (addr(0x4000), 4, VIRTQ_DESC_F_WRITE),
+            (addr(0x5000), 8, VIRTQ_DESC_F_WRITE),
+        ]);
+        let mut queue = test_queue.queue();
+        let head = queue.pop(&mem).unwrap();
+
+        assert_eq!(head.write_from(b"0123456789"), 10);
+
+        let mut first = [0; 4];
+        mem.read_slice(&mut first, addr(0x4000)).unwrap();
+        assert_eq!(&first, b"0123");
+        let mut second = [0; 8];
+        mem.read_slice(&mut second, addr(0x5000)).unwrap();
+        assert_eq!(&second, b"456789\0\0");
+
+        // the chain only holds 12 bytes
+        assert_eq!(head.write_from(&[0; 16]), 12);
+    }
+
+    #[test]
+    fn test_read_to_skips_write_only() {
+        let mem = test_guest_memory(0x10000);
+        mem.write_slice(b"abcd", addr(0x4000)).unwrap();
+        mem.write_slice(b"efgh", addr(0x6000)).unwrap();
+        let mut test_queue = TestQueue::new(&mem, 16);
+        test_queue.add_chain(&[
+            (addr(0x4000), 4, 0),
+            (addr(0x5000), 4, VIRTQ_DESC_F_WRITE),
+            (addr(0x6000), 4, 0),
+        ]);
+        let mut queue = test_queue.queue();
+        let head = queue.pop(&mem).unwrap();
+
+        let mut buf = [0; 16];
+        assert_eq!(head.read_to(&mut buf), 8);
+        assert_eq!(&buf[..8], b"abcdefgh");
+    }
+
+    #[test]
+    fn test_desc_reader_writer() {
+        let mem = test_guest_memory(0x10000);
+        let header = [1u8, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0];
+        // a header split over two descriptors, followed by a status byte
+        mem.write_slice(&header[..6], addr(0x4000)).unwrap();
+        mem.write_slice(&header[6..], addr(0x5000)).unwrap();
+        let mut test_queue = TestQueue::new(&mem, 16);
+        test_queue.add_chain(&[
+            (addr(0x4000), 6, 0),
+            (addr(0x5000), 10, 0),
+            (addr(0x6000), 1, VIRTQ_DESC_F_WRITE),
+        ]);
+        let mut queue = test_queue.queue();
+        let head = queue.pop(&mem).unwrap();
+        let status = head.next_descriptor().unwrap().next_descriptor().unwrap();
+
+        let mut reader = DescReader::new(head);
+        let mut read = [0; 16];
+        reader.read_exact(&mut read).unwrap();
+        assert_eq!(read, header);
+        assert_eq!(reader.read(&mut read).unwrap(), 0);
+
+        let mut writer = DescWriter::new(status);
+        writer.write_all(&[7]).unwrap();
+        assert_eq!(mem.read_obj::<u8>(addr(0x6000)).unwrap(), 7);
+        let err = writer.write_all(&[1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+}
@@ -5,26 +5,56 @@ use std::sync::atomic::{AtomicU32, Ordering};
 
 use event_manager::{MutEventSubscriber, SubscriberOps};
 
-use kvm_ioctls::VmFd;
 use linux_loader::loader::Cmdline;
 use std::io::{self};
 use std::sync::{Arc, Mutex};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
+use versionize_derive::Versionize;
 use vm_superio::Trigger;
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::vmm::event_manager::EventManager;
 use crate::vmm::memory::GuestMemoryMmap;
-use crate::vmm::mmio::mmio_manager::MMIODeviceManager;
+use crate::vmm::metrics::DeviceMetrics;
+use crate::vmm::mmio::mmio_manager::{IrqRegistrar, MMIODeviceManager};
 use crate::vmm::mmio::mmio_transport::MmioTransport;
 
+use self::bus::BusError;
+use self::queue::{Queue, QueueError};
+
 mod descriptor;
 mod i8042;
-mod queue;
 
+pub mod balloon;
 pub mod block;
 pub mod bus;
+pub mod console;
 pub mod net;
+pub mod queue;
 pub mod serial;
+pub mod watchdog;
+
+/// Virtio device status bits, as written by the driver to the Status register.
+pub mod device_status {
+    pub const INIT: u32 = 0;
+    pub const ACKNOWLEDGE: u32 = 1;
+    pub const DRIVER: u32 = 2;
+    pub const DRIVER_OK: u32 = 4;
+    pub const FEATURES_OK: u32 = 8;
+    pub const DEVICE_NEEDS_RESET: u32 = 64;
+    pub const FAILED: u32 = 128;
+}
+
+/// The device conforms to the virtio 1.0 spec, as opposed to legacy virtio.
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+
+/// The device uses buffers in the order the driver made them available.
+pub const VIRTIO_F_IN_ORDER: u32 = 35;
+
+pub const TYPE_NET: u32 = 1;
+pub const TYPE_BLOCK: u32 = 2;
+pub const TYPE_CONSOLE: u32 = 3;
+pub const TYPE_BALLOON: u32 = 5;
 
 pub trait AsAny {
     /// Return the immutable any encapsulated object.
@@ -48,26 +78,50 @@ impl<T: Any> AsAny for T {
 pub enum DeviceState {
     Inactive,
     Activated(GuestMemoryMmap),
+    /// The device failed and stopped servicing its queues, until the driver resets it.
+    Broken,
 }
 
 impl DeviceState {
     /// Checks if the device is activated.
     pub fn is_activated(&self) -> bool {
         match self {
-            DeviceState::Inactive => false,
+            DeviceState::Inactive | DeviceState::Broken => false,
             DeviceState::Activated(_) => true,
         }
     }
 
+    pub fn is_broken(&self) -> bool {
+        matches!(self, DeviceState::Broken)
+    }
+
     /// Gets the memory attached to the device if it is activated.
     pub fn mem(&self) -> Option<&GuestMemoryMmap> {
         match self {
             DeviceState::Activated(ref mem) => Some(mem),
-            DeviceState::Inactive => None,
+            DeviceState::Inactive | DeviceState::Broken => None,
         }
     }
 }
 
+#[derive(Debug)]
+pub enum ActivateError {
+    /// The queue at the given index was set up with an invalid layout.
+    InvalidQueue(usize, QueueError),
+    /// Failed to signal the device's activate event.
+    EventFd(io::Error),
+    /// The device's host side couldn't be set up for the acked features.
+    Backend(io::Error),
+}
+
+#[derive(Debug)]
+pub enum QuiesceError {
+    /// Failed to complete the requests pending in a queue.
+    Queue(QueueError),
+    /// Failed to flush the device's backend.
+    Io(io::Error),
+}
+
 #[derive(Debug)]
 pub enum IrqType {
     /// Interrupt triggered by change in config.
@@ -80,17 +134,25 @@ pub enum IrqType {
 #[derive(Debug)]
 pub struct IrqTrigger {
     pub(crate) irq_status: Arc<AtomicU32>,
+    /// Read by the driver from the transport's `ConfigGeneration` register.
+    pub(crate) config_generation: Arc<AtomicU32>,
     pub(crate) irq_evt: EventFd,
+    pub(crate) metrics: Arc<DeviceMetrics>,
 }
 
 impl IrqTrigger {
     pub fn new() -> std::io::Result<Self> {
         Ok(Self {
             irq_status: Arc::new(AtomicU32::new(0)),
+            config_generation: Arc::new(AtomicU32::new(0)),
             irq_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            metrics: Arc::new(DeviceMetrics::default()),
         })
     }
 
+    /// Sets the interrupt status bit for `irq_type` and signals the guest. Fails when the
+    /// interrupt eventfd can't be written, in which case the guest doesn't see the interrupt
+    /// until the next one is delivered.
     pub fn trigger_irq(&self, irq_type: IrqType) -> Result<(), std::io::Error> {
         let irq = match irq_type {
             IrqType::Config => 0x02,
@@ -98,29 +160,119 @@ impl IrqTrigger {
         };
         self.irq_status.fetch_or(irq, Ordering::SeqCst);
 
-        self.irq_evt
-            .write(1)
-            .map_err(|err| {
-                panic!("Failed to send irq to the guest: {:?}", err);
-            })
-            .unwrap();
+        eventfd_write_retry(&self.irq_evt, 1)?;
+        self.metrics.irq_count.inc();
 
         Ok(())
     }
+
+    /// Tells the driver the device changed its config space on its own. The new generation
+    /// makes the driver read the config space again, even if it's in the middle of reading it.
+    pub fn notify_config_change(&self) -> Result<(), std::io::Error> {
+        self.config_generation.fetch_add(1, Ordering::SeqCst);
+        self.trigger_irq(IrqType::Config)
+    }
+}
+
+// Enough for any realistic signal load, an eventfd write interrupted more often than that
+// isn't going to succeed.
+const EVENTFD_WRITE_RETRIES: u32 = 16;
+
+/// Adds `value` to `evt`, retrying when a signal interrupts the write. Other errors, and
+/// interruptions past `EVENTFD_WRITE_RETRIES`, are returned.
+pub fn eventfd_write_retry(evt: &EventFd, value: u64) -> io::Result<()> {
+    let mut retries = 0;
+    loop {
+        match evt.write(value) {
+            Err(err)
+                if err.kind() == io::ErrorKind::Interrupted && retries < EVENTFD_WRITE_RETRIES =>
+            {
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
 pub trait VirtioDevice: AsAny + Send {
     fn device_type(&self) -> u32;
 
+    /// Feature bits offered to the driver.
+    fn avail_features(&self) -> u64 {
+        0
+    }
+
+    /// Called with the feature bits the driver accepted, 32 bits at a time. `page` 0 holds
+    /// bits 0 to 31 and page 1 bits 32 to 63.
+    fn ack_features_by_page(&mut self, _page: u32, _value: u32) {}
+
+    fn queues(&self) -> &[Queue];
+
+    fn queues_mut(&mut self) -> &mut [Queue];
+
     fn queue_events(&self) -> &[EventFd];
 
     fn interrupt_evt(&self) -> &EventFd;
 
     fn interrupt_status(&self) -> Arc<AtomicU32>;
 
-    fn reset(&mut self) -> Option<(EventFd, Vec<EventFd>)> {
+    /// Counter of changes to the config space, bumped by the transport on driver writes and by
+    /// the device through `IrqTrigger::notify_config_change`.
+    fn config_generation(&self) -> Arc<AtomicU32>;
+
+    /// Called from the vcpu thread once the driver set `DRIVER_OK`. Devices finish activating
+    /// on the device thread, signalled through their activate event. Nothing is lost if the
+    /// driver kicks a queue before that: the kick stays pending in the queue's eventfd, and
+    /// epoll reports it as soon as the device registers it.
+    fn activate(&mut self, mem: GuestMemoryMmap) -> Result<(), ActivateError>;
+
+    fn is_activated(&self) -> bool;
+
+    /// Signalled by the device thread once it finished activating the device. The transport
+    /// can hold the vcpu until then, see `MmioTransport::set_activation_barrier`.
+    fn ready_event(&self) -> Option<&EventFd> {
         None
     }
+
+    /// Whether the device failed and stopped servicing its queues. The transport then reports
+    /// `DEVICE_NEEDS_RESET` to the driver.
+    fn is_broken(&self) -> bool {
+        false
+    }
+
+    /// Reads the device specific configuration space, `offset` is relative to its start. The
+    /// guest controls `offset` and the length of `data`, bytes past the end of the config space
+    /// have to be left as they are.
+    fn read_config(&self, _offset: u64, _data: &mut [u8]) {}
+
+    /// Writes the device specific configuration space, `offset` is relative to its start.
+    /// Writes past the end of the config space have to be ignored.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Completes the requests the driver made available and flushes the device's backend,
+    /// so nothing is in flight when the device state is saved.
+    fn quiesce(&mut self) -> Result<(), QuiesceError> {
+        Ok(())
+    }
+
+    /// Brings the device back to its state before the driver set it up: inactive, with
+    /// fresh queues and no negotiated features. The queue and interrupt eventfds are kept, so
+    /// their KVM and event loop registrations stay valid. Returns false if the device can't
+    /// be reset.
+    fn reset(&mut self) -> bool {
+        false
+    }
+}
+
+/// Copies the part of `config` at `offset` into `data`. Bytes past the end of the config
+/// space are left untouched, the transport zeroes `data` before asking the device.
+pub fn read_config_bytes(config: &[u8], offset: u64, data: &mut [u8]) {
+    let start = match usize::try_from(offset) {
+        Ok(start) if start < config.len() => start,
+        _ => return,
+    };
+    let len = data.len().min(config.len() - start);
+    data[..len].copy_from_slice(&config[start..start + len]);
 }
 
 impl fmt::Debug for dyn VirtioDevice {
@@ -129,11 +281,13 @@ impl fmt::Debug for dyn VirtioDevice {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Copy)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Copy, Versionize)]
 pub enum DeviceType {
     Virtio(u32),
     Serial,
     Rtc,
+    Pci,
+    Watchdog,
 }
 
 impl fmt::Display for DeviceType {
@@ -144,17 +298,69 @@ impl fmt::Display for DeviceType {
 
 pub fn attach_virtio_device<T: 'static + VirtioDevice + MutEventSubscriber + Debug>(
     guest_memory: &GuestMemoryMmap,
-    vm_fd: &VmFd,
+    vm_fd: &dyn IrqRegistrar,
     mmio_device_manager: &mut MMIODeviceManager,
     event_manager: &mut EventManager,
     id: String,
     device: Arc<Mutex<T>>,
     cmdline: &mut Cmdline,
     is_vhost_user: bool,
-) {
+) -> Result<(), BusError> {
     event_manager.add_subscriber(device.clone());
 
     let device = MmioTransport::new(guest_memory.clone(), device, is_vhost_user);
 
-    mmio_device_manager.register_mmio_virtio_for_boot(vm_fd, id, device, cmdline);
+    mmio_device_manager.register_mmio_virtio_for_boot(vm_fd, id, device, cmdline)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::thread;
+    use std::time::Duration;
+
+    use vmm_sys_util::signal::SIGRTMIN;
+
+    use crate::vmm::api::register_kick_handler;
+
+    use super::*;
+
+    #[test]
+    fn test_trigger_irq_closed_eventfd() {
+        let mut trigger = IrqTrigger::new().unwrap();
+        trigger.trigger_irq(IrqType::Vring).unwrap();
+        assert_eq!(trigger.irq_evt.read().unwrap(), 1);
+
+        // stands in for an eventfd that was closed, without closing an fd another test may
+        // have been given in the meantime
+        // SAFETY: -1 is never a valid fd, writes to it and closing it fail with EBADF.
+        trigger.irq_evt = unsafe { EventFd::from_raw_fd(-1) };
+
+        assert!(trigger.trigger_irq(IrqType::Vring).is_err());
+        assert_eq!(trigger.metrics.irq_count.count(), 1);
+    }
+
+    #[test]
+    fn test_eventfd_write_retry_interrupted() {
+        register_kick_handler().unwrap();
+        // a blocking eventfd with a full counter, writes to it wait for a read
+        let evt = Arc::new(EventFd::new(0).unwrap());
+        evt.write(u64::MAX - 1).unwrap();
+
+        let writer_evt = evt.clone();
+        let writer = thread::spawn(move || eventfd_write_retry(&writer_evt, 1));
+        thread::sleep(Duration::from_millis(100));
+        // SAFETY: The thread isn't joined yet, so it still exists.
+        unsafe { libc::pthread_kill(writer.as_pthread_t(), SIGRTMIN()) };
+        thread::sleep(Duration::from_millis(100));
+        // the interrupted write was retried and waits again
+        assert!(!writer.is_finished());
+
+        assert_eq!(evt.read().unwrap(), u64::MAX - 1);
+        writer.join().unwrap().unwrap();
+        assert_eq!(evt.read().unwrap(), 1);
+    }
 }
@@ -1,41 +1,374 @@
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
 use std::sync::{atomic::AtomicU32, Arc};
 
-use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use event_manager::{Error as EventManagerError, EventOps, EventSet, Events, MutEventSubscriber};
+use log::{debug, error, warn};
 use vmm_sys_util::eventfd::EventFd;
 
-use super::{IrqTrigger, VirtioDevice};
+use crate::vmm::memory::GuestMemoryMmap;
+use crate::vmm::metrics::DeviceMetrics;
+use crate::vmm::rate_limiter::{RateLimiter, RateLimiterConfig};
+
+use super::descriptor::{DescReader, DescWriter};
+use super::queue::{Queue, QueueError};
+use super::{
+    eventfd_write_retry, read_config_bytes, ActivateError, DeviceState, IrqTrigger, IrqType,
+    VirtioDevice, TYPE_NET, VIRTIO_F_VERSION_1,
+};
+
+use self::tap::NetBackend;
+
+pub mod tap;
+
+// Offloads: the CSUM/HOST features let the guest hand over packets with partial checksums
+// and large segments, the GUEST ones let the device do the same towards the guest.
+const VIRTIO_NET_F_CSUM: u32 = 0;
+const VIRTIO_NET_F_GUEST_CSUM: u32 = 1;
+/// The device has a fixed MAC address, readable at the start of the config space.
+const VIRTIO_NET_F_MAC: u32 = 5;
+const VIRTIO_NET_F_GUEST_TSO4: u32 = 7;
+const VIRTIO_NET_F_GUEST_TSO6: u32 = 8;
+const VIRTIO_NET_F_GUEST_UFO: u32 = 10;
+const VIRTIO_NET_F_HOST_TSO4: u32 = 11;
+const VIRTIO_NET_F_HOST_TSO6: u32 = 12;
+const VIRTIO_NET_F_HOST_UFO: u32 = 14;
+/// The link status is readable in the config space, after the MAC address.
+const VIRTIO_NET_F_STATUS: u32 = 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const RX_INDEX: usize = 0;
+const TX_INDEX: usize = 1;
+
+/// Queue size of devices that don't configure one.
+pub const QUEUE_SIZE: u16 = 256;
+
+/// Size of the `struct virtio_net_hdr_v1` every packet on the queues starts with.
+pub const VIRTIO_NET_HDR_SIZE: usize = 12;
+
+// Largest frame with its header: a 64 KiB TSO or UFO segment and the ethernet header.
+const MAX_FRAME_SIZE: usize = VIRTIO_NET_HDR_SIZE + 65550;
+
+// Offload flags for the TUNSETOFFLOAD ioctl, from `linux/if_tun.h`.
+const TUN_F_CSUM: u32 = 0x01;
+const TUN_F_TSO4: u32 = 0x02;
+const TUN_F_TSO6: u32 = 0x04;
+const TUN_F_UFO: u32 = 0x10;
 
 #[derive(Debug)]
 pub struct Net {
+    pub queues: Vec<Queue>,
     pub queue_events: Vec<EventFd>,
     pub irq_trigger: IrqTrigger,
     pub activate_event: EventFd,
+    pub device_state: DeviceState,
+    pub metrics: Arc<DeviceMetrics>,
+    /// Where frames go to and come from. Without one the device drops what the guest sends
+    /// and never receives anything.
+    pub backend: Option<Box<dyn NetBackend + Send>>,
+    /// MAC address offered to the guest, without one the guest driver picks a random one.
+    pub mac: Option<[u8; 6]>,
+    pub acked_features: u64,
+    /// Link status reported to the guest, up unless changed with `set_link_up`.
+    pub link_up: bool,
+    pub rx_rate_limiter: RateLimiter,
+    pub tx_rate_limiter: RateLimiter,
+    // Frame read from the backend that didn't fit in the rx queue yet, `rx_frame_len` bytes
+    // of it. The backend isn't read again until it's delivered.
+    rx_frame: Vec<u8>,
+    rx_frame_len: usize,
+    tx_frame: Vec<u8>,
 }
 
 impl Net {
-    pub fn new() -> Net {
-        let net_que_size = [256; 2];
+    pub fn new(
+        mac: Option<[u8; 6]>,
+        backend: Option<Box<dyn NetBackend + Send>>,
+        rx_rate_limiter: RateLimiterConfig,
+        tx_rate_limiter: RateLimiterConfig,
+        queue_size: u16,
+    ) -> Net {
+        let net_que_size = [queue_size; 2];
+        let mut queues = Vec::new();
         let mut queue_events = Vec::new();
 
-        for _size in net_que_size {
+        for size in net_que_size {
+            queues.push(Queue::new(size));
             queue_events.push(EventFd::new(libc::EFD_NONBLOCK).unwrap());
         }
 
         let irq_trigger = IrqTrigger::new().unwrap();
 
         let activate_event = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let metrics = irq_trigger.metrics.clone();
+        let rx_rate_limiter = RateLimiter::new(rx_rate_limiter).unwrap();
+        let tx_rate_limiter = RateLimiter::new(tx_rate_limiter).unwrap();
 
         Net {
+            queues,
             queue_events,
             irq_trigger,
             activate_event,
+            device_state: DeviceState::Inactive,
+            metrics,
+            backend,
+            mac,
+            acked_features: 0,
+            link_up: true,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            rx_frame: vec![0; MAX_FRAME_SIZE],
+            rx_frame_len: 0,
+            tx_frame: Vec::with_capacity(MAX_FRAME_SIZE),
+        }
+    }
+
+    /// Changes the link status the guest sees and notifies the driver when it changed.
+    pub fn set_link_up(&mut self, link_up: bool) -> std::io::Result<()> {
+        if self.link_up == link_up {
+            return Ok(());
+        }
+        self.link_up = link_up;
+
+        self.irq_trigger.notify_config_change()
+    }
+
+    /// Sends the frames the guest transmitted to the backend, or drops them without one.
+    /// Stops early when the tx rate limiter runs out of budget; the remaining frames are
+    /// picked up again once its timer fires.
+    pub fn process_tx_queue(&mut self) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let queue = &mut self.queues[TX_INDEX];
+
+        let mut used_any = false;
+        while let Some(head) = queue.pop(mem) {
+            let index = head.index;
+            self.tx_frame.clear();
+            let reader = DescReader::new(head).take(MAX_FRAME_SIZE as u64);
+            let read = io::copy(&mut { reader }, &mut self.tx_frame);
+
+            if !self.tx_rate_limiter.consume(self.tx_frame.len() as u64) {
+                queue.undo_pop();
+                break;
+            }
+
+            match (read, self.backend.as_mut()) {
+                (Ok(_), Some(backend)) => match backend.write_frame(&self.tx_frame) {
+                    Ok(()) => self.metrics.write_bytes.add(self.tx_frame.len() as u64),
+                    Err(err) => warn!("failed to send net frame: {:?}", err),
+                },
+                (Ok(_), None) => {}
+                (Err(err), _) => warn!("failed to read net frame: {:?}", err),
+            }
+
+            queue.add_used(mem, index, 0)?;
+            self.metrics.requests_completed.inc();
+            used_any = true;
+        }
+
+        if used_any {
+            if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+                error!("failed to trigger net irq: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the frames pending on the backend to the buffers the driver made available on
+    /// the rx queue. A frame that doesn't fit in its buffer is dropped. Stops early when the
+    /// rx rate limiter runs out of budget, the frame then waits for its timer.
+    pub fn process_rx(&mut self) -> Result<(), QueueError> {
+        let mem = match self.device_state.mem() {
+            Some(mem) => mem,
+            None => return Ok(()),
+        };
+        let backend = match self.backend.as_mut() {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+        let queue = &mut self.queues[RX_INDEX];
+
+        let mut used_any = false;
+        loop {
+            // the backend is edge triggered, it has to be read until it runs dry
+            if self.rx_frame_len == 0 {
+                match backend.read_frame(&mut self.rx_frame) {
+                    Ok(0) => break,
+                    Ok(len) => self.rx_frame_len = len,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        error!("failed to receive net frame: {:?}", err);
+                        break;
+                    }
+                }
+            }
+
+            if !self.rx_rate_limiter.consume(self.rx_frame_len as u64) {
+                break;
+            }
+
+            // kept until the driver adds buffers
+            let head = match queue.pop(mem) {
+                Some(head) => head,
+                None => {
+                    self.metrics.queue_full.inc();
+                    break;
+                }
+            };
+            let index = head.index;
+
+            let frame = &self.rx_frame[..self.rx_frame_len];
+            let len = match DescWriter::new(head).write_all(frame) {
+                Ok(()) => {
+                    self.metrics.read_bytes.add(frame.len() as u64);
+                    frame.len() as u32
+                }
+                Err(err) => {
+                    warn!("dropping net frame of {} bytes: {:?}", frame.len(), err);
+                    0
+                }
+            };
+            self.rx_frame_len = 0;
+
+            queue.add_used(mem, index, len)?;
+            self.metrics.requests_completed.inc();
+            used_any = true;
         }
+
+        if used_any {
+            if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+                error!("failed to trigger net irq: {:?}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Stops servicing the queues after a failure the driver can only recover from with a
+    // reset, and tells it through a config change.
+    fn mark_broken(&mut self, err: &dyn Debug) {
+        error!("net device failed: {:?}", err);
+        self.device_state = DeviceState::Broken;
+        if let Err(err) = self.irq_trigger.notify_config_change() {
+            error!("failed to trigger net irq: {:?}", err);
+        }
+    }
+
+    fn process_activate_event(&mut self, ops: &mut EventOps) {
+        if let Err(err) = self.activate_event.read() {
+            panic!("Failed to consume net activate event: {:?}", err);
+        }
+
+        // After a reset the device is activated again with these still registered, the
+        // activate event stays registered for the same reason.
+        for queue_event in &self.queue_events {
+            match ops.add(Events::new(queue_event, EventSet::IN)) {
+                Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+                Err(err) => panic!("Failed to register net queue event: {}", err),
+            }
+        }
+
+        for rate_limiter in [&self.rx_rate_limiter, &self.tx_rate_limiter] {
+            match ops.add(Events::new(rate_limiter, EventSet::IN)) {
+                Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+                Err(err) => panic!("Failed to register net rate limiter event: {}", err),
+            }
+        }
+
+        let result = match &self.backend {
+            Some(backend) => ops.add(Events::new_raw(
+                backend.as_raw_fd(),
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            )),
+            None => Ok(()),
+        };
+        match result {
+            Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+            Err(err) => {
+                self.mark_broken(&err);
+                return;
+            }
+        }
+
+        // packets the driver queued before the device was ready, and frames that arrived
+        // before then
+        if let Err(err) = self.process_tx_queue() {
+            self.mark_broken(&err);
+            return;
+        }
+        if let Err(err) = self.process_rx() {
+            self.mark_broken(&err);
+        }
+    }
+
+    /// Offloads the tap has to do for the features the driver acked, as passed to
+    /// `TUNSETOFFLOAD`. Packets for the guest may only use the offloads it accepted.
+    pub fn tap_offload_flags(&self) -> u32 {
+        let acked = |feature: u32| self.acked_features & (1 << feature) != 0;
+
+        let mut flags = 0;
+        if acked(VIRTIO_NET_F_GUEST_CSUM) {
+            flags |= TUN_F_CSUM;
+        }
+        if acked(VIRTIO_NET_F_GUEST_TSO4) {
+            flags |= TUN_F_TSO4;
+        }
+        if acked(VIRTIO_NET_F_GUEST_TSO6) {
+            flags |= TUN_F_TSO6;
+        }
+        if acked(VIRTIO_NET_F_GUEST_UFO) {
+            flags |= TUN_F_UFO;
+        }
+        flags
     }
 }
 
 impl VirtioDevice for Net {
     fn device_type(&self) -> u32 {
-        1
+        TYPE_NET
+    }
+
+    fn avail_features(&self) -> u64 {
+        let mut features = (1 << VIRTIO_F_VERSION_1) | (1 << VIRTIO_NET_F_STATUS);
+        // the tap does the offloads, frames are only dropped without one
+        if self.backend.is_some() {
+            features |= (1 << VIRTIO_NET_F_CSUM)
+                | (1 << VIRTIO_NET_F_GUEST_CSUM)
+                | (1 << VIRTIO_NET_F_GUEST_TSO4)
+                | (1 << VIRTIO_NET_F_GUEST_TSO6)
+                | (1 << VIRTIO_NET_F_GUEST_UFO)
+                | (1 << VIRTIO_NET_F_HOST_TSO4)
+                | (1 << VIRTIO_NET_F_HOST_TSO6)
+                | (1 << VIRTIO_NET_F_HOST_UFO);
+        }
+        if self.mac.is_some() {
+            features |= 1 << VIRTIO_NET_F_MAC;
+        }
+        features
+    }
+
+    fn ack_features_by_page(&mut self, page: u32, value: u32) {
+        let features = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => return,
+        };
+        // the driver can't accept anything that wasn't offered
+        self.acked_features |= features & self.avail_features();
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
     }
 
     fn queue_events(&self) -> &[EventFd] {
@@ -49,17 +382,268 @@ impl VirtioDevice for Net {
     fn interrupt_status(&self) -> Arc<AtomicU32> {
         self.irq_trigger.irq_status.clone()
     }
+
+    fn config_generation(&self) -> Arc<AtomicU32> {
+        self.irq_trigger.config_generation.clone()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> Result<(), ActivateError> {
+        // the features are final now, the tap must not hand the guest offloaded frames it
+        // didn't accept
+        let offload_flags = self.tap_offload_flags();
+        if let Some(backend) = self.backend.as_mut() {
+            backend
+                .set_offload(offload_flags)
+                .map_err(ActivateError::Backend)?;
+        }
+
+        eventfd_write_retry(&self.activate_event, 1).map_err(ActivateError::EventFd)?;
+        self.device_state = DeviceState::Activated(mem);
+
+        Ok(())
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn is_broken(&self) -> bool {
+        self.device_state.is_broken()
+    }
+
+    fn reset(&mut self) -> bool {
+        self.queues = self
+            .queues
+            .iter()
+            .map(|queue| Queue::new(queue.get_max_size()))
+            .collect();
+        self.acked_features = 0;
+        self.device_state = DeviceState::Inactive;
+        self.rx_frame_len = 0;
+        // drop kicks the driver made before the reset
+        for queue_event in &self.queue_events {
+            let _ = queue_event.read();
+        }
+
+        true
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // `struct virtio_net_config` up to the status, the MAC is only valid with
+        // VIRTIO_NET_F_MAC
+        let mut config = [0; 8];
+        if let Some(mac) = &self.mac {
+            config[..6].copy_from_slice(mac);
+        }
+        let status = if self.link_up {
+            VIRTIO_NET_S_LINK_UP
+        } else {
+            0
+        };
+        config[6..].copy_from_slice(&status.to_le_bytes());
+
+        read_config_bytes(&config, offset, data);
+    }
 }
 
 impl MutEventSubscriber for Net {
     fn process(&mut self, event: Events, ops: &mut EventOps) {
-        todo!();
+        let source = event.fd();
+
+        if source == self.activate_event.as_raw_fd() {
+            self.process_activate_event(ops);
+        } else if source == self.queue_events[TX_INDEX].as_raw_fd() {
+            let _ = self.queue_events[TX_INDEX].read();
+            if let Err(err) = self.process_tx_queue() {
+                self.mark_broken(&err);
+            }
+        } else if source == self.queue_events[RX_INDEX].as_raw_fd() {
+            // new buffers for a frame that didn't fit before
+            let _ = self.queue_events[RX_INDEX].read();
+            if let Err(err) = self.process_rx() {
+                self.mark_broken(&err);
+            }
+        } else if self
+            .backend
+            .as_ref()
+            .is_some_and(|backend| source == backend.as_raw_fd())
+        {
+            if let Err(err) = self.process_rx() {
+                self.mark_broken(&err);
+            }
+        } else if source == self.rx_rate_limiter.as_raw_fd() {
+            if let Err(err) = self.rx_rate_limiter.event_handler() {
+                panic!("Failed to handle net rx rate limiter event: {:?}", err);
+            }
+            if let Err(err) = self.process_rx() {
+                self.mark_broken(&err);
+            }
+        } else if source == self.tx_rate_limiter.as_raw_fd() {
+            if let Err(err) = self.tx_rate_limiter.event_handler() {
+                panic!("Failed to handle net tx rate limiter event: {:?}", err);
+            }
+            if let Err(err) = self.process_tx_queue() {
+                self.mark_broken(&err);
+            }
+        }
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        dbg!("net device init called");
+        debug!("net device init called");
         if let Err(err) = ops.add(Events::new(&self.activate_event, EventSet::IN)) {
             panic!("Failed to register activate event: {}", err);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    use crate::vmm::memory::test_guest_memory;
+
+    use super::*;
+
+    // Records the offloads it was asked for, and never has frames for the guest.
+    #[derive(Debug)]
+    struct MockTap {
+        offload: Arc<Mutex<Option<u32>>>,
+        evt: EventFd,
+    }
+
+    impl NetBackend for MockTap {
+        fn read_frame(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::WouldBlock))
+        }
+
+        fn write_frame(&mut self, _buf: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_offload(&mut self, flags: u32) -> io::Result<()> {
+            *self.offload.lock().unwrap() = Some(flags);
+            Ok(())
+        }
+    }
+
+    impl AsRawFd for MockTap {
+        fn as_raw_fd(&self) -> RawFd {
+            self.evt.as_raw_fd()
+        }
+    }
+
+    fn net_with_mock_tap() -> (Net, Arc<Mutex<Option<u32>>>) {
+        let offload = Arc::new(Mutex::new(None));
+        let tap = MockTap {
+            offload: offload.clone(),
+            evt: EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        };
+        let net = Net::new(
+            None,
+            Some(Box::new(tap)),
+            RateLimiterConfig::default(),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        );
+
+        (net, offload)
+    }
+
+    #[test]
+    fn test_acked_csum_sets_tap_offload() {
+        let (mut net, offload) = net_with_mock_tap();
+        net.ack_features_by_page(0, (1 << VIRTIO_NET_F_CSUM) | (1 << VIRTIO_NET_F_GUEST_CSUM));
+        net.activate(test_guest_memory(0x10000)).unwrap();
+
+        assert_eq!(*offload.lock().unwrap(), Some(TUN_F_CSUM));
+    }
+
+    #[test]
+    fn test_acked_gso_sets_tap_offload() {
+        let (mut net, offload) = net_with_mock_tap();
+        net.ack_features_by_page(
+            0,
+            (1 << VIRTIO_NET_F_GUEST_CSUM)
+                | (1 << VIRTIO_NET_F_GUEST_TSO4)
+                | (1 << VIRTIO_NET_F_GUEST_TSO6)
+                | (1 << VIRTIO_NET_F_GUEST_UFO),
+        );
+        net.activate(test_guest_memory(0x10000)).unwrap();
+
+        assert_eq!(
+            *offload.lock().unwrap(),
+            Some(TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_UFO)
+        );
+    }
+
+    #[test]
+    fn test_no_offloads_without_tap() {
+        let net = Net::new(
+            None,
+            None,
+            RateLimiterConfig::default(),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        );
+
+        let offloads = (1 << VIRTIO_NET_F_CSUM) | (1 << VIRTIO_NET_F_GUEST_CSUM);
+        assert_eq!(net.avail_features() & offloads, 0);
+    }
+
+    #[test]
+    fn test_read_mac() {
+        let mac = [0x06, 0x00, 0xac, 0x10, 0x00, 0x02];
+        let net = Net::new(
+            Some(mac),
+            None,
+            RateLimiterConfig::default(),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        );
+
+        let mut data = [0; 6];
+        net.read_config(0, &mut data);
+        assert_eq!(data, mac);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MAC), 0);
+    }
+
+    #[test]
+    fn test_no_mac() {
+        let net = Net::new(
+            None,
+            None,
+            RateLimiterConfig::default(),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        );
+
+        let mut data = [0xff; 6];
+        net.read_config(0, &mut data);
+        assert_eq!(data, [0; 6]);
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_MAC), 0);
+    }
+
+    #[test]
+    fn test_set_link_up_notifies_config_change() {
+        let (mut net, _) = net_with_mock_tap();
+        let mut status = [0; 2];
+
+        net.set_link_up(false).unwrap();
+        net.read_config(6, &mut status);
+        assert_eq!(u16::from_le_bytes(status), 0);
+        assert_eq!(net.config_generation().load(Ordering::SeqCst), 1);
+        assert_eq!(net.interrupt_status().load(Ordering::SeqCst), 0x02);
+        assert_eq!(net.irq_trigger.irq_evt.read().unwrap(), 1);
+
+        // setting the same status again is not a change
+        net.set_link_up(false).unwrap();
+        assert_eq!(net.config_generation().load(Ordering::SeqCst), 1);
+
+        net.set_link_up(true).unwrap();
+        net.read_config(6, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP);
+        assert_eq!(net.config_generation().load(Ordering::SeqCst), 2);
+    }
+}
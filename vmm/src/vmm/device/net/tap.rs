@@ -0,0 +1,111 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::VIRTIO_NET_HDR_SIZE;
+
+// From `linux/if_tun.h`, all `_IOW('T', nr, int)`.
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNSETOFFLOAD: libc::c_ulong = 0x4004_54d0;
+const TUNSETVNETHDRSZ: libc::c_ulong = 0x4004_54d8;
+
+/// Host side of a net device, exchanging frames that start with a `struct virtio_net_hdr_v1`,
+/// the way they sit on the queues.
+pub trait NetBackend: Debug + AsRawFd {
+    /// Reads one frame into `buf`, failing with `WouldBlock` when none is pending. Frames
+    /// longer than `buf` are truncated.
+    fn read_frame(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes the frame in `buf`.
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Lets the backend hand over frames using the offloads in `flags`, a combination of the
+    /// `TUN_F_*` flags.
+    fn set_offload(&mut self, flags: u32) -> io::Result<()>;
+}
+
+// `struct ifreq` with the flags member of its union, padded to the full size.
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// A tap interface, opened non-blocking with a virtio net header in front of every frame.
+#[derive(Debug)]
+pub struct Tap {
+    file: File,
+}
+
+impl Tap {
+    /// Attaches to the tap interface `if_name`, which is created if it doesn't exist yet.
+    pub fn open(if_name: &str) -> io::Result<Tap> {
+        // leave room for the nul
+        if if_name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+
+        let mut ifreq = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: (libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR) as libc::c_short,
+            _pad: [0; 22],
+        };
+        ifreq.name[..if_name.len()].copy_from_slice(if_name.as_bytes());
+        // SAFETY: `ifreq` has the layout of `struct ifreq`, which the kernel reads and writes.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut ifreq) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let hdr_size = VIRTIO_NET_HDR_SIZE as libc::c_int;
+        // SAFETY: The kernel only reads the int.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETVNETHDRSZ, &hdr_size) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Tap { file })
+    }
+}
+
+impl NetBackend for Tap {
+    fn read_frame(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> io::Result<()> {
+        // the tap takes whole frames, a short write drops the rest
+        self.file.write(buf).map(|_| ())
+    }
+
+    fn set_offload(&mut self, flags: u32) -> io::Result<()> {
+        // SAFETY: TUNSETOFFLOAD takes the flags by value.
+        let ret = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                TUNSETOFFLOAD,
+                libc::c_ulong::from(flags),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -2,12 +2,54 @@ use std::cmp::min;
 use std::num::Wrapping;
 use std::sync::atomic::{fence, Ordering};
 
+use log::warn;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
 use crate::vmm::device::descriptor::DescriptorChain;
+#[cfg(test)]
+use crate::vmm::device::descriptor::VIRTQ_DESC_F_NEXT;
+#[cfg(test)]
+use crate::vmm::memory::GuestMemoryMmap;
 use crate::vmm::memory::{Address, Bytes, GuestAddress, GuestMemory};
 
+#[derive(Debug)]
 pub enum QueueError {
     DescIndexOutOfBounds(u16),
     UsedRing(vm_memory::GuestMemoryError),
+    DescTableOutOfBounds(GuestAddress),
+    AvailRingOutOfBounds(GuestAddress),
+    UsedRingOutOfBounds(GuestAddress),
+    RingsOverlap,
+    /// The driver hasn't marked the queue ready.
+    NotReady,
+    /// The size isn't a power of two between 1 and the queue's maximum size.
+    BadSize(u16),
+    DescTableMisaligned(GuestAddress),
+    AvailRingMisaligned(GuestAddress),
+    UsedRingMisaligned(GuestAddress),
+    /// The used length given for a chain, the second value, is larger than its write only
+    /// descriptors.
+    UsedLenTooLarge(u16, u32),
+}
+
+/// Largest queue size the virtio spec allows for split virtqueues.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+/// Snapshot of a queue's driver-programmed configuration and ring positions.
+#[derive(Clone, Debug, Default, Versionize)]
+pub struct QueueState {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    pub next_avail: Wrapping<u16>,
+    pub next_used: Wrapping<u16>,
+    pub uses_notif_suppression: bool,
+    pub num_added: Wrapping<u16>,
+    pub in_order: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -38,6 +80,9 @@ pub struct Queue {
     pub(crate) uses_notif_suppression: bool,
     /// The number of added used buffers since last guest kick
     pub(crate) num_added: Wrapping<u16>,
+
+    /// VIRTIO_F_IN_ORDER negotiated (buffers are used in the order they were made available)
+    pub(crate) in_order: bool,
 }
 
 impl Queue {
@@ -54,6 +99,61 @@ impl Queue {
             next_used: Wrapping(0),
             uses_notif_suppression: false,
             num_added: Wrapping(0),
+            in_order: false,
+        }
+    }
+
+    /// Constructs a ready queue of `size` elements with the rings at the given guest addresses,
+    /// as if a driver had set it up. Meant for harnesses driving the queue without a transport,
+    /// the addresses aren't validated.
+    pub fn from_parts(
+        max_size: u16,
+        size: u16,
+        desc_table: GuestAddress,
+        avail_ring: GuestAddress,
+        used_ring: GuestAddress,
+    ) -> Queue {
+        Queue {
+            size,
+            ready: true,
+            desc_table,
+            avail_ring,
+            used_ring,
+            ..Queue::new(max_size)
+        }
+    }
+
+    /// Saves the queue state so it can be restored in a new process.
+    pub fn save(&self) -> QueueState {
+        QueueState {
+            max_size: self.max_size,
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table.0,
+            avail_ring: self.avail_ring.0,
+            used_ring: self.used_ring.0,
+            next_avail: self.next_avail,
+            next_used: self.next_used,
+            uses_notif_suppression: self.uses_notif_suppression,
+            num_added: self.num_added,
+            in_order: self.in_order,
+        }
+    }
+
+    /// Rebuilds a queue from a previously saved state.
+    pub fn restore(state: &QueueState) -> Queue {
+        Queue {
+            max_size: state.max_size,
+            size: state.size,
+            ready: state.ready,
+            desc_table: GuestAddress(state.desc_table),
+            avail_ring: GuestAddress(state.avail_ring),
+            used_ring: GuestAddress(state.used_ring),
+            next_avail: state.next_avail,
+            next_used: state.next_used,
+            uses_notif_suppression: state.uses_notif_suppression,
+            num_added: state.num_added,
+            in_order: state.in_order,
         }
     }
 
@@ -68,57 +168,81 @@ impl Queue {
         min(self.size, self.max_size)
     }
 
-    /// Validates the queue's in-memory layout is correct.
-    pub fn is_layout_valid<M: GuestMemory>(&self, mem: &M) -> bool {
+    /// Checks the queue's in-memory layout, returning the first problem found: the queue
+    /// isn't ready, its size isn't a power of two up to `max_size`, one of the rings is
+    /// misaligned or doesn't fit in guest memory.
+    pub fn check_layout<M: GuestMemory>(&self, mem: &M) -> Result<(), QueueError> {
         let queue_size = usize::from(self.actual_size());
-        let desc_table = self.desc_table;
         let desc_table_size = 16 * queue_size;
-        let avail_ring = self.avail_ring;
         let avail_ring_size = 6 + 2 * queue_size;
-        let used_ring = self.used_ring;
         let used_ring_size = 6 + 8 * queue_size;
 
         if !self.ready {
-            dbg!("attempt to use virtio queue that is not marked ready");
-            false
-        } else if self.size > self.max_size || self.size == 0 || (self.size & (self.size - 1)) != 0
-        {
-            dbg!("virtio queue with invalid size: {}", self.size);
-            false
-        } else if desc_table.raw_value() & 0xf != 0 {
-            dbg!("virtio queue descriptor table breaks alignment constraints");
-            false
-        } else if avail_ring.raw_value() & 0x1 != 0 {
-            dbg!("virtio queue available ring breaks alignment constraints");
-            false
-        } else if used_ring.raw_value() & 0x3 != 0 {
-            dbg!("virtio queue used ring breaks alignment constraints");
-            false
+            return Err(QueueError::NotReady);
+        }
+        if self.size > self.max_size || !self.size.is_power_of_two() {
+            return Err(QueueError::BadSize(self.size));
+        }
+        if self.desc_table.raw_value() & 0xf != 0 {
+            return Err(QueueError::DescTableMisaligned(self.desc_table));
+        }
+        if self.avail_ring.raw_value() & 0x1 != 0 {
+            return Err(QueueError::AvailRingMisaligned(self.avail_ring));
+        }
+        if self.used_ring.raw_value() & 0x3 != 0 {
+            return Err(QueueError::UsedRingMisaligned(self.used_ring));
+        }
         // range check entire descriptor table to be assigned valid guest physical addresses
-        } else if mem.get_slice(desc_table, desc_table_size).is_err() {
-            dbg!(
-                "virtio queue descriptor table goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                desc_table.raw_value(),
-                desc_table_size
-            );
-            false
-        } else if mem.get_slice(avail_ring, avail_ring_size).is_err() {
-            dbg!(
-                "virtio queue available ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                avail_ring.raw_value(),
-                avail_ring_size
-            );
-            false
-        } else if mem.get_slice(used_ring, used_ring_size).is_err() {
-            dbg!(
-                "virtio queue used ring goes out of bounds: start:0x{:08x} size:0x{:08x}",
-                used_ring.raw_value(),
-                used_ring_size
-            );
-            false
-        } else {
-            true
+        if mem.get_slice(self.desc_table, desc_table_size).is_err() {
+            return Err(QueueError::DescTableOutOfBounds(self.desc_table));
+        }
+        if mem.get_slice(self.avail_ring, avail_ring_size).is_err() {
+            return Err(QueueError::AvailRingOutOfBounds(self.avail_ring));
         }
+        if mem.get_slice(self.used_ring, used_ring_size).is_err() {
+            return Err(QueueError::UsedRingOutOfBounds(self.used_ring));
+        }
+
+        Ok(())
+    }
+
+    /// Validates the queue's in-memory layout is correct, logging what's wrong otherwise.
+    pub fn is_layout_valid<M: GuestMemory>(&self, mem: &M) -> bool {
+        match self.check_layout(mem) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("invalid virtio queue layout: {:?}", err);
+                false
+            }
+        }
+    }
+
+    /// Checks the layout like `check_layout`, and that the descriptor table and the two rings
+    /// don't overlap each other. A driver programming overlapping rings would have the device
+    /// overwrite descriptors it hasn't consumed yet, so this is checked before the queue is put
+    /// to use.
+    pub fn validate_ring_addresses<M: GuestMemory>(&self, mem: &M) -> Result<(), QueueError> {
+        self.check_layout(mem)?;
+
+        let queue_size = usize::from(self.actual_size());
+        let desc_table_size = 16 * queue_size;
+        let avail_ring_size = 6 + 2 * queue_size;
+        let used_ring_size = 6 + 8 * queue_size;
+
+        let areas = [
+            (self.desc_table.raw_value(), desc_table_size as u64),
+            (self.avail_ring.raw_value(), avail_ring_size as u64),
+            (self.used_ring.raw_value(), used_ring_size as u64),
+        ];
+        for (i, &(start, len)) in areas.iter().enumerate() {
+            for &(other_start, other_len) in &areas[i + 1..] {
+                if start < other_start + other_len && other_start < start + len {
+                    return Err(QueueError::RingsOverlap);
+                }
+            }
+        }
+
+        Ok(())
     }
 
     /// Validates that the queue's representation is correct.
@@ -126,7 +250,7 @@ impl Queue {
         if !self.is_layout_valid(mem) {
             false
         } else if self.len(mem) > self.max_size {
-            dbg!(
+            warn!(
                 "virtio queue number of available descriptors {} is greater than queue max size {}",
                 self.len(mem),
                 self.max_size
@@ -150,7 +274,14 @@ impl Queue {
     }
 
     /// Pop the first available descriptor chain from the avail ring.
+    ///
+    /// Nothing is popped from a queue the driver hasn't marked ready: its notifications can
+    /// reach the device through the ioeventfd without passing the transport's checks.
     pub fn pop<'b, M: GuestMemory>(&mut self, mem: &'b M) -> Option<DescriptorChain<'b, M>> {
+        if !self.ready {
+            return None;
+        }
+
         debug_assert!(self.is_layout_valid(mem));
 
         let len = self.len(mem);
@@ -180,6 +311,10 @@ impl Queue {
         &mut self,
         mem: &'b M,
     ) -> Option<DescriptorChain<'b, M>> {
+        if !self.ready {
+            return None;
+        }
+
         if !self.uses_notif_suppression {
             return self.pop(mem);
         }
@@ -231,9 +366,10 @@ impl Queue {
         // `self.is_valid()` already performed all the bound checks on the descriptor table
         // and virtq rings, so it's safe to unwrap guest memory reads and to use unchecked
         // offsets.
-        let desc_index: u16 = mem
-            .read_obj(self.avail_ring.unchecked_add(u64::from(index_offset)))
-            .unwrap();
+        let desc_index = u16::from_le(
+            mem.read_obj(self.avail_ring.unchecked_add(u64::from(index_offset)))
+                .unwrap(),
+        );
 
         DescriptorChain::checked_new(mem, self.desc_table, self.actual_size(), desc_index).map(
             |dc| {
@@ -250,6 +386,11 @@ impl Queue {
     }
 
     /// Puts an available descriptor head into the used ring for use by the guest.
+    ///
+    /// `len` is the number of bytes the device wrote to the chain's write only descriptors,
+    /// so it can't be more than their total length. Chains the device only read from, and
+    /// requests that failed before anything was written, are completed with a `len` of 0.
+    /// A larger `len` is refused, debug builds panic on it.
     pub fn add_used<M: GuestMemory>(
         &mut self,
         mem: &M,
@@ -257,35 +398,120 @@ impl Queue {
         len: u32,
     ) -> Result<(), QueueError> {
         debug_assert!(self.is_layout_valid(mem));
+        debug_assert!(
+            u64::from(len) <= self.writable_len(mem, desc_index),
+            "used len {} is larger than the write only descriptors of chain {}",
+            len,
+            desc_index
+        );
 
         if desc_index >= self.actual_size() {
-            dbg!(
+            warn!(
                 "attempted to add out of bounds descriptor to used ring: {}",
                 desc_index
             );
             return Err(QueueError::DescIndexOutOfBounds(desc_index));
         }
+        // the driver would read past the buffers it gave the device
+        if u64::from(len) > self.writable_len(mem, desc_index) {
+            warn!(
+                "used len {} is larger than the write only descriptors of chain {}",
+                len, desc_index
+            );
+            return Err(QueueError::UsedLenTooLarge(desc_index, len));
+        }
 
-        let used_ring = self.used_ring;
-        let next_used = u64::from(self.next_used.0 % self.actual_size());
-        let used_elem = used_ring.unchecked_add(4 + next_used * 8);
+        self.write_used_elem(mem, self.next_used, desc_index, len);
+        self.num_added += Wrapping(1);
+        self.next_used += Wrapping(1);
 
-        mem.write_obj(u32::from(desc_index), used_elem).unwrap();
+        self.publish_used(mem)
+    }
 
-        let len_addr = used_elem.unchecked_add(4);
-        mem.write_obj(len, len_addr).unwrap();
+    /// Puts a batch of used descriptor heads, given with their `len` as for `add_used`, into
+    /// the used ring, in order.
+    ///
+    /// With VIRTIO_F_IN_ORDER negotiated, only the last element of the batch is written and the
+    /// used index is moved past all of them at once: the driver knows every buffer made
+    /// available before that one was used too. Otherwise this is the same as calling
+    /// `add_used` for each of them.
+    pub fn add_used_batch<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        used: &[(u16, u32)],
+    ) -> Result<(), QueueError> {
+        if !self.in_order {
+            for &(desc_index, len) in used {
+                self.add_used(mem, desc_index, len)?;
+            }
+            return Ok(());
+        }
 
-        self.num_added += Wrapping(1);
-        self.next_used += Wrapping(1);
+        let (desc_index, len) = match used.last() {
+            Some(&last) => last,
+            None => return Ok(()),
+        };
+        debug_assert!(self.is_layout_valid(mem));
+        if desc_index >= self.actual_size() {
+            warn!(
+                "attempted to add out of bounds descriptor to used ring: {}",
+                desc_index
+            );
+            return Err(QueueError::DescIndexOutOfBounds(desc_index));
+        }
+
+        // the driver skips the elements of the rest of the batch
+        let count = Wrapping(used.len() as u16);
+        self.write_used_elem(mem, self.next_used + count - Wrapping(1), desc_index, len);
+        self.num_added += count;
+        self.next_used += count;
+
+        self.publish_used(mem)
+    }
+
+    // Writes the used element at ring position `position`.
+    fn write_used_elem<M: GuestMemory>(
+        &self,
+        mem: &M,
+        position: Wrapping<u16>,
+        desc_index: u16,
+        len: u32,
+    ) {
+        let slot = u64::from(position.0 % self.actual_size());
+        let used_elem = self.used_ring.unchecked_add(4 + slot * 8);
+
+        // the rings are little endian whatever the host is
+        mem.write_obj(u32::from(desc_index).to_le(), used_elem)
+            .unwrap();
+
+        let len_addr = used_elem.unchecked_add(4);
+        mem.write_obj(len.to_le(), len_addr).unwrap();
+    }
 
+    // Makes the used elements up to `next_used` visible to the driver.
+    fn publish_used<M: GuestMemory>(&self, mem: &M) -> Result<(), QueueError> {
         // This fence ensures all descriptor writes are visible before the index update is.
         fence(Ordering::Release);
 
-        let next_used_addr = used_ring.unchecked_add(2);
-        mem.write_obj(self.next_used.0, next_used_addr)
+        let next_used_addr = self.used_ring.unchecked_add(2);
+        mem.write_obj(self.next_used.0.to_le(), next_used_addr)
             .map_err(QueueError::UsedRing)
     }
 
+    // Total length of the write only descriptors in the chain starting at `desc_index`.
+    fn writable_len<M: GuestMemory>(&self, mem: &M, desc_index: u16) -> u64 {
+        let mut len = 0;
+        let mut desc =
+            DescriptorChain::checked_new(mem, self.desc_table, self.actual_size(), desc_index);
+        while let Some(current) = desc {
+            if current.is_write_only() {
+                len += u64::from(current.len);
+            }
+            desc = current.next_descriptor();
+        }
+        len
+    }
+
     /// Fetch the available ring index (`virtq_avail->idx`) from guest memory.
     /// This is written by the driver, to indicate the next slot that will be filled in the avail
     /// ring.
@@ -296,7 +522,7 @@ impl Queue {
         // guest       after device activation, so we can be certain that no change has
         // occurred since the last `self.is_valid()` check.
         let addr = self.avail_ring.unchecked_add(2);
-        Wrapping(mem.read_obj::<u16>(addr).unwrap())
+        Wrapping(u16::from_le(mem.read_obj(addr).unwrap()))
     }
 
     /// Get the value of the used event field of the avail ring.
@@ -309,7 +535,7 @@ impl Queue {
             .avail_ring
             .unchecked_add(u64::from(4 + 2 * self.actual_size()));
 
-        Wrapping(mem.read_obj::<u16>(used_event_addr).unwrap())
+        Wrapping(u16::from_le(mem.read_obj(used_event_addr).unwrap()))
     }
 
     /// Helper method that writes `val` to the `avail_event` field of the used ring.
@@ -320,7 +546,7 @@ impl Queue {
             .used_ring
             .unchecked_add(u64::from(4 + 8 * self.actual_size()));
 
-        mem.write_obj(val, avail_event_addr).unwrap();
+        mem.write_obj(val.to_le(), avail_event_addr).unwrap();
     }
 
     /// Try to enable notification events from the guest driver. Returns true if notifications were
@@ -361,6 +587,12 @@ impl Queue {
         self.next_avail.0 == self.avail_idx(mem).0
     }
 
+    /// Uses the buffers in the order they were made available, once VIRTIO_F_IN_ORDER is
+    /// negotiated. See `add_used_batch`.
+    pub fn enable_in_order(&mut self) {
+        self.in_order = true;
+    }
+
     /// Enable notification suppression.
     pub fn enable_notif_suppression(&mut self) {
         self.uses_notif_suppression = true;
@@ -393,3 +625,318 @@ impl Queue {
         new - used_event - Wrapping(1) < new - old
     }
 }
+
+/// Driver side of a queue whose rings are laid out from `DRAM_MEM_START`, for device tests.
+/// Buffers are left to the test, past the end of the rings.
+#[cfg(test)]
+pub struct TestQueue<'a> {
+    mem: &'a GuestMemoryMmap,
+    size: u16,
+    desc_table: GuestAddress,
+    avail_ring: GuestAddress,
+    used_ring: GuestAddress,
+    next_desc: u16,
+    avail_idx: u16,
+}
+
+#[cfg(test)]
+impl<'a> TestQueue<'a> {
+    pub fn new(mem: &'a GuestMemoryMmap, size: u16) -> TestQueue<'a> {
+        let desc_table = GuestAddress(crate::vmm::layout::DRAM_MEM_START);
+        let avail_ring = desc_table.unchecked_add(16 * u64::from(size));
+        let used_ring = GuestAddress((avail_ring.0 + 6 + 2 * u64::from(size)).next_multiple_of(4));
+
+        TestQueue {
+            mem,
+            size,
+            desc_table,
+            avail_ring,
+            used_ring,
+            next_desc: 0,
+            avail_idx: 0,
+        }
+    }
+
+    /// A ready queue using these rings, as the driver would have set it up.
+    pub fn queue(&self) -> Queue {
+        Queue::from_parts(
+            self.size,
+            self.size,
+            self.desc_table,
+            self.avail_ring,
+            self.used_ring,
+        )
+    }
+
+    /// Writes the descriptors of a chain, given as address, length and flags, and makes it
+    /// available. Returns the index of its head.
+    pub fn add_chain(&mut self, descs: &[(GuestAddress, u32, u16)]) -> u16 {
+        let head = self.next_desc;
+        for (i, &(addr, len, flags)) in descs.iter().enumerate() {
+            let index = self.next_desc;
+            self.next_desc = (self.next_desc + 1) % self.size;
+
+            let mut flags = flags;
+            if i + 1 < descs.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let desc = self.desc_table.unchecked_add(16 * u64::from(index));
+            self.mem.write_obj(addr.0.to_le(), desc).unwrap();
+            self.mem
+                .write_obj(len.to_le(), desc.unchecked_add(8))
+                .unwrap();
+            self.mem
+                .write_obj(flags.to_le(), desc.unchecked_add(12))
+                .unwrap();
+            self.mem
+                .write_obj(self.next_desc.to_le(), desc.unchecked_add(14))
+                .unwrap();
+        }
+
+        let slot = u64::from(self.avail_idx % self.size);
+        self.mem
+            .write_obj(head.to_le(), self.avail_ring.unchecked_add(4 + 2 * slot))
+            .unwrap();
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.mem
+            .write_obj(self.avail_idx.to_le(), self.avail_ring.unchecked_add(2))
+            .unwrap();
+
+        head
+    }
+
+    /// Index of the next used element the device writes.
+    pub fn used_idx(&self) -> u16 {
+        let idx: u16 = self.mem.read_obj(self.used_ring.unchecked_add(2)).unwrap();
+        u16::from_le(idx)
+    }
+
+    /// The head index and length of the used element at ring position `position`.
+    pub fn used_elem(&self, position: u16) -> (u32, u32) {
+        let elem = self
+            .used_ring
+            .unchecked_add(4 + 8 * u64::from(position % self.size));
+        let id: u32 = self.mem.read_obj(elem).unwrap();
+        let len: u32 = self.mem.read_obj(elem.unchecked_add(4)).unwrap();
+        (u32::from_le(id), u32::from_le(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vmm::device::descriptor::VIRTQ_DESC_F_WRITE;
+    use crate::vmm::layout::DRAM_MEM_START;
+    use crate::vmm::logger::test_logger;
+    use crate::vmm::memory::test_guest_memory;
+
+    use super::*;
+
+    fn addr(offset: u64) -> GuestAddress {
+        GuestAddress(DRAM_MEM_START + offset)
+    }
+
+    #[test]
+    fn test_ring_addresses_overlap() {
+        let mem = test_guest_memory(0x10000);
+
+        let queue = Queue::from_parts(16, 16, addr(0), addr(0x80), addr(0x1000));
+        assert!(matches!(
+            queue.validate_ring_addresses(&mem),
+            Err(QueueError::RingsOverlap)
+        ));
+
+        let queue = Queue::from_parts(16, 16, addr(0), addr(0x1000), addr(0x2000));
+        queue.validate_ring_addresses(&mem).unwrap();
+    }
+
+    #[test]
+    fn test_from_parts_pop() {
+        let mem = test_guest_memory(0x10000);
+        let mut queue = Queue::from_parts(16, 8, addr(0), addr(0x1000), addr(0x2000));
+        assert!(queue.is_valid(&mem));
+
+        // one descriptor at index 0, made available in avail ring slot 0
+        mem.write_obj(addr(0x4000).0.to_le(), addr(0)).unwrap();
+        mem.write_obj(64u32.to_le(), addr(8)).unwrap();
+        mem.write_obj(VIRTQ_DESC_F_WRITE.to_le(), addr(12)).unwrap();
+        mem.write_obj(0u16, addr(0x1004)).unwrap();
+        mem.write_obj(1u16.to_le(), addr(0x1002)).unwrap();
+
+        let desc = queue.pop(&mem).unwrap();
+        assert_eq!(desc.index, 0);
+        assert_eq!(desc.addr, addr(0x4000));
+        assert_eq!(desc.len, 64);
+        assert!(desc.is_write_only());
+        assert!(!desc.has_next());
+        assert!(queue.pop(&mem).is_none());
+    }
+
+    #[test]
+    fn test_add_used() {
+        let mem = test_guest_memory(0x10000);
+        let mut test_queue = TestQueue::new(&mem, 16);
+        let read_write = test_queue.add_chain(&[
+            (addr(0x4000), 64, 0),
+            (addr(0x5000), 128, VIRTQ_DESC_F_WRITE),
+        ]);
+        let read_only = test_queue.add_chain(&[(addr(0x6000), 64, 0)]);
+        let mut queue = test_queue.queue();
+
+        queue.add_used(&mem, read_write, 100).unwrap();
+        // nothing written
+        queue.add_used(&mem, read_only, 0).unwrap();
+
+        assert_eq!(test_queue.used_idx(), 2);
+        assert_eq!(test_queue.used_elem(0), (u32::from(read_write), 100));
+        assert_eq!(test_queue.used_elem(1), (u32::from(read_only), 0));
+    }
+
+    #[test]
+    fn test_add_used_batch_in_order() {
+        let mem = test_guest_memory(0x10000);
+        let mut test_queue = TestQueue::new(&mem, 16);
+        let heads: Vec<u16> = (0..4)
+            .map(|i| test_queue.add_chain(&[(addr(0x4000 + i * 0x100), 64, VIRTQ_DESC_F_WRITE)]))
+            .collect();
+        let mut queue = test_queue.queue();
+        queue.enable_in_order();
+
+        queue
+            .add_used_batch(&mem, &[(heads[0], 10), (heads[1], 20), (heads[2], 30)])
+            .unwrap();
+        // only the last element of the batch is written
+        assert_eq!(test_queue.used_idx(), 3);
+        assert_eq!(test_queue.used_elem(0), (0, 0));
+        assert_eq!(test_queue.used_elem(2), (u32::from(heads[2]), 30));
+
+        queue.add_used_batch(&mem, &[(heads[3], 40)]).unwrap();
+        assert_eq!(test_queue.used_idx(), 4);
+        assert_eq!(test_queue.used_elem(3), (u32::from(heads[3]), 40));
+    }
+
+    #[test]
+    fn test_add_used_batch() {
+        let mem = test_guest_memory(0x10000);
+        let mut test_queue = TestQueue::new(&mem, 16);
+        let heads: Vec<u16> = (0..2)
+            .map(|i| test_queue.add_chain(&[(addr(0x4000 + i * 0x100), 64, VIRTQ_DESC_F_WRITE)]))
+            .collect();
+        let mut queue = test_queue.queue();
+
+        queue
+            .add_used_batch(&mem, &[(heads[1], 20), (heads[0], 10)])
+            .unwrap();
+
+        assert_eq!(test_queue.used_idx(), 2);
+        assert_eq!(test_queue.used_elem(0), (u32::from(heads[1]), 20));
+        assert_eq!(test_queue.used_elem(1), (u32::from(heads[0]), 10));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "larger than the write only descriptors")]
+    fn test_add_used_len_too_large() {
+        let mem = test_guest_memory(0x10000);
+        let mut test_queue = TestQueue::new(&mem, 16);
+        let head = test_queue.add_chain(&[
+            (addr(0x4000), 64, 0),
+            (addr(0x5000), 128, VIRTQ_DESC_F_WRITE),
+        ]);
+        let mut queue = test_queue.queue();
+
+        let _ = queue.add_used(&mem, head, 129);
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn test_add_used_len_too_large_refused() {
+        let mem = test_guest_memory(0x10000);
+        let mut test_queue = TestQueue::new(&mem, 16);
+        let head = test_queue.add_chain(&[
+            (addr(0x4000), 64, 0),
+            (addr(0x5000), 128, VIRTQ_DESC_F_WRITE),
+        ]);
+        let mut queue = test_queue.queue();
+
+        assert!(matches!(
+            queue.add_used(&mem, head, 129),
+            Err(QueueError::UsedLenTooLarge(index, 129)) if index == head
+        ));
+        assert_eq!(test_queue.used_idx(), 0);
+        queue.add_used(&mem, head, 128).unwrap();
+        assert_eq!(test_queue.used_idx(), 1);
+    }
+
+    #[test]
+    fn test_invalid_layout_logged() {
+        test_logger::install();
+        let mem = test_guest_memory(0x10000);
+        let queue = Queue::from_parts(16, 16, addr(0), addr(0x1001), addr(0x2000));
+        test_logger::take_records();
+
+        assert!(!queue.is_layout_valid(&mem));
+
+        let records = test_logger::take_records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, log::Level::Warn);
+        assert!(records[0].1.contains("AvailRingMisaligned"));
+    }
+
+    #[test]
+    fn test_check_layout() {
+        let mem = test_guest_memory(0x10000);
+        let valid = || Queue::from_parts(16, 16, addr(0), addr(0x1000), addr(0x2000));
+        valid().check_layout(&mem).unwrap();
+
+        let mut queue = valid();
+        queue.ready = false;
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::NotReady)
+        ));
+
+        let mut queue = valid();
+        queue.size = 12;
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::BadSize(12))
+        ));
+        queue.size = 32;
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::BadSize(32))
+        ));
+
+        let queue = Queue::from_parts(16, 16, addr(0x8), addr(0x1000), addr(0x2000));
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::DescTableMisaligned(_))
+        ));
+        let queue = Queue::from_parts(16, 16, addr(0), addr(0x1001), addr(0x2000));
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::AvailRingMisaligned(_))
+        ));
+        let queue = Queue::from_parts(16, 16, addr(0), addr(0x1000), addr(0x2002));
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::UsedRingMisaligned(_))
+        ));
+
+        let queue = Queue::from_parts(16, 16, addr(0xff80), addr(0x1000), addr(0x2000));
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::DescTableOutOfBounds(_))
+        ));
+        let queue = Queue::from_parts(16, 16, addr(0), addr(0xfff0), addr(0x2000));
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::AvailRingOutOfBounds(_))
+        ));
+        let queue = Queue::from_parts(16, 16, addr(0), addr(0x1000), addr(0xff80));
+        assert!(matches!(
+            queue.check_layout(&mem),
+            Err(QueueError::UsedRingOutOfBounds(_))
+        ));
+    }
+}
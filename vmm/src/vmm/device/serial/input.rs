@@ -0,0 +1,30 @@
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::pty::Pty;
+
+/// Where the serial device reads its input from.
+#[derive(Debug)]
+pub enum SerialInput {
+    Stdin(std::io::Stdin),
+    /// The master side of a pty, which also keeps the slave open.
+    Pty(Pty),
+}
+
+impl Read for SerialInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Stdin(stdin) => stdin.read(buf),
+            Self::Pty(pty) => pty.master.read(buf),
+        }
+    }
+}
+
+impl AsRawFd for SerialInput {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Stdin(stdin) => stdin.as_raw_fd(),
+            Self::Pty(pty) => pty.master.as_raw_fd(),
+        }
+    }
+}
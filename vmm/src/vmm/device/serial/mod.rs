@@ -1,10 +1,31 @@
+use std::path::PathBuf;
+
 pub use self::{
+    input::SerialInput,
+    pty::Pty,
     trigger::EventFdTrigger,
-    wrapper::{SerialEventsWrapper, SerialWrapper},
+    wrapper::{is_pollable, SerialEventsWrapper, SerialWrapper},
 };
 
+mod input;
 pub mod out;
+mod pty;
 mod trigger;
 mod wrapper;
 
 pub type SerialDevice<I> = SerialWrapper<EventFdTrigger, SerialEventsWrapper, I>;
+
+/// Host side the serial console is attached to.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ConsoleBackend {
+    /// The VMM's own stdin/stdout.
+    #[default]
+    Stdio,
+    /// A newly allocated pseudo-terminal; its slave path is reported once the VM is created.
+    Pty,
+    /// Guest output is written to the file, which is created or truncated. There is no input.
+    File(PathBuf),
+    /// Guest output is kept in memory, up to the given number of bytes, and read through
+    /// `Vm::console_buffer`. There is no input.
+    Buffer(usize),
+}
@@ -1,7 +1,35 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use crate::vmm::metrics::Counter;
+
+/// Guest console output kept in memory, shared with whoever reads it.
+pub type ConsoleBuffer = Arc<Mutex<VecDeque<u8>>>;
+
+/// How much output `BufferedOut` holds back while its destination isn't writable.
+pub const PENDING_CAPACITY: usize = 64 << 10;
+
 #[derive(Debug)]
 pub enum SerialOut {
     Sink(std::io::Sink),
     Stdout(std::io::Stdout),
+    File(File),
+    /// Keeps at most the given number of bytes, dropping the oldest ones when full.
+    Buffer(ConsoleBuffer, usize),
+}
+
+impl SerialOut {
+    /// The fd output is written to, if it goes to one.
+    pub fn as_raw_fd(&self) -> Option<RawFd> {
+        match self {
+            Self::Stdout(stdout) => Some(stdout.as_raw_fd()),
+            Self::File(file) => Some(file.as_raw_fd()),
+            Self::Sink(_) | Self::Buffer(_, _) => None,
+        }
+    }
 }
 
 impl std::io::Write for SerialOut {
@@ -9,12 +37,223 @@ impl std::io::Write for SerialOut {
         match self {
             Self::Sink(sink) => sink.write(buf),
             Self::Stdout(stdout) => stdout.write(buf),
+            Self::File(file) => file.write(buf),
+            Self::Buffer(buffer, capacity) => {
+                let mut buffer = buffer.lock().expect("Poisoned lock");
+                let kept = &buf[buf.len().saturating_sub(*capacity)..];
+                let overflow = (buffer.len() + kept.len()).saturating_sub(*capacity);
+                buffer.drain(..overflow);
+                buffer.extend(kept);
+                Ok(buf.len())
+            }
         }
     }
     fn flush(&mut self) -> std::io::Result<()> {
         match self {
             Self::Sink(sink) => sink.flush(),
             Self::Stdout(stdout) => stdout.flush(),
+            Self::File(file) => file.flush(),
+            Self::Buffer(_, _) => Ok(()),
+        }
+    }
+}
+
+/// Serial output that doesn't lose bytes a non-blocking stdout or pty can't take right away.
+///
+/// They are kept, in order, until `drain` is called once the fd is writable again. Only when
+/// more than `capacity` bytes are waiting is the newest output dropped, and counted in
+/// `lost_bytes`. Other write errors are returned as they are. Clones share the output, so
+/// the serial wrapper can drain what the serial device wrote.
+#[derive(Debug, Clone)]
+pub struct BufferedOut {
+    inner: Arc<Mutex<PendingOut>>,
+}
+
+#[derive(Debug)]
+struct PendingOut {
+    out: SerialOut,
+    pending: VecDeque<u8>,
+    capacity: usize,
+    lost_bytes: Arc<Counter>,
+}
+
+impl BufferedOut {
+    pub fn new(out: SerialOut, capacity: usize, lost_bytes: Arc<Counter>) -> BufferedOut {
+        BufferedOut {
+            inner: Arc::new(Mutex::new(PendingOut {
+                out,
+                pending: VecDeque::new(),
+                capacity,
+                lost_bytes,
+            })),
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> Option<RawFd> {
+        self.inner.lock().expect("Poisoned lock").out.as_raw_fd()
+    }
+
+    /// Writes as much of the waiting output as the destination takes without blocking.
+    pub fn drain(&self) -> io::Result<()> {
+        self.inner.lock().expect("Poisoned lock").drain()
+    }
+}
+
+impl PendingOut {
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let (front, _) = self.pending.as_slices();
+            match self.out.write(front) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(count) => {
+                    self.pending.drain(..count);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        // stdout keeps what it couldn't flush in its own buffer
+        match self.out.flush() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    // Returns how much of `buf` was written before the destination would block.
+    fn write_direct(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            match self.out.write(&buf[written..]) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(count) => written += count,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // nothing may overtake output that's still waiting
+        let written = if self.pending.is_empty() {
+            self.write_direct(buf)?
+        } else {
+            0
+        };
+
+        let rest = &buf[written..];
+        let kept = rest.len().min(self.capacity - self.pending.len());
+        self.pending.extend(&rest[..kept]);
+        self.lost_bytes.add((rest.len() - kept) as u64);
+
+        Ok(buf.len())
+    }
+}
+
+// Writes what's still waiting, as far as the destination takes it, and makes a file's
+// contents durable.
+impl Drop for PendingOut {
+    fn drop(&mut self) {
+        let _ = self.drain();
+        if let SerialOut::File(file) = &self.out {
+            // a pty master is a file too, syncing it fails harmlessly
+            let _ = file.sync_all();
+        }
+    }
+}
+
+impl std::io::Write for BufferedOut {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.lock().expect("Poisoned lock").write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.drain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+
+    // A non-blocking pipe with nothing left of its buffer, returned as reader and writer.
+    fn full_pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two fds pipe2 returns.
+        assert_eq!(
+            unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK) },
+            0
+        );
+        // SAFETY: both fds were just created and are owned by nothing else.
+        let (reader, mut writer) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        while writer.write(&[0; 4096]).is_ok() {}
+        while writer.write(&[0]).is_ok() {}
+        (reader, writer)
+    }
+
+    fn read_available(mut reader: &File) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        while let Ok(count) = reader.read(&mut buf) {
+            if count == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..count]);
         }
+        data
+    }
+
+    #[test]
+    fn test_buffer_keeps_newest_bytes() {
+        let buffer = ConsoleBuffer::default();
+        let mut out = SerialOut::Buffer(buffer.clone(), 4);
+
+        out.write_all(b"abc").unwrap();
+        out.write_all(b"def").unwrap();
+        assert!(buffer.lock().unwrap().iter().eq(b"cdef"));
+
+        // a single write larger than the buffer
+        out.write_all(b"0123456789").unwrap();
+        assert!(buffer.lock().unwrap().iter().eq(b"6789"));
+    }
+
+    #[test]
+    fn test_slow_sink_keeps_pending_bytes() {
+        let (reader, writer) = full_pipe();
+        let lost_bytes = Arc::new(Counter::default());
+        let mut out = BufferedOut::new(SerialOut::File(writer), 1024, lost_bytes.clone());
+        let output: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+
+        out.write_all(&output[..512]).unwrap();
+        out.write_all(&output[512..]).unwrap();
+        assert_eq!(lost_bytes.count(), 0);
+
+        read_available(&reader);
+        out.drain().unwrap();
+        assert_eq!(read_available(&reader), output);
+    }
+
+    #[test]
+    fn test_slow_sink_drops_newest_past_capacity() {
+        let (reader, writer) = full_pipe();
+        let lost_bytes = Arc::new(Counter::default());
+        let mut out = BufferedOut::new(SerialOut::File(writer), 1024, lost_bytes.clone());
+        let output: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+
+        out.write_all(&output).unwrap();
+        assert_eq!(lost_bytes.count(), 476);
+
+        read_available(&reader);
+        out.drain().unwrap();
+        assert_eq!(read_available(&reader), output[..1024]);
     }
 }
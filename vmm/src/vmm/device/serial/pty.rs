@@ -0,0 +1,137 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+
+/// A pseudo-terminal pair. The VMM drives the serial device through the master side while a
+/// user attaches a terminal emulator (`screen`, `minicom`) to the slave path.
+#[derive(Debug)]
+pub struct Pty {
+    pub master: File,
+    pub slave_path: PathBuf,
+    // Kept open so reads on the master don't fail with EIO while nobody is attached.
+    _slave: File,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Pty> {
+        let mut master: RawFd = -1;
+        let mut slave: RawFd = -1;
+
+        // SAFETY: The fd pointers are valid for writing and the optional name, termios and
+        // winsize arguments are allowed to be null.
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: openpty succeeded, so both fds are open and exclusively owned by us.
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+        set_raw_mode(slave.as_raw_fd())?;
+        set_nonblocking(master.as_raw_fd())?;
+
+        let mut name = [0 as libc::c_char; 64];
+        // SAFETY: The buffer is valid for writing `name.len()` bytes.
+        let ret = unsafe { libc::ttyname_r(slave.as_raw_fd(), name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        // SAFETY: ttyname_r succeeded, so the buffer holds a nul terminated string.
+        let slave_path = unsafe { CStr::from_ptr(name.as_ptr()) };
+
+        Ok(Pty {
+            master,
+            slave_path: PathBuf::from(slave_path.to_string_lossy().into_owned()),
+            _slave: slave,
+        })
+    }
+}
+
+// Without raw mode the line discipline would echo the guest output written to the master
+// straight back to it as serial input.
+fn set_raw_mode(fd: RawFd) -> io::Result<()> {
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+
+    // SAFETY: The pointer is valid for writing a libc::termios structure.
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: tcgetattr succeeded, so the structure is initialized.
+    let mut termios = unsafe { termios.assume_init() };
+
+    // SAFETY: The termios structure is valid and initialized.
+    unsafe { libc::cfmakeraw(&mut termios) };
+
+    // SAFETY: The termios structure is valid and initialized.
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // SAFETY: Call is safe since parameters are valid.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL, 0) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: Call is safe since parameters are valid.
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    // The tty layer hands data over asynchronously, wait for it to reach the master.
+    fn wait_readable(fd: RawFd) {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is valid for the duration of the call.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 1000) };
+        assert_eq!(ret, 1);
+    }
+
+    #[test]
+    fn test_pty_round_trip() {
+        let mut pty = Pty::open().unwrap();
+        assert!(pty.slave_path.starts_with("/dev/pts"));
+
+        let mut slave = File::options()
+            .read(true)
+            .write(true)
+            .open(&pty.slave_path)
+            .unwrap();
+
+        slave.write_all(b"host").unwrap();
+        wait_readable(pty.master.as_raw_fd());
+        let mut buf = [0; 4];
+        pty.master.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"host");
+
+        pty.master.write_all(b"guest").unwrap();
+        let mut buf = [0; 5];
+        slave.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"guest");
+    }
+}
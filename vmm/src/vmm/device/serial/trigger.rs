@@ -6,6 +6,8 @@ use vm_superio::Trigger;
 
 use vmm_sys_util::eventfd::EventFd;
 
+use crate::vmm::device::eventfd_write_retry;
+
 #[derive(Debug)]
 pub struct EventFdTrigger(EventFd);
 
@@ -13,7 +15,7 @@ impl Trigger for EventFdTrigger {
     type E = Error;
 
     fn trigger(&self) -> Result<()> {
-        self.write(1)
+        eventfd_write_retry(self, 1)
     }
 }
 
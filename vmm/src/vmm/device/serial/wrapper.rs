@@ -1,20 +1,24 @@
-use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use event_manager::{Error as EventManagerError, EventOps, EventSet, Events, MutEventSubscriber};
+use log::{debug, warn};
 use std::fmt::Debug;
-use std::io::Read;
+use std::io::{self, Read};
 use std::os::fd::RawFd;
 use std::os::unix::io::AsRawFd;
 use vm_superio::serial::{NoEvents, SerialEvents};
 use vm_superio::{Serial, Trigger};
 
-use super::out::SerialOut;
+use super::out::BufferedOut;
 use super::trigger::EventFdTrigger;
+use crate::vmm::device::eventfd_write_retry;
 
 #[derive(Debug)]
 pub struct SerialWrapper<T: Trigger, EV: SerialEvents, I: Read + AsRawFd + Send> {
     /// Serial device object.
-    pub serial: Serial<T, EV, SerialOut>,
+    pub serial: Serial<T, EV, BufferedOut>,
     /// Input to the serial device (needs to be readable).
     pub input: Option<I>,
+    /// Shares the serial device's output, drained whenever its fd becomes writable again.
+    pub output: BufferedOut,
 }
 
 fn is_fifo(fd: RawFd) -> bool {
@@ -34,15 +38,102 @@ fn is_fifo(fd: RawFd) -> bool {
     (stat.st_mode & libc::S_IFIFO) != 0
 }
 
+/// Only terminals and pipes are registered with epoll, regular files are always readable and
+/// can't be polled.
+pub fn is_pollable(fd: RawFd) -> bool {
+    // SAFETY: isatty only reads the fd, an invalid one makes it return 0.
+    (unsafe { libc::isatty(fd) } == 1) || is_fifo(fd)
+}
+
+impl<I: Read + AsRawFd + Send> SerialWrapper<EventFdTrigger, SerialEventsWrapper, I> {
+    /// Moves as many bytes as fit in the FIFO from the input to the serial device. Fails with
+    /// `ENOBUFS` when the FIFO is already full.
+    fn recv_bytes(&mut self) -> io::Result<usize> {
+        let avail_cap = self.serial.fifo_capacity();
+        if avail_cap == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+        }
+
+        let input = match self.input.as_mut() {
+            Some(input) => input,
+            None => return Err(io::Error::from_raw_os_error(libc::ENOTTY)),
+        };
+
+        let mut buf = vec![0u8; avail_cap];
+        let count = input.read(&mut buf)?;
+        if count > 0 {
+            self.serial
+                .enqueue_raw_bytes(&buf[..count])
+                .map_err(|_| io::Error::from_raw_os_error(libc::ENOBUFS))?;
+        }
+
+        Ok(count)
+    }
+}
+
 impl<I: Read + AsRawFd + Send + Debug> MutEventSubscriber
     for SerialWrapper<EventFdTrigger, SerialEventsWrapper, I>
 {
     fn process(&mut self, event: Events, ops: &mut EventOps) {
-        todo!();
+        let input_fd = self.input.as_ref().map_or(-1, |input| input.as_raw_fd());
+        let buffer_ready_fd = self
+            .serial
+            .events()
+            .buffer_ready_event_fd
+            .as_ref()
+            .map_or(-1, |buf_ready| buf_ready.as_raw_fd());
+
+        let output_fd = self.output.as_raw_fd().unwrap_or(-1);
+
+        if event.fd() == output_fd {
+            if let Err(err) = self.output.drain() {
+                warn!("failed to write serial output: {:?}", err);
+            }
+        } else if event.fd() == buffer_ready_fd {
+            if let Some(buf_ready) = self.serial.events().buffer_ready_event_fd.as_ref() {
+                let _ = buf_ready.read();
+            }
+
+            // The guest emptied the FIFO, so the input can be read again if it was paused below.
+            if is_pollable(input_fd) {
+                match ops.add(Events::new(&input_fd, EventSet::IN)) {
+                    Ok(()) | Err(EventManagerError::FdAlreadyRegistered) => {}
+                    Err(err) => panic!("Failed to register serial input fd: {}", err),
+                }
+            }
+        } else if event.fd() == input_fd {
+            match self.recv_bytes() {
+                // EOF, e.g. stdin was closed. Nothing more will ever be read from it.
+                Ok(0) => {
+                    if let Err(err) = ops.remove(Events::new(&input_fd, EventSet::IN)) {
+                        panic!("Failed to unregister serial input fd: {}", err);
+                    }
+                }
+                Ok(_) => {}
+                // The FIFO is full, stop polling the input until the guest reads from it,
+                // otherwise the level triggered input fd keeps waking us up.
+                Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => {
+                    if let Err(err) = ops.remove(Events::new(&input_fd, EventSet::IN)) {
+                        panic!("Failed to unregister serial input fd: {}", err);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => {
+                    warn!("failed to read serial input: {:?}", err);
+                }
+            }
+        }
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        dbg!("serial device init called");
+        debug!("serial device init called");
+        // edge triggered, a writable fd would wake us up all the time otherwise
+        if let Some(output_fd) = self.output.as_raw_fd().filter(|fd| is_pollable(*fd)) {
+            let events = EventSet::OUT | EventSet::EDGE_TRIGGERED;
+            if let Err(err) = ops.add(Events::new(&output_fd, events)) {
+                panic!("Failed to register serial output fd: {}", err);
+            }
+        }
         if self.input.is_some() && self.serial.events().buffer_ready_event_fd.is_some() {
             let serial_fd = self.input.as_ref().map_or(-1, |input| input.as_raw_fd());
             let buf_ready_evt = self
@@ -52,7 +143,7 @@ impl<I: Read + AsRawFd + Send + Debug> MutEventSubscriber
                 .as_ref()
                 .map_or(-1, |buf_ready| buf_ready.as_raw_fd());
 
-            if unsafe { libc::isatty(serial_fd) } == 1 || is_fifo(serial_fd) {
+            if is_pollable(serial_fd) {
                 if let Err(err) = ops.add(Events::new(&serial_fd, EventSet::IN)) {
                     panic!("Failed to register serial input fd: {}", err);
                 }
@@ -80,7 +171,7 @@ impl SerialEvents for SerialEventsWrapper {
         match self
             .buffer_ready_event_fd
             .as_ref()
-            .map_or(Ok(()), |buf_ready| buf_ready.write(1))
+            .map_or(Ok(()), |buf_ready| eventfd_write_retry(buf_ready, 1))
         {
             Ok(_) => (),
             Err(err) => panic!(
@@ -90,3 +181,73 @@ impl SerialEvents for SerialEventsWrapper {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::Arc;
+
+    use vmm_sys_util::eventfd::EventFd;
+
+    use crate::vmm::device::serial::out::{SerialOut, PENDING_CAPACITY};
+    use crate::vmm::metrics::Counter;
+
+    use super::*;
+
+    // Serial data register, when the divisor latch isn't selected.
+    const DATA: u8 = 0;
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` is valid for writing two fds.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // SAFETY: pipe succeeded, both fds are open and owned by nothing else.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    fn serial_with_input(input: File) -> SerialWrapper<EventFdTrigger, SerialEventsWrapper, File> {
+        let trigger = || EventFdTrigger::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let output = BufferedOut::new(
+            SerialOut::Sink(std::io::sink()),
+            PENDING_CAPACITY,
+            Arc::new(Counter::default()),
+        );
+        let events = SerialEventsWrapper {
+            buffer_ready_event_fd: Some(trigger()),
+        };
+
+        SerialWrapper {
+            serial: Serial::with_events(trigger(), events, output.clone()),
+            input: Some(input),
+            output,
+        }
+    }
+
+    #[test]
+    fn test_input_reaches_fifo() {
+        let (read_end, mut write_end) = pipe();
+        assert!(is_pollable(read_end.as_raw_fd()));
+        let mut serial = serial_with_input(read_end);
+
+        write_end.write_all(b"abc").unwrap();
+        assert_eq!(serial.recv_bytes().unwrap(), 3);
+
+        let received: Vec<u8> = (0..3).map(|_| serial.serial.read(DATA)).collect();
+        assert_eq!(received, b"abc");
+    }
+
+    #[test]
+    fn test_full_fifo_stops_input() {
+        let (read_end, mut write_end) = pipe();
+        let mut serial = serial_with_input(read_end);
+
+        let capacity = serial.serial.fifo_capacity();
+        write_end.write_all(&vec![b'x'; capacity + 1]).unwrap();
+        assert_eq!(serial.recv_bytes().unwrap(), capacity);
+
+        let err = serial.recv_bytes().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOBUFS));
+    }
+}
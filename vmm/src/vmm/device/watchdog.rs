@@ -0,0 +1,188 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use event_manager::{EventOps, EventSet, Events, MutEventSubscriber};
+use log::{error, warn};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::vmm::api::{kick_vcpu, VcpuThread};
+use crate::vmm::device::eventfd_write_retry;
+use crate::vmm::timerfd::TimerFd;
+
+/// Writing `CONTROL_ENABLE` starts the countdown, writing 0 stops it.
+const WDT_CONTROL: u64 = 0x0;
+/// Any write restarts the countdown.
+const WDT_PING: u64 = 0x4;
+/// Read only, the timeout in milliseconds.
+const WDT_TIMEOUT: u64 = 0x8;
+
+const CONTROL_ENABLE: u32 = 1;
+
+// A kick right before the vcpu enters the guest is missed, so it's repeated until the VM
+// reboots the guest.
+const KICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Resets the guest once it stops pinging.
+///
+/// The guest enables the watchdog through `WDT_CONTROL`, then has to write `WDT_PING` at least
+/// once per timeout. When it doesn't, `reset_evt` is signalled and the vcpu kicked out of the
+/// guest, so `Vm::run` reboots it. Registers are 32 bits wide. A reboot disarms the watchdog,
+/// the guest has to enable it again.
+#[derive(Debug)]
+pub struct Watchdog {
+    timeout: Duration,
+    enabled: bool,
+    expired: bool,
+    timer: TimerFd,
+    reset_evt: EventFd,
+    vcpu_thread: VcpuThread,
+}
+
+impl Watchdog {
+    pub fn new(
+        timeout: Duration,
+        reset_evt: EventFd,
+        vcpu_thread: VcpuThread,
+    ) -> io::Result<Watchdog> {
+        Ok(Watchdog {
+            timeout,
+            enabled: false,
+            expired: false,
+            timer: TimerFd::new()?,
+            reset_evt,
+            vcpu_thread,
+        })
+    }
+
+    pub fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let value = match offset {
+            WDT_CONTROL => u32::from(self.enabled),
+            WDT_TIMEOUT => self.timeout.as_millis().min(u128::from(u32::MAX)) as u32,
+            _ => 0,
+        };
+        if let Ok(data) = <&mut [u8; 4]>::try_from(data) {
+            *data = value.to_le_bytes();
+        }
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        let value = match <&[u8; 4]>::try_from(data) {
+            Ok(data) => u32::from_le_bytes(*data),
+            Err(_) => return,
+        };
+
+        match offset {
+            WDT_CONTROL if value & CONTROL_ENABLE != 0 => self.enable(),
+            WDT_CONTROL => self.disarm(),
+            WDT_PING if self.enabled && !self.expired => self.restart(),
+            _ => {}
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Starts the countdown, like the guest writing `CONTROL_ENABLE`.
+    pub fn enable(&mut self) {
+        self.enabled = true;
+        self.restart();
+    }
+
+    /// Stops the countdown and forgets a pending reset.
+    pub fn disarm(&mut self) {
+        self.enabled = false;
+        self.expired = false;
+        if let Err(err) = self.timer.disarm() {
+            error!("failed to disarm the watchdog timer: {:?}", err);
+        }
+    }
+
+    fn restart(&mut self) {
+        if let Err(err) = self.timer.set_oneshot(self.timeout) {
+            error!("failed to arm the watchdog timer: {:?}", err);
+        }
+    }
+
+    fn process_timer(&mut self) {
+        // a ping or disarm may have raced with the expiration
+        if self.timer.read().is_err() || !self.enabled {
+            return;
+        }
+
+        if !self.expired {
+            warn!("watchdog expired, resetting the guest");
+            self.expired = true;
+            if let Err(err) = eventfd_write_retry(&self.reset_evt, 1) {
+                error!("failed to signal the watchdog reset: {:?}", err);
+            }
+            if let Err(err) = self.timer.set_interval(KICK_INTERVAL) {
+                error!("failed to arm the watchdog timer: {:?}", err);
+            }
+        }
+        kick_vcpu(&self.vcpu_thread);
+    }
+}
+
+impl MutEventSubscriber for Watchdog {
+    fn process(&mut self, event: Events, _ops: &mut EventOps) {
+        if event.fd() == self.timer.as_raw_fd() {
+            self.process_timer();
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.timer, EventSet::IN)) {
+            panic!("Failed to register watchdog timer: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn watchdog(timeout: Duration) -> (Watchdog, EventFd) {
+        let reset_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let watchdog = Watchdog::new(
+            timeout,
+            reset_evt.try_clone().unwrap(),
+            VcpuThread::default(),
+        )
+        .unwrap();
+        (watchdog, reset_evt)
+    }
+
+    #[test]
+    fn test_reset_without_pings() {
+        let (mut watchdog, reset_evt) = watchdog(Duration::from_millis(10));
+        watchdog.write(WDT_CONTROL, &CONTROL_ENABLE.to_le_bytes());
+
+        thread::sleep(Duration::from_millis(20));
+        watchdog.process_timer();
+
+        assert_eq!(reset_evt.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_no_reset_when_disabled() {
+        let (mut watchdog, reset_evt) = watchdog(Duration::from_millis(10));
+        watchdog.write(WDT_CONTROL, &CONTROL_ENABLE.to_le_bytes());
+        watchdog.write(WDT_CONTROL, &0u32.to_le_bytes());
+
+        thread::sleep(Duration::from_millis(20));
+        watchdog.process_timer();
+
+        assert!(reset_evt.read().is_err());
+        let mut control = [0; 4];
+        watchdog.read(WDT_CONTROL, &mut control);
+        assert_eq!(u32::from_le_bytes(control), 0);
+    }
+}
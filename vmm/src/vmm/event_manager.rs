@@ -1,4 +1,4 @@
 pub use event_manager::{EventManager as BaseEventManager, MutEventSubscriber, SubscriberOps};
 use std::sync::{Arc, Mutex};
 
-pub type EventManager = BaseEventManager<Arc<Mutex<dyn MutEventSubscriber>>>;
+pub type EventManager = BaseEventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>;
@@ -1,16 +1,19 @@
-use vm_fdt::{Error, FdtWriter};
-
-const PHANDLE_GIC: u32 = 1;
+use std::collections::BTreeMap;
 
-const AARCH64_FDT_MAX_SIZE: u64 = 0x200000;
-
-// This indicates the start of DRAM inside the physical address space.
-const AARCH64_PHYS_MEM_START: u64 = 0x80000000;
+use vm_fdt::{Error, FdtWriter};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError};
 
-// This is the base address of MMIO devices.
-const AARCH64_MMIO_BASE: u64 = 1 << 30;
+use crate::vmm::layout::{FDT_MAX_SIZE, MAPPED_IO_START};
+use crate::vmm::memory::GuestMemoryMmap;
+use crate::vmm::pci::{PCI_MMIO_BASE, PCI_MMIO_SIZE};
 
-const AARCH64_AXI_BASE: u64 = 0x40000000;
+// Flattened device tree format, see the devicetree specification chapter 5.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
 
 // These constants indicate the address space used by the ARM vGIC.
 const AARCH64_GIC_DIST_SIZE: u64 = 0x10000;
@@ -18,7 +21,7 @@ const AARCH64_GIC_CPUI_SIZE: u64 = 0x20000;
 
 // These constants indicate the placement of the GIC registers in the physical
 // address space.
-pub const AARCH64_GIC_DIST_BASE: u64 = AARCH64_AXI_BASE - AARCH64_GIC_DIST_SIZE;
+pub const AARCH64_GIC_DIST_BASE: u64 = MAPPED_IO_START - AARCH64_GIC_DIST_SIZE;
 pub const AARCH64_GIC_CPUI_BASE: u64 = AARCH64_GIC_DIST_BASE - AARCH64_GIC_CPUI_SIZE;
 pub const AARCH64_GIC_REDIST_SIZE: u64 = 0x20000;
 
@@ -32,7 +35,25 @@ const IRQ_TYPE_EDGE_RISING: u32 = 0x00000001;
 const IRQ_TYPE_LEVEL_HIGH: u32 = 0x00000004;
 const IRQ_TYPE_LEVEL_LOW: u32 = 0x00000008;
 // PMU PPI interrupt, same as qemu
-const AARCH64_PMU_IRQ: u32 = 7;
+pub const AARCH64_PMU_IRQ: u32 = 7;
+
+/// Hands out the phandles of one blob, so no two nodes get the same one.
+struct PhandleAllocator {
+    next: u32,
+}
+
+impl PhandleAllocator {
+    fn new() -> Self {
+        // 0 and 0xffffffff aren't valid phandles
+        PhandleAllocator { next: 1 }
+    }
+
+    fn allocate(&mut self) -> u32 {
+        let phandle = self.next;
+        self.next += 1;
+        phandle
+    }
+}
 
 struct DeviceInfo {
     addr: u64,
@@ -40,19 +61,237 @@ struct DeviceInfo {
     irq: u32,
 }
 
+/// Conduit the guest uses to make PSCI calls.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PsciMethod {
+    /// Trap to the hypervisor, which is where KVM handles PSCI.
+    #[default]
+    Hvc,
+    /// Trap to the secure monitor.
+    Smc,
+}
+
+impl PsciMethod {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PsciMethod::Hvc => "hvc",
+            PsciMethod::Smc => "smc",
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct FdtBuilder {
     cmdline: String,
-    mem_size: u64,
+    // (start, size) of each guest memory region
+    mem_regions: Vec<(u64, u64)>,
     virtio_devices: Vec<DeviceInfo>,
-    serial_console: (u64, u64),
-    rtc: (u64, u64),
+    // nodes for devices that aren't set are left out
+    serial_console: Option<(u64, u64)>,
+    rtc: Option<(u64, u64)>,
+    // ECAM of the PCIe host bridge
+    pci: Option<(u64, u64)>,
+    watchdog: Option<(u64, u64)>,
+    pmu: bool,
+    psci_method: PsciMethod,
+    // MPIDR of each vcpu, a single vcpu with MPIDR 0 when empty
+    cpu_mpidrs: Vec<u64>,
+    initrd: Option<(u64, u64)>,
 }
 
 pub struct Fdt {
     pub fdt_blob: Vec<u8>,
 }
 
+#[derive(Debug)]
+pub enum FdtReadError {
+    GuestMemory(GuestMemoryError),
+    InvalidMagic(u32),
+    InvalidSize(u32),
+    /// The structure block can't be parsed.
+    Malformed,
+    /// A node every guest needs, given by its path, isn't in the blob.
+    MissingNode(String),
+    /// The node at the given path lacks the named property.
+    MissingProperty(String, &'static str),
+    /// The root's `interrupt-parent` isn't the phandle of `/intc`.
+    InterruptParentMismatch,
+}
+
+// Nodes every guest needs, with the properties the kernel reads from them.
+const REQUIRED_NODES: [(&str, &[&str]); 3] = [
+    ("/chosen", &["bootargs"]),
+    ("/memory", &["device_type", "reg"]),
+    ("/intc", &["compatible", "reg", "interrupt-controller"]),
+];
+const CPU_PROPERTIES: [&str; 3] = ["device_type", "enable-method", "reg"];
+const VIRTIO_MMIO_PROPERTIES: [&str; 3] = ["compatible", "reg", "interrupts"];
+
+impl Fdt {
+    /// Reads back a blob written to guest memory at `addr`, checking its header first.
+    pub fn from_guest_memory(
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+    ) -> Result<Fdt, FdtReadError> {
+        let mut header = [0u8; FDT_HEADER_SIZE];
+        mem.read_slice(&mut header, addr)
+            .map_err(FdtReadError::GuestMemory)?;
+
+        let magic = be_u32(&header, 0).unwrap();
+        if magic != FDT_MAGIC {
+            return Err(FdtReadError::InvalidMagic(magic));
+        }
+        let size = be_u32(&header, 4).unwrap();
+        if (size as usize) < FDT_HEADER_SIZE || u64::from(size) > FDT_MAX_SIZE {
+            return Err(FdtReadError::InvalidSize(size));
+        }
+
+        let mut fdt_blob = vec![0u8; size as usize];
+        mem.read_slice(&mut fdt_blob, addr)
+            .map_err(FdtReadError::GuestMemory)?;
+
+        Ok(Fdt { fdt_blob })
+    }
+
+    pub fn magic(&self) -> Option<u32> {
+        be_u32(&self.fdt_blob, 0)
+    }
+
+    /// Looks up the value of property `name` of the node at `path`, e.g. `/chosen`.
+    pub fn property(&self, path: &str, name: &str) -> Option<&[u8]> {
+        let wanted: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        let strings_offset = be_u32(&self.fdt_blob, 12)? as usize;
+
+        // Names of the nodes enclosing the current token, starting with the unnamed root.
+        let mut nodes: Vec<&str> = Vec::new();
+        let mut offset = be_u32(&self.fdt_blob, 8)? as usize;
+        loop {
+            let token = be_u32(&self.fdt_blob, offset)?;
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node = self.string_at(offset)?;
+                    offset += align4(node.len() + 1);
+                    nodes.push(node);
+                }
+                FDT_END_NODE => {
+                    nodes.pop()?;
+                }
+                FDT_PROP => {
+                    let len = be_u32(&self.fdt_blob, offset)? as usize;
+                    let name_offset = be_u32(&self.fdt_blob, offset + 4)? as usize;
+                    let value = self.fdt_blob.get(offset + 8..offset + 8 + len)?;
+                    offset += 8 + align4(len);
+
+                    if nodes.get(1..) == Some(&wanted[..])
+                        && self.string_at(strings_offset + name_offset)? == name
+                    {
+                        return Some(value);
+                    }
+                }
+                FDT_NOP => {}
+                // FDT_END or a corrupted token.
+                _ => return None,
+            }
+        }
+    }
+
+    /// Paths of all nodes in the blob, e.g. `/cpus/cpu@0`, in the order they appear.
+    pub fn node_paths(&self) -> Option<Vec<String>> {
+        let mut paths = Vec::new();
+
+        let mut nodes: Vec<&str> = Vec::new();
+        let mut offset = be_u32(&self.fdt_blob, 8)? as usize;
+        loop {
+            let token = be_u32(&self.fdt_blob, offset)?;
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node = self.string_at(offset)?;
+                    offset += align4(node.len() + 1);
+                    nodes.push(node);
+                    // the root is the only node with an empty name
+                    if nodes.len() > 1 {
+                        paths.push(nodes[1..].iter().map(|node| format!("/{}", node)).collect());
+                    }
+                }
+                FDT_END_NODE => {
+                    nodes.pop()?;
+                    if nodes.is_empty() {
+                        return Some(paths);
+                    }
+                }
+                FDT_PROP => {
+                    let len = be_u32(&self.fdt_blob, offset)? as usize;
+                    offset += 8 + align4(len);
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+
+    /// Parses the blob back and checks the nodes every guest needs are there with the
+    /// properties it reads from them: `/chosen`, `/memory`, a cpu under `/cpus`, `/intc`,
+    /// the properties of every `virtio_mmio` node, and that interrupts are routed to `/intc`.
+    pub fn validate(&self) -> Result<(), FdtReadError> {
+        let paths = self.node_paths().ok_or(FdtReadError::Malformed)?;
+
+        let has_properties = |path: &str, names: &[&'static str]| {
+            for name in names {
+                if self.property(path, name).is_none() {
+                    return Err(FdtReadError::MissingProperty(path.to_string(), name));
+                }
+            }
+            Ok(())
+        };
+
+        for (path, names) in REQUIRED_NODES {
+            if !paths.iter().any(|node| node == path) {
+                return Err(FdtReadError::MissingNode(path.to_string()));
+            }
+            has_properties(path, names)?;
+        }
+
+        let cpu = paths
+            .iter()
+            .find(|path| path.starts_with("/cpus/cpu@"))
+            .ok_or_else(|| FdtReadError::MissingNode("/cpus/cpu@0".to_string()))?;
+        has_properties(cpu, &CPU_PROPERTIES)?;
+
+        for path in paths
+            .iter()
+            .filter(|path| path.starts_with("/virtio_mmio@"))
+        {
+            has_properties(path, &VIRTIO_MMIO_PROPERTIES)?;
+        }
+
+        has_properties("/intc", &["phandle"])?;
+        if self.property("/", "interrupt-parent") != self.property("/intc", "phandle") {
+            return Err(FdtReadError::InterruptParentMismatch);
+        }
+
+        Ok(())
+    }
+
+    fn string_at(&self, offset: usize) -> Option<&str> {
+        let bytes = self.fdt_blob.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        std::str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
 impl FdtBuilder {
     pub fn new() -> Self {
         FdtBuilder::default()
@@ -63,8 +302,8 @@ impl FdtBuilder {
         self
     }
 
-    pub fn with_mem_size(&mut self, mem_size: u64) -> &mut Self {
-        self.mem_size = mem_size;
+    pub fn with_mem_regions(&mut self, mem_regions: Vec<(u64, u64)>) -> &mut Self {
+        self.mem_regions = mem_regions;
         self
     }
 
@@ -74,12 +313,50 @@ impl FdtBuilder {
     }
 
     pub fn with_serial_console(&mut self, addr: u64, size: u64) -> &mut Self {
-        self.serial_console = (addr, size);
+        self.serial_console = Some((addr, size));
         self
     }
 
     pub fn with_rtc(&mut self, addr: u64, size: u64) -> &mut Self {
-        self.rtc = (addr, size);
+        self.rtc = Some((addr, size));
+        self
+    }
+
+    pub fn with_pci(&mut self, addr: u64, size: u64) -> &mut Self {
+        self.pci = Some((addr, size));
+        self
+    }
+
+    pub fn with_watchdog(&mut self, addr: u64, size: u64) -> &mut Self {
+        self.watchdog = Some((addr, size));
+        self
+    }
+
+    pub fn with_cpu_mpidr(&mut self, mpidr: u64) -> &mut Self {
+        self.with_cpu_topology(&[mpidr])
+    }
+
+    /// Describes one vcpu per MPIDR, with a `cpu-map` grouping them into clusters.
+    ///
+    /// vcpus that only differ in Aff0 share a cluster, where each is a core. That's how KVM
+    /// assigns MPIDRs, 16 vcpus to a cluster.
+    pub fn with_cpu_topology(&mut self, mpidrs: &[u64]) -> &mut Self {
+        self.cpu_mpidrs = mpidrs.to_vec();
+        self
+    }
+
+    pub fn with_pmu(&mut self, pmu: bool) -> &mut Self {
+        self.pmu = pmu;
+        self
+    }
+
+    pub fn with_psci_method(&mut self, method: PsciMethod) -> &mut Self {
+        self.psci_method = method;
+        self
+    }
+
+    pub fn with_initrd(&mut self, addr: u64, size: u64) -> &mut Self {
+        self.initrd = Some((addr, size));
         self
     }
 
@@ -90,8 +367,19 @@ impl FdtBuilder {
     pub fn create_fdt(&self) -> Result<Fdt, Error> {
         let mut fdt = FdtWriter::new()?;
 
+        // referenced before the nodes that own them are written
+        let mut phandles = PhandleAllocator::new();
+        let gic_phandle = phandles.allocate();
+        let clock_phandle = phandles.allocate();
+        let mpidrs: &[u64] = if self.cpu_mpidrs.is_empty() {
+            &[0]
+        } else {
+            &self.cpu_mpidrs
+        };
+        let cpu_phandles: Vec<u32> = mpidrs.iter().map(|_| phandles.allocate()).collect();
+
         let root_node = fdt.begin_node("")?;
-        fdt.property_u32("interrupt-parent", 1)?;
+        fdt.property_u32("interrupt-parent", gic_phandle)?;
         fdt.property_string("compatible", "linux,dummy-virt")?;
         fdt.property_u32("#address-cells", 0x2)?;
         fdt.property_u32("#size-cells", 0x2)?;
@@ -99,10 +387,18 @@ impl FdtBuilder {
         // chosen node
         let chosen_node = fdt.begin_node("chosen")?;
         fdt.property_string("bootargs", self.cmdline.as_ref())?;
+        if let Some((addr, size)) = self.initrd {
+            fdt.property_u64("linux,initrd-start", addr)?;
+            fdt.property_u64("linux,initrd-end", addr + size)?;
+        }
         fdt.end_node(chosen_node)?;
 
         // create memory node
-        let mem_reg_prop = [0x80000000, self.mem_size];
+        let mem_reg_prop: Vec<u64> = self
+            .mem_regions
+            .iter()
+            .flat_map(|(start, size)| [*start, *size])
+            .collect();
         let memory_node = fdt.begin_node("memory")?;
         fdt.property_string("device_type", "memory")?;
         fdt.property_array_u64("reg", &mem_reg_prop)?;
@@ -112,13 +408,39 @@ impl FdtBuilder {
         let cpus_node = fdt.begin_node("cpus")?;
         fdt.property_u32("#address-cells", 0x1)?;
         fdt.property_u32("#size-cells", 0x0)?;
-        let cpu_name = format!("cpu@{:x}", 0);
-        let cpu_node = fdt.begin_node(&cpu_name)?;
-        fdt.property_string("device_type", "cpu")?;
-        fdt.property_string("compatible", "arm,arm-v8")?;
-        fdt.property_string("enable-method", "psci")?;
-        fdt.property_u32("reg", 0)?;
-        fdt.end_node(cpu_node)?;
+        // cores of each cluster, keyed by the affinity bits above Aff0
+        let mut clusters: BTreeMap<u64, Vec<(u64, u32)>> = BTreeMap::new();
+        for (&mpidr, &phandle) in mpidrs.iter().zip(&cpu_phandles) {
+            // With a single address cell, reg holds the MPIDR affinity bits Aff2..Aff0.
+            let cpu_reg = (mpidr & 0x00ff_ffff) as u32;
+            let cpu_name = format!("cpu@{:x}", cpu_reg);
+            let cpu_node = fdt.begin_node(&cpu_name)?;
+            fdt.property_string("device_type", "cpu")?;
+            fdt.property_string("compatible", "arm,arm-v8")?;
+            fdt.property_string("enable-method", "psci")?;
+            fdt.property_u32("reg", cpu_reg)?;
+            fdt.property_phandle(phandle)?;
+            fdt.end_node(cpu_node)?;
+
+            clusters
+                .entry(u64::from(cpu_reg) >> 8)
+                .or_default()
+                .push((u64::from(cpu_reg) & 0xff, phandle));
+        }
+
+        // the kernel wants clusters and cores numbered from 0 without gaps
+        let cpu_map_node = fdt.begin_node("cpu-map")?;
+        for (cluster_index, cores) in clusters.values_mut().enumerate() {
+            cores.sort_unstable();
+            let cluster_node = fdt.begin_node(&format!("cluster{}", cluster_index))?;
+            for (core_index, (_, phandle)) in cores.iter().enumerate() {
+                let core_node = fdt.begin_node(&format!("core{}", core_index))?;
+                fdt.property_u32("cpu", *phandle)?;
+                fdt.end_node(core_node)?;
+            }
+            fdt.end_node(cluster_node)?;
+        }
+        fdt.end_node(cpu_map_node)?;
         fdt.end_node(cpus_node)?;
 
         // create gicv node
@@ -130,22 +452,22 @@ impl FdtBuilder {
         fdt.property_u32("#interrupt-cells", GIC_FDT_IRQ_NUM_CELLS)?;
         fdt.property_null("interrupt-controller")?;
         fdt.property_array_u64("reg", &gic_reg_prop)?;
-        fdt.property_phandle(PHANDLE_GIC)?;
+        fdt.property_phandle(gic_phandle)?;
         fdt.property_u32("#address-cells", 2)?;
         fdt.property_u32("#size-cells", 2)?;
         fdt.end_node(intc_node)?;
 
         // create serial node
-        let serial_node = fdt.begin_node(&format!("uart@{:x}", self.serial_console.0))?;
-        fdt.property_string("compatible", "ns16550a")?;
-        let serial_reg_prop = [self.serial_console.0, self.serial_console.1];
-        fdt.property_array_u64("reg", &serial_reg_prop)?;
-        const CLK_PHANDLE: u32 = 24;
-        fdt.property_u32("clocks", CLK_PHANDLE)?;
-        fdt.property_string("clock-names", "apb_pclk")?;
-        let irq = [GIC_FDT_IRQ_TYPE_SPI, 4, IRQ_TYPE_EDGE_RISING];
-        fdt.property_array_u32("interrupts", &irq)?;
-        fdt.end_node(serial_node)?;
+        if let Some((addr, size)) = self.serial_console {
+            let serial_node = fdt.begin_node(&format!("uart@{:x}", addr))?;
+            fdt.property_string("compatible", "ns16550a")?;
+            fdt.property_array_u64("reg", &[addr, size])?;
+            fdt.property_u32("clocks", clock_phandle)?;
+            fdt.property_string("clock-names", "apb_pclk")?;
+            let irq = [GIC_FDT_IRQ_TYPE_SPI, 4, IRQ_TYPE_EDGE_RISING];
+            fdt.property_array_u32("interrupts", &irq)?;
+            fdt.end_node(serial_node)?;
+        }
 
         // create rtc node
         let clock_node = fdt.begin_node("apb-pclk")?;
@@ -153,21 +475,29 @@ impl FdtBuilder {
         fdt.property_string("compatible", "fixed-clock")?;
         fdt.property_u32("clock-frequency", 24_000_000)?;
         fdt.property_string("clock-output-names", "clk24mhz")?;
-        fdt.property_phandle(24)?;
+        fdt.property_phandle(clock_phandle)?;
         fdt.end_node(clock_node)?;
-        let rtc_name = format!("rtc@{:x}", self.rtc.0);
-        let reg = [self.rtc.0, self.rtc.1];
-        let irq = [GIC_FDT_IRQ_TYPE_SPI, 33, IRQ_TYPE_LEVEL_HIGH];
-        let rtc_node = fdt.begin_node(&rtc_name)?;
-        fdt.property_string_list(
-            "compatible",
-            vec![String::from("arm,pl031"), String::from("arm,primecell")],
-        )?;
-        fdt.property_array_u64("reg", &reg)?;
-        fdt.property_array_u32("interrupts", &irq)?;
-        fdt.property_u32("clocks", CLK_PHANDLE)?;
-        fdt.property_string("clock-names", "apb_pclk")?;
-        fdt.end_node(rtc_node)?;
+        if let Some((addr, size)) = self.rtc {
+            let irq = [GIC_FDT_IRQ_TYPE_SPI, 33, IRQ_TYPE_LEVEL_HIGH];
+            let rtc_node = fdt.begin_node(&format!("rtc@{:x}", addr))?;
+            fdt.property_string_list(
+                "compatible",
+                vec![String::from("arm,pl031"), String::from("arm,primecell")],
+            )?;
+            fdt.property_array_u64("reg", &[addr, size])?;
+            fdt.property_array_u32("interrupts", &irq)?;
+            fdt.property_u32("clocks", clock_phandle)?;
+            fdt.property_string("clock-names", "apb_pclk")?;
+            fdt.end_node(rtc_node)?;
+        }
+
+        // create watchdog node
+        if let Some((addr, size)) = self.watchdog {
+            let watchdog_node = fdt.begin_node(&format!("watchdog@{:x}", addr))?;
+            fdt.property_string("compatible", "arm-vm,watchdog")?;
+            fdt.property_array_u64("reg", &[addr, size])?;
+            fdt.end_node(watchdog_node)?;
+        }
 
         // create timer node
         let irqs = [13, 14, 11, 10];
@@ -190,22 +520,24 @@ impl FdtBuilder {
         let compatible = "arm,psci-0.2";
         let psci_node = fdt.begin_node("psci")?;
         fdt.property_string("compatible", compatible)?;
-        fdt.property_string("method", "hvc")?;
+        fdt.property_string("method", self.psci_method.as_str())?;
         fdt.end_node(psci_node)?;
 
-        // create pmu node
-        let compatible = "arm,armv8-pmuv3";
-        let cpu_mask: u32 =
-            (((1 << 1) - 1) << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK;
-        let irq = [
-            GIC_FDT_IRQ_TYPE_PPI,
-            AARCH64_PMU_IRQ,
-            cpu_mask | IRQ_TYPE_LEVEL_HIGH,
-        ];
-        let pmu_node = fdt.begin_node("pmu")?;
-        fdt.property_string("compatible", compatible)?;
-        fdt.property_array_u32("interrupts", &irq)?;
-        fdt.end_node(pmu_node)?;
+        // create pmu node, only when the vcpus have a PMU
+        if self.pmu {
+            let compatible = "arm,armv8-pmuv3";
+            let cpu_mask: u32 =
+                (((1 << 1) - 1) << GIC_FDT_IRQ_PPI_CPU_SHIFT) & GIC_FDT_IRQ_PPI_CPU_MASK;
+            let irq = [
+                GIC_FDT_IRQ_TYPE_PPI,
+                AARCH64_PMU_IRQ,
+                cpu_mask | IRQ_TYPE_LEVEL_HIGH,
+            ];
+            let pmu_node = fdt.begin_node("pmu")?;
+            fdt.property_string("compatible", compatible)?;
+            fdt.property_array_u32("interrupts", &irq)?;
+            fdt.end_node(pmu_node)?;
+        }
 
         // create virtio device nodes
         for info in &self.virtio_devices {
@@ -216,10 +548,34 @@ impl FdtBuilder {
                 "interrupts",
                 &[GIC_FDT_IRQ_TYPE_SPI, info.irq, IRQ_TYPE_EDGE_RISING],
             )?;
-            fdt.property_array_u32("interrupt-parent", &[PHANDLE_GIC])?;
+            fdt.property_array_u32("interrupt-parent", &[gic_phandle])?;
             fdt.end_node(virtio_mmio)?;
         }
 
+        // create pci host bridge node
+        if let Some((addr, size)) = self.pci {
+            let pci_node = fdt.begin_node(&format!("pci@{:x}", addr))?;
+            fdt.property_string("compatible", "pci-host-ecam-generic")?;
+            fdt.property_string("device_type", "pci")?;
+            fdt.property_array_u64("reg", &[addr, size])?;
+            fdt.property_array_u32("bus-range", &[0, 0])?;
+            fdt.property_u32("#address-cells", 3)?;
+            fdt.property_u32("#size-cells", 2)?;
+            // 32 bit memory space, identity mapped: pci address, cpu address, size
+            let ranges = [
+                0x0200_0000,
+                (PCI_MMIO_BASE >> 32) as u32,
+                PCI_MMIO_BASE as u32,
+                (PCI_MMIO_BASE >> 32) as u32,
+                PCI_MMIO_BASE as u32,
+                (PCI_MMIO_SIZE >> 32) as u32,
+                PCI_MMIO_SIZE as u32,
+            ];
+            fdt.property_array_u32("ranges", &ranges)?;
+            fdt.property_null("dma-coherent")?;
+            fdt.end_node(pci_node)?;
+        }
+
         fdt.end_node(root_node)?;
 
         Ok(Fdt {
@@ -227,3 +583,209 @@ impl FdtBuilder {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::vmm::layout::DRAM_MEM_START;
+    use crate::vmm::memory::{arch_memory_regions, get_fdt_addr, test_guest_memory};
+    use crate::vmm::pci::{PCI_ECAM_BASE, PCI_ECAM_SIZE};
+
+    use super::*;
+
+    const CMDLINE: &str = "console=ttyS0 reboot=k panic=1";
+
+    fn builder() -> FdtBuilder {
+        let mut builder = FdtBuilder::new();
+        builder
+            .with_cmdline(CMDLINE.to_string())
+            .with_mem_regions(vec![(DRAM_MEM_START, 128 << 20)])
+            .with_serial_console(MAPPED_IO_START, 0x1000)
+            .with_rtc(MAPPED_IO_START + 0x1000, 0x1000)
+            .add_virtio_device(MAPPED_IO_START + 0x2000, 0x1000, 34);
+        builder
+    }
+
+    #[test]
+    fn test_read_back_from_guest_memory() {
+        let mem = test_guest_memory(0x10000);
+        let fdt = builder().create_fdt().unwrap();
+        let addr = GuestAddress(get_fdt_addr(&mem));
+        mem.write_slice(&fdt.fdt_blob, addr).unwrap();
+
+        let read = Fdt::from_guest_memory(&mem, addr).unwrap();
+
+        assert_eq!(read.magic(), Some(0xd00d_feed));
+        assert_eq!(read.fdt_blob, fdt.fdt_blob);
+        let bootargs = read.property("/chosen", "bootargs").unwrap();
+        assert_eq!(bootargs, format!("{}\0", CMDLINE).as_bytes());
+    }
+
+    #[test]
+    fn test_read_back_invalid_magic() {
+        let mem = test_guest_memory(0x10000);
+
+        assert!(matches!(
+            Fdt::from_guest_memory(&mem, GuestAddress(DRAM_MEM_START)),
+            Err(FdtReadError::InvalidMagic(0))
+        ));
+    }
+
+    fn be_u64s(bytes: &[u8]) -> Vec<u64> {
+        bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_two_memory_regions() {
+        let mut builder = builder();
+        builder.with_mem_regions(vec![
+            (DRAM_MEM_START, 2 << 30),
+            (DRAM_MEM_START + (4 << 30), 1 << 30),
+        ]);
+
+        let fdt = builder.create_fdt().unwrap();
+
+        let reg = fdt.property("/memory", "reg").unwrap();
+        assert_eq!(
+            be_u64s(reg),
+            [DRAM_MEM_START, 2 << 30, DRAM_MEM_START + (4 << 30), 1 << 30]
+        );
+    }
+
+    #[test]
+    fn test_pci_node() {
+        let mut builder = builder();
+        builder.with_pci(PCI_ECAM_BASE, PCI_ECAM_SIZE);
+
+        let fdt = builder.create_fdt().unwrap();
+
+        let node = format!("/pci@{:x}", PCI_ECAM_BASE);
+        assert_eq!(fdt.property(&node, "device_type"), Some(&b"pci\0"[..]));
+        assert_eq!(
+            be_u64s(fdt.property(&node, "reg").unwrap()),
+            [PCI_ECAM_BASE, PCI_ECAM_SIZE]
+        );
+        assert_eq!(fdt.property(&node, "bus-range"), Some(&[0; 8][..]));
+    }
+
+    #[test]
+    fn test_validate() {
+        let fdt = builder().create_fdt().unwrap();
+        fdt.validate().unwrap();
+
+        // FDT_BEGIN_NODE of /intc, renamed to /intd
+        let intc = [&[0, 0, 0, 1][..], b"intc\0"].concat();
+        let mut renamed = fdt.fdt_blob.clone();
+        let pos = renamed
+            .windows(intc.len())
+            .position(|window| window == intc)
+            .unwrap();
+        renamed[pos + 7] = b'd';
+        assert!(matches!(
+            Fdt { fdt_blob: renamed }.validate(),
+            Err(FdtReadError::MissingNode(node)) if node == "/intc"
+        ));
+
+        let truncated = fdt.fdt_blob[..64].to_vec();
+        assert!(matches!(
+            Fdt {
+                fdt_blob: truncated
+            }
+            .validate(),
+            Err(FdtReadError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_psci_method() {
+        let fdt = builder().create_fdt().unwrap();
+        assert_eq!(fdt.property("/psci", "method"), Some(&b"hvc\0"[..]));
+
+        let mut builder = builder();
+        builder.with_psci_method(PsciMethod::Smc);
+        let fdt = builder.create_fdt().unwrap();
+        assert_eq!(fdt.property("/psci", "method"), Some(&b"smc\0"[..]));
+    }
+
+    #[test]
+    fn test_phandles() {
+        let mut builder = builder();
+        builder.with_cpu_topology(&[0, 1]);
+        let fdt = builder.create_fdt().unwrap();
+
+        let mut phandles: Vec<&[u8]> = fdt
+            .node_paths()
+            .unwrap()
+            .iter()
+            .filter_map(|path| fdt.property(path, "phandle"))
+            .collect();
+        // the gic, the clock and both cpus
+        assert_eq!(phandles.len(), 4);
+        phandles.sort_unstable();
+        phandles.dedup();
+        assert_eq!(phandles.len(), 4);
+
+        assert_eq!(
+            fdt.property("/", "interrupt-parent"),
+            fdt.property("/intc", "phandle")
+        );
+        let uart = format!("/uart@{:x}", MAPPED_IO_START);
+        assert_eq!(
+            fdt.property(&uart, "clocks"),
+            fdt.property("/apb-pclk", "phandle")
+        );
+    }
+
+    #[test]
+    fn test_memory_node_matches_regions() {
+        let regions = arch_memory_regions(128 << 20);
+        let mut builder = builder();
+        builder.with_mem_regions(
+            regions
+                .iter()
+                .map(|(addr, size)| (addr.0, *size as u64))
+                .collect(),
+        );
+
+        let fdt = builder.create_fdt().unwrap();
+
+        let reg = be_u64s(fdt.property("/memory", "reg").unwrap());
+        assert_eq!(reg[0], regions[0].0 .0);
+        assert_eq!(reg[0], DRAM_MEM_START);
+    }
+
+    #[test]
+    fn test_cpu_topology() {
+        let mut builder = builder();
+        builder.with_cpu_topology(&[0x0, 0x1, 0x100, 0x101]);
+
+        let fdt = builder.create_fdt().unwrap();
+
+        let paths = fdt.node_paths().unwrap();
+        let cpu_map: Vec<&str> = paths
+            .iter()
+            .filter_map(|path| path.strip_prefix("/cpus/cpu-map/"))
+            .collect();
+        assert_eq!(
+            cpu_map,
+            [
+                "cluster0",
+                "cluster0/core0",
+                "cluster0/core1",
+                "cluster1",
+                "cluster1/core0",
+                "cluster1/core1"
+            ]
+        );
+        assert_eq!(
+            fdt.property("/cpus/cpu-map/cluster1/core1", "cpu"),
+            fdt.property("/cpus/cpu@101", "phandle")
+        );
+        assert_eq!(
+            fdt.property("/cpus/cpu@101", "reg"),
+            Some(&[0, 0, 1, 1][..])
+        );
+    }
+}
@@ -0,0 +1,499 @@
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::info;
+
+use crate::vmm::api::{kick_vcpu, register_kick_handler, VcpuThread};
+use crate::vmm::cpu::{core_reg_ids, Cpu, SCTLR_EL1, TCR_EL1, TTBR0_EL1, TTBR1_EL1};
+use crate::vmm::memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+// gdb's interrupt request, sent outside of any packet while the guest runs.
+const CTRL_C: u8 = 0x03;
+// How often the interrupt watcher polls the connection, and kicks the vcpu once gdb asked
+// for a stop.
+const WATCH_INTERVAL: Duration = Duration::from_millis(10);
+
+// Register numbers of `target.xml`: x0-x30, sp, pc, then the 32 bit cpsr.
+const REG_CPSR: usize = 33;
+const REG_COUNT: usize = 34;
+
+const PAGE_SIZE: u64 = 0x1000;
+// Output address bits of a descriptor, with 4 KiB pages and 48 bit physical addresses.
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// Why the vcpu stopped, reported to gdb as a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A breakpoint or a single step, `SIGTRAP`.
+    Trap,
+    /// gdb interrupted the guest, `SIGINT`.
+    Interrupt,
+}
+
+impl StopReason {
+    fn signal(self) -> u8 {
+        match self {
+            StopReason::Trap => 5,
+            StopReason::Interrupt => 2,
+        }
+    }
+}
+
+/// What gdb wants the vcpu to do next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdbAction {
+    Continue,
+    Step,
+    /// gdb detached, the guest keeps running without the debugger.
+    Detach,
+    /// gdb killed the guest.
+    Kill,
+}
+
+/// gdb remote serial protocol server for the vcpu, listening on TCP.
+///
+/// The stub runs on the vcpu thread. It waits for a debugger before the guest starts and
+/// answers its packets whenever the vcpu is stopped, on a breakpoint, after a single step or
+/// when gdb interrupts it. Addresses are guest virtual addresses, translated with the
+/// guest's page tables when its MMU is on; only the 4 KiB granule is supported.
+pub struct GdbStub {
+    listener: TcpListener,
+    conn: Option<Connection>,
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl GdbStub {
+    pub fn bind(addr: SocketAddr) -> io::Result<GdbStub> {
+        // interrupts are delivered to the vcpu with the api kick
+        register_kick_handler()?;
+
+        Ok(GdbStub {
+            listener: TcpListener::bind(addr)?,
+            conn: None,
+        })
+    }
+
+    /// Reports the stop to gdb and serves its requests until it resumes the guest. Waits for
+    /// gdb to connect first if it isn't connected yet, in which case nothing is reported
+    /// since gdb asks on its own.
+    pub fn handle_stop(
+        &mut self,
+        cpu: &Cpu,
+        mem: &GuestMemoryMmap,
+        reason: StopReason,
+    ) -> io::Result<GdbAction> {
+        let conn = match &mut self.conn {
+            Some(conn) => {
+                conn.send(&format!("S{:02x}", reason.signal()))?;
+                conn
+            }
+            None => {
+                info!(
+                    "waiting for gdb to connect on {:?}",
+                    self.listener.local_addr()
+                );
+                let (stream, _) = self.listener.accept()?;
+                stream.set_nodelay(true)?;
+                self.conn.insert(Connection {
+                    writer: stream.try_clone()?,
+                    reader: BufReader::new(stream),
+                })
+            }
+        };
+
+        loop {
+            let packet = conn.receive()?;
+            let (reply, action) = handle_packet(&packet, cpu, mem, reason);
+            if let Some(reply) = reply {
+                conn.send(&reply)?;
+            }
+            if let Some(action) = action {
+                if matches!(action, GdbAction::Detach | GdbAction::Kill) {
+                    self.conn = None;
+                }
+                return Ok(action);
+            }
+        }
+    }
+
+    /// Watches the connection while the guest runs, kicking the vcpu out of the guest once
+    /// gdb asks for an interrupt. The watch ends when the returned guard is dropped.
+    pub fn watch_interrupts(&self, vcpu_thread: VcpuThread) -> io::Result<Option<InterruptWatch>> {
+        let conn = match &self.conn {
+            Some(conn) => conn,
+            None => return Ok(None),
+        };
+        let stream = conn.writer.try_clone()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("gdb".to_string())
+            .spawn(move || {
+                let mut pollfd = libc::pollfd {
+                    fd: stream.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                let mut requested = false;
+                while !thread_stop.load(Ordering::Acquire) {
+                    if requested {
+                        kick_vcpu(&vcpu_thread);
+                        thread::sleep(WATCH_INTERVAL);
+                        continue;
+                    }
+                    // SAFETY: `pollfd` is a valid pollfd for the stream, which outlives the call.
+                    let ret =
+                        unsafe { libc::poll(&mut pollfd, 1, WATCH_INTERVAL.as_millis() as i32) };
+                    requested = ret > 0;
+                }
+            })?;
+
+        Ok(Some(InterruptWatch {
+            stop,
+            thread: Some(thread),
+        }))
+    }
+
+    /// Whether gdb sent an interrupt request, consuming it.
+    pub fn take_interrupt(&mut self) -> io::Result<bool> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => return Ok(false),
+        };
+
+        conn.writer.set_nonblocking(true)?;
+        let mut byte = [0; 1];
+        let result = conn.reader.get_mut().read(&mut byte);
+        conn.writer.set_nonblocking(false)?;
+
+        match result {
+            Ok(1) => Ok(byte[0] == CTRL_C),
+            Ok(_) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Stops the interrupt watcher thread when dropped.
+pub struct InterruptWatch {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for InterruptWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Connection {
+    /// Reads the next packet, acknowledging it. Acks from gdb and stray interrupt requests
+    /// are skipped.
+    fn receive(&mut self) -> io::Result<String> {
+        loop {
+            let mut byte = [0; 1];
+            self.reader.read_exact(&mut byte)?;
+            if byte[0] != b'$' {
+                continue;
+            }
+
+            let mut payload = Vec::new();
+            loop {
+                self.reader.read_exact(&mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                payload.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if expected != Some(packet_checksum(&payload)) {
+                self.writer.write_all(b"-")?;
+                continue;
+            }
+            self.writer.write_all(b"+")?;
+
+            return Ok(String::from_utf8_lossy(&payload).into_owned());
+        }
+    }
+
+    fn send(&mut self, payload: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", payload, packet_checksum(payload.as_bytes()));
+        self.writer.write_all(packet.as_bytes())
+    }
+}
+
+fn packet_checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Answers a single packet. Returns the reply, if any, and what to do with the vcpu when the
+/// packet resumes it. Unsupported packets get an empty reply.
+fn handle_packet(
+    packet: &str,
+    cpu: &Cpu,
+    mem: &GuestMemoryMmap,
+    reason: StopReason,
+) -> (Option<String>, Option<GdbAction>) {
+    let reply = |reply: String| (Some(reply), None);
+    let error = || (Some("E01".to_string()), None);
+
+    let (command, args) = packet.split_at(packet.len().min(1));
+    match command {
+        "?" => reply(format!("S{:02x}", reason.signal())),
+        "g" => match read_regs(cpu) {
+            Some(regs) => reply(regs),
+            None => error(),
+        },
+        "G" => match write_regs(cpu, args) {
+            Some(()) => reply("OK".to_string()),
+            None => error(),
+        },
+        "p" => match usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|reg| read_reg(cpu, reg))
+        {
+            Some(value) => reply(value),
+            None => error(),
+        },
+        "P" => match write_reg_packet(cpu, args) {
+            Some(()) => reply("OK".to_string()),
+            None => error(),
+        },
+        "m" => match read_mem_packet(cpu, mem, args) {
+            Some(data) => reply(data),
+            None => error(),
+        },
+        "M" => match write_mem_packet(cpu, mem, args) {
+            Some(()) => reply("OK".to_string()),
+            None => error(),
+        },
+        // resuming at another address isn't supported, gdb sets the pc with `P` instead
+        "c" => (None, Some(GdbAction::Continue)),
+        "s" => (None, Some(GdbAction::Step)),
+        "D" => (Some("OK".to_string()), Some(GdbAction::Detach)),
+        "k" => (None, Some(GdbAction::Kill)),
+        // there's a single thread, whatever gdb selects
+        "H" | "T" => reply("OK".to_string()),
+        "q" => reply(handle_query(packet).unwrap_or_default()),
+        _ => reply(String::new()),
+    }
+}
+
+fn handle_query(packet: &str) -> Option<String> {
+    if packet.starts_with("qSupported") {
+        return Some("PacketSize=4000;qXfer:features:read+".to_string());
+    }
+    if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+        let (offset, len) = args.split_once(',')?;
+        let offset = usize::from_str_radix(offset, 16).ok()?;
+        let len = usize::from_str_radix(len, 16).ok()?;
+
+        let xml = target_xml();
+        let start = offset.min(xml.len());
+        let end = start.saturating_add(len).min(xml.len());
+        let prefix = if end == xml.len() { 'l' } else { 'm' };
+        return Some(format!("{}{}", prefix, &xml[start..end]));
+    }
+
+    match packet {
+        "qAttached" => Some("1".to_string()),
+        "qC" => Some("QC1".to_string()),
+        "qfThreadInfo" => Some("m1".to_string()),
+        "qsThreadInfo" => Some("l".to_string()),
+        _ => None,
+    }
+}
+
+// Describes only the core registers, so gdb doesn't expect the FP and SIMD ones in `g`.
+fn target_xml() -> String {
+    let mut regs: Vec<String> = (0..31)
+        .map(|i| format!("<reg name=\"x{}\" bitsize=\"64\"/>", i))
+        .collect();
+    regs.push("<reg name=\"sp\" bitsize=\"64\" type=\"data_ptr\"/>".to_string());
+    regs.push("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\"/>".to_string());
+    regs.push("<reg name=\"cpsr\" bitsize=\"32\"/>".to_string());
+
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>aarch64</architecture>\
+         <feature name=\"org.gnu.gdb.aarch64.core\">{}</feature></target>",
+        regs.concat()
+    )
+}
+
+fn reg_size(reg: usize) -> usize {
+    match reg {
+        REG_CPSR => 4,
+        _ => 8,
+    }
+}
+
+fn read_reg(cpu: &Cpu, reg: usize) -> Option<String> {
+    let reg_id = *core_reg_ids().get(reg)?;
+    let value = cpu.get_reg(reg_id).ok()?;
+
+    Some(hex_encode(&value.to_le_bytes()[..reg_size(reg)]))
+}
+
+fn write_reg(cpu: &Cpu, reg: usize, bytes: &[u8]) -> Option<()> {
+    let reg_id = *core_reg_ids().get(reg)?;
+    if bytes.len() != reg_size(reg) {
+        return None;
+    }
+    let mut value = [0; 8];
+    value[..bytes.len()].copy_from_slice(bytes);
+
+    cpu.set_reg(reg_id, u64::from_le_bytes(value)).ok()
+}
+
+fn read_regs(cpu: &Cpu) -> Option<String> {
+    (0..REG_COUNT).map(|reg| read_reg(cpu, reg)).collect()
+}
+
+fn write_regs(cpu: &Cpu, args: &str) -> Option<()> {
+    let bytes = hex_decode(args)?;
+    let mut offset = 0;
+    for reg in 0..REG_COUNT {
+        let end = offset + reg_size(reg);
+        write_reg(cpu, reg, bytes.get(offset..end)?)?;
+        offset = end;
+    }
+
+    Some(())
+}
+
+// `P` packets are `reg=value`.
+fn write_reg_packet(cpu: &Cpu, args: &str) -> Option<()> {
+    let (reg, value) = args.split_once('=')?;
+    let reg = usize::from_str_radix(reg, 16).ok()?;
+
+    write_reg(cpu, reg, &hex_decode(value)?)
+}
+
+// `m` packets are `addr,len`.
+fn read_mem_packet(cpu: &Cpu, mem: &GuestMemoryMmap, args: &str) -> Option<String> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = u64::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+
+    let mut data = vec![0; len];
+    let mut done = 0;
+    while done < len {
+        let (gpa, chunk) = translate_chunk(cpu, mem, addr + done as u64, len - done)?;
+        mem.read_slice(&mut data[done..done + chunk], gpa).ok()?;
+        done += chunk;
+    }
+
+    Some(hex_encode(&data))
+}
+
+// `M` packets are `addr,len:data`.
+fn write_mem_packet(cpu: &Cpu, mem: &GuestMemoryMmap, args: &str) -> Option<()> {
+    let (location, data) = args.split_once(':')?;
+    let (addr, len) = location.split_once(',')?;
+    let addr = u64::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    let data = hex_decode(data)?;
+    if data.len() != len {
+        return None;
+    }
+
+    let mut done = 0;
+    while done < len {
+        let (gpa, chunk) = translate_chunk(cpu, mem, addr + done as u64, len - done)?;
+        mem.write_slice(&data[done..done + chunk], gpa).ok()?;
+        done += chunk;
+    }
+
+    Some(())
+}
+
+// Translates `addr` and returns how many of the `len` bytes from it are on the same page.
+fn translate_chunk(
+    cpu: &Cpu,
+    mem: &GuestMemoryMmap,
+    addr: u64,
+    len: usize,
+) -> Option<(GuestAddress, usize)> {
+    let page_left = (PAGE_SIZE - addr % PAGE_SIZE) as usize;
+    let gpa = translate(cpu, mem, addr)?;
+
+    Some((GuestAddress(gpa), len.min(page_left)))
+}
+
+/// Walks the guest's stage 1 page tables to translate the virtual address `va`. Addresses
+/// are used as is while the guest's MMU is off.
+fn translate(cpu: &Cpu, mem: &GuestMemoryMmap, va: u64) -> Option<u64> {
+    let sctlr = cpu.get_reg(SCTLR_EL1).ok()?;
+    if sctlr & 1 == 0 {
+        return Some(va);
+    }
+
+    // bit 55 picks the upper, kernel, range or the lower one
+    let tcr = cpu.get_reg(TCR_EL1).ok()?;
+    let (ttbr, size_offset, granule_4k) = if (va >> 55) & 1 == 1 {
+        (TTBR1_EL1, (tcr >> 16) & 0x3f, (tcr >> 30) & 0x3 == 0b10)
+    } else {
+        (TTBR0_EL1, tcr & 0x3f, (tcr >> 14) & 0x3 == 0b00)
+    };
+    if !granule_4k {
+        return None;
+    }
+
+    // each level resolves 9 bits above the 12 bit page offset
+    let va_bits = 64 - size_offset;
+    let mut level = 4 - (va_bits - 12).div_ceil(9);
+    let mut table = cpu.get_reg(ttbr).ok()? & 0x0000_ffff_ffff_fffe;
+    loop {
+        let shift = 12 + 9 * (3 - level);
+        let index_bits = (va_bits - shift).min(9);
+        let index = (va >> shift) & ((1 << index_bits) - 1);
+
+        let desc: u64 = mem.read_obj(GuestAddress(table + index * 8)).ok()?;
+        if desc & 1 == 0 {
+            return None;
+        }
+        let out = desc & DESC_ADDR_MASK;
+        let offset_mask = (1 << shift) - 1;
+        // a page at the last level, a block before it
+        if level == 3 || desc & 2 == 0 {
+            return Some((out & !offset_mask) | (va & offset_mask));
+        }
+
+        table = out;
+        level += 1;
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
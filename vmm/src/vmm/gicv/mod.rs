@@ -1,6 +1,8 @@
-use crate::vmm::gicv::regs::{GicState, GicVcpuState};
+pub use crate::vmm::gicv::regs::{GicState, GicVcpuState};
 use kvm_ioctls::{DeviceFd, VmFd};
 
+use crate::vmm::layout::MAPPED_IO_START;
+
 mod regs;
 
 #[derive(Debug)]
@@ -10,6 +12,7 @@ pub struct GICv2 {
     vcpu_count: u64,
 }
 
+#[derive(Debug)]
 pub enum GicError {
     CreateGIC(kvm_ioctls::Error),
     DeviceAttribute(kvm_ioctls::Error, bool, u32),
@@ -26,9 +29,6 @@ impl GICv2 {
     // Device trees specific constants
     const ARCH_GIC_V2_MAINT_IRQ: u32 = 8;
 
-    /// Below this address will reside the GIC, above this address will reside the MMIO devices.
-    const MAPPED_IO_START: u64 = 1 << 30; // 1 GB
-
     pub fn device_fd(&self) -> &DeviceFd {
         &self.fd
     }
@@ -42,7 +42,7 @@ impl GICv2 {
     }
 
     const fn get_dist_addr() -> u64 {
-        GICv2::MAPPED_IO_START - GICv2::KVM_VGIC_V2_DIST_SIZE
+        MAPPED_IO_START - GICv2::KVM_VGIC_V2_DIST_SIZE
     }
 
     const fn get_dist_size() -> u64 {
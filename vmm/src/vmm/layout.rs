@@ -0,0 +1,13 @@
+// Guest physical address space, shared by everything that places something in it. The FDT
+// has to describe the same layout KVM and the devices are set up with.
+
+/// Start of guest DRAM, guest memory is a single region from here.
+pub const DRAM_MEM_START: u64 = 0x8000_0000;
+
+/// Start of the window MMIO devices are allocated from, it runs up to `DRAM_MEM_START`. The
+/// GIC sits right below it.
+pub const MAPPED_IO_START: u64 = 1 << 30;
+pub const MAPPED_IO_SIZE: u64 = DRAM_MEM_START - MAPPED_IO_START;
+
+/// Space reserved for the FDT at the end of guest memory.
+pub const FDT_MAX_SIZE: u64 = 0x20_0000;
@@ -0,0 +1,70 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Writes log records to stderr, prefixed with their level and the module they come from.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Installs the stderr logger, unless a logger was installed already, and only lets records
+/// at `level` or more severe through.
+pub fn set_log_level(level: LevelFilter) {
+    // fails when an earlier call or the embedder already installed a logger, which is kept
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// A logger keeping the records of each thread, so tests running in parallel can check what
+/// they logged.
+#[cfg(test)]
+pub mod test_logger {
+    use std::cell::RefCell;
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    thread_local! {
+        static RECORDS: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct TestLogger;
+
+    impl Log for TestLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let message = record.args().to_string();
+            RECORDS.with(|records| records.borrow_mut().push((record.level(), message)));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger;
+
+    /// Installs the test logger for the whole test binary and lets every record through.
+    pub fn install() {
+        // fails when another test installed it already
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(LevelFilter::Trace);
+    }
+
+    /// Takes the records the current thread logged so far.
+    pub fn take_records() -> Vec<(Level, String)> {
+        RECORDS.with(|records| records.take())
+    }
+}
@@ -1,6 +1,11 @@
 use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use memfd::{FileSeal, Memfd, MemfdOptions, SealsHashSet};
+use memfd::{FileSeal, HugetlbSize, Memfd, MemfdOptions, SealsHashSet};
+use serde::Deserialize;
+
+use crate::vmm::layout::{DRAM_MEM_START, FDT_MAX_SIZE};
 pub use vm_memory::{
     bitmap::AtomicBitmap,
     mmap::{MmapRegionBuilder, MmapRegionError, NewBitmap},
@@ -8,12 +13,54 @@ pub use vm_memory::{
     GuestMemoryRegion,
 };
 
+#[derive(Debug)]
 pub enum MemoryError {
     FileError(std::io::Error),
     MmapRegionError(MmapRegionError),
     VmMemoryError(VmMemoryError),
+    /// The host has no NUMA node with this id.
+    InvalidNumaNode(u32),
+    /// Binding guest memory to a NUMA node failed.
+    Mbind(std::io::Error),
+}
+
+/// Pages backing guest memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HugePages {
+    /// The host's base pages.
+    #[default]
+    None,
+    /// 2 MiB pages from the host's hugetlbfs pool, which must be large enough for the whole
+    /// guest memory.
+    #[serde(rename = "2M")]
+    Hugetlbfs2M,
+    /// 1 GiB pages from the host's hugetlbfs pool.
+    #[serde(rename = "1G")]
+    Hugetlbfs1G,
+}
+
+impl HugePages {
+    /// Size of the huge pages in bytes, guest memory must be a multiple of it.
+    pub fn page_size(self) -> Option<usize> {
+        match self {
+            HugePages::None => None,
+            HugePages::Hugetlbfs2M => Some(2 << 20),
+            HugePages::Hugetlbfs1G => Some(1 << 30),
+        }
+    }
+
+    fn hugetlb_size(self) -> Option<HugetlbSize> {
+        match self {
+            HugePages::None => None,
+            HugePages::Hugetlbfs2M => Some(HugetlbSize::Huge2MB),
+            HugePages::Hugetlbfs1G => Some(HugetlbSize::Huge1GB),
+        }
+    }
 }
 
+// From linux/mempolicy.h, libc doesn't export it.
+const MPOL_MF_MOVE: libc::c_int = 1 << 1;
+
 pub type GuestMemoryMmap = vm_memory::GuestMemoryMmap<Option<AtomicBitmap>>;
 pub type GuestRegionMmap = vm_memory::GuestRegionMmap<Option<AtomicBitmap>>;
 pub type GuestMmapRegion = vm_memory::MmapRegion<Option<AtomicBitmap>>;
@@ -22,17 +69,35 @@ pub trait GuestMemoryExtension
 where
     Self: Sized,
 {
-    fn with_file(file: &File, track_dirty_pages: bool) -> Result<Self, MemoryError>;
+    /// With `prefault` every page is faulted in when mapping the file, instead of on the
+    /// guest's first access.
+    fn with_file(
+        file: &File,
+        track_dirty_pages: bool,
+        shared: bool,
+        prefault: bool,
+        numa_node: Option<u32>,
+    ) -> Result<Self, MemoryError>;
 
+    /// With `numa_node` every region is bound to that host NUMA node, pages already faulted
+    /// in are moved there.
     fn from_raw_regions_file(
         regions: Vec<(FileOffset, GuestAddress, usize)>,
         track_dirty_pages: bool,
         shared: bool,
+        prefault: bool,
+        numa_node: Option<u32>,
     ) -> Result<Self, MemoryError>;
 }
 
 impl GuestMemoryExtension for GuestMemoryMmap {
-    fn with_file(file: &File, track_dirty_pages: bool) -> Result<Self, MemoryError> {
+    fn with_file(
+        file: &File,
+        track_dirty_pages: bool,
+        shared: bool,
+        prefault: bool,
+        numa_node: Option<u32>,
+    ) -> Result<Self, MemoryError> {
         let metadata = file.metadata().map_err(MemoryError::FileError)?;
         let mem_size = metadata.len() as usize;
 
@@ -47,20 +112,31 @@ impl GuestMemoryExtension for GuestMemoryMmap {
             })
             .collect::<Result<Vec<_>, MemoryError>>()?;
 
-        Self::from_raw_regions_file(regions, track_dirty_pages, true)
+        Self::from_raw_regions_file(regions, track_dirty_pages, shared, prefault, numa_node)
     }
 
     fn from_raw_regions_file(
         regions: Vec<(FileOffset, GuestAddress, usize)>,
         track_dirty_pages: bool,
         shared: bool,
+        prefault: bool,
+        numa_node: Option<u32>,
     ) -> Result<Self, MemoryError> {
+        if let Some(node) = numa_node {
+            if !numa_node_exists(node) {
+                return Err(MemoryError::InvalidNumaNode(node));
+            }
+        }
+
         let prot = libc::PROT_READ | libc::PROT_WRITE;
-        let flags = if shared {
+        let mut flags = if shared {
             libc::MAP_NORESERVE | libc::MAP_SHARED
         } else {
             libc::MAP_NORESERVE | libc::MAP_PRIVATE
         };
+        if prefault {
+            flags |= libc::MAP_POPULATE;
+        }
         let regions = regions
             .into_iter()
             .map(|(file_offset, guest_address, region_size)| {
@@ -74,6 +150,9 @@ impl GuestMemoryExtension for GuestMemoryMmap {
                     .with_file_offset(file_offset)
                     .build()
                     .map_err(MemoryError::MmapRegionError)?;
+                if let Some(node) = numa_node {
+                    mbind(region.as_ptr(), region_size, node)?;
+                }
                 GuestRegionMmap::new(region, guest_address).map_err(MemoryError::VmMemoryError)
             })
             .collect::<Result<Vec<_>, MemoryError>>()?;
@@ -82,10 +161,53 @@ impl GuestMemoryExtension for GuestMemoryMmap {
     }
 }
 
-pub fn create_memfd(size: usize) -> Memfd {
-    let mem_size = size << 20;
-    let opts = MemfdOptions::default().allow_sealing(true);
-    let mem_file = match opts.create("guest_mem") {
+fn numa_node_exists(node: u32) -> bool {
+    Path::new(&format!("/sys/devices/system/node/node{}", node)).exists()
+}
+
+// Binds `len` bytes at `addr` to `node`, moving the pages already faulted in.
+fn mbind(addr: *mut u8, len: usize, node: u32) -> Result<(), MemoryError> {
+    let bits = u64::BITS as usize;
+    let mut nodemask = vec![0u64; node as usize / bits + 1];
+    nodemask[node as usize / bits] |= 1 << (node as usize % bits);
+    // the kernel reads one bit less than maxnode
+    let maxnode = nodemask.len() * bits + 1;
+
+    // SAFETY: the range is a mapping owned by the caller, and the kernel only reads
+    // `maxnode - 1` bits of the mask.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            len,
+            libc::MPOL_BIND,
+            nodemask.as_ptr(),
+            maxnode,
+            MPOL_MF_MOVE,
+        )
+    };
+    if ret < 0 {
+        return Err(MemoryError::Mbind(std::io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+// Numbers the memfds created by this process, so each VM's memory has its own name.
+static MEMFD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates a sealed memfd of exactly `mem_size` bytes to back guest memory. With huge pages
+/// `mem_size` must be a multiple of their size.
+pub fn create_memfd(mem_size: usize, huge_pages: HugePages) -> Memfd {
+    let name = format!(
+        "guest_mem_{}_{}",
+        std::process::id(),
+        MEMFD_COUNT.fetch_add(1, Ordering::Relaxed)
+    );
+    let opts = MemfdOptions::default()
+        .allow_sealing(true)
+        .hugetlb(huge_pages.hugetlb_size());
+    let mem_file = match opts.create(name) {
         Ok(value) => value,
         Err(error) => panic!("{}", error),
     };
@@ -111,8 +233,15 @@ pub fn create_memfd(size: usize) -> Memfd {
     mem_file
 }
 
+/// Guest memory of `mem_size` bytes at `DRAM_MEM_START`, for device tests.
+#[cfg(test)]
+pub fn test_guest_memory(mem_size: usize) -> GuestMemoryMmap {
+    let memfd = create_memfd(mem_size, HugePages::None);
+    GuestMemoryMmap::with_file(memfd.as_file(), false, true, false, None).unwrap()
+}
+
 pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize)> {
-    vec![(GuestAddress(0x8000_0000), size)]
+    vec![(GuestAddress(DRAM_MEM_START), size)]
 }
 
 // Auxiliary function to get the address where the device tree blob is loaded.
@@ -121,11 +250,100 @@ pub fn get_fdt_addr(mem: &GuestMemoryMmap) -> u64 {
     // we return the start of the DRAM so that
     // we allow the code to try and load the FDT.
 
-    if let Some(addr) = mem.last_addr().checked_sub(0x20_0000 as u64 - 1) {
+    if let Some(addr) = mem.last_addr().checked_sub(FDT_MAX_SIZE - 1) {
         if mem.address_in_range(addr) {
             return addr.raw_value();
         }
     }
 
-    0x8000_0000
+    DRAM_MEM_START
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+
+    use super::*;
+
+    // From linux/mempolicy.h
+    const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+
+    fn create_memory(numa_node: Option<u32>) -> Result<GuestMemoryMmap, MemoryError> {
+        let memfd = create_memfd(2 << 20, HugePages::None);
+        GuestMemoryMmap::with_file(memfd.as_file(), false, true, false, numa_node)
+    }
+
+    #[test]
+    fn test_invalid_numa_node() {
+        assert!(matches!(
+            create_memory(Some(4095)),
+            Err(MemoryError::InvalidNumaNode(4095))
+        ));
+    }
+
+    #[test]
+    fn test_numa_node_policy() {
+        // only multi-node hosts have a node 1
+        if !numa_node_exists(1) {
+            return;
+        }
+
+        let memory = create_memory(Some(1)).unwrap();
+        let addr = memory
+            .get_host_address(GuestAddress(DRAM_MEM_START))
+            .unwrap();
+
+        let mut mode: libc::c_int = 0;
+        let mut nodemask = [0u64; 16];
+        // SAFETY: `nodemask` holds `maxnode` bits and `addr` is mapped.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut mode,
+                nodemask.as_mut_ptr(),
+                nodemask.len() * 64,
+                addr,
+                MPOL_F_ADDR,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(mode, libc::MPOL_BIND);
+        assert_eq!(nodemask[0], 1 << 1);
+    }
+
+    #[test]
+    fn test_memfd_size() {
+        let size = 3 << 20;
+        let memfd = create_memfd(size, HugePages::None);
+
+        assert_eq!(memfd.as_file().metadata().unwrap().len(), size as u64);
+        // sealed against resizing
+        assert!(memfd.as_file().set_len(size as u64 * 2).is_err());
+        assert!(memfd.as_file().set_len(0).is_err());
+
+        // every VM gets a memfd of its own name
+        let other = create_memfd(size, HugePages::None);
+        let name = |memfd: &Memfd| {
+            std::fs::read_link(format!("/proc/self/fd/{}", memfd.as_raw_fd())).unwrap()
+        };
+        assert_ne!(name(&memfd), name(&other));
+    }
+
+    #[test]
+    fn test_prefault_resident() {
+        let size = 2 << 20;
+        let memfd = create_memfd(size, HugePages::None);
+        let memory = GuestMemoryMmap::with_file(memfd.as_file(), false, true, true, None).unwrap();
+        let addr = memory
+            .get_host_address(GuestAddress(DRAM_MEM_START))
+            .unwrap();
+
+        // SAFETY: sysconf only reads a system setting.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut resident = vec![0u8; size / page_size];
+        // SAFETY: `addr` is mapped for `size` bytes and `resident` has a byte for each page.
+        let ret = unsafe { libc::mincore(addr.cast(), size, resident.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        assert!(resident.iter().all(|page| page & 1 != 0));
+    }
 }
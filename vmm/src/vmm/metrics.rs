@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+/// Counter that can be incremented from the device thread and read from anywhere else
+/// without locking the device.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters shared between a virtio device and its interrupt trigger.
+#[derive(Debug, Default)]
+pub struct DeviceMetrics {
+    /// Bytes the device read from its backend into guest memory.
+    pub read_bytes: Counter,
+    /// Bytes the device wrote from guest memory to its backend.
+    pub write_bytes: Counter,
+    /// Requests added to the used ring.
+    pub requests_completed: Counter,
+    /// Times the device had data for the guest but no available descriptor to put it in.
+    pub queue_full: Counter,
+    /// Interrupts sent to the guest.
+    pub irq_count: Counter,
+}
+
+impl DeviceMetrics {
+    pub fn snapshot(&self) -> DeviceMetricsSnapshot {
+        DeviceMetricsSnapshot {
+            read_bytes: self.read_bytes.count(),
+            write_bytes: self.write_bytes.count(),
+            requests_completed: self.requests_completed.count(),
+            queue_full: self.queue_full.count(),
+            irq_count: self.irq_count.count(),
+        }
+    }
+
+    /// Sums the counters of several devices of the same kind.
+    pub fn total(metrics: &[Arc<DeviceMetrics>]) -> DeviceMetricsSnapshot {
+        let mut total = DeviceMetricsSnapshot::default();
+        for snapshot in metrics.iter().map(|metrics| metrics.snapshot()) {
+            total.read_bytes += snapshot.read_bytes;
+            total.write_bytes += snapshot.write_bytes;
+            total.requests_completed += snapshot.requests_completed;
+            total.queue_full += snapshot.queue_full;
+            total.irq_count += snapshot.irq_count;
+        }
+
+        total
+    }
+}
+
+/// Values of a device's counters at the time `DeviceMetrics::snapshot` was called.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DeviceMetricsSnapshot {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub requests_completed: u64,
+    pub queue_full: u64,
+    pub irq_count: u64,
+}
+
+/// Counters of all the devices of a VM, as returned by `Vm::metrics`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct VmMetrics {
+    pub block: DeviceMetricsSnapshot,
+    pub net: DeviceMetricsSnapshot,
+    /// Serial output dropped because stdout or the pty didn't keep up with the guest.
+    pub serial_lost_bytes: u64,
+}
@@ -1,20 +1,37 @@
 use kvm_ioctls::{IoEventAddress, VmFd};
 use linux_loader::loader::Cmdline;
+use log::warn;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
 };
-use vm_allocator::{AddressAllocator, AllocPolicy, IdAllocator};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vm_allocator::{AddressAllocator, AllocPolicy, IdAllocator, RangeInclusive};
 use vm_superio::rtc_pl031::{NoEvents, Rtc};
+use vmm_sys_util::eventfd::EventFd;
 
+use crate::vmm::cmdline::{insert_quoted, CmdlineError};
 use crate::vmm::device::{
-    bus::{Bus, BusDevice},
+    bus::{Bus, BusDevice, BusError},
     DeviceType,
 };
 
-use super::mmio_transport::MmioTransport;
+use crate::vmm::layout::{MAPPED_IO_SIZE, MAPPED_IO_START};
+use crate::vmm::pci::{PciRoot, PCI_ECAM_BASE, PCI_ECAM_SIZE, PCI_MMIO_BASE, PCI_MMIO_SIZE};
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+use super::mmio_transport::{MmioTransport, QUEUE_NOTIFY};
+
+/// First and last interrupt handed out to MMIO devices. These are the GSIs their irqfds are
+/// registered with, which KVM maps to the GIC SPIs of the same number.
+pub const IRQ_BASE: u32 = 32;
+pub const IRQ_MAX: u32 = 128;
+
+/// Size of the MMIO window a device gets unless it asks for another one, enough for a
+/// virtio-mmio transport and its config space. Windows are aligned to it.
+pub const MMIO_LEN: u64 = 0x1000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
 pub struct MMIODeviceInfo {
     /// Mmio address at which the device is registered.
     pub addr: u64,
@@ -24,6 +41,104 @@ pub struct MMIODeviceInfo {
     pub irqs: Vec<u32>,
 }
 
+/// A registered device, as listed by `MMIODeviceManager::devices`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceSummary {
+    pub device_type: DeviceType,
+    pub id: String,
+    pub addr: u64,
+    pub len: u64,
+    pub irqs: Vec<u32>,
+    /// Whether the driver activated the device. Devices other than virtio ones are always
+    /// active.
+    pub activated: bool,
+}
+
+/// The KVM side of registering a device: turning guest writes to an MMIO address into eventfd
+/// signals, and eventfd signals into guest interrupts. Implemented by `VmFd`, other
+/// implementations can stand in for it where there's no VM.
+pub trait IrqRegistrar {
+    /// Signals `fd` when the guest writes `datamatch` to `addr`, other values written there
+    /// don't signal it.
+    fn register_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: u64,
+        datamatch: u32,
+    ) -> Result<(), kvm_ioctls::Error>;
+
+    fn unregister_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: u64,
+        datamatch: u32,
+    ) -> Result<(), kvm_ioctls::Error>;
+
+    /// Raises interrupt `gsi` in the guest whenever `fd` is signalled.
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<(), kvm_ioctls::Error>;
+
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<(), kvm_ioctls::Error>;
+}
+
+impl IrqRegistrar for VmFd {
+    fn register_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: u64,
+        datamatch: u32,
+    ) -> Result<(), kvm_ioctls::Error> {
+        VmFd::register_ioevent(self, fd, &IoEventAddress::Mmio(addr), datamatch)
+    }
+
+    fn unregister_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: u64,
+        datamatch: u32,
+    ) -> Result<(), kvm_ioctls::Error> {
+        VmFd::unregister_ioevent(self, fd, &IoEventAddress::Mmio(addr), datamatch)
+    }
+
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<(), kvm_ioctls::Error> {
+        VmFd::register_irqfd(self, fd, gsi)
+    }
+
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<(), kvm_ioctls::Error> {
+        VmFd::unregister_irqfd(self, fd, gsi)
+    }
+}
+
+/// Accepts every registration without doing anything, for laying out devices without a VM.
+pub struct NoopRegistrar;
+
+impl IrqRegistrar for NoopRegistrar {
+    fn register_ioevent(
+        &self,
+        _fd: &EventFd,
+        _addr: u64,
+        _datamatch: u32,
+    ) -> Result<(), kvm_ioctls::Error> {
+        Ok(())
+    }
+
+    fn unregister_ioevent(
+        &self,
+        _fd: &EventFd,
+        _addr: u64,
+        _datamatch: u32,
+    ) -> Result<(), kvm_ioctls::Error> {
+        Ok(())
+    }
+
+    fn register_irqfd(&self, _fd: &EventFd, _gsi: u32) -> Result<(), kvm_ioctls::Error> {
+        Ok(())
+    }
+
+    fn unregister_irqfd(&self, _fd: &EventFd, _gsi: u32) -> Result<(), kvm_ioctls::Error> {
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct MMIODeviceManager {
     pub(crate) bus: Bus,
@@ -34,13 +149,8 @@ pub struct MMIODeviceManager {
 
 impl MMIODeviceManager {
     pub fn new() -> MMIODeviceManager {
-        let mmio_base = 1 << 30;
-        let mmio_size = 0x8000_0000 - 1 << 30;
-        let irq_start = 32;
-        let irq_end = 128;
-
-        let irq_allocator = IdAllocator::new(irq_start, irq_end).unwrap();
-        let address_allocator = AddressAllocator::new(mmio_base, mmio_size).unwrap();
+        let irq_allocator = IdAllocator::new(IRQ_BASE, IRQ_MAX).unwrap();
+        let address_allocator = AddressAllocator::new(MAPPED_IO_START, MAPPED_IO_SIZE).unwrap();
         let bus = Bus::new();
         let id_to_dev_info = HashMap::new();
 
@@ -57,20 +167,122 @@ impl MMIODeviceManager {
         identifier: (DeviceType, String),
         device_info: MMIODeviceInfo,
         device: Arc<Mutex<BusDevice>>,
-    ) {
-        self.bus.insert(device, device_info.addr, device_info.len);
+    ) -> Result<(), BusError> {
+        self.bus.insert(device, device_info.addr, device_info.len)?;
         self.id_to_dev_info.insert(identifier, device_info);
+
+        Ok(())
+    }
+
+    /// Removes a device from the bus and gives its irq and MMIO range back to the allocators,
+    /// returning the removed device. The ioeventfds and irqfd of a virtio device stay
+    /// registered with KVM until the device's eventfds are closed.
+    pub fn unregister(&mut self, id: &(DeviceType, String)) -> Option<Arc<Mutex<BusDevice>>> {
+        let device_info = self.id_to_dev_info.remove(id)?;
+        let device = self.bus.remove(device_info.addr);
+
+        // Devices restored from a snapshot are registered at their saved resources without
+        // going through the allocators, so there may be nothing to free.
+        for irq in &device_info.irqs {
+            let _ = self.irq_allocator.free_id(*irq);
+        }
+
+        let range =
+            RangeInclusive::new(device_info.addr, device_info.addr + device_info.len - 1).unwrap();
+        let _ = self.address_allocator.free(&range);
+
+        device
+    }
+
+    /// Lists the registered devices, ordered by address.
+    pub fn devices(&self) -> Vec<DeviceSummary> {
+        let mut devices: Vec<DeviceSummary> = self
+            .id_to_dev_info
+            .iter()
+            .map(|((device_type, id), device_info)| {
+                let activated = match self.bus.get_device(device_info.addr) {
+                    Some((_, device)) => {
+                        let locked_device = device.lock().expect("Poisoned lock");
+                        locked_device
+                            .mmio_transport_ref()
+                            .is_none_or(|transport| transport.locked_device().is_activated())
+                    }
+                    None => false,
+                };
+
+                DeviceSummary {
+                    device_type: *device_type,
+                    id: id.clone(),
+                    addr: device_info.addr,
+                    len: device_info.len,
+                    irqs: device_info.irqs.clone(),
+                    activated,
+                }
+            })
+            .collect();
+        devices.sort_by_key(|device| device.addr);
+
+        devices
+    }
+
+    /// Resets every virtio device to inactive with fresh queues, as if its driver had reset
+    /// it. Devices that don't support a reset are left as they are.
+    pub fn reset_virtio_devices(&self) {
+        for device_info in self.id_to_dev_info.values() {
+            if let Some((_, device)) = self.bus.get_device(device_info.addr) {
+                let mut locked_device = device.lock().expect("Poisoned lock");
+                if let Some(transport) = locked_device.mmio_transport_mut() {
+                    transport.reset();
+                }
+            }
+        }
+    }
+
+    /// Unregisters the ioeventfds and irqfds `register_mmio_virtio` and `register_mmio_serial`
+    /// gave to KVM, so the devices' eventfds can be closed without KVM holding on to them.
+    /// Failures are logged, there's nothing left to do about them once the VM is going away.
+    pub fn unregister_eventfds(&self, vm: &dyn IrqRegistrar) {
+        for device_info in self.id_to_dev_info.values() {
+            let device = match self.bus.get_device(device_info.addr) {
+                Some((_, device)) => device,
+                None => continue,
+            };
+            let locked_device = device.lock().expect("Poisoned lock");
+
+            if let Some(transport) = locked_device.mmio_transport_ref() {
+                let virtio_device = transport.locked_device();
+                for (i, queue_evt) in virtio_device.queue_events().iter().enumerate() {
+                    let io_addr = device_info.addr + QUEUE_NOTIFY;
+                    if let Err(err) =
+                        vm.unregister_ioevent(queue_evt, io_addr, u32::try_from(i).unwrap())
+                    {
+                        warn!("Failed to unregister ioeventfd: {:?}", err);
+                    }
+                }
+                if let Err(err) =
+                    vm.unregister_irqfd(virtio_device.interrupt_evt(), device_info.irqs[0])
+                {
+                    warn!("Failed to unregister irqfd: {:?}", err);
+                }
+            } else if let Some(serial) = locked_device.serial_ref() {
+                if let Err(err) =
+                    vm.unregister_irqfd(serial.serial.interrupt_evt(), device_info.irqs[0])
+                {
+                    warn!("Failed to unregister irqfd: {:?}", err);
+                }
+            }
+        }
     }
 
     pub fn register_mmio_virtio(
         &mut self,
-        vm: &VmFd,
+        vm: &dyn IrqRegistrar,
         device_id: String,
         mmio_device: MmioTransport,
         device_info: &MMIODeviceInfo,
-    ) {
+    ) -> Result<(), BusError> {
         if device_info.irqs.len() != 1 {
-            panic!("invalid irq config");
+            return Err(BusError::InvalidIrqCount(device_info.irqs.len()));
         }
 
         let identifier;
@@ -78,15 +290,29 @@ impl MMIODeviceManager {
             let locked_device = mmio_device.locked_device();
             identifier = (DeviceType::Virtio(locked_device.device_type()), device_id);
 
-            for (i, queue_evt) in locked_device.queue_events().iter().enumerate() {
-                let io_addr = IoEventAddress::Mmio(device_info.addr + 0x50);
-
-                vm.register_ioevent(queue_evt, &io_addr, u32::try_from(i).unwrap())
-                    .unwrap();
+            // every queue shares QueueNotify, the datamatch on the written queue index is what
+            // routes a notification to that queue's eventfd
+            let io_addr = device_info.addr + QUEUE_NOTIFY;
+            let queue_events = locked_device.queue_events();
+            let unregister_ioevents = |count: usize| {
+                for (i, queue_evt) in queue_events.iter().enumerate().take(count) {
+                    if let Err(err) = vm.unregister_ioevent(queue_evt, io_addr, i as u32) {
+                        warn!("Failed to unregister ioevent: {:?}", err);
+                    }
+                }
+            };
+            for (i, queue_evt) in queue_events.iter().enumerate() {
+                if let Err(err) = vm.register_ioevent(queue_evt, io_addr, i as u32) {
+                    unregister_ioevents(i);
+                    return Err(BusError::Ioevent(err));
+                }
             }
 
-            vm.register_irqfd(locked_device.interrupt_evt(), device_info.irqs[0])
-                .unwrap();
+            if let Err(err) = vm.register_irqfd(locked_device.interrupt_evt(), device_info.irqs[0])
+            {
+                unregister_ioevents(queue_events.len());
+                return Err(BusError::Irqfd(err));
+            }
         }
 
         self.register_mmio_device(
@@ -98,27 +324,27 @@ impl MMIODeviceManager {
 
     pub fn register_mmio_virtio_for_boot(
         &mut self,
-        vm: &VmFd,
+        vm: &dyn IrqRegistrar,
         device_id: String,
         mmio_device: MmioTransport,
         _cmdline: &mut Cmdline,
-    ) -> MMIODeviceInfo {
-        let device_info = self.allocate_mmio_resources(1);
-        self.register_mmio_virtio(vm, device_id, mmio_device, &device_info);
+    ) -> Result<MMIODeviceInfo, BusError> {
+        let device_info = self.allocate_mmio_resources(1, MMIO_LEN);
+        self.register_mmio_virtio(vm, device_id, mmio_device, &device_info)?;
 
-        device_info
+        Ok(device_info)
     }
 
     pub fn register_mmio_serial(
         &mut self,
-        vm: &VmFd,
+        vm: &dyn IrqRegistrar,
         serial: Arc<Mutex<BusDevice>>,
         device_info_opt: Option<MMIODeviceInfo>,
-    ) {
+    ) -> Result<(), BusError> {
         let device_info = if let Some(device_info) = device_info_opt {
             device_info
         } else {
-            self.allocate_mmio_resources(1)
+            self.allocate_mmio_resources(1, MMIO_LEN)
         };
 
         vm.register_irqfd(
@@ -137,26 +363,24 @@ impl MMIODeviceManager {
         self.register_mmio_device(identifier, device_info, serial)
     }
 
-    pub fn add_mmio_serial_to_cmdline(
-        &self,
-        cmdline: &mut Cmdline,
-    ) -> Result<(), linux_loader::cmdline::Error> {
+    pub fn add_mmio_serial_to_cmdline(&self, cmdline: &mut Cmdline) -> Result<(), CmdlineError> {
         let device_info = self
             .id_to_dev_info
             .get(&(DeviceType::Serial, DeviceType::Serial.to_string()))
             .unwrap();
-        cmdline.insert("earlycon", &format!("uart,mmio,0x{:08x}", device_info.addr))
+        let earlycon = format!("uart,mmio,0x{:08x}", device_info.addr);
+        insert_quoted(cmdline, "earlycon", &earlycon)
     }
 
     pub fn register_mmio_rtc(
         &mut self,
         rtc: Rtc<NoEvents>,
         device_info_opt: Option<MMIODeviceInfo>,
-    ) {
+    ) -> Result<(), BusError> {
         let device_info = if let Some(device_info) = device_info_opt {
             device_info
         } else {
-            self.allocate_mmio_resources(1)
+            self.allocate_mmio_resources(1, MMIO_LEN)
         };
 
         let identifier = (DeviceType::Rtc, DeviceType::Rtc.to_string());
@@ -168,24 +392,264 @@ impl MMIODeviceManager {
         )
     }
 
-    fn allocate_mmio_resources(&mut self, irq_count: u32) -> MMIODeviceInfo {
+    pub fn register_mmio_watchdog(
+        &mut self,
+        watchdog: Arc<Mutex<BusDevice>>,
+        device_info_opt: Option<MMIODeviceInfo>,
+    ) -> Result<(), BusError> {
+        let device_info = if let Some(device_info) = device_info_opt {
+            device_info
+        } else {
+            self.allocate_mmio_resources(0, MMIO_LEN)
+        };
+        let identifier = (DeviceType::Watchdog, DeviceType::Watchdog.to_string());
+
+        self.register_mmio_device(identifier, device_info, watchdog)
+    }
+
+    /// Puts the ECAM of a PCIe host bridge at `PCI_ECAM_BASE` and reserves the window for
+    /// its BARs, so virtio-mmio devices are never allocated on top of either.
+    pub fn register_pci_root(&mut self, pci_root: PciRoot) -> Result<(), BusError> {
+        let ecam = self
+            .address_allocator
+            .allocate(
+                PCI_ECAM_SIZE,
+                PCI_ECAM_SIZE,
+                AllocPolicy::ExactMatch(PCI_ECAM_BASE),
+            )
+            .unwrap();
+        self.address_allocator
+            .allocate(
+                PCI_MMIO_SIZE,
+                0x1000,
+                AllocPolicy::ExactMatch(PCI_MMIO_BASE),
+            )
+            .unwrap();
+
+        let device_info = MMIODeviceInfo {
+            addr: ecam.start(),
+            len: ecam.len(),
+            irqs: Vec::new(),
+        };
+        let identifier = (DeviceType::Pci, DeviceType::Pci.to_string());
+
+        self.register_mmio_device(
+            identifier,
+            device_info,
+            Arc::new(Mutex::new(BusDevice::PciRoot(pci_root))),
+        )
+    }
+
+    /// Hands out `irq_count` interrupts and an MMIO window of `len` bytes, which the FDT node of
+    /// the device then describes. Devices registered with their own `MMIODeviceInfo`, like
+    /// through `register_mmio_virtio`, can get a larger window than `MMIO_LEN` this way.
+    pub fn allocate_mmio_resources(&mut self, irq_count: u32, len: u64) -> MMIODeviceInfo {
         let irqs = (0..irq_count)
             .map(|_| self.irq_allocator.allocate_id())
             .collect::<vm_allocator::Result<_>>()
             .unwrap();
 
-        let mmio_len = 0x1000;
-
         let device_info = MMIODeviceInfo {
             addr: self
                 .address_allocator
-                .allocate(mmio_len, mmio_len, AllocPolicy::FirstMatch)
+                .allocate(len, MMIO_LEN, AllocPolicy::FirstMatch)
                 .unwrap()
                 .start(),
-            len: mmio_len,
+            len,
             irqs,
         };
 
         device_info
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::vmm::device::block::backend::MemDisk;
+    use crate::vmm::device::block::{Block, QUEUE_SIZE};
+    use crate::vmm::device::net::Net;
+    use crate::vmm::fdt::AARCH64_GIC_DIST_BASE;
+    use crate::vmm::memory::test_guest_memory;
+    use crate::vmm::rate_limiter::RateLimiterConfig;
+
+    use super::*;
+
+    // Records the ioevent addresses and datamatches and the irqs it's asked to register, and
+    // optionally fails the irqfd registration.
+    #[derive(Default)]
+    struct MockRegistrar {
+        ioevents: RefCell<Vec<(u64, u32)>>,
+        irqfds: RefCell<Vec<u32>>,
+        fail_irqfd: bool,
+    }
+
+    impl IrqRegistrar for MockRegistrar {
+        fn register_ioevent(
+            &self,
+            _fd: &EventFd,
+            addr: u64,
+            datamatch: u32,
+        ) -> Result<(), kvm_ioctls::Error> {
+            self.ioevents.borrow_mut().push((addr, datamatch));
+            Ok(())
+        }
+
+        fn unregister_ioevent(
+            &self,
+            _fd: &EventFd,
+            addr: u64,
+            datamatch: u32,
+        ) -> Result<(), kvm_ioctls::Error> {
+            self.ioevents
+                .borrow_mut()
+                .retain(|&ioevent| ioevent != (addr, datamatch));
+            Ok(())
+        }
+
+        fn register_irqfd(&self, _fd: &EventFd, gsi: u32) -> Result<(), kvm_ioctls::Error> {
+            if self.fail_irqfd {
+                return Err(kvm_ioctls::Error::new(libc::EINVAL));
+            }
+            self.irqfds.borrow_mut().push(gsi);
+            Ok(())
+        }
+
+        fn unregister_irqfd(&self, _fd: &EventFd, gsi: u32) -> Result<(), kvm_ioctls::Error> {
+            self.irqfds.borrow_mut().retain(|&irq| irq != gsi);
+            Ok(())
+        }
+    }
+
+    fn block_transport() -> MmioTransport {
+        let block = Block::new(
+            "block",
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        );
+
+        MmioTransport::new(
+            test_guest_memory(0x10000),
+            Arc::new(Mutex::new(block)),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_unregister_frees_resources() {
+        let mut manager = MMIODeviceManager::new();
+        manager.register_mmio_rtc(Rtc::new(), None).unwrap();
+        let id = (DeviceType::Rtc, DeviceType::Rtc.to_string());
+        let device_info = manager.id_to_dev_info[&id].clone();
+
+        assert!(manager.unregister(&id).is_some());
+        assert!(manager.bus.get_device(device_info.addr).is_none());
+        assert!(manager.devices().is_empty());
+        assert!(manager.unregister(&id).is_none());
+
+        // the freed window and irq are handed out again
+        assert_eq!(manager.allocate_mmio_resources(1, MMIO_LEN), device_info);
+    }
+
+    #[test]
+    fn test_pci_root_reserves_ecam() {
+        let mut manager = MMIODeviceManager::new();
+        manager.register_pci_root(PciRoot::new()).unwrap();
+
+        assert!(manager.bus.get_device(PCI_ECAM_BASE).is_some());
+        for addr in [PCI_ECAM_BASE, PCI_MMIO_BASE] {
+            assert!(manager
+                .address_allocator
+                .allocate(MMIO_LEN, MMIO_LEN, AllocPolicy::ExactMatch(addr))
+                .is_err());
+        }
+        let device_info = manager.allocate_mmio_resources(1, MMIO_LEN);
+        let pci_window = PCI_ECAM_BASE..PCI_MMIO_BASE + PCI_MMIO_SIZE;
+        assert!(!pci_window.contains(&device_info.addr));
+    }
+
+    #[test]
+    fn test_window_starts_at_mapped_io() {
+        let mut manager = MMIODeviceManager::new();
+
+        let device_info = manager.allocate_mmio_resources(1, MMIO_LEN);
+
+        assert_eq!(device_info.addr, MAPPED_IO_START);
+        assert_eq!(device_info.irqs, [IRQ_BASE]);
+        // the FDT puts the GIC right below the devices
+        assert!(AARCH64_GIC_DIST_BASE < device_info.addr);
+    }
+
+    #[test]
+    fn test_register_virtio_eventfds() {
+        let mut manager = MMIODeviceManager::new();
+        let registrar = MockRegistrar::default();
+        let device_info = manager.allocate_mmio_resources(1, MMIO_LEN);
+
+        manager
+            .register_mmio_virtio(
+                &registrar,
+                "block".to_string(),
+                block_transport(),
+                &device_info,
+            )
+            .unwrap();
+
+        assert_eq!(
+            *registrar.ioevents.borrow(),
+            [(device_info.addr + QUEUE_NOTIFY, 0)]
+        );
+        assert_eq!(*registrar.irqfds.borrow(), [IRQ_BASE]);
+
+        manager.unregister_eventfds(&registrar);
+        assert!(registrar.ioevents.borrow().is_empty());
+        assert!(registrar.irqfds.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_register_virtio_rolls_back_ioevents() {
+        let mut manager = MMIODeviceManager::new();
+        let registrar = MockRegistrar {
+            fail_irqfd: true,
+            ..Default::default()
+        };
+        let device_info = manager.allocate_mmio_resources(1, MMIO_LEN);
+
+        let result = manager.register_mmio_virtio(
+            &registrar,
+            "block".to_string(),
+            block_transport(),
+            &device_info,
+        );
+
+        assert!(matches!(result, Err(BusError::Irqfd(_))));
+        assert!(registrar.ioevents.borrow().is_empty());
+        assert!(manager.bus.get_device(device_info.addr).is_none());
+    }
+
+    #[test]
+    fn test_queue_datamatch() {
+        let mut manager = MMIODeviceManager::new();
+        let registrar = MockRegistrar::default();
+        let device_info = manager.allocate_mmio_resources(1, MMIO_LEN);
+        let net = Net::new(
+            None,
+            None,
+            RateLimiterConfig::default(),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        );
+        let transport =
+            MmioTransport::new(test_guest_memory(0x10000), Arc::new(Mutex::new(net)), false);
+
+        manager
+            .register_mmio_virtio(&registrar, "net".to_string(), transport, &device_info)
+            .unwrap();
+
+        // rx and tx share QueueNotify, told apart by the queue index written to it
+        let io_addr = device_info.addr + QUEUE_NOTIFY;
+        assert_eq!(*registrar.ioevents.borrow(), [(io_addr, 0), (io_addr, 1)]);
+    }
+}
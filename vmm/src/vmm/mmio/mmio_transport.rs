@@ -1,6 +1,76 @@
-use std::sync::{atomic::AtomicU32, Arc, Mutex, MutexGuard};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex, MutexGuard,
+};
+use std::time::{Duration, Instant};
 
-use crate::vmm::{device::VirtioDevice, memory::GuestMemoryMmap};
+use log::{error, warn};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::vmm::{
+    device::{
+        device_status, eventfd_write_retry,
+        queue::{Queue, QueueState},
+        ActivateError, VirtioDevice,
+    },
+    memory::{GuestAddress, GuestMemoryMmap},
+};
+
+// Register offsets as laid out in the virtio-mmio spec (version 2).
+const MAGIC_VALUE: u64 = 0x00;
+const VERSION: u64 = 0x04;
+const DEVICE_ID: u64 = 0x08;
+const VENDOR_ID: u64 = 0x0c;
+const DEVICE_FEATURES: u64 = 0x10;
+const DEVICE_FEATURES_SEL: u64 = 0x14;
+const DRIVER_FEATURES: u64 = 0x20;
+const DRIVER_FEATURES_SEL: u64 = 0x24;
+// Only used by legacy (version 1) drivers.
+const GUEST_PAGE_SIZE: u64 = 0x28;
+const QUEUE_SEL: u64 = 0x30;
+const QUEUE_NUM_MAX: u64 = 0x34;
+const QUEUE_NUM: u64 = 0x38;
+// Only used by legacy (version 1) drivers.
+const QUEUE_ALIGN: u64 = 0x3c;
+const QUEUE_PFN: u64 = 0x40;
+const QUEUE_READY: u64 = 0x44;
+/// The driver writes the index of a queue here to notify it. One address serves every queue.
+pub const QUEUE_NOTIFY: u64 = 0x50;
+const INTERRUPT_STATUS: u64 = 0x60;
+const INTERRUPT_ACK: u64 = 0x64;
+const STATUS: u64 = 0x70;
+const QUEUE_DESC_LOW: u64 = 0x80;
+const QUEUE_DESC_HIGH: u64 = 0x84;
+const QUEUE_AVAIL_LOW: u64 = 0x90;
+const QUEUE_AVAIL_HIGH: u64 = 0x94;
+const QUEUE_USED_LOW: u64 = 0xa0;
+const QUEUE_USED_HIGH: u64 = 0xa4;
+const CONFIG_GENERATION: u64 = 0xfc;
+/// Start of the device specific configuration space.
+const CONFIG_SPACE: u64 = 0x100;
+
+// "virt" in little endian
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+const MMIO_VERSION: u32 = 2;
+const MMIO_LEGACY_VERSION: u32 = 1;
+
+/// Snapshot of the transport registers together with the queues of the device behind it.
+#[derive(Debug, Default, Versionize)]
+pub struct MmioTransportState {
+    pub features_select: u32,
+    pub acked_features_select: u32,
+    pub queue_select: u32,
+    pub device_status: u32,
+    pub config_generation: u32,
+    pub version: u32,
+    pub guest_page_size: u32,
+    pub queue_align: u32,
+    pub queues: Vec<QueueState>,
+}
 
 #[derive(Debug)]
 pub struct MmioTransport {
@@ -9,10 +79,17 @@ pub struct MmioTransport {
     pub(crate) acked_features_select: u32,
     pub(crate) queue_select: u32,
     pub(crate) device_status: u32,
-    pub(crate) config_generation: u32,
+    pub(crate) config_generation: Arc<AtomicU32>,
+    // MMIO_VERSION unless legacy was enabled with `enable_legacy`
+    version: u32,
+    // legacy drivers give the queue addresses as page frame numbers
+    guest_page_size: u32,
+    queue_align: u32,
     mem: GuestMemoryMmap,
     pub(crate) interrupt_status: Arc<AtomicU32>,
     pub is_vhost_user: bool,
+    // the device's ready event and how long to wait for it after activating the device
+    activation_barrier: Option<(EventFd, Duration)>,
 }
 
 impl MmioTransport {
@@ -23,6 +100,7 @@ impl MmioTransport {
         is_vhost_user: bool,
     ) -> MmioTransport {
         let interrupt_status = device.lock().expect("Poisoned lock").interrupt_status();
+        let config_generation = device.lock().expect("Poisoned lock").config_generation();
 
         MmioTransport {
             device,
@@ -30,15 +108,689 @@ impl MmioTransport {
             acked_features_select: 0,
             queue_select: 0,
             device_status: 0,
-            config_generation: 0,
+            config_generation,
+            version: MMIO_VERSION,
+            guest_page_size: 0,
+            queue_align: 0,
             mem,
             interrupt_status,
             is_vhost_user,
+            activation_barrier: None,
         }
     }
 
+    /// Makes the driver's `DRIVER_OK` write wait, for at most `timeout`, until the device
+    /// thread finished activating the device. The guest only goes on using the device once
+    /// the host set it up, e.g. before mounting its root filesystem from it. Devices without a
+    /// ready event aren't waited for.
+    pub fn set_activation_barrier(&mut self, timeout: Duration) -> io::Result<()> {
+        let ready_event = match self.locked_device().ready_event() {
+            Some(ready_event) => ready_event.try_clone()?,
+            None => return Ok(()),
+        };
+        self.activation_barrier = Some((ready_event, timeout));
+
+        Ok(())
+    }
+
+    /// Makes the transport speak the legacy (version 1) interface, for old guest drivers that
+    /// don't support version 2. The legacy queue registers are ignored otherwise.
+    pub fn enable_legacy(&mut self) {
+        self.version = MMIO_LEGACY_VERSION;
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == MMIO_LEGACY_VERSION
+    }
+
     /// Gets the encapsulated locked VirtioDevice.
     pub fn locked_device(&self) -> MutexGuard<dyn VirtioDevice + 'static> {
         self.device.lock().expect("Poisoned lock")
     }
+
+    /// Saves the transport registers and the device's queues.
+    pub fn save(&self) -> MmioTransportState {
+        MmioTransportState {
+            features_select: self.features_select,
+            acked_features_select: self.acked_features_select,
+            queue_select: self.queue_select,
+            device_status: self.device_status,
+            config_generation: self.config_generation.load(Ordering::SeqCst),
+            version: self.version,
+            guest_page_size: self.guest_page_size,
+            queue_align: self.queue_align,
+            queues: self
+                .locked_device()
+                .queues()
+                .iter()
+                .map(Queue::save)
+                .collect(),
+        }
+    }
+
+    /// Restores the transport registers and the device's queues from a saved state.
+    pub fn restore(&mut self, state: &MmioTransportState) {
+        self.features_select = state.features_select;
+        self.acked_features_select = state.acked_features_select;
+        self.queue_select = state.queue_select;
+        self.device_status = state.device_status;
+        self.config_generation
+            .store(state.config_generation, Ordering::SeqCst);
+        self.version = state.version;
+        self.guest_page_size = state.guest_page_size;
+        self.queue_align = state.queue_align;
+
+        {
+            let mut device = self.locked_device();
+            for (queue, queue_state) in device.queues_mut().iter_mut().zip(&state.queues) {
+                *queue = Queue::restore(queue_state);
+            }
+        }
+
+        if self.device_status & device_status::DRIVER_OK != 0 {
+            if let Err(err) = self.activate() {
+                warn!("failed to activate restored virtio device: {:?}", err);
+                self.device_status |= device_status::DEVICE_NEEDS_RESET;
+            }
+        }
+    }
+
+    /// Validates the queues set up by the driver and activates the device. Queues the driver
+    /// didn't set up, like those of features it didn't accept, are left alone.
+    fn activate(&self) -> Result<(), ActivateError> {
+        let mut device = self.locked_device();
+        for (index, queue) in device.queues().iter().enumerate() {
+            if !queue.ready {
+                continue;
+            }
+            queue
+                .validate_ring_addresses(&self.mem)
+                .map_err(|err| ActivateError::InvalidQueue(index, err))?;
+        }
+
+        device.activate(self.mem.clone())
+    }
+
+    fn set_device_status(&mut self, status: u32) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+
+        let was_driver_ok = self.device_status & device_status::DRIVER_OK != 0;
+        self.device_status = status;
+
+        if status & device_status::DRIVER_OK != 0 && !was_driver_ok {
+            // a late signal of an earlier activation doesn't count
+            if let Some((ready_event, _)) = &self.activation_barrier {
+                let _ = ready_event.read();
+            }
+            match self.activate() {
+                Ok(()) => self.wait_until_ready(),
+                Err(err) => {
+                    warn!("failed to activate virtio device: {:?}", err);
+                    self.device_status |= device_status::DEVICE_NEEDS_RESET;
+                }
+            }
+        }
+    }
+
+    // Blocks the calling vcpu until the device signals its ready event or the activation
+    // barrier's timeout runs out. The device lock isn't held, the device thread needs it.
+    fn wait_until_ready(&self) {
+        let (ready_event, timeout) = match &self.activation_barrier {
+            Some(barrier) => barrier,
+            None => return,
+        };
+
+        let deadline = Instant::now() + *timeout;
+        let mut pollfd = libc::pollfd {
+            fd: ready_event.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // SAFETY: `pollfd` is a valid pollfd for the eventfd, which outlives the call.
+            let ret = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as i32) };
+            match ret {
+                // signals kicking the vcpu interrupt the wait
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                -1 => {
+                    error!(
+                        "failed to wait for the virtio device: {:?}",
+                        io::Error::last_os_error()
+                    );
+                    return;
+                }
+                0 => {
+                    warn!("virtio device not ready after {:?}", timeout);
+                    return;
+                }
+                _ => {
+                    let _ = ready_event.read();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Resets the device the way the driver does by writing 0 to the status register.
+    pub fn reset(&mut self) {
+        if !self.locked_device().reset() {
+            warn!("virtio device doesn't support reset");
+            self.device_status |= device_status::DEVICE_NEEDS_RESET;
+            return;
+        }
+
+        self.features_select = 0;
+        self.acked_features_select = 0;
+        self.queue_select = 0;
+        self.device_status = 0;
+        self.interrupt_status.store(0, Ordering::SeqCst);
+    }
+
+    /// Whether the driver may set up the queues now: after feature negotiation and before
+    /// DRIVER_OK. Legacy drivers don't negotiate features, they only have to have found the
+    /// driver. Once the device runs, moving or resizing a queue under it would let the guest
+    /// point it anywhere.
+    fn queues_configurable(&self) -> bool {
+        let required = if self.is_legacy() {
+            device_status::DRIVER
+        } else {
+            device_status::FEATURES_OK
+        };
+        let forbidden =
+            device_status::DRIVER_OK | device_status::FAILED | device_status::DEVICE_NEEDS_RESET;
+
+        self.device_status & required != 0 && self.device_status & forbidden == 0
+    }
+
+    // A selector past the device's queues reads as 0. That's how the driver finds out how
+    // many queues there are: `QueueNumMax` is 0 for a queue that doesn't exist.
+    fn with_queue<F: FnOnce(&Queue) -> u32>(&self, f: F) -> u32 {
+        self.locked_device()
+            .queues()
+            .get(self.queue_select as usize)
+            .map_or(0, f)
+    }
+
+    fn with_queue_mut<F: FnOnce(&mut Queue)>(&mut self, f: F) {
+        if let Some(queue) = self
+            .locked_device()
+            .queues_mut()
+            .get_mut(self.queue_select as usize)
+        {
+            f(queue);
+        }
+    }
+
+    /// Lays out the selected queue from the page frame number a legacy driver wrote: the
+    /// descriptor table at the start of the page, directly followed by the available ring,
+    /// and the used ring at the next `queue_align` boundary. A zero pfn stops the queue.
+    fn set_queue_pfn(&mut self, pfn: u32) {
+        let page_size = u64::from(self.guest_page_size);
+        let align = u64::from(self.queue_align).max(1);
+        self.with_queue_mut(|q| {
+            if pfn == 0 {
+                q.ready = false;
+                return;
+            }
+
+            let size = u64::from(q.size);
+            let desc_table = u64::from(pfn) * page_size;
+            let avail_ring = desc_table + 16 * size;
+            let avail_end = avail_ring + 6 + 2 * size;
+            let used_ring = avail_end.div_ceil(align) * align;
+
+            q.desc_table = GuestAddress(desc_table);
+            q.avail_ring = GuestAddress(avail_ring);
+            q.used_ring = GuestAddress(used_ring);
+            q.ready = true;
+        });
+    }
+
+    fn queue_pfn(&self) -> u32 {
+        let page_size = u64::from(self.guest_page_size).max(1);
+        self.with_queue(|q| {
+            if q.ready {
+                (q.desc_table.0 / page_size) as u32
+            } else {
+                0
+            }
+        })
+    }
+
+    /// Kicks the queue event of the queue at `index`.
+    ///
+    /// A buggy driver may notify a queue it hasn't finished setting up, or one that doesn't
+    /// exist; those notifications are dropped instead of waking the device. Kicks through the
+    /// ioeventfd skip this check, `Queue::pop` doesn't hand out chains of such queues. Repeated
+    /// notifications for a ready queue are coalesced by the eventfd counter, so the device
+    /// wakes up once no matter how many kicks arrived in between.
+    fn queue_notify(&self, index: u32) {
+        let device = self.locked_device();
+        if device.is_broken() {
+            return;
+        }
+
+        let ready = device
+            .queues()
+            .get(index as usize)
+            .is_some_and(|queue| queue.ready);
+        if !ready {
+            return;
+        }
+
+        if let Some(queue_evt) = device.queue_events().get(index as usize) {
+            if let Err(err) = eventfd_write_retry(queue_evt, 1) {
+                error!("Failed to signal queue {} event: {:?}", index, err);
+            }
+        }
+    }
+
+    /// Handles a guest read from the device's MMIO region.
+    pub fn bus_read(&self, offset: u64, data: &mut [u8]) {
+        if offset >= CONFIG_SPACE {
+            // the guest picks the offset, whatever lies past the device's config reads as zero
+            data.fill(0);
+            self.locked_device()
+                .read_config(offset - CONFIG_SPACE, data);
+            return;
+        }
+
+        let v = match offset {
+            MAGIC_VALUE => MMIO_MAGIC_VALUE,
+            VERSION => self.version,
+            DEVICE_ID => self.locked_device().device_type(),
+            VENDOR_ID => 0,
+            DEVICE_FEATURES => match self.features_select {
+                0 => self.locked_device().avail_features() as u32,
+                1 => (self.locked_device().avail_features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => self.with_queue(|q| u32::from(q.get_max_size())),
+            QUEUE_READY if !self.is_legacy() => self.with_queue(|q| u32::from(q.ready)),
+            QUEUE_PFN if self.is_legacy() => self.queue_pfn(),
+            INTERRUPT_STATUS => self.interrupt_status.load(Ordering::SeqCst),
+            STATUS if self.locked_device().is_broken() => {
+                self.device_status | device_status::DEVICE_NEEDS_RESET
+            }
+            STATUS => self.device_status,
+            CONFIG_GENERATION => self.config_generation.load(Ordering::SeqCst),
+            _ => 0,
+        };
+
+        // registers below the config space are all 32 bits wide
+        if data.len() == 4 {
+            data.copy_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    /// Handles a guest write to the device's MMIO region.
+    pub fn bus_write(&mut self, offset: u64, data: &[u8]) {
+        if offset >= CONFIG_SPACE {
+            self.locked_device()
+                .write_config(offset - CONFIG_SPACE, data);
+            self.config_generation.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+
+        let v = match data.try_into() {
+            Ok(bytes) => u32::from_le_bytes(bytes),
+            Err(_) => return,
+        };
+
+        let queue_register = matches!(
+            offset,
+            QUEUE_NUM
+                | QUEUE_READY
+                | QUEUE_ALIGN
+                | QUEUE_PFN
+                | QUEUE_DESC_LOW
+                | QUEUE_DESC_HIGH
+                | QUEUE_AVAIL_LOW
+                | QUEUE_AVAIL_HIGH
+                | QUEUE_USED_LOW
+                | QUEUE_USED_HIGH
+        );
+        if queue_register && !self.queues_configurable() {
+            warn!(
+                "ignoring queue register {:#x} write in device status {:#x}",
+                offset, self.device_status
+            );
+            return;
+        }
+
+        match offset {
+            DEVICE_FEATURES_SEL => self.features_select = v,
+            DRIVER_FEATURES => self
+                .locked_device()
+                .ack_features_by_page(self.acked_features_select, v),
+            DRIVER_FEATURES_SEL => self.acked_features_select = v,
+            QUEUE_SEL => self.queue_select = v,
+            QUEUE_NUM => self.with_queue_mut(|q| q.size = v as u16),
+            QUEUE_READY if !self.is_legacy() => self.with_queue_mut(|q| q.ready = v == 1),
+            GUEST_PAGE_SIZE if self.is_legacy() => self.guest_page_size = v,
+            QUEUE_ALIGN if self.is_legacy() => self.queue_align = v,
+            QUEUE_PFN if self.is_legacy() => self.set_queue_pfn(v),
+            QUEUE_NOTIFY => self.queue_notify(v),
+            INTERRUPT_ACK => {
+                self.interrupt_status.fetch_and(!v, Ordering::SeqCst);
+            }
+            STATUS => self.set_device_status(v),
+            QUEUE_DESC_LOW => self.with_queue_mut(|q| set_low(&mut q.desc_table, v)),
+            QUEUE_DESC_HIGH => self.with_queue_mut(|q| set_high(&mut q.desc_table, v)),
+            QUEUE_AVAIL_LOW => self.with_queue_mut(|q| set_low(&mut q.avail_ring, v)),
+            QUEUE_AVAIL_HIGH => self.with_queue_mut(|q| set_high(&mut q.avail_ring, v)),
+            QUEUE_USED_LOW => self.with_queue_mut(|q| set_low(&mut q.used_ring, v)),
+            QUEUE_USED_HIGH => self.with_queue_mut(|q| set_high(&mut q.used_ring, v)),
+            _ => {}
+        }
+    }
+}
+
+fn set_low(addr: &mut GuestAddress, v: u32) {
+    *addr = GuestAddress((addr.0 & 0xffff_ffff_0000_0000) | u64::from(v));
+}
+
+fn set_high(addr: &mut GuestAddress, v: u32) {
+    *addr = GuestAddress((addr.0 & 0x0000_0000_ffff_ffff) | (u64::from(v) << 32));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use event_manager::SubscriberOps;
+
+    use crate::vmm::device::block::backend::MemDisk;
+    use crate::vmm::device::block::{Block, QUEUE_SIZE};
+    use crate::vmm::device::{IrqType, TYPE_BLOCK};
+    use crate::vmm::event_manager::EventManager;
+    use crate::vmm::layout::DRAM_MEM_START;
+    use crate::vmm::memory::test_guest_memory;
+    use crate::vmm::rate_limiter::RateLimiterConfig;
+
+    use super::*;
+
+    fn block_transport() -> MmioTransport {
+        block_transport_with_queue_size(QUEUE_SIZE)
+    }
+
+    fn block_transport_with_queue_size(queue_size: u16) -> MmioTransport {
+        let block = Block::new(
+            "block",
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            queue_size,
+        );
+
+        MmioTransport::new(
+            test_guest_memory(0x10000),
+            Arc::new(Mutex::new(block)),
+            false,
+        )
+    }
+
+    fn write_reg(transport: &mut MmioTransport, offset: u64, value: u32) {
+        transport.bus_write(offset, &value.to_le_bytes());
+    }
+
+    fn read_reg(transport: &MmioTransport, offset: u64) -> u32 {
+        let mut data = [0; 4];
+        transport.bus_read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    // Sets the status bits one at a time, the way a driver does.
+    fn set_status(transport: &mut MmioTransport, bits: &[u32]) {
+        let mut status = read_reg(transport, STATUS);
+        for bit in bits {
+            status |= bit;
+            write_reg(transport, STATUS, status);
+        }
+    }
+
+    const FEATURES_OK: [u32; 3] = [
+        device_status::ACKNOWLEDGE,
+        device_status::DRIVER,
+        device_status::FEATURES_OK,
+    ];
+
+    // Lays out queue 0 with 16 entries at the start of guest memory.
+    fn set_up_queue(transport: &mut MmioTransport) {
+        write_reg(transport, QUEUE_SEL, 0);
+        write_reg(transport, QUEUE_NUM, 16);
+        write_reg(transport, QUEUE_DESC_LOW, DRAM_MEM_START as u32);
+        write_reg(transport, QUEUE_AVAIL_LOW, DRAM_MEM_START as u32 + 0x1000);
+        write_reg(transport, QUEUE_USED_LOW, DRAM_MEM_START as u32 + 0x2000);
+        write_reg(transport, QUEUE_READY, 1);
+    }
+
+    #[test]
+    fn test_notify_only_ready_queue() {
+        let mut transport = block_transport();
+        set_status(&mut transport, &FEATURES_OK);
+
+        write_reg(&mut transport, QUEUE_SEL, 0);
+        write_reg(&mut transport, QUEUE_NOTIFY, 0);
+        assert!(transport.locked_device().queue_events()[0].read().is_err());
+
+        write_reg(&mut transport, QUEUE_READY, 1);
+        write_reg(&mut transport, QUEUE_NOTIFY, 0);
+        assert_eq!(
+            transport.locked_device().queue_events()[0].read().unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_notify_missing_queue() {
+        let mut transport = block_transport();
+
+        write_reg(&mut transport, QUEUE_NOTIFY, 1);
+        assert!(transport.locked_device().queue_events()[0].read().is_err());
+    }
+
+    #[test]
+    fn test_read_block_capacity() {
+        let mut transport = block_transport();
+
+        let mut capacity = [0; 8];
+        transport.bus_read(CONFIG_SPACE, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 2048);
+        // drivers read it as two 32 bit halves
+        assert_eq!(read_reg(&transport, CONFIG_SPACE), 2048);
+        assert_eq!(read_reg(&transport, CONFIG_SPACE + 4), 0);
+
+        let generation = read_reg(&transport, CONFIG_GENERATION);
+        write_reg(&mut transport, CONFIG_SPACE, 0);
+        assert_eq!(read_reg(&transport, CONFIG_GENERATION), generation + 1);
+    }
+
+    #[test]
+    fn test_read_past_config() {
+        let mut transport = block_transport();
+
+        let mut data = [0xff; 8];
+        transport.bus_read(CONFIG_SPACE + 0x1000, &mut data);
+        assert_eq!(data, [0; 8]);
+
+        let mut data = [0xff; 8];
+        transport.bus_read(u64::MAX, &mut data);
+        assert_eq!(data, [0; 8]);
+
+        // ignored, and the capacity is unchanged
+        write_reg(&mut transport, CONFIG_SPACE + 0x1000, 0xffff_ffff);
+        assert_eq!(read_reg(&transport, CONFIG_SPACE), 2048);
+    }
+
+    #[test]
+    fn test_reset_to_inactive() {
+        let mut transport = block_transport();
+
+        set_status(&mut transport, &FEATURES_OK);
+        set_up_queue(&mut transport);
+        set_status(&mut transport, &[device_status::DRIVER_OK]);
+        assert!(transport.locked_device().is_activated());
+
+        write_reg(&mut transport, STATUS, 0);
+
+        assert!(!transport.locked_device().is_activated());
+        assert_eq!(read_reg(&transport, STATUS), 0);
+        assert_eq!(read_reg(&transport, QUEUE_READY), 0);
+        assert_eq!(read_reg(&transport, QUEUE_NUM_MAX), u32::from(QUEUE_SIZE));
+    }
+
+    #[test]
+    fn test_version() {
+        let mut transport = block_transport();
+
+        assert_eq!(read_reg(&transport, MAGIC_VALUE), MMIO_MAGIC_VALUE);
+        assert_eq!(read_reg(&transport, VERSION), 2);
+        assert_eq!(read_reg(&transport, DEVICE_ID), TYPE_BLOCK);
+        // the legacy queue registers don't exist in version 2
+        write_reg(&mut transport, QUEUE_PFN, 0x8_0000);
+        assert_eq!(read_reg(&transport, QUEUE_PFN), 0);
+        assert_eq!(read_reg(&transport, QUEUE_READY), 0);
+
+        transport.enable_legacy();
+        assert_eq!(read_reg(&transport, VERSION), 1);
+    }
+
+    #[test]
+    fn test_queue_num_max() {
+        let mut transport = block_transport_with_queue_size(128);
+
+        write_reg(&mut transport, QUEUE_SEL, 0);
+        assert_eq!(read_reg(&transport, QUEUE_NUM_MAX), 128);
+    }
+
+    #[test]
+    fn test_queue_num_max_out_of_range() {
+        let mut transport = block_transport();
+
+        // the block device only has queue 0
+        for index in [1, u32::MAX] {
+            write_reg(&mut transport, QUEUE_SEL, index);
+            assert_eq!(read_reg(&transport, QUEUE_NUM_MAX), 0);
+            assert_eq!(read_reg(&transport, QUEUE_READY), 0);
+        }
+    }
+
+    #[test]
+    fn test_interrupt_ack() {
+        let block = Block::new(
+            "block",
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        );
+        block.irq_trigger.trigger_irq(IrqType::Vring).unwrap();
+        block.irq_trigger.trigger_irq(IrqType::Config).unwrap();
+        let mut transport = MmioTransport::new(
+            test_guest_memory(0x10000),
+            Arc::new(Mutex::new(block)),
+            false,
+        );
+        assert_eq!(read_reg(&transport, INTERRUPT_STATUS), 0x03);
+
+        // only the acknowledged bit is cleared
+        write_reg(&mut transport, INTERRUPT_ACK, 0x01);
+        assert_eq!(read_reg(&transport, INTERRUPT_STATUS), 0x02);
+        write_reg(&mut transport, INTERRUPT_ACK, 0x02);
+        assert_eq!(read_reg(&transport, INTERRUPT_STATUS), 0);
+    }
+
+    #[test]
+    fn test_queue_registers_locked_while_running() {
+        let mut transport = block_transport();
+
+        // before feature negotiation
+        set_up_queue(&mut transport);
+        assert_eq!(read_reg(&transport, QUEUE_READY), 0);
+
+        set_status(&mut transport, &FEATURES_OK);
+        set_up_queue(&mut transport);
+        set_status(&mut transport, &[device_status::DRIVER_OK]);
+        assert!(transport.locked_device().is_activated());
+
+        write_reg(&mut transport, QUEUE_NUM, 0);
+        write_reg(&mut transport, QUEUE_DESC_LOW, 0);
+        write_reg(&mut transport, QUEUE_READY, 0);
+
+        let device = transport.locked_device();
+        let queue = &device.queues()[0];
+        assert_eq!(queue.size, 16);
+        assert_eq!(queue.desc_table, GuestAddress(DRAM_MEM_START));
+        assert!(queue.ready);
+    }
+
+    #[test]
+    fn test_bad_queue_size_not_activated() {
+        for size in [0, 3] {
+            let mut transport = block_transport();
+            set_status(&mut transport, &FEATURES_OK);
+            set_up_queue(&mut transport);
+            write_reg(&mut transport, QUEUE_NUM, size);
+
+            set_status(&mut transport, &[device_status::DRIVER_OK]);
+
+            assert!(!transport.locked_device().is_activated());
+            assert_ne!(
+                read_reg(&transport, STATUS) & device_status::DEVICE_NEEDS_RESET,
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn test_activation_waits_for_device_thread() {
+        let block = Arc::new(Mutex::new(Block::new(
+            "block",
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            QUEUE_SIZE,
+        )));
+        let mut event_manager = EventManager::new().unwrap();
+        event_manager.add_subscriber(block.clone());
+        let mut transport = MmioTransport::new(test_guest_memory(0x10000), block.clone(), false);
+        transport
+            .set_activation_barrier(Duration::from_secs(10))
+            .unwrap();
+        // the device thread only gets to the activate event after a while
+        let device_thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            event_manager.run_with_timeout(1000).unwrap();
+        });
+
+        set_status(&mut transport, &FEATURES_OK);
+        set_up_queue(&mut transport);
+        set_status(&mut transport, &[device_status::DRIVER_OK]);
+
+        // the driver only got here after the device thread handled the activation
+        assert!(block.lock().unwrap().activate_event.read().is_err());
+        assert!(block.lock().unwrap().is_activated());
+        device_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_activation_barrier_times_out() {
+        let mut transport = block_transport();
+        transport
+            .set_activation_barrier(Duration::from_millis(10))
+            .unwrap();
+
+        set_status(&mut transport, &FEATURES_OK);
+        set_up_queue(&mut transport);
+        // nothing handles the activate event, the driver goes on anyway
+        set_status(&mut transport, &[device_status::DRIVER_OK]);
+
+        assert!(transport.locked_device().is_activated());
+        assert_eq!(
+            read_reg(&transport, STATUS) & device_status::DEVICE_NEEDS_RESET,
+            0
+        );
+    }
 }
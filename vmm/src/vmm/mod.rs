@@ -1,271 +1,2990 @@
+use ::event_manager::{RemoteEndpoint, SubscriberId};
+use flate2::read::GzDecoder;
 use kvm_bindings::kvm_userspace_memory_region;
-use kvm_ioctls::{Kvm, VmFd};
+use kvm_ioctls::{Cap, Kvm, VmFd};
 use linux_loader;
 use linux_loader::loader::{Cmdline, KernelLoader, KernelLoaderResult};
+use log::{error, warn, LevelFilter};
 use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, Mutex};
-use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryRegion};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
+use versionize_derive::Versionize;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryRegion, ReadVolatile};
+use vm_superio::rtc_pl031::{NoEvents, RtcState};
+use vm_superio::serial::SerialState;
 use vm_superio::{Rtc, Serial};
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::vmm::device::DeviceType;
-use crate::vmm::fdt::FdtBuilder;
+use crate::vmm::fdt::{Fdt, FdtBuilder, FdtReadError};
 use crate::vmm::memory::get_fdt_addr;
 
-use self::cpu::Cpu;
+use self::api::{
+    handle_calls, register_kick_handler, ApiCall, ApiRequest, ApiResponse, ApiServer, PauseHandle,
+    VcpuThread,
+};
+use self::cmdline::{insert_args, CmdlineError};
+use self::config::{BlockConfig, NetConfig, VmBuilder, VmConfig};
+use self::cpu::{Cpu, CpuExit, CpuFeatures, CpuState, GuestDebug};
 use self::device::attach_virtio_device;
-use self::device::block::Block;
-use self::device::bus::BusDevice;
-use self::device::net::Net;
-use self::device::serial::out::SerialOut;
-use self::device::serial::{EventFdTrigger, SerialEventsWrapper, SerialWrapper};
-use self::event_manager::{EventManager, SubscriberOps};
-use self::memory::{GuestMemoryExtension, GuestMemoryMmap};
-use self::mmio::mmio_manager::MMIODeviceManager;
+use self::device::balloon::{Balloon, BalloonConfig, BalloonStats};
+use self::device::block::backend::{DiskBackend, IoEngine, MemDisk};
+use self::device::block::uring::IoUringDisk;
+use self::device::block::{Block, QUEUE_SIZE as BLOCK_QUEUE_SIZE};
+use self::device::bus::{BusDevice, BusError};
+use self::device::console::Console;
+use self::device::net::tap::{NetBackend, Tap};
+use self::device::net::{Net, QUEUE_SIZE as NET_QUEUE_SIZE};
+use self::device::queue::MAX_QUEUE_SIZE;
+use self::device::serial::out::{BufferedOut, ConsoleBuffer, SerialOut, PENDING_CAPACITY};
+use self::device::serial::{
+    ConsoleBackend, EventFdTrigger, Pty, SerialEventsWrapper, SerialInput, SerialWrapper,
+};
+use self::device::watchdog::Watchdog;
+use self::device::{QuiesceError, TYPE_BALLOON, TYPE_BLOCK, TYPE_CONSOLE, TYPE_NET};
+use self::event_manager::{EventManager, MutEventSubscriber, SubscriberOps};
+use self::gdb::{GdbAction, GdbStub, StopReason};
+use self::gicv::{GICv2, GicError, GicState};
+use self::layout::DRAM_MEM_START;
+use self::memory::{GuestMemoryExtension, GuestMemoryMmap, HugePages, MemoryError};
+use self::metrics::{Counter, DeviceMetrics, VmMetrics};
+use self::mmio::mmio_manager::{
+    DeviceSummary, IrqRegistrar, MMIODeviceInfo, MMIODeviceManager, NoopRegistrar, IRQ_BASE,
+    IRQ_MAX,
+};
+use self::mmio::mmio_transport::{MmioTransport, MmioTransportState};
+use self::pci::PciRoot;
+use self::rate_limiter::RateLimiterConfig;
+use self::reboot::RebootTracker;
 
+mod api;
+mod cmdline;
+pub mod config;
 mod cpu;
 mod device;
 mod event_manager;
 mod fdt;
+mod gdb;
 mod gicv;
+mod layout;
+mod logger;
 mod memory;
+mod metrics;
 mod mmio;
+mod pci;
+mod rate_limiter;
+mod reboot;
+mod timerfd;
 
-pub const DEFAULT_KERNEL_CMDLINE: &str = "reboot=k panic=1 pci=off";
+// How long the device thread waits for events before checking whether it should stop.
+const DEVICE_THREAD_TIMEOUT_MS: i32 = 100;
+// How often a paused vcpu checks for API calls.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `pci=off` is added to it for VMs without a PCIe host bridge.
+pub const DEFAULT_KERNEL_CMDLINE: &str = "reboot=k panic=1";
+
+// The initrd is placed on a page boundary.
+const INITRD_ALIGN: u64 = 0x1000;
+
+// Every arm64 kernel starts with the Image header, EFI stub kernels hide a PE header in it.
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_MAGIC_OFFSET: usize = 0x38;
+const IMAGE_MAGIC: &[u8; 4] = b"ARM\x64";
+const PE_MAGIC: &[u8; 2] = b"MZ";
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+// Images without an image size predate v3.17 and were linked at this offset.
+const LEGACY_TEXT_OFFSET: u64 = 0x8_0000;
+// gzip compressed kernels are inflated before loading
+const GZIP_MAGIC: &[u8; 2] = b"\x1f\x8b";
+
+const SNAPSHOT_VERSION: u16 = 1;
+const SNAPSHOT_MEMORY_FILE: &str = "memory";
+const SNAPSHOT_STATE_FILE: &str = "state";
+
+#[derive(Debug)]
+pub enum VmError {
+    Io(std::io::Error),
+    Kvm(kvm_ioctls::Error),
+    Memory(MemoryError),
+    GuestMemory(vm_memory::GuestMemoryError),
+    Gic(GicError),
+    Snapshot(VersionizeError),
+    Quiesce(QuiesceError),
+    Fdt(FdtReadError),
+    /// The FDT couldn't be generated.
+    FdtCreate(vm_fdt::Error),
+    Bus(BusError),
+    EventManager(::event_manager::Error),
+    Kernel(linux_loader::loader::Error),
+    Cmdline(CmdlineError),
+    /// The kernel has no arm64 Image header, e.g. because it's compressed with something other
+    /// than gzip.
+    UnknownKernelFormat,
+    /// The kernel is packaged in a format there's no arm64 loader for.
+    UnsupportedKernelFormat(KernelFormat),
+    /// The initrd doesn't fit between the kernel and the FDT.
+    InitrdTooLarge,
+    UnsupportedVcpuCount(u8),
+    /// More vcpus were asked for than the host's KVM allows, which is the second value.
+    VcpuLimitExceeded(u8, usize),
+    /// The serial and the virtio console are both on stdio, they can't share stdin.
+    StdioConsoleInUse,
+    /// No vcpu has this index.
+    InvalidVcpu(u8),
+    /// The host cpu set is empty or names a cpu past `CPU_SETSIZE`.
+    InvalidCpuSet,
+    /// A saved block device has no backing file recorded.
+    MissingDiskPath(String),
+    UnknownDevice(u32),
+    /// The interrupt is outside the range handed out to devices.
+    InvalidIrq(u32),
+    BalloonNotAttached,
+    /// A device every VM needs, like the serial console, isn't registered.
+    MissingDevice(DeviceType),
+    /// The VM has no guest memory mapped.
+    NoMemory,
+    /// A queue size isn't a power of two or is larger than `MAX_QUEUE_SIZE`.
+    InvalidQueueSize(u16),
+    /// The memory size isn't a multiple of the huge page size.
+    UnalignedMemorySize(usize),
+    /// The host's KVM lacks a capability every VM needs.
+    MissingCapability(Cap),
+    /// The saved serial input FIFO holds more bytes than the device's.
+    InvalidSerialState,
+    /// The vcpu exited for a reason the VMM doesn't handle, the log has the exit.
+    UnhandledVcpuExit,
+    /// The device event loop is gone, its thread panicked or couldn't be started.
+    NoEventLoop,
+}
+
+/// How an arm64 kernel is packaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelFormat {
+    /// A raw `Image`.
+    Image,
+    /// An `Image` with an EFI stub, as built with `CONFIG_EFI`.
+    Pe,
+    /// An uncompressed `vmlinux`.
+    Elf,
+}
+
+impl KernelFormat {
+    /// Tells the format from the first `IMAGE_HEADER_SIZE` bytes of the kernel.
+    pub fn detect(header: &[u8]) -> Result<KernelFormat, VmError> {
+        if header.starts_with(ELF_MAGIC) {
+            return Ok(KernelFormat::Elf);
+        }
+
+        let magic = header.get(IMAGE_MAGIC_OFFSET..IMAGE_MAGIC_OFFSET + IMAGE_MAGIC.len());
+        if magic != Some(IMAGE_MAGIC.as_slice()) {
+            return Err(VmError::UnknownKernelFormat);
+        }
+
+        if header.starts_with(PE_MAGIC) {
+            Ok(KernelFormat::Pe)
+        } else {
+            Ok(KernelFormat::Image)
+        }
+    }
+}
+
+/// Why the VM stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExitReason {
+    /// The guest powered off.
+    Shutdown,
+    /// The guest rebooted more often than allowed by `Vm::set_max_reboots`.
+    RebootLoop,
+    /// A `Shutdown` request came in over the API socket, or gdb killed the guest.
+    Stopped,
+}
+
+/// Saved state of a virtio device together with its placement on the MMIO bus.
+#[derive(Debug, Versionize)]
+pub struct VirtioDeviceState {
+    pub device_type: u32,
+    pub id: String,
+    pub device_info: MMIODeviceInfo,
+    pub transport: MmioTransportState,
+    /// Backing file of block devices.
+    pub disk_path: Option<String>,
+    pub io_engine: Option<IoEngine>,
+    /// MAC address of net devices that have one.
+    pub net_mac: Option<[u8; 6]>,
+    /// Tap interface of net devices attached to one.
+    pub net_host_dev_name: Option<String>,
+    /// The limiter of block devices, or the rx and tx limiters of net devices.
+    pub rate_limiters: Vec<RateLimiterConfig>,
+    /// Target and actual size of balloon devices.
+    pub balloon_config: Option<BalloonConfig>,
+    /// Host side of console devices.
+    pub console: Option<ConsoleBackendState>,
+}
+
+/// Registers and pending input of the serial device, see `vm_superio::serial::SerialState`.
+#[derive(Debug, Default, Versionize)]
+pub struct SerialDeviceState {
+    pub baud_divisor_low: u8,
+    pub baud_divisor_high: u8,
+    pub interrupt_enable: u8,
+    pub interrupt_identification: u8,
+    pub line_control: u8,
+    pub line_status: u8,
+    pub modem_control: u8,
+    pub modem_status: u8,
+    pub scratch: u8,
+    pub in_buffer: Vec<u8>,
+}
+
+impl From<SerialState> for SerialDeviceState {
+    fn from(state: SerialState) -> Self {
+        SerialDeviceState {
+            baud_divisor_low: state.baud_divisor_low,
+            baud_divisor_high: state.baud_divisor_high,
+            interrupt_enable: state.interrupt_enable,
+            interrupt_identification: state.interrupt_identification,
+            line_control: state.line_control,
+            line_status: state.line_status,
+            modem_control: state.modem_control,
+            modem_status: state.modem_status,
+            scratch: state.scratch,
+            in_buffer: state.in_buffer,
+        }
+    }
+}
+
+impl From<&SerialDeviceState> for SerialState {
+    fn from(state: &SerialDeviceState) -> Self {
+        SerialState {
+            baud_divisor_low: state.baud_divisor_low,
+            baud_divisor_high: state.baud_divisor_high,
+            interrupt_enable: state.interrupt_enable,
+            interrupt_identification: state.interrupt_identification,
+            line_control: state.line_control,
+            line_status: state.line_status,
+            modem_control: state.modem_control,
+            modem_status: state.modem_status,
+            scratch: state.scratch,
+            in_buffer: state.in_buffer.clone(),
+        }
+    }
+}
+
+/// Registers of the RTC, including its offset from the host clock, see
+/// `vm_superio::rtc_pl031::RtcState`.
+#[derive(Debug, Default, Versionize)]
+pub struct RtcDeviceState {
+    pub lr: u32,
+    pub offset: i64,
+    pub mr: u32,
+    pub imsc: u32,
+    pub ris: u32,
+}
+
+impl From<RtcState> for RtcDeviceState {
+    fn from(state: RtcState) -> Self {
+        RtcDeviceState {
+            lr: state.lr,
+            offset: state.offset,
+            mr: state.mr,
+            imsc: state.imsc,
+            ris: state.ris,
+        }
+    }
+}
+
+impl From<&RtcDeviceState> for RtcState {
+    fn from(state: &RtcDeviceState) -> Self {
+        RtcState {
+            lr: state.lr,
+            offset: state.offset,
+            mr: state.mr,
+            imsc: state.imsc,
+            ris: state.ris,
+        }
+    }
+}
+
+/// Host side of a console, see `ConsoleBackend`.
+#[derive(Debug, Versionize)]
+pub enum ConsoleBackendState {
+    Stdio,
+    Pty,
+    File(String),
+    Buffer(u64),
+}
+
+impl From<&ConsoleBackend> for ConsoleBackendState {
+    fn from(backend: &ConsoleBackend) -> Self {
+        match backend {
+            ConsoleBackend::Stdio => ConsoleBackendState::Stdio,
+            ConsoleBackend::Pty => ConsoleBackendState::Pty,
+            ConsoleBackend::File(path) => {
+                ConsoleBackendState::File(path.to_string_lossy().into_owned())
+            }
+            ConsoleBackend::Buffer(capacity) => ConsoleBackendState::Buffer(*capacity as u64),
+        }
+    }
+}
+
+impl From<&ConsoleBackendState> for ConsoleBackend {
+    fn from(state: &ConsoleBackendState) -> Self {
+        match state {
+            ConsoleBackendState::Stdio => ConsoleBackend::Stdio,
+            ConsoleBackendState::Pty => ConsoleBackend::Pty,
+            ConsoleBackendState::File(path) => ConsoleBackend::File(PathBuf::from(path)),
+            ConsoleBackendState::Buffer(capacity) => ConsoleBackend::Buffer(*capacity as usize),
+        }
+    }
+}
+
+/// The watchdog's placement and whether the guest had enabled it.
+#[derive(Debug, Versionize)]
+pub struct WatchdogState {
+    pub device_info: MMIODeviceInfo,
+    pub timeout_ms: u64,
+    pub enabled: bool,
+}
+
+/// Everything besides guest memory needed to recreate a VM.
+#[derive(Debug, Versionize)]
+pub struct VmState {
+    /// Guest memory size in MiB.
+    pub memory_size: u64,
+    pub cmdline: String,
+    pub cpu: CpuState,
+    pub cpu_features: CpuFeatures,
+    pub gic: GicState,
+    pub virtio_devices: Vec<VirtioDeviceState>,
+    pub serial_info: MMIODeviceInfo,
+    pub serial: SerialDeviceState,
+    pub console: ConsoleBackendState,
+    pub rtc_info: MMIODeviceInfo,
+    pub rtc: RtcDeviceState,
+    /// Whether the VM has a PCIe host bridge.
+    pub pci: bool,
+    pub watchdog: Option<WatchdogState>,
+}
+
+/// The devices `Vm::attach_devices` created, and what the VM keeps of them.
+struct Devices {
+    mmio_device_manager: MMIODeviceManager,
+    block_metrics: Vec<Arc<DeviceMetrics>>,
+    net_metrics: Vec<Arc<DeviceMetrics>>,
+    balloon: Option<Arc<Mutex<Balloon>>>,
+    serial_handles: SerialHandles,
+    virtio_console_handles: Option<SerialHandles>,
+    watchdog: Option<Arc<Mutex<BusDevice>>>,
+    watchdog_reset: Option<EventFd>,
+}
+
+/// Which of the KVM capabilities a VM needs the host supports, see `Vm::probe_host`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostCapabilities {
+    pub user_memory: bool,
+    pub irqfd: bool,
+    pub ioeventfd: bool,
+    /// PSCI 0.2, which the guest uses to bring up and power off vcpus.
+    pub arm_psci: bool,
+    /// Creating in-kernel devices, the GIC is one.
+    pub gic: bool,
+}
+
+impl HostCapabilities {
+    fn from_kvm(kvm: &Kvm) -> HostCapabilities {
+        HostCapabilities {
+            user_memory: kvm.check_extension(Cap::UserMemory),
+            irqfd: kvm.check_extension(Cap::Irqfd),
+            ioeventfd: kvm.check_extension(Cap::Ioeventfd),
+            arm_psci: kvm.check_extension(Cap::ArmPsci02),
+            gic: kvm.check_extension(Cap::DeviceCtrl),
+        }
+    }
+
+    /// The first capability the host lacks, if any.
+    pub fn missing(&self) -> Option<Cap> {
+        [
+            (self.user_memory, Cap::UserMemory),
+            (self.irqfd, Cap::Irqfd),
+            (self.ioeventfd, Cap::Ioeventfd),
+            (self.arm_psci, Cap::ArmPsci02),
+            (self.gic, Cap::DeviceCtrl),
+        ]
+        .into_iter()
+        .find(|(supported, _)| !supported)
+        .map(|(_, cap)| cap)
+    }
+}
+
+/// The layout `Vm::build_config_only` computes for a config.
+pub struct ConfigLayout {
+    pub fdt: Fdt,
+    pub devices: Vec<DeviceSummary>,
+    pub cmdline: String,
+}
+
+/// Host side handles of the serial console, depending on its backend.
+#[derive(Default)]
+struct SerialHandles {
+    pty_path: Option<PathBuf>,
+    buffer: Option<ConsoleBuffer>,
+    lost_bytes: Arc<Counter>,
+}
 
 pub struct Vm {
     fd: VmFd,
     cpu: Cpu,
+    cpu_features: CpuFeatures,
+    gic: GICv2,
     memory: GuestMemoryMmap,
+    // in MiB
     memory_size: usize,
     mmio_device_manager: MMIODeviceManager,
     cmdline: Cmdline,
+    // reloaded when the guest reboots, unknown for VMs restored from a snapshot
+    kernel_path: Option<PathBuf>,
+    initrd_path: Option<PathBuf>,
+    // guest address and size of the initrd
+    initrd: Option<(u64, u64)>,
+    block_devices: Vec<BlockConfig>,
+    net_devices: Vec<NetConfig>,
+    balloon: Option<Arc<Mutex<Balloon>>>,
+    // host side of the serial and virtio consoles, reopened on restore
+    console: ConsoleBackend,
+    virtio_console: Option<ConsoleBackend>,
+    serial_pty_path: Option<PathBuf>,
+    console_buffer: Option<ConsoleBuffer>,
+    virtio_console_pty_path: Option<PathBuf>,
+    virtio_console_buffer: Option<ConsoleBuffer>,
+    // serial output dropped because its destination didn't keep up
+    serial_lost_bytes: Arc<Counter>,
+    block_metrics: Vec<Arc<DeviceMetrics>>,
+    net_metrics: Vec<Arc<DeviceMetrics>>,
+    reboot_tracker: RebootTracker,
+    // Owned by the VM until the device thread is spawned, which hands it back when stopped.
+    event_manager: Option<EventManager>,
+    device_thread: Option<DeviceThread>,
+    // stdout flags from before the VM made it non-blocking, put back on drop
+    stdout_flags: Option<i32>,
+    api_server: Option<ApiServer>,
+    api_calls: Option<Receiver<ApiCall>>,
+    vcpu_thread: VcpuThread,
+    pause: PauseHandle,
+    // host cpus the vcpu thread is pinned to once it runs
+    vcpu_affinity: Option<Vec<usize>>,
+    gdb: Option<GdbStub>,
+    watchdog: Option<Arc<Mutex<BusDevice>>>,
+    // signalled by the watchdog when it expires
+    watchdog_reset: Option<EventFd>,
+}
+
+struct DeviceThread {
+    handle: JoinHandle<EventManager>,
+    stop: Arc<AtomicBool>,
+    // reaches the event manager while the thread owns it
+    endpoint: RemoteEndpoint<Arc<Mutex<dyn MutEventSubscriber + Send>>>,
 }
 
 impl Vm {
+    /// Creates a VM with `memory_size` MiB of memory and its serial console on stdio.
     pub fn new(memory_size: usize) -> Vm {
-        let guest_memory = Vm::create_memory(memory_size);
+        Vm::with_console(memory_size, ConsoleBackend::Stdio)
+    }
+
+    /// Creates a VM with its serial console on a new pty, returning the path of the pty slave
+    /// to attach a terminal to.
+    pub fn with_serial_pty(memory_size: usize) -> (Vm, PathBuf) {
+        let vm = Vm::with_console(memory_size, ConsoleBackend::Pty);
+        let path = vm.serial_pty_path.clone().unwrap();
+
+        (vm, path)
+    }
+
+    /// Creates a VM whose serial console is attached to the given host backend.
+    pub fn with_console(memory_size: usize, console: ConsoleBackend) -> Vm {
+        let mut builder = VmBuilder::new();
+        builder
+            .memory_size(memory_size)
+            .add_block("Root", "./rootfs")
+            .add_net("Netif", None)
+            .balloon(true)
+            .console(console);
+
+        match builder.build() {
+            Ok(value) => value,
+            Err(error) => panic!("{:?}", error),
+        }
+    }
 
-        let kernel = Vm::load_kernel(&guest_memory);
+    /// Creates a VM like `Vm::new` with a watchdog that reboots the guest once it stops
+    /// pinging it for `timeout`.
+    pub fn with_watchdog(memory_size: usize, timeout: Duration) -> Vm {
+        let mut builder = VmBuilder::new();
+        builder
+            .memory_size(memory_size)
+            .add_block("Root", "./rootfs")
+            .add_net("Netif", None)
+            .balloon(true)
+            .watchdog(Some(timeout));
 
-        let (kvm, kvm_fd) = Vm::create_kvm(&guest_memory);
+        match builder.build() {
+            Ok(value) => value,
+            Err(error) => panic!("{:?}", error),
+        }
+    }
+
+    /// Creates a VM with the memory, kernel and devices described by `config`.
+    pub fn from_config(config: VmConfig) -> Result<Vm, VmError> {
+        // checked first, no build could run this many vcpus on this host
+        Vm::check_vcpu_limit(config.vcpu_count, Vm::max_vcpus())?;
+        Vm::validate_config(&config)?;
+
+        let mem_size = config.memory_size << 20;
+        let guest_memory = Vm::create_memory(
+            mem_size,
+            config.huge_pages,
+            config.prefault_memory,
+            config.numa_node,
+        )?;
+
+        let kernel = Vm::load_kernel(&guest_memory, &config.kernel_path)?;
+
+        let initrd = match &config.initrd_path {
+            Some(path) => Some(Vm::load_initrd(&guest_memory, &kernel, path)?),
+            None => None,
+        };
+
+        let (_kvm, kvm_fd) = Vm::create_kvm(&guest_memory)?;
 
         let cpu = Vm::create_cpu(&kvm_fd);
 
+        let gic = Vm::create_gic(&kvm_fd);
+
         let mut event_manager = EventManager::new().unwrap();
 
-        let mut cmdline = Cmdline::try_from(DEFAULT_KERNEL_CMDLINE, 2048).unwrap();
+        let mut cmdline = Vm::create_cmdline(&config)?;
 
-        let mut mmio_device_manager = MMIODeviceManager::new();
+        // only a console on stdio touches the process' stdout
+        let on_stdio = config.console == ConsoleBackend::Stdio
+            || config.virtio_console == Some(ConsoleBackend::Stdio);
+        let stdout_flags = if config.nonblocking_stdout && on_stdio {
+            Some(Vm::set_stdout_nonblocking())
+        } else {
+            None
+        };
 
-        // attach block device
-        let block = Arc::new(Mutex::new(Block::new()));
-        attach_virtio_device(
+        let vcpu_thread = VcpuThread::default();
+        let devices = Vm::attach_devices(
+            &config,
+            false,
             &guest_memory,
             &kvm_fd,
-            &mut mmio_device_manager,
             &mut event_manager,
-            "Root".to_string(),
-            block,
             &mut cmdline,
-            false,
-        );
+            &vcpu_thread,
+        )?;
 
-        // attach net device
-        let net = Arc::new(Mutex::new(Net::new()));
-        attach_virtio_device(
+        Ok(Vm {
+            fd: kvm_fd,
+            cpu,
+            cpu_features: config.cpu_features,
+            gic,
+            memory: guest_memory,
+            mmio_device_manager: devices.mmio_device_manager,
+            cmdline,
+            memory_size: config.memory_size,
+            kernel_path: Some(config.kernel_path),
+            initrd_path: config.initrd_path,
+            initrd,
+            block_devices: config.block_devices,
+            net_devices: config.net_devices,
+            balloon: devices.balloon,
+            console: config.console,
+            virtio_console: config.virtio_console,
+            serial_pty_path: devices.serial_handles.pty_path,
+            console_buffer: devices.serial_handles.buffer,
+            virtio_console_pty_path: devices
+                .virtio_console_handles
+                .as_ref()
+                .and_then(|handles| handles.pty_path.clone()),
+            virtio_console_buffer: devices
+                .virtio_console_handles
+                .and_then(|handles| handles.buffer),
+            serial_lost_bytes: devices.serial_handles.lost_bytes,
+            block_metrics: devices.block_metrics,
+            net_metrics: devices.net_metrics,
+            reboot_tracker: RebootTracker::new(config.max_reboots),
+            event_manager: Some(event_manager),
+            device_thread: None,
+            api_server: None,
+            api_calls: None,
+            pause: PauseHandle::new(vcpu_thread.clone()),
+            vcpu_thread,
+            vcpu_affinity: None,
+            gdb: None,
+            watchdog: devices.watchdog,
+            watchdog_reset: devices.watchdog_reset,
+            stdout_flags,
+        })
+    }
+
+    /// Lays out the VM `config` describes without creating it, so it works without KVM:
+    /// allocates the devices' MMIO ranges and irqs, builds the cmdline and generates the FDT.
+    /// Only the kernel and initrd are read, since the initrd is placed after the kernel. No
+    /// disk, tap or console is opened, the consoles go to buffers, and the vcpu is given
+    /// MPIDR 0, which is what KVM gives the first vcpu.
+    pub fn build_config_only(config: VmConfig) -> Result<ConfigLayout, VmError> {
+        Vm::validate_config(&config)?;
+
+        let guest_memory =
+            Vm::create_memory(config.memory_size << 20, HugePages::None, false, None)?;
+        let kernel = Vm::load_kernel(&guest_memory, &config.kernel_path)?;
+        let initrd = match &config.initrd_path {
+            Some(path) => Some(Vm::load_initrd(&guest_memory, &kernel, path)?),
+            None => None,
+        };
+
+        // the devices are dropped with it once the layout is read
+        let mut event_manager = EventManager::new().unwrap();
+        let mut cmdline = Vm::create_cmdline(&config)?;
+        let devices = Vm::attach_devices(
+            &config,
+            true,
             &guest_memory,
-            &kvm_fd,
-            &mut mmio_device_manager,
+            &NoopRegistrar,
             &mut event_manager,
-            "Netif".to_string(),
-            net,
             &mut cmdline,
-            false,
-        );
+            &VcpuThread::default(),
+        )?;
+
+        let fdt = Vm::layout_fdt(
+            config.cpu_features.pmu,
+            0,
+            &devices.mmio_device_manager,
+            initrd,
+            &cmdline,
+            &guest_memory,
+        )?;
+
+        Ok(ConfigLayout {
+            fdt,
+            devices: devices.mmio_device_manager.devices(),
+            cmdline: cmdline.as_cstring().unwrap().into_string().unwrap(),
+        })
+    }
+
+    // `max` is the host's limit, unknown without KVM.
+    fn check_vcpu_limit(vcpu_count: u8, max: Option<usize>) -> Result<(), VmError> {
+        match max {
+            Some(max) if usize::from(vcpu_count) > max => {
+                Err(VmError::VcpuLimitExceeded(vcpu_count, max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_config(config: &VmConfig) -> Result<(), VmError> {
+        if config.vcpu_count != 1 {
+            return Err(VmError::UnsupportedVcpuCount(config.vcpu_count));
+        }
+
+        if config.console == ConsoleBackend::Stdio
+            && config.virtio_console == Some(ConsoleBackend::Stdio)
+        {
+            return Err(VmError::StdioConsoleInUse);
+        }
+
+        let queue_sizes = config
+            .block_devices
+            .iter()
+            .map(|block| block.queue_size)
+            .chain(config.net_devices.iter().map(|net| net.queue_size));
+        for queue_size in queue_sizes {
+            if !queue_size.is_power_of_two() || queue_size > MAX_QUEUE_SIZE {
+                return Err(VmError::InvalidQueueSize(queue_size));
+            }
+        }
+
+        if let Some(page_size) = config.huge_pages.page_size() {
+            if !(config.memory_size << 20).is_multiple_of(page_size) {
+                return Err(VmError::UnalignedMemorySize(page_size));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn create_cmdline(config: &VmConfig) -> Result<Cmdline, VmError> {
+        let mut cmdline = Cmdline::try_from(DEFAULT_KERNEL_CMDLINE, 2048).unwrap();
+        if !config.pci {
+            insert_args(&mut cmdline, "pci=off").map_err(VmError::Cmdline)?;
+        }
+        if let Some(extra) = &config.cmdline_extra {
+            insert_args(&mut cmdline, extra).map_err(VmError::Cmdline)?;
+        }
+
+        Ok(cmdline)
+    }
+
+    /// Creates the devices `config` asks for and registers them, in the order that decides
+    /// their MMIO ranges and irqs. With `dry_run` the devices touch nothing on the host: the
+    /// disks are empty and in memory, the net devices have no tap and the consoles go to
+    /// buffers.
+    fn attach_devices(
+        config: &VmConfig,
+        dry_run: bool,
+        guest_memory: &GuestMemoryMmap,
+        registrar: &dyn IrqRegistrar,
+        event_manager: &mut EventManager,
+        cmdline: &mut Cmdline,
+        vcpu_thread: &VcpuThread,
+    ) -> Result<Devices, VmError> {
+        let mut mmio_device_manager = MMIODeviceManager::new();
+
+        // attach block devices
+        let mut block_metrics = Vec::new();
+        for block_config in &config.block_devices {
+            let disk: Box<dyn DiskBackend + Send> = if dry_run {
+                Box::new(MemDisk::default())
+            } else {
+                Vm::open_disk(block_config)?
+            };
+            let block = Block::new(
+                &block_config.id,
+                disk,
+                block_config.rate_limiter,
+                block_config.queue_size,
+            );
+            block_metrics.push(block.metrics.clone());
+            let block = Arc::new(Mutex::new(block));
+            event_manager.add_subscriber(block.clone());
+            let mut transport = MmioTransport::new(guest_memory.clone(), block, false);
+            if let Some(timeout) = block_config.activation_timeout {
+                transport
+                    .set_activation_barrier(timeout)
+                    .map_err(VmError::Io)?;
+            }
+            mmio_device_manager
+                .register_mmio_virtio_for_boot(
+                    registrar,
+                    block_config.id.clone(),
+                    transport,
+                    cmdline,
+                )
+                .map_err(VmError::Bus)?;
+        }
+
+        // attach net devices
+        let mut net_metrics = Vec::new();
+        for net_config in &config.net_devices {
+            let backend = if dry_run {
+                None
+            } else {
+                Vm::open_tap(net_config)?
+            };
+            let net = Net::new(
+                net_config.mac,
+                backend,
+                net_config.rx_rate_limiter,
+                net_config.tx_rate_limiter,
+                net_config.queue_size,
+            );
+            net_metrics.push(net.metrics.clone());
+            attach_virtio_device(
+                guest_memory,
+                registrar,
+                &mut mmio_device_manager,
+                event_manager,
+                net_config.id.clone(),
+                Arc::new(Mutex::new(net)),
+                cmdline,
+                false,
+            )
+            .map_err(VmError::Bus)?;
+        }
+
+        // attach balloon device
+        let mut balloon = None;
+        if config.balloon {
+            let device = Arc::new(Mutex::new(Balloon::new()));
+            attach_virtio_device(
+                guest_memory,
+                registrar,
+                &mut mmio_device_manager,
+                event_manager,
+                "Balloon".to_string(),
+                device.clone(),
+                cmdline,
+                false,
+            )
+            .map_err(VmError::Bus)?;
+            balloon = Some(device);
+        }
+
+        let mut serial_handles = SerialHandles::default();
 
-        // set stdout non-blocking
-        Vm::set_stdout_nonblocking();
+        // attach virtio console, its lost output is counted with the serial one
+        let mut virtio_console_handles = None;
+        if let Some(virtio_console) = &config.virtio_console {
+            let mut handles = SerialHandles {
+                lost_bytes: serial_handles.lost_bytes.clone(),
+                ..SerialHandles::default()
+            };
+            let backend = if dry_run {
+                ConsoleBackend::Buffer(0)
+            } else {
+                virtio_console.clone()
+            };
+            let (input, output) = Vm::open_console(backend, &mut handles)?;
+            attach_virtio_device(
+                guest_memory,
+                registrar,
+                &mut mmio_device_manager,
+                event_manager,
+                "Console".to_string(),
+                Arc::new(Mutex::new(Console::new(input, output))),
+                cmdline,
+                false,
+            )
+            .map_err(VmError::Bus)?;
+            virtio_console_handles = Some(handles);
+        }
 
         // add serial device
-        let serial_device = Vm::create_serial_device();
+        let console = if dry_run {
+            ConsoleBackend::Buffer(0)
+        } else {
+            config.console.clone()
+        };
+        let serial_device = Vm::create_serial_device(console, &mut serial_handles, None)?;
         event_manager.add_subscriber(serial_device.clone());
-        mmio_device_manager.register_mmio_serial(&kvm_fd, serial_device, None);
         mmio_device_manager
-            .add_mmio_serial_to_cmdline(&mut cmdline)
+            .register_mmio_serial(registrar, serial_device, None)
+            .map_err(VmError::Bus)?;
+        mmio_device_manager
+            .add_mmio_serial_to_cmdline(cmdline)
             .unwrap();
 
         // add rtc device
         let rtc_device = Rtc::new();
-        mmio_device_manager.register_mmio_rtc(rtc_device, None);
+        mmio_device_manager
+            .register_mmio_rtc(rtc_device, None)
+            .map_err(VmError::Bus)?;
 
-        Vm {
-            fd: kvm_fd,
-            cpu,
-            memory: guest_memory,
-            mmio_device_manager,
-            cmdline,
-            memory_size,
+        // add pci host bridge
+        if config.pci {
+            mmio_device_manager
+                .register_pci_root(PciRoot::new())
+                .map_err(VmError::Bus)?;
         }
-    }
 
-    pub fn configure(&self) {
-        self.cpu.init(&self.fd);
-        self.cpu.configure_regs(&self.memory);
+        // add watchdog device
+        let mut watchdog = None;
+        let mut watchdog_reset = None;
+        if let Some(timeout) = config.watchdog_timeout {
+            if !dry_run {
+                register_kick_handler().map_err(VmError::Io)?;
+            }
+            let (device, reset_evt) = Vm::create_watchdog(timeout, vcpu_thread)?;
+            event_manager.add_subscriber(device.clone());
+            mmio_device_manager
+                .register_mmio_watchdog(device.clone(), None)
+                .map_err(VmError::Bus)?;
+            watchdog = Some(device);
+            watchdog_reset = Some(reset_evt);
+        }
 
-        let mut fdt = FdtBuilder::new();
+        Ok(Devices {
+            mmio_device_manager,
+            block_metrics,
+            net_metrics,
+            balloon,
+            serial_handles,
+            virtio_console_handles,
+            watchdog,
+            watchdog_reset,
+        })
+    }
 
-        let rtc_info = self
-            .mmio_device_manager
-            .id_to_dev_info
-            .get(&(DeviceType::Rtc, "Rtc".to_string()))
-            .unwrap();
-        fdt.with_rtc(rtc_info.addr, rtc_info.len);
+    /// Runs the device event loop on its own thread, so the devices' subscribers get their
+    /// events processed. Does nothing if the thread is already running.
+    pub fn spawn_device_thread(&mut self) -> Result<(), VmError> {
+        let mut event_manager = match self.event_manager.take() {
+            Some(event_manager) => event_manager,
+            None => return Ok(()),
+        };
 
-        let serial_info = self
-            .mmio_device_manager
-            .id_to_dev_info
-            .get(&(DeviceType::Serial, "Serial".to_string()))
-            .unwrap();
-        fdt.with_serial_console(serial_info.addr, serial_info.len);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let endpoint = event_manager.remote_endpoint();
+        let handle = thread::Builder::new()
+            .name("devices".to_string())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Acquire) {
+                    if let Err(err) = event_manager.run_with_timeout(DEVICE_THREAD_TIMEOUT_MS) {
+                        panic!("Failed to run the device event loop: {:?}", err);
+                    }
+                }
+                event_manager
+            })
+            .map_err(VmError::Io)?;
 
-        let block_info = self
-            .mmio_device_manager
-            .id_to_dev_info
-            .get(&(DeviceType::Virtio(2), "Root".to_string()))
-            .unwrap();
-        fdt.add_virtio_device(block_info.addr, block_info.len, block_info.irqs[0]);
+        self.device_thread = Some(DeviceThread {
+            handle,
+            stop,
+            endpoint,
+        });
 
-        let net_info = self
-            .mmio_device_manager
-            .id_to_dev_info
-            .get(&(DeviceType::Virtio(1), "Netif".to_string()))
-            .unwrap();
-        fdt.add_virtio_device(net_info.addr, net_info.len, net_info.irqs[0]);
+        Ok(())
+    }
 
-        fdt.with_cmdline(self.cmdline.as_cstring().unwrap().into_string().unwrap());
-        fdt.with_mem_size(self.memory_size as u64);
+    /// Adds a subscriber to the device event loop, where it's driven like the VM's own
+    /// devices. Works whether or not the device thread is running.
+    pub fn add_subscriber(
+        &mut self,
+        subscriber: Arc<Mutex<dyn MutEventSubscriber + Send>>,
+    ) -> Result<SubscriberId, VmError> {
+        if let Some(event_manager) = self.event_manager.as_mut() {
+            return Ok(event_manager.add_subscriber(subscriber));
+        }
 
-        // write fdt to memory
-        let raw = fdt.create_fdt().unwrap();
-        let ftd_addr = GuestAddress(get_fdt_addr(&self.memory));
-        self.memory
-            .write_slice(raw.fdt_blob.as_slice(), ftd_addr)
-            .unwrap();
+        // the event manager is owned by the running device thread
+        let device_thread = self.device_thread.as_ref().ok_or(VmError::NoEventLoop)?;
+        device_thread
+            .endpoint
+            .call_blocking(move |ops| Ok(ops.add_subscriber(subscriber)))
+            .map_err(VmError::EventManager)
     }
 
-    fn create_memory(memory_size: usize) -> GuestMemoryMmap {
-        let memfd = memory::create_memfd(memory_size);
-        let guest_memory = match GuestMemoryMmap::with_file(memfd.as_file(), false) {
-            Ok(value) => value,
-            Err(_) => panic!("can't create guest memory"),
-        };
+    /// Logs the VMM's warnings and errors, or more depending on `level`, to stderr. The level
+    /// is shared by every VM in the process. An embedder that installed its own logger keeps
+    /// it, only the level changes.
+    pub fn set_log_level(level: LevelFilter) {
+        logger::set_log_level(level);
+    }
 
-        guest_memory
+    /// Listens for `ApiRequest`s on a unix socket at `path`, which must not exist yet. The
+    /// requests are handled by `run`, so they wait until the VM runs.
+    pub fn start_api_server(&mut self, path: &Path) -> Result<(), VmError> {
+        let (calls, api_calls) = mpsc::channel();
+        let server =
+            ApiServer::start(path, calls, self.vcpu_thread.clone()).map_err(VmError::Io)?;
+
+        self.api_server = Some(server);
+        self.api_calls = Some(api_calls);
+
+        Ok(())
     }
 
-    fn load_kernel(guest_memory: &GuestMemoryMmap) -> KernelLoaderResult {
-        let mut kernel_image = match File::open("./kernel") {
-            Ok(value) => value,
-            Err(error) => panic!("{}", error),
-        };
-        let kernel = match linux_loader::loader::pe::PE::load(
-            guest_memory,
-            Some(GuestAddress(0x8000_0000)),
-            &mut kernel_image,
-            None,
-        ) {
-            Ok(value) => value,
-            Err(error) => panic!("{}", error),
-        };
+    /// Makes `run` wait for gdb to connect on `addr` before starting the guest, which is then
+    /// debugged until gdb detaches. See `GdbStub` for what the debugger can do.
+    pub fn start_gdb_server(&mut self, addr: SocketAddr) -> Result<(), VmError> {
+        self.gdb = Some(GdbStub::bind(addr).map_err(VmError::Io)?);
 
-        kernel
+        Ok(())
     }
 
-    fn create_kvm(guest_memory: &GuestMemoryMmap) -> (Kvm, VmFd) {
-        let kvm = match Kvm::new() {
-            Ok(kvm) => kvm,
-            Err(error) => panic!("{}", error),
-        };
-        let kvm_fd = match kvm.create_vm() {
-            Ok(value) => value,
-            Err(error) => panic!("{}", error),
-        };
+    /// Stops the device thread and waits for it to exit. Dropping the VM does this too.
+    pub fn shutdown(&mut self) {
+        if let Some(device_thread) = self.device_thread.take() {
+            device_thread.stop.store(true, Ordering::Release);
+            match device_thread.handle.join() {
+                Ok(event_manager) => self.event_manager = Some(event_manager),
+                // the panic was already reported, and this may run in `drop`
+                Err(_) => error!("device thread panicked"),
+            }
+        }
+    }
 
-        // set kvm memory regions
-        let _ = match guest_memory
-            .iter()
-            .enumerate()
-            .try_for_each(|(index, region)| {
-                let flags: u32 = 0u32;
+    /// Recreates a VM from a snapshot taken with `snapshot`, resuming where it left off.
+    pub fn restore(dir: &Path) -> Result<Vm, VmError> {
+        let mut state_file = File::open(dir.join(SNAPSHOT_STATE_FILE)).map_err(VmError::Io)?;
+        let state = VmState::deserialize(&mut state_file, &VersionMap::new(), SNAPSHOT_VERSION)
+            .map_err(VmError::Snapshot)?;
 
-                let memory_region = kvm_userspace_memory_region {
-                    slot: u32::try_from(index).unwrap(),
-                    guest_phys_addr: region.start_addr().raw_value(),
-                    memory_size: region.len(),
-                    userspace_addr: guest_memory.get_host_address(region.start_addr()).unwrap()
-                        as u64,
-                    flags,
-                };
+        // The memory file is mapped private, so the restored guest never modifies the snapshot.
+        let memory_file = File::open(dir.join(SNAPSHOT_MEMORY_FILE)).map_err(VmError::Io)?;
+        let guest_memory = GuestMemoryMmap::with_file(&memory_file, false, false, false, None)
+            .map_err(VmError::Memory)?;
 
-                unsafe { kvm_fd.set_user_memory_region(memory_region) }
-            }) {
-            Ok(value) => value,
-            Err(error) => panic!("{}", error),
-        };
+        let (_kvm, kvm_fd) = Vm::create_kvm(&guest_memory)?;
 
-        (kvm, kvm_fd)
-    }
+        let mut cpu = Vm::create_cpu(&kvm_fd);
 
-    fn create_cpu(kvm_fd: &VmFd) -> Cpu {
-        let exit_evt = match EventFd::new(libc::EFD_NONBLOCK) {
-            Ok(value) => value,
-            Err(error) => panic!("{}", error),
-        };
+        let gic = Vm::create_gic(&kvm_fd);
 
-        let cpu = cpu::Cpu::new(0, kvm_fd, exit_evt);
+        cpu.init(&kvm_fd, &state.cpu_features);
+        cpu.restore_state(&state.cpu).map_err(VmError::Kvm)?;
+        gic.restore_device(&[state.cpu.mpidr], &state.gic)
+            .map_err(VmError::Gic)?;
 
-        // setup interrupt handler
-        let _ = match gicv::GICv2::create(kvm_fd, 1) {
-            Ok(value) => value,
-            Err(_) => panic!("cannot create gicv2"),
+        let mut event_manager = EventManager::new().unwrap();
+        let vcpu_thread = VcpuThread::default();
+
+        let cmdline = Cmdline::try_from(&state.cmdline, 2048).unwrap();
+
+        let mut mmio_device_manager = MMIODeviceManager::new();
+
+        let mut balloon = None;
+        let mut block_devices = Vec::new();
+        let mut net_devices = Vec::new();
+        let mut block_metrics = Vec::new();
+        let mut net_metrics = Vec::new();
+
+        // re-attach virtio devices at their saved location, the virtio console's lost output
+        // is counted with the serial one
+        let mut serial_handles = SerialHandles::default();
+        let mut virtio_console = None;
+        let mut virtio_console_handles = SerialHandles {
+            lost_bytes: serial_handles.lost_bytes.clone(),
+            ..SerialHandles::default()
         };
+        for device_state in &state.virtio_devices {
+            let rate_limiter = |index: usize| {
+                device_state
+                    .rate_limiters
+                    .get(index)
+                    .copied()
+                    .unwrap_or_default()
+            };
 
-        cpu
-    }
+            // the transport restores the queues, including their size
+            let queue_size = |default| {
+                device_state
+                    .transport
+                    .queues
+                    .first()
+                    .map_or(default, |queue| queue.max_size)
+            };
+            let mut transport = match device_state.device_type {
+                TYPE_BLOCK => {
+                    let path = device_state
+                        .disk_path
+                        .as_ref()
+                        .ok_or_else(|| VmError::MissingDiskPath(device_state.id.clone()))?;
+                    let block_config = BlockConfig {
+                        id: device_state.id.clone(),
+                        path: PathBuf::from(path),
+                        rate_limiter: rate_limiter(0),
+                        io_engine: device_state.io_engine.unwrap_or_default(),
+                        queue_size: queue_size(BLOCK_QUEUE_SIZE),
+                        activation_timeout: None,
+                    };
+                    let block = Block::new(
+                        &block_config.id,
+                        Vm::open_disk(&block_config)?,
+                        block_config.rate_limiter,
+                        block_config.queue_size,
+                    );
+                    block_metrics.push(block.metrics.clone());
+                    block_devices.push(block_config);
+                    let block = Arc::new(Mutex::new(block));
+                    event_manager.add_subscriber(block.clone());
+                    MmioTransport::new(guest_memory.clone(), block, false)
+                }
+                TYPE_NET => {
+                    let net_config = NetConfig {
+                        id: device_state.id.clone(),
+                        mac: device_state.net_mac,
+                        host_dev_name: device_state.net_host_dev_name.clone(),
+                        rx_rate_limiter: rate_limiter(0),
+                        tx_rate_limiter: rate_limiter(1),
+                        queue_size: queue_size(NET_QUEUE_SIZE),
+                    };
+                    let net = Net::new(
+                        net_config.mac,
+                        Vm::open_tap(&net_config)?,
+                        net_config.rx_rate_limiter,
+                        net_config.tx_rate_limiter,
+                        net_config.queue_size,
+                    );
+                    net_metrics.push(net.metrics.clone());
+                    net_devices.push(net_config);
+                    let net = Arc::new(Mutex::new(net));
+                    event_manager.add_subscriber(net.clone());
+                    MmioTransport::new(guest_memory.clone(), net, false)
+                }
+                TYPE_BALLOON => {
+                    let mut device = Balloon::new();
+                    device.config = device_state.balloon_config.unwrap_or_default();
+                    let device = Arc::new(Mutex::new(device));
+                    event_manager.add_subscriber(device.clone());
+                    balloon = Some(device.clone());
+                    MmioTransport::new(guest_memory.clone(), device, false)
+                }
+                TYPE_CONSOLE => {
+                    let backend = device_state
+                        .console
+                        .as_ref()
+                        .map_or(ConsoleBackend::Stdio, ConsoleBackend::from);
+                    let (input, output) =
+                        Vm::open_console(backend.clone(), &mut virtio_console_handles)?;
+                    virtio_console = Some(backend);
+                    let device = Arc::new(Mutex::new(Console::new(input, output)));
+                    event_manager.add_subscriber(device.clone());
+                    MmioTransport::new(guest_memory.clone(), device, false)
+                }
+                device_type => return Err(VmError::UnknownDevice(device_type)),
+            };
+            transport.restore(&device_state.transport);
 
-    fn set_stdout_nonblocking() {
-        // SAFETY: Call is safe since parameters are valid.
-        let flags = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_GETFL, 0) };
-        if flags < 0 {
-            panic!("Could not get Firecracker stdout flags.");
-        }
-        // SAFETY: Call is safe since parameters are valid.
-        let rc =
-            unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_SETFL, flags | libc::O_NONBLOCK) };
-        if rc < 0 {
-            panic!("Could not set stdout to non-blocking.");
+            mmio_device_manager
+                .register_mmio_virtio(
+                    &kvm_fd,
+                    device_state.id.clone(),
+                    transport,
+                    &device_state.device_info,
+                )
+                .map_err(VmError::Bus)?;
         }
-    }
 
-    fn create_serial_device() -> Arc<Mutex<BusDevice>> {
-        let interrupt_evt = EventFdTrigger::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
-        let kick_stdin_read_evt = EventFdTrigger::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        // add serial device
+        let console = ConsoleBackend::from(&state.console);
+        let serial_device = Vm::create_serial_device(
+            console.clone(),
+            &mut serial_handles,
+            Some(&SerialState::from(&state.serial)),
+        )?;
+        event_manager.add_subscriber(serial_device.clone());
+        mmio_device_manager
+            .register_mmio_serial(&kvm_fd, serial_device, Some(state.serial_info.clone()))
+            .map_err(VmError::Bus)?;
 
-        let input = std::io::stdin();
-        let out = std::io::stdout();
+        // add rtc device
+        let rtc_device = Rtc::from_state(&RtcState::from(&state.rtc), NoEvents);
+        mmio_device_manager
+            .register_mmio_rtc(rtc_device, Some(state.rtc_info.clone()))
+            .map_err(VmError::Bus)?;
 
-        let serial = Arc::new(Mutex::new(BusDevice::Serial(SerialWrapper {
-            serial: Serial::with_events(
-                interrupt_evt,
-                SerialEventsWrapper {
-                    buffer_ready_event_fd: Some(kick_stdin_read_evt),
-                },
-                SerialOut::Stdout(out),
-            ),
-            input: Some(input),
-        })));
+        // add pci host bridge, it always sits at the same address
+        if state.pci {
+            mmio_device_manager
+                .register_pci_root(PciRoot::new())
+                .map_err(VmError::Bus)?;
+        }
+
+        // add watchdog device, armed again if the guest had enabled it
+        let mut watchdog = None;
+        let mut watchdog_reset = None;
+        if let Some(watchdog_state) = &state.watchdog {
+            let timeout = Duration::from_millis(watchdog_state.timeout_ms);
+            register_kick_handler().map_err(VmError::Io)?;
+            let (device, reset_evt) = Vm::create_watchdog(timeout, &vcpu_thread)?;
+            if watchdog_state.enabled {
+                let mut device = device.lock().expect("Poisoned lock");
+                device.watchdog_mut().unwrap().enable();
+            }
+            event_manager.add_subscriber(device.clone());
+            mmio_device_manager
+                .register_mmio_watchdog(device.clone(), Some(watchdog_state.device_info.clone()))
+                .map_err(VmError::Bus)?;
+            watchdog = Some(device);
+            watchdog_reset = Some(reset_evt);
+        }
 
-        serial
+        Ok(Vm {
+            fd: kvm_fd,
+            cpu,
+            cpu_features: state.cpu_features,
+            gic,
+            memory: guest_memory,
+            mmio_device_manager,
+            cmdline,
+            memory_size: state.memory_size as usize,
+            kernel_path: None,
+            initrd_path: None,
+            initrd: None,
+            block_devices,
+            net_devices,
+            balloon,
+            console,
+            virtio_console,
+            serial_pty_path: serial_handles.pty_path,
+            console_buffer: serial_handles.buffer,
+            virtio_console_pty_path: virtio_console_handles.pty_path,
+            virtio_console_buffer: virtio_console_handles.buffer,
+            serial_lost_bytes: serial_handles.lost_bytes,
+            block_metrics,
+            net_metrics,
+            reboot_tracker: RebootTracker::default(),
+            event_manager: Some(event_manager),
+            device_thread: None,
+            api_server: None,
+            api_calls: None,
+            pause: PauseHandle::new(vcpu_thread.clone()),
+            vcpu_thread,
+            vcpu_affinity: None,
+            gdb: None,
+            watchdog,
+            watchdog_reset,
+            stdout_flags: None,
+        })
+    }
+
+    /// Completes the requests pending in every virtio queue and syncs the block backends, so
+    /// the devices can be saved without losing in flight I/O. The vcpu must not be running,
+    /// otherwise it can queue new requests right after. Serial output needs no flushing, the
+    /// serial device flushes its output after every byte.
+    pub fn quiesce(&self) -> Result<(), VmError> {
+        for ((device_type, _), device_info) in &self.mmio_device_manager.id_to_dev_info {
+            if let DeviceType::Virtio(_) = device_type {
+                let (_, device) = self
+                    .mmio_device_manager
+                    .bus
+                    .get_device(device_info.addr)
+                    .unwrap();
+                device
+                    .lock()
+                    .expect("Poisoned lock")
+                    .mmio_transport_ref()
+                    .unwrap()
+                    .locked_device()
+                    .quiesce()
+                    .map_err(VmError::Quiesce)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Saves guest memory plus the vcpu, GIC and device state into `dir`. The vcpu must not be
+    /// running, otherwise the saved state is inconsistent.
+    pub fn snapshot(&self, dir: &Path) -> Result<(), VmError> {
+        self.quiesce()?;
+
+        std::fs::create_dir_all(dir).map_err(VmError::Io)?;
+
+        let mut memory_file = File::create(dir.join(SNAPSHOT_MEMORY_FILE)).map_err(VmError::Io)?;
+        for region in self.memory.iter() {
+            self.memory
+                .write_all_volatile_to(region.start_addr(), &mut memory_file, region.len() as usize)
+                .map_err(VmError::GuestMemory)?;
+        }
+
+        let cpu = self.cpu.save_state().map_err(VmError::Kvm)?;
+        let gic = self.gic.save_device(&[cpu.mpidr]).map_err(VmError::Gic)?;
+
+        let mut virtio_devices = Vec::new();
+        for ((device_type, id), device_info) in &self.mmio_device_manager.id_to_dev_info {
+            if let DeviceType::Virtio(device_type) = device_type {
+                let (_, device) = self
+                    .mmio_device_manager
+                    .bus
+                    .get_device(device_info.addr)
+                    .unwrap();
+                let transport = device
+                    .lock()
+                    .expect("Poisoned lock")
+                    .mmio_transport_ref()
+                    .unwrap()
+                    .save();
+
+                let block_config = self
+                    .block_devices
+                    .iter()
+                    .find(|block_config| &block_config.id == id);
+                let net_config = self
+                    .net_devices
+                    .iter()
+                    .find(|net_config| &net_config.id == id);
+
+                let disk_path = block_config
+                    .map(|block_config| block_config.path.to_string_lossy().into_owned());
+                let io_engine = block_config.map(|block_config| block_config.io_engine);
+                let net_mac = net_config.and_then(|net_config| net_config.mac);
+                let net_host_dev_name =
+                    net_config.and_then(|net_config| net_config.host_dev_name.clone());
+
+                let balloon_config = match (*device_type, &self.balloon) {
+                    (TYPE_BALLOON, Some(balloon)) => {
+                        Some(balloon.lock().expect("Poisoned lock").config)
+                    }
+                    _ => None,
+                };
+                let console = match *device_type {
+                    TYPE_CONSOLE => self.virtio_console.as_ref().map(ConsoleBackendState::from),
+                    _ => None,
+                };
+
+                let mut rate_limiters = Vec::new();
+                if let Some(block_config) = block_config {
+                    rate_limiters.push(block_config.rate_limiter);
+                }
+                if let Some(net_config) = net_config {
+                    rate_limiters.push(net_config.rx_rate_limiter);
+                    rate_limiters.push(net_config.tx_rate_limiter);
+                }
+
+                virtio_devices.push(VirtioDeviceState {
+                    device_type: *device_type,
+                    id: id.clone(),
+                    device_info: device_info.clone(),
+                    transport,
+                    disk_path,
+                    io_engine,
+                    net_mac,
+                    net_host_dev_name,
+                    rate_limiters,
+                    balloon_config,
+                    console,
+                });
+            }
+        }
+        virtio_devices.sort_by_key(|device_state| device_state.device_info.addr);
+
+        let state = VmState {
+            memory_size: self.memory_size as u64,
+            cmdline: self.cmdline.as_cstring().unwrap().into_string().unwrap(),
+            cpu,
+            cpu_features: self.cpu_features,
+            gic,
+            virtio_devices,
+            serial_info: self
+                .mmio_device_manager
+                .id_to_dev_info
+                .get(&(DeviceType::Serial, "Serial".to_string()))
+                .unwrap()
+                .clone(),
+            serial: self.serial_state(),
+            console: ConsoleBackendState::from(&self.console),
+            rtc_info: self
+                .mmio_device_manager
+                .id_to_dev_info
+                .get(&(DeviceType::Rtc, "Rtc".to_string()))
+                .unwrap()
+                .clone(),
+            rtc: self.rtc_state(),
+            pci: self
+                .mmio_device_manager
+                .id_to_dev_info
+                .contains_key(&(DeviceType::Pci, DeviceType::Pci.to_string())),
+            watchdog: self.watchdog_state(),
+        };
+
+        let mut state_file = File::create(dir.join(SNAPSHOT_STATE_FILE)).map_err(VmError::Io)?;
+        state
+            .serialize(&mut state_file, &VersionMap::new(), SNAPSHOT_VERSION)
+            .map_err(VmError::Snapshot)
+    }
+
+    fn serial_state(&self) -> SerialDeviceState {
+        let device_info = &self.mmio_device_manager.id_to_dev_info
+            [&(DeviceType::Serial, DeviceType::Serial.to_string())];
+        let (_, device) = self
+            .mmio_device_manager
+            .bus
+            .get_device(device_info.addr)
+            .unwrap();
+        let device = device.lock().expect("Poisoned lock");
+        device.serial_ref().unwrap().serial.state().into()
+    }
+
+    fn watchdog_state(&self) -> Option<WatchdogState> {
+        let watchdog = self.watchdog.as_ref()?;
+        let device_info = self
+            .mmio_device_manager
+            .id_to_dev_info
+            .get(&(DeviceType::Watchdog, DeviceType::Watchdog.to_string()))?
+            .clone();
+        let watchdog = watchdog.lock().expect("Poisoned lock");
+        let watchdog = watchdog.watchdog_ref()?;
+
+        Some(WatchdogState {
+            device_info,
+            timeout_ms: watchdog.timeout().as_millis() as u64,
+            enabled: watchdog.is_enabled(),
+        })
+    }
+
+    fn rtc_state(&self) -> RtcDeviceState {
+        let device_info = &self.mmio_device_manager.id_to_dev_info
+            [&(DeviceType::Rtc, DeviceType::Rtc.to_string())];
+        let (_, device) = self
+            .mmio_device_manager
+            .bus
+            .get_device(device_info.addr)
+            .unwrap();
+        let device = device.lock().expect("Poisoned lock");
+        device.rtc_ref().unwrap().state().into()
+    }
+
+    /// Checks the VM has what `configure` needs, so a missing device is reported instead of
+    /// booting a guest without a console.
+    pub fn validate(&self) -> Result<(), VmError> {
+        if self.memory.num_regions() == 0 {
+            return Err(VmError::NoMemory);
+        }
+
+        let serial = (DeviceType::Serial, DeviceType::Serial.to_string());
+        if !self
+            .mmio_device_manager
+            .id_to_dev_info
+            .contains_key(&serial)
+        {
+            return Err(VmError::MissingDevice(DeviceType::Serial));
+        }
+
+        Ok(())
+    }
+
+    pub fn configure(&mut self) -> Result<(), VmError> {
+        self.validate()?;
+
+        self.cpu.init(&self.fd, &self.cpu_features);
+        self.cpu.configure_regs(&self.memory);
+        self.cpu.configure_mpidr().map_err(VmError::Kvm)?;
+
+        self.write_fdt()
+    }
+
+    fn write_fdt(&self) -> Result<(), VmError> {
+        let raw = self.build_fdt()?;
+
+        // write fdt to memory
+        let ftd_addr = GuestAddress(get_fdt_addr(&self.memory));
+        self.memory
+            .write_slice(raw.fdt_blob.as_slice(), ftd_addr)
+            .map_err(VmError::GuestMemory)
+    }
+
+    /// Generates the FDT describing the VM's memory, devices and cmdline, the blob `configure`
+    /// writes to guest memory. The vcpu's MPIDR is only known once `configure` ran.
+    pub fn build_fdt(&self) -> Result<Fdt, VmError> {
+        Vm::layout_fdt(
+            self.cpu_features.pmu,
+            self.cpu.mpidr(),
+            &self.mmio_device_manager,
+            self.initrd,
+            &self.cmdline,
+            &self.memory,
+        )
+    }
+
+    fn layout_fdt(
+        pmu: bool,
+        mpidr: u64,
+        mmio_device_manager: &MMIODeviceManager,
+        initrd: Option<(u64, u64)>,
+        cmdline: &Cmdline,
+        memory: &GuestMemoryMmap,
+    ) -> Result<Fdt, VmError> {
+        let mut fdt = FdtBuilder::new();
+        fdt.with_pmu(pmu);
+        fdt.with_cpu_mpidr(mpidr);
+
+        // every registered device gets a node, in the order they were attached
+        let mut devices: Vec<(&DeviceType, &MMIODeviceInfo)> = mmio_device_manager
+            .id_to_dev_info
+            .iter()
+            .map(|((device_type, _), device_info)| (device_type, device_info))
+            .collect();
+        devices.sort_by_key(|(_, device_info)| device_info.addr);
+        for (device_type, device_info) in devices {
+            match device_type {
+                DeviceType::Virtio(_) => {
+                    fdt.add_virtio_device(device_info.addr, device_info.len, device_info.irqs[0])
+                }
+                DeviceType::Serial => fdt.with_serial_console(device_info.addr, device_info.len),
+                DeviceType::Rtc => fdt.with_rtc(device_info.addr, device_info.len),
+                DeviceType::Pci => fdt.with_pci(device_info.addr, device_info.len),
+                DeviceType::Watchdog => fdt.with_watchdog(device_info.addr, device_info.len),
+            };
+        }
+
+        if let Some((addr, size)) = initrd {
+            fdt.with_initrd(addr, size);
+        }
+
+        fdt.with_cmdline(cmdline.as_cstring().unwrap().into_string().unwrap());
+        fdt.with_mem_regions(
+            memory
+                .iter()
+                .map(|region| (region.start_addr().raw_value(), region.len()))
+                .collect(),
+        );
+
+        let raw = fdt.create_fdt().map_err(VmError::FdtCreate)?;
+        raw.validate().map_err(VmError::Fdt)?;
+
+        Ok(raw)
+    }
+
+    /// Reads the FDT written by `configure` back from guest memory.
+    pub fn read_guest_fdt(&self) -> Result<Fdt, VmError> {
+        let fdt_addr = GuestAddress(get_fdt_addr(&self.memory));
+        Fdt::from_guest_memory(&self.memory, fdt_addr).map_err(VmError::Fdt)
+    }
+
+    /// Fills `buf` with guest memory starting at `addr`. Fails when the range isn't entirely
+    /// backed by guest memory.
+    pub fn read_guest(&self, addr: GuestAddress, buf: &mut [u8]) -> Result<(), VmError> {
+        self.memory
+            .read_slice(buf, addr)
+            .map_err(VmError::GuestMemory)
+    }
+
+    /// Writes `buf` to guest memory starting at `addr`. Fails when the range isn't entirely
+    /// backed by guest memory.
+    pub fn write_guest(&self, addr: GuestAddress, buf: &[u8]) -> Result<(), VmError> {
+        self.memory
+            .write_slice(buf, addr)
+            .map_err(VmError::GuestMemory)
+    }
+
+    /// Asks the guest to inflate or deflate its balloon until it holds `mb` MiB.
+    pub fn set_balloon_target(&self, mb: u64) -> Result<(), VmError> {
+        let balloon = self.balloon.as_ref().ok_or(VmError::BalloonNotAttached)?;
+
+        // The balloon counts in 4K pages, whatever the guest page size is.
+        let num_pages = u32::try_from(mb << 8).unwrap_or(u32::MAX);
+        balloon
+            .lock()
+            .expect("Poisoned lock")
+            .update_target(num_pages)
+            .map_err(VmError::Io)
+    }
+
+    /// The memory statistics the guest reported last, and asks it for fresh ones. Only
+    /// guests whose driver accepted the stats queue report anything.
+    pub fn balloon_stats(&self) -> Result<BalloonStats, VmError> {
+        let balloon = self.balloon.as_ref().ok_or(VmError::BalloonNotAttached)?;
+
+        let mut balloon = balloon.lock().expect("Poisoned lock");
+        if let Err(err) = balloon.request_stats() {
+            warn!("failed to request balloon stats: {:?}", err);
+        }
+        Ok(balloon.stats())
+    }
+
+    /// Pulses the interrupt `gsi` from the host, as a device would through its irqfd.
+    pub fn trigger_irq(&self, gsi: u32) -> Result<(), VmError> {
+        if !(IRQ_BASE..=IRQ_MAX).contains(&gsi) {
+            return Err(VmError::InvalidIrq(gsi));
+        }
+
+        // KVM_IRQ_LINE takes the GIC interrupt id, SPIs come after the 32 SGIs and PPIs
+        let irq = (kvm_bindings::KVM_ARM_IRQ_TYPE_SPI << kvm_bindings::KVM_ARM_IRQ_TYPE_SHIFT)
+            | (gsi + 32);
+        self.fd.set_irq_line(irq, true).map_err(VmError::Kvm)?;
+        self.fd.set_irq_line(irq, false).map_err(VmError::Kvm)
+    }
+
+    /// Selects the optional vcpu features, must be called before `configure`.
+    pub fn set_cpu_features(&mut self, features: CpuFeatures) {
+        self.cpu_features = features;
+    }
+
+    /// Limits how many times the guest may reboot within a minute before the VM is stopped
+    /// with `VmExitReason::RebootLoop`. `None`, the default, allows any number of reboots.
+    pub fn set_max_reboots(&mut self, max_reboots: Option<u32>) {
+        self.reboot_tracker = RebootTracker::new(max_reboots);
+    }
+
+    /// Pins the thread running vcpu `vcpu_index` to the host cpus in `cpuset`. The thread is
+    /// the one calling `run`, it's pinned each time `run` starts.
+    pub fn set_vcpu_affinity(&mut self, vcpu_index: u8, cpuset: &[usize]) -> Result<(), VmError> {
+        if vcpu_index != 0 {
+            return Err(VmError::InvalidVcpu(vcpu_index));
+        }
+        if cpuset.is_empty() || cpuset.iter().any(|&cpu| cpu >= libc::CPU_SETSIZE as usize) {
+            return Err(VmError::InvalidCpuSet);
+        }
+
+        if let Some(thread) = *self.vcpu_thread.lock().expect("Poisoned lock") {
+            Vm::set_thread_affinity(thread, cpuset).map_err(VmError::Io)?;
+        }
+        self.vcpu_affinity = Some(cpuset.to_vec());
+
+        Ok(())
+    }
+
+    /// Controls pausing the vcpu from other threads while `run` owns the VM.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Stops the vcpu between two guest exits, see `PauseHandle::pause`. Called before `run`,
+    /// the guest doesn't start until `resume`.
+    pub fn pause(&self) -> Result<(), VmError> {
+        self.pause.pause().map_err(VmError::Io)
+    }
+
+    /// Lets a paused vcpu run again.
+    pub fn resume(&self) {
+        self.pause.resume()
+    }
+
+    /// Runs the vcpu on the current thread until the guest powers off. A guest reboot
+    /// restarts it in place, reloading the kernel when the VM was booted from one.
+    pub fn run(&mut self) -> Result<VmExitReason, VmError> {
+        // SAFETY: Plain syscall without arguments.
+        let thread = unsafe { libc::pthread_self() };
+        if let Some(cpuset) = &self.vcpu_affinity {
+            Vm::set_thread_affinity(thread, cpuset).map_err(VmError::Io)?;
+        }
+
+        *self.vcpu_thread.lock().expect("Poisoned lock") = Some(thread);
+        let result = self.run_vcpu();
+        *self.vcpu_thread.lock().expect("Poisoned lock") = None;
+
+        result
+    }
+
+    fn run_vcpu(&mut self) -> Result<VmExitReason, VmError> {
+        // a debugged guest only starts once gdb resumes it
+        if let Some(reason) = self.gdb_stop(StopReason::Trap)? {
+            return Ok(reason);
+        }
+
+        loop {
+            if self.handle_api_calls() {
+                return Ok(VmExitReason::Stopped);
+            }
+            // a shutdown requested while paused doesn't wait for a resume
+            while self.pause.wait_while_paused(PAUSE_POLL_INTERVAL) {
+                if self.handle_api_calls() {
+                    return Ok(VmExitReason::Stopped);
+                }
+            }
+
+            let watch = match &self.gdb {
+                Some(gdb) => gdb
+                    .watch_interrupts(self.vcpu_thread.clone())
+                    .map_err(VmError::Io)?,
+                None => None,
+            };
+            let exit = self
+                .cpu
+                .run(&self.mmio_device_manager.bus)
+                .map_err(VmError::Kvm)?;
+            drop(watch);
+
+            match exit {
+                CpuExit::Shutdown => return Ok(VmExitReason::Shutdown),
+                CpuExit::Interrupted => {
+                    if self.watchdog_expired() {
+                        if let Err(reason) = self.record_reboot() {
+                            return Ok(reason);
+                        }
+                        self.reboot()?;
+                    }
+                    if self.gdb_interrupted()? {
+                        if let Some(reason) = self.gdb_stop(StopReason::Interrupt)? {
+                            return Ok(reason);
+                        }
+                    }
+                }
+                CpuExit::Debug => {
+                    if let Some(reason) = self.gdb_stop(StopReason::Trap)? {
+                        return Ok(reason);
+                    }
+                }
+                CpuExit::Reboot => {
+                    if let Err(reason) = self.record_reboot() {
+                        return Ok(reason);
+                    }
+                    self.reboot()?;
+                }
+                CpuExit::Unhandled => return Err(VmError::UnhandledVcpuExit),
+            }
+        }
+    }
+
+    // Hands the stopped vcpu to gdb until it resumes the guest. Returns the exit reason when
+    // gdb killed the guest.
+    fn gdb_stop(&mut self, reason: StopReason) -> Result<Option<VmExitReason>, VmError> {
+        let gdb = match &mut self.gdb {
+            Some(gdb) => gdb,
+            None => return Ok(None),
+        };
+        let action = match gdb.handle_stop(&self.cpu, &self.memory, reason) {
+            Ok(action) => action,
+            Err(err) => {
+                warn!("gdb connection failed, detaching: {:?}", err);
+                GdbAction::Detach
+            }
+        };
+
+        // breakpoints are BRK instructions gdb writes into guest memory
+        let controls: &[GuestDebug] = match action {
+            GdbAction::Continue => &[GuestDebug::SoftwareBreakpoints],
+            GdbAction::Step => &[GuestDebug::SoftwareBreakpoints, GuestDebug::SingleStep],
+            GdbAction::Detach => {
+                self.gdb_detach()?;
+                return Ok(None);
+            }
+            GdbAction::Kill => {
+                self.gdb = None;
+                return Ok(Some(VmExitReason::Stopped));
+            }
+        };
+        self.cpu.set_guest_debug(controls).map_err(VmError::Kvm)?;
+
+        Ok(None)
+    }
+
+    // Whether the vcpu was interrupted because gdb asked for it.
+    fn gdb_interrupted(&mut self) -> Result<bool, VmError> {
+        let gdb = match &mut self.gdb {
+            Some(gdb) => gdb,
+            None => return Ok(false),
+        };
+        match gdb.take_interrupt() {
+            Ok(interrupted) => Ok(interrupted),
+            Err(err) => {
+                warn!("gdb connection failed, detaching: {:?}", err);
+                self.gdb_detach()?;
+                Ok(false)
+            }
+        }
+    }
+
+    // The guest keeps running without debugging, gdb is expected to have removed its
+    // breakpoints.
+    fn gdb_detach(&mut self) -> Result<(), VmError> {
+        self.gdb = None;
+        self.cpu.set_guest_debug(&[]).map_err(VmError::Kvm)
+    }
+
+    // Whether the vcpu was interrupted because the watchdog expired.
+    fn watchdog_expired(&self) -> bool {
+        match &self.watchdog_reset {
+            Some(reset_evt) => reset_evt.read().is_ok(),
+            None => false,
+        }
+    }
+
+    // Returns true when one of the calls asked to stop the VM.
+    fn handle_api_calls(&mut self) -> bool {
+        let api_calls = match self.api_calls.take() {
+            Some(api_calls) => api_calls,
+            None => return false,
+        };
+
+        let mut stop = false;
+        handle_calls(&api_calls, |request| match request {
+            ApiRequest::Shutdown => {
+                stop = true;
+                ApiResponse::Ok
+            }
+            ApiRequest::BalloonSet { mb } => match self.set_balloon_target(mb) {
+                Ok(()) => ApiResponse::Ok,
+                Err(err) => ApiResponse::Error(format!("{:?}", err)),
+            },
+            ApiRequest::GetMetrics => ApiResponse::Metrics(self.metrics()),
+        });
+        self.api_calls = Some(api_calls);
+
+        stop
+    }
+
+    /// Restarts the guest from scratch in the same memory: reloads the kernel and initrd,
+    /// resets the virtio devices and the vcpu registers, and rewrites the FDT. The vcpu must
+    /// not be running.
+    pub fn reboot(&mut self) -> Result<(), VmError> {
+        // the rebooted guest enables the watchdog again if it wants one
+        if let Some(watchdog) = &self.watchdog {
+            let mut watchdog = watchdog.lock().expect("Poisoned lock");
+            watchdog.watchdog_mut().unwrap().disarm();
+        }
+        // a reset signalled before the disarm is handled by this reboot
+        let _ = self.watchdog_expired();
+
+        if let Some(kernel_path) = &self.kernel_path {
+            let kernel = Vm::load_kernel(&self.memory, kernel_path)?;
+            if let Some(initrd_path) = &self.initrd_path {
+                self.initrd = Some(Vm::load_initrd(&self.memory, &kernel, initrd_path)?);
+            }
+        }
+
+        // requests the old kernel left on the queues are dropped with them
+        self.mmio_device_manager.reset_virtio_devices();
+
+        self.cpu.reset().map_err(VmError::Kvm)?;
+        self.cpu.configure_regs(&self.memory);
+        self.write_fdt()
+    }
+
+    /// Called whenever the guest reboots, returns the reason to stop the VM if it rebooted too
+    /// often.
+    fn record_reboot(&mut self) -> Result<(), VmExitReason> {
+        if self.reboot_tracker.record(std::time::Instant::now()) {
+            Ok(())
+        } else {
+            Err(VmExitReason::RebootLoop)
+        }
+    }
+
+    /// Lists the devices the VM was built with and where they sit in the guest.
+    pub fn devices(&self) -> Vec<DeviceSummary> {
+        self.mmio_device_manager.devices()
+    }
+
+    /// Reads the device counters. The devices keep running while they are read, so the values
+    /// of different counters may be a few requests apart.
+    pub fn metrics(&self) -> VmMetrics {
+        VmMetrics {
+            block: DeviceMetrics::total(&self.block_metrics),
+            net: DeviceMetrics::total(&self.net_metrics),
+            serial_lost_bytes: self.serial_lost_bytes.count(),
+        }
+    }
+
+    /// Path of the pty slave the serial console is attached to, when using `ConsoleBackend::Pty`.
+    pub fn serial_pty_path(&self) -> Option<&Path> {
+        self.serial_pty_path.as_deref()
+    }
+
+    /// Guest console output, when using `ConsoleBackend::Buffer`. Callers may drain it to
+    /// consume what they've read.
+    pub fn console_buffer(&self) -> Option<ConsoleBuffer> {
+        self.console_buffer.clone()
+    }
+
+    /// Like `serial_pty_path`, for the virtio console.
+    pub fn virtio_console_pty_path(&self) -> Option<&Path> {
+        self.virtio_console_pty_path.as_deref()
+    }
+
+    /// Like `console_buffer`, for the virtio console.
+    pub fn virtio_console_buffer(&self) -> Option<ConsoleBuffer> {
+        self.virtio_console_buffer.clone()
+    }
+
+    // `mem_size` is in bytes
+    fn create_memory(
+        mem_size: usize,
+        huge_pages: HugePages,
+        prefault: bool,
+        numa_node: Option<u32>,
+    ) -> Result<GuestMemoryMmap, VmError> {
+        let memfd = memory::create_memfd(mem_size, huge_pages);
+        GuestMemoryMmap::with_file(memfd.as_file(), false, true, prefault, numa_node)
+            .map_err(VmError::Memory)
+    }
+
+    fn load_kernel(
+        guest_memory: &GuestMemoryMmap,
+        path: &Path,
+    ) -> Result<KernelLoaderResult, VmError> {
+        let mut kernel_image = File::open(path).map_err(VmError::Io)?;
+        let mut magic = [0; 2];
+        let compressed = match kernel_image.read_exact(&mut magic) {
+            Ok(()) => &magic == GZIP_MAGIC,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(err) => return Err(VmError::Io(err)),
+        };
+        kernel_image.rewind().map_err(VmError::Io)?;
+        if !compressed {
+            return Vm::load_kernel_image(guest_memory, &mut kernel_image);
+        }
+
+        // the loader seeks around the kernel, so it's inflated in memory first, the inflated
+        // kernel has to fit in guest memory anyway
+        let mem_size = guest_memory.iter().map(|region| region.len()).sum();
+        let mut inflated = Vec::new();
+        GzDecoder::new(kernel_image)
+            .take(mem_size)
+            .read_to_end(&mut inflated)
+            .map_err(VmError::Io)?;
+
+        Vm::load_kernel_image(guest_memory, &mut Cursor::new(inflated))
+    }
+
+    fn load_kernel_image<F: Read + ReadVolatile + Seek>(
+        guest_memory: &GuestMemoryMmap,
+        kernel_image: &mut F,
+    ) -> Result<KernelLoaderResult, VmError> {
+        let mut header = [0; IMAGE_HEADER_SIZE];
+        kernel_image
+            .read_exact(&mut header)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::UnexpectedEof => VmError::UnknownKernelFormat,
+                _ => VmError::Io(err),
+            })?;
+
+        match KernelFormat::detect(&header)? {
+            KernelFormat::Image => Vm::load_raw_image(guest_memory, kernel_image, &header),
+            KernelFormat::Pe => linux_loader::loader::pe::PE::load(
+                guest_memory,
+                Some(GuestAddress(DRAM_MEM_START)),
+                kernel_image,
+                None,
+            )
+            .map_err(VmError::Kernel),
+            // linux-loader only loads ELF kernels on x86
+            format @ KernelFormat::Elf => Err(VmError::UnsupportedKernelFormat(format)),
+        }
+    }
+
+    /// Copies a raw `Image` to `text_offset` past the start of DRAM, as the arm64 boot
+    /// protocol asks for.
+    fn load_raw_image<F: Read + ReadVolatile + Seek>(
+        guest_memory: &GuestMemoryMmap,
+        kernel_image: &mut F,
+        header: &[u8; IMAGE_HEADER_SIZE],
+    ) -> Result<KernelLoaderResult, VmError> {
+        let mut text_offset = u64::from_le_bytes(header[0x08..0x10].try_into().unwrap());
+        let image_size = u64::from_le_bytes(header[0x10..0x18].try_into().unwrap());
+        if image_size == 0 {
+            text_offset = LEGACY_TEXT_OFFSET;
+        }
+
+        let kernel_size = kernel_image.seek(SeekFrom::End(0)).map_err(VmError::Io)?;
+        let kernel_load = GuestAddress(DRAM_MEM_START)
+            .checked_add(text_offset)
+            .ok_or(VmError::Kernel(linux_loader::loader::Error::MemoryOverflow))?;
+        // the kernel's bss and early page tables live past the end of the file, up to
+        // `image_size`
+        let kernel_end = kernel_load
+            .checked_add(image_size.max(kernel_size))
+            .ok_or(VmError::Kernel(linux_loader::loader::Error::MemoryOverflow))?;
+
+        kernel_image.rewind().map_err(VmError::Io)?;
+        guest_memory
+            .read_exact_volatile_from(kernel_load, kernel_image, kernel_size as usize)
+            .map_err(VmError::GuestMemory)?;
+
+        Ok(KernelLoaderResult {
+            kernel_load,
+            kernel_end: kernel_end.raw_value(),
+        })
+    }
+
+    /// Loads the initrd right below the FDT and returns its guest address and size.
+    fn load_initrd(
+        guest_memory: &GuestMemoryMmap,
+        kernel: &KernelLoaderResult,
+        path: &Path,
+    ) -> Result<(u64, u64), VmError> {
+        let initrd = std::fs::read(path).map_err(VmError::Io)?;
+        let size = initrd.len() as u64;
+
+        let addr = get_fdt_addr(guest_memory)
+            .checked_sub(size)
+            .map(|addr| addr & !(INITRD_ALIGN - 1))
+            .filter(|addr| *addr >= kernel.kernel_end)
+            .ok_or(VmError::InitrdTooLarge)?;
+
+        guest_memory
+            .write_slice(&initrd, GuestAddress(addr))
+            .map_err(VmError::GuestMemory)?;
+
+        Ok((addr, size))
+    }
+
+    fn open_disk(config: &BlockConfig) -> Result<Box<dyn DiskBackend + Send>, VmError> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(&config.path)
+            .map_err(VmError::Io)?;
+
+        match config.io_engine {
+            IoEngine::Sync => Ok(Box::new(file)),
+            IoEngine::Async => {
+                let disk =
+                    IoUringDisk::new(file, u32::from(config.queue_size)).map_err(VmError::Io)?;
+                Ok(Box::new(disk))
+            }
+        }
+    }
+
+    fn open_tap(config: &NetConfig) -> Result<Option<Box<dyn NetBackend + Send>>, VmError> {
+        match &config.host_dev_name {
+            Some(if_name) => Ok(Some(Box::new(Tap::open(if_name).map_err(VmError::Io)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Asks the host's KVM which of the capabilities a VM needs it supports. Nothing is
+    /// supported when `/dev/kvm` can't be opened.
+    pub fn probe_host() -> HostCapabilities {
+        Kvm::new()
+            .map(|kvm| HostCapabilities::from_kvm(&kvm))
+            .unwrap_or_default()
+    }
+
+    /// How many vcpus the host's KVM allows a VM to have, `None` when `/dev/kvm` can't be
+    /// opened.
+    pub fn max_vcpus() -> Option<usize> {
+        Kvm::new().ok().map(|kvm| kvm.get_max_vcpus())
+    }
+
+    fn create_kvm(guest_memory: &GuestMemoryMmap) -> Result<(Kvm, VmFd), VmError> {
+        let kvm = Kvm::new().map_err(VmError::Kvm)?;
+        if let Some(cap) = HostCapabilities::from_kvm(&kvm).missing() {
+            return Err(VmError::MissingCapability(cap));
+        }
+        let kvm_fd = kvm.create_vm().map_err(VmError::Kvm)?;
+
+        // set kvm memory regions
+        guest_memory
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, region)| {
+                let flags: u32 = 0u32;
+
+                let memory_region = kvm_userspace_memory_region {
+                    slot: u32::try_from(index).unwrap(),
+                    guest_phys_addr: region.start_addr().raw_value(),
+                    memory_size: region.len(),
+                    userspace_addr: guest_memory.get_host_address(region.start_addr()).unwrap()
+                        as u64,
+                    flags,
+                };
+
+                unsafe { kvm_fd.set_user_memory_region(memory_region) }
+            })
+            .map_err(VmError::Kvm)?;
+
+        Ok((kvm, kvm_fd))
+    }
+
+    fn create_cpu(kvm_fd: &VmFd) -> Cpu {
+        let exit_evt = match EventFd::new(libc::EFD_NONBLOCK) {
+            Ok(value) => value,
+            Err(error) => panic!("{}", error),
+        };
+
+        cpu::Cpu::new(0, kvm_fd, exit_evt)
+    }
+
+    fn create_gic(kvm_fd: &VmFd) -> GICv2 {
+        // setup interrupt handler
+        match GICv2::create(kvm_fd, 1) {
+            Ok(value) => value,
+            Err(_) => panic!("cannot create gicv2"),
+        }
+    }
+
+    fn set_thread_affinity(thread: libc::pthread_t, cpuset: &[usize]) -> io::Result<()> {
+        // SAFETY: An all zero cpu_set_t is an empty set.
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in cpuset {
+            // SAFETY: `cpu` was checked to be below `CPU_SETSIZE`.
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+
+        // SAFETY: `thread` is a running thread and `set` a valid cpu_set_t of the given size.
+        let ret = unsafe {
+            libc::pthread_setaffinity_np(thread, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the flags stdout had before, to be restored with `restore_stdout_flags`.
+    fn set_stdout_nonblocking() -> i32 {
+        // SAFETY: Call is safe since parameters are valid.
+        let flags = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_GETFL, 0) };
+        if flags < 0 {
+            panic!("Could not get Firecracker stdout flags.");
+        }
+        // SAFETY: Call is safe since parameters are valid.
+        let rc =
+            unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+        if rc < 0 {
+            panic!("Could not set stdout to non-blocking.");
+        }
+
+        flags
+    }
+
+    fn restore_stdout_flags(flags: i32) {
+        // SAFETY: Call is safe since parameters are valid.
+        let rc = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_SETFL, flags) };
+        if rc < 0 {
+            warn!("Could not restore stdout flags.");
+        }
+    }
+
+    /// The watchdog and the eventfd it signals when it expires. It kicks the vcpu out of the
+    /// guest then, which needs `register_kick_handler` to have been called.
+    fn create_watchdog(
+        timeout: Duration,
+        vcpu_thread: &VcpuThread,
+    ) -> Result<(Arc<Mutex<BusDevice>>, EventFd), VmError> {
+        let reset_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(VmError::Io)?;
+        let device = Watchdog::new(
+            timeout,
+            reset_evt.try_clone().map_err(VmError::Io)?,
+            vcpu_thread.clone(),
+        )
+        .map_err(VmError::Io)?;
+
+        Ok((Arc::new(Mutex::new(BusDevice::Watchdog(device))), reset_evt))
+    }
+
+    /// With `state` the device starts with the registers and pending input of a saved one.
+    fn create_serial_device(
+        console: ConsoleBackend,
+        handles: &mut SerialHandles,
+        state: Option<&SerialState>,
+    ) -> Result<Arc<Mutex<BusDevice>>, VmError> {
+        let interrupt_evt = EventFdTrigger::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let kick_stdin_read_evt = EventFdTrigger::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let events = SerialEventsWrapper {
+            buffer_ready_event_fd: Some(kick_stdin_read_evt),
+        };
+
+        let (input, output) = Vm::open_console(console, handles)?;
+        let serial = match state {
+            Some(state) => Serial::from_state(state, interrupt_evt, events, output.clone())
+                .map_err(|_| VmError::InvalidSerialState)?,
+            None => Serial::with_events(interrupt_evt, events, output.clone()),
+        };
+
+        Ok(Arc::new(Mutex::new(BusDevice::Serial(SerialWrapper {
+            serial,
+            input,
+            output,
+        }))))
+    }
+
+    /// Opens the host side of a console, filling in the handles the backend has.
+    fn open_console(
+        console: ConsoleBackend,
+        handles: &mut SerialHandles,
+    ) -> Result<(Option<SerialInput>, BufferedOut), VmError> {
+        let (input, out) = match console {
+            ConsoleBackend::Stdio => (
+                Some(SerialInput::Stdin(std::io::stdin())),
+                SerialOut::Stdout(std::io::stdout()),
+            ),
+            ConsoleBackend::Pty => {
+                let pty = Pty::open().map_err(VmError::Io)?;
+                let out = pty.master.try_clone().map_err(VmError::Io)?;
+                handles.pty_path = Some(pty.slave_path.clone());
+
+                (Some(SerialInput::Pty(pty)), SerialOut::File(out))
+            }
+            ConsoleBackend::File(path) => {
+                let out = File::create(path).map_err(VmError::Io)?;
+
+                (None, SerialOut::File(out))
+            }
+            ConsoleBackend::Buffer(capacity) => {
+                let buffer = ConsoleBuffer::default();
+                handles.buffer = Some(buffer.clone());
+
+                (None, SerialOut::Buffer(buffer, capacity))
+            }
+        };
+
+        let output = BufferedOut::new(out, PENDING_CAPACITY, handles.lost_bytes.clone());
+
+        Ok((input, output))
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        // Dropping the calls first fails the pending ones, so the server thread can be joined.
+        self.api_calls = None;
+        self.api_server = None;
+        self.shutdown();
+        // the vcpu and the device thread are stopped, so nothing adds requests anymore
+        if let Err(err) = self.quiesce() {
+            warn!("failed to flush the devices: {:?}", err);
+        }
+        self.mmio_device_manager.unregister_eventfds(&self.fd);
+
+        if let Some(flags) = self.stdout_flags.take() {
+            Vm::restore_stdout_flags(flags);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::sync::atomic::AtomicU64;
+    use std::time::Instant;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use kvm_bindings::{PSR_MODE_EL1h, PSR_A_BIT, PSR_D_BIT, PSR_F_BIT, PSR_I_BIT};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::vmm::api::kick_vcpu;
+    use crate::vmm::memory::test_guest_memory;
+    use crate::vmm::mmio::mmio_manager::MMIO_LEN;
+
+    use super::*;
+
+    fn image_header(text_offset: u64) -> Vec<u8> {
+        let mut header = vec![0; IMAGE_HEADER_SIZE];
+        header[0x08..0x10].copy_from_slice(&text_offset.to_le_bytes());
+        header[0x10..0x18].copy_from_slice(&0x1000u64.to_le_bytes());
+        header[IMAGE_MAGIC_OFFSET..IMAGE_MAGIC_OFFSET + IMAGE_MAGIC.len()]
+            .copy_from_slice(IMAGE_MAGIC);
+        header
+    }
+
+    // Config of a VM booting a raw Image that spins in place, with its console in a buffer.
+    // `None` when the host has no KVM. The kernel file has to outlive the VM, reboots reload
+    // it.
+    fn test_vm_builder() -> Option<(VmBuilder, TempFile)> {
+        if Kvm::new().is_err() {
+            return None;
+        }
+        let kernel = TempFile::new().unwrap();
+        let mut image = image_header(0x8_0000);
+        // b .
+        image[..4].copy_from_slice(&0x1400_0000u32.to_le_bytes());
+        kernel.as_file().write_all(&image).unwrap();
+
+        let mut builder = VmBuilder::new();
+        builder
+            .memory_size(64)
+            .kernel(kernel.as_path())
+            .console(ConsoleBackend::Buffer(4096));
+        Some((builder, kernel))
+    }
+
+    fn test_vm() -> Option<(Vm, TempFile)> {
+        let (builder, kernel) = test_vm_builder()?;
+        Some((builder.build().unwrap(), kernel))
+    }
+
+    fn stdout_flags() -> i32 {
+        // SAFETY: Plain fcntl on stdout, it takes no pointers.
+        unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_GETFL) }
+    }
+
+    fn block_transport(memory: &GuestMemoryMmap) -> MmioTransport {
+        let block = Block::new(
+            "rootfs",
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            BLOCK_QUEUE_SIZE,
+        );
+        MmioTransport::new(memory.clone(), Arc::new(Mutex::new(block)), false)
+    }
+
+    #[test]
+    fn test_detect_kernel_format() {
+        let image = image_header(0);
+        assert_eq!(KernelFormat::detect(&image).unwrap(), KernelFormat::Image);
+
+        let mut pe = image.clone();
+        pe[..PE_MAGIC.len()].copy_from_slice(PE_MAGIC);
+        assert_eq!(KernelFormat::detect(&pe).unwrap(), KernelFormat::Pe);
+
+        let mut elf = vec![0; IMAGE_HEADER_SIZE];
+        elf[..ELF_MAGIC.len()].copy_from_slice(ELF_MAGIC);
+        assert_eq!(KernelFormat::detect(&elf).unwrap(), KernelFormat::Elf);
+
+        assert!(matches!(
+            KernelFormat::detect(&[0; IMAGE_HEADER_SIZE]),
+            Err(VmError::UnknownKernelFormat)
+        ));
+    }
+
+    #[test]
+    fn test_image_loaded_at_text_offset() {
+        let guest_memory = test_guest_memory(4 << 20);
+        let mut kernel = image_header(0x10_0000);
+        kernel.extend_from_slice(&[0xaa; 0x100]);
+
+        let result = Vm::load_kernel_image(&guest_memory, &mut Cursor::new(&kernel)).unwrap();
+
+        let load = GuestAddress(DRAM_MEM_START + 0x10_0000);
+        assert_eq!(result.kernel_load, load);
+        // the header's image_size is larger than the file
+        assert_eq!(result.kernel_end, load.raw_value() + 0x1000);
+        let mut loaded = vec![0; kernel.len()];
+        guest_memory.read_slice(&mut loaded, load).unwrap();
+        assert_eq!(loaded, kernel);
+    }
+
+    #[test]
+    fn test_image_end_covers_file() {
+        let guest_memory = test_guest_memory(4 << 20);
+        let mut kernel = image_header(0x10_0000);
+        kernel.extend_from_slice(&[0xaa; 0x2000]);
+
+        let result = Vm::load_kernel_image(&guest_memory, &mut Cursor::new(&kernel)).unwrap();
+
+        // the file is larger than the header's image_size
+        let load = DRAM_MEM_START + 0x10_0000;
+        assert_eq!(result.kernel_end, load + kernel.len() as u64);
+    }
+
+    #[test]
+    fn test_elf_kernel_unsupported() {
+        let guest_memory = test_guest_memory(4 << 20);
+        let mut kernel = vec![0; IMAGE_HEADER_SIZE];
+        kernel[..ELF_MAGIC.len()].copy_from_slice(ELF_MAGIC);
+
+        assert!(matches!(
+            Vm::load_kernel_image(&guest_memory, &mut Cursor::new(&kernel)),
+            Err(VmError::UnsupportedKernelFormat(KernelFormat::Elf))
+        ));
+    }
+
+    #[test]
+    fn test_build_config_only_two_drives() {
+        let kernel = TempFile::new().unwrap();
+        kernel.as_file().write_all(&image_header(0x8_0000)).unwrap();
+
+        // the disks don't exist, a dry run never opens them
+        let mut builder = VmBuilder::new();
+        builder
+            .kernel(kernel.as_path())
+            .add_block("rootfs", "/nonexistent/rootfs.ext4")
+            .add_block("data", "/nonexistent/data.ext4");
+
+        let layout = Vm::build_config_only(builder.config().clone()).unwrap();
+
+        layout.fdt.validate().unwrap();
+        let blocks: Vec<_> = layout
+            .devices
+            .iter()
+            .filter(|device| device.device_type == DeviceType::Virtio(TYPE_BLOCK))
+            .map(|device| device.id.as_str())
+            .collect();
+        assert_eq!(blocks, ["rootfs", "data"]);
+    }
+
+    #[test]
+    fn test_file_console_writes_output() {
+        let file = TempFile::new().unwrap();
+        let console = ConsoleBackend::File(file.as_path().to_path_buf());
+
+        let device =
+            Vm::create_serial_device(console, &mut SerialHandles::default(), None).unwrap();
+        for byte in b"hello" {
+            device.lock().unwrap().write(0, &[*byte]);
+        }
+        // dropping the device writes out and syncs what's left
+        drop(device);
+
+        assert_eq!(std::fs::read(file.as_path()).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_file_console_in_missing_directory() {
+        let console = ConsoleBackend::File(PathBuf::from("/nonexistent/console.log"));
+
+        assert!(matches!(
+            Vm::open_console(console, &mut SerialHandles::default()),
+            Err(VmError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_device_subscribers_kept_after_build() {
+        let (mut builder, _kernel) = match test_vm_builder() {
+            Some(builder) => builder,
+            None => return,
+        };
+        let disk = TempFile::new().unwrap();
+        disk.as_file().set_len(1 << 20).unwrap();
+        builder.add_block("rootfs", disk.as_path());
+        let mut vm = builder.build().unwrap();
+        assert!(vm.event_manager.is_some());
+
+        vm.spawn_device_thread().unwrap();
+        let block = vm
+            .devices()
+            .into_iter()
+            .find(|device| device.device_type == DeviceType::Virtio(TYPE_BLOCK))
+            .unwrap();
+        let ready_event = {
+            let (_, device) = vm.mmio_device_manager.bus.get_device(block.addr).unwrap();
+            let mut locked_device = device.lock().unwrap();
+            let transport = locked_device.mmio_transport_mut().unwrap();
+            let mut block = transport.locked_device();
+            block.activate(vm.memory.clone()).unwrap();
+            block.ready_event().unwrap().try_clone().unwrap()
+        };
+
+        // the block subscriber registered while building handles its activation on the
+        // device thread
+        let mut pollfd = libc::pollfd {
+            fd: ready_event.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is a valid pollfd for the eventfd, which outlives the call.
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 5000) }, 1);
+    }
+
+    #[test]
+    fn test_add_subscriber_without_event_loop() {
+        let (mut vm, _kernel) = match test_vm() {
+            Some(vm) => vm,
+            None => return,
+        };
+        // as left behind by a device thread that panicked
+        vm.event_manager = None;
+
+        let subscriber = Arc::new(Mutex::new(Block::new(
+            "data",
+            Box::new(MemDisk::new(1 << 20)),
+            RateLimiterConfig::default(),
+            BLOCK_QUEUE_SIZE,
+        )));
+        assert!(matches!(
+            vm.add_subscriber(subscriber),
+            Err(VmError::NoEventLoop)
+        ));
+    }
+
+    #[test]
+    fn test_guest_memory_round_trip() {
+        let (vm, _kernel) = match test_vm() {
+            Some(vm) => vm,
+            None => return,
+        };
+        let blob: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+
+        vm.write_guest(GuestAddress(DRAM_MEM_START), &blob).unwrap();
+
+        let mut read = vec![0; blob.len()];
+        vm.read_guest(GuestAddress(DRAM_MEM_START), &mut read)
+            .unwrap();
+        assert_eq!(read, blob);
+        // nothing is mapped below DRAM
+        assert!(matches!(
+            vm.read_guest(GuestAddress(DRAM_MEM_START - 0x100), &mut read),
+            Err(VmError::GuestMemory(_))
+        ));
+    }
+
+    #[test]
+    fn test_stdout_untouched_without_stdio_console() {
+        let (mut builder, _kernel) = match test_vm_builder() {
+            Some(builder) => builder,
+            None => return,
+        };
+        builder.nonblocking_stdout(true);
+        let flags = stdout_flags();
+
+        let vm = builder.build().unwrap();
+        assert_eq!(stdout_flags(), flags);
+        drop(vm);
+        assert_eq!(stdout_flags(), flags);
+    }
+
+    #[test]
+    fn test_drop_closes_fds() {
+        let open_fds = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+        let (builder, _kernel) = match test_vm_builder() {
+            Some(builder) => builder,
+            None => return,
+        };
+        // fds opened once for the whole process don't count
+        drop(builder.build().unwrap());
+
+        let before = open_fds();
+        for _ in 0..8 {
+            let mut vm = builder.build().unwrap();
+            vm.spawn_device_thread().unwrap();
+            drop(vm);
+        }
+
+        // each VM has a few dozen fds, other tests running meanwhile only account for a few
+        assert!(open_fds() < before + 8);
+    }
+
+    #[test]
+    fn test_configure_block_and_serial_only() {
+        let (mut builder, _kernel) = match test_vm_builder() {
+            Some(builder) => builder,
+            None => return,
+        };
+        let disk = TempFile::new().unwrap();
+        disk.as_file().set_len(1 << 20).unwrap();
+        builder.add_block("rootfs", disk.as_path());
+        let mut vm = builder.build().unwrap();
+
+        vm.configure().unwrap();
+
+        let fdt = vm.read_guest_fdt().unwrap();
+        fdt.validate().unwrap();
+        let node_paths = fdt.node_paths().unwrap();
+        let virtio_nodes = node_paths
+            .iter()
+            .filter(|path| path.starts_with("/virtio_mmio@"))
+            .count();
+        assert_eq!(virtio_nodes, 1);
+        assert!(node_paths.iter().any(|path| path.starts_with("/uart@")));
+    }
+
+    #[test]
+    fn test_reboot_resets_devices_and_vcpu() {
+        let (mut builder, _kernel) = match test_vm_builder() {
+            Some(builder) => builder,
+            None => return,
+        };
+        let disk = TempFile::new().unwrap();
+        disk.as_file().set_len(1 << 20).unwrap();
+        builder.add_block("rootfs", disk.as_path());
+        let mut vm = builder.build().unwrap();
+        vm.configure().unwrap();
+
+        let block = vm
+            .devices()
+            .into_iter()
+            .find(|device| device.device_type == DeviceType::Virtio(TYPE_BLOCK))
+            .unwrap();
+        {
+            let (_, device) = vm.mmio_device_manager.bus.get_device(block.addr).unwrap();
+            let mut locked_device = device.lock().unwrap();
+            let transport = locked_device.mmio_transport_mut().unwrap();
+            transport
+                .locked_device()
+                .activate(vm.memory.clone())
+                .unwrap();
+        }
+        assert!(vm.devices().iter().all(|device| device.activated));
+
+        // the old guest ran: registers moved and the kernel overwrote itself
+        let kernel_addr = GuestAddress(DRAM_MEM_START + 0x8_0000);
+        let x0 = cpu::core_reg_ids()[0];
+        vm.cpu.set_pstate(0).unwrap();
+        vm.cpu.set_reg(x0, 0).unwrap();
+        vm.write_guest(kernel_addr, &[0; 4]).unwrap();
+
+        vm.reboot().unwrap();
+
+        let block = vm
+            .devices()
+            .into_iter()
+            .find(|device| device.device_type == DeviceType::Virtio(TYPE_BLOCK))
+            .unwrap();
+        assert!(!block.activated);
+        let pstate = u64::from(PSR_MODE_EL1h | PSR_D_BIT | PSR_A_BIT | PSR_I_BIT | PSR_F_BIT);
+        assert_eq!(vm.cpu.pstate().unwrap(), pstate);
+        assert_eq!(vm.cpu.get_reg(x0).unwrap(), get_fdt_addr(&vm.memory));
+        let mut code = [0; 4];
+        vm.read_guest(kernel_addr, &mut code).unwrap();
+        assert_eq!(code, 0x1400_0000u32.to_le_bytes());
+        vm.read_guest_fdt().unwrap().validate().unwrap();
+    }
+
+    #[test]
+    fn test_devices_listed() {
+        let (mut builder, _kernel) = match test_vm_builder() {
+            Some(builder) => builder,
+            None => return,
+        };
+        let disk = TempFile::new().unwrap();
+        disk.as_file().set_len(1 << 20).unwrap();
+        builder.add_block("rootfs", disk.as_path());
+        builder.add_net("eth0", None);
+        let vm = builder.build().unwrap();
+
+        let devices = vm.devices();
+        let mut types: Vec<DeviceType> = devices.iter().map(|device| device.device_type).collect();
+        types.sort_by_key(|device_type| device_type.to_string());
+        assert_eq!(
+            types,
+            [
+                DeviceType::Rtc,
+                DeviceType::Serial,
+                DeviceType::Virtio(TYPE_NET),
+                DeviceType::Virtio(TYPE_BLOCK),
+            ]
+        );
+        let block = devices
+            .iter()
+            .find(|device| device.device_type == DeviceType::Virtio(TYPE_BLOCK))
+            .unwrap();
+        assert_eq!(block.id, "rootfs");
+        let net = devices
+            .iter()
+            .find(|device| device.device_type == DeviceType::Virtio(TYPE_NET))
+            .unwrap();
+        assert_eq!(net.id, "eth0");
+
+        // sorted by address, each in the MMIO window and mapped on the bus where it's listed
+        for (device, next) in devices.iter().zip(devices.iter().skip(1)) {
+            assert!(device.addr + device.len <= next.addr);
+        }
+        for device in &devices {
+            assert!(device.addr >= layout::MAPPED_IO_START);
+            assert!(device.addr + device.len <= DRAM_MEM_START);
+            assert!(device.len >= MMIO_LEN);
+            let (offset, _) = vm.mmio_device_manager.bus.get_device(device.addr).unwrap();
+            assert_eq!(offset, 0);
+            // only the virtio devices wait for a driver
+            assert_eq!(
+                device.activated,
+                !matches!(device.device_type, DeviceType::Virtio(_))
+            );
+        }
+    }
+
+    #[test]
+    fn test_guest_memory_end() {
+        let (vm, _kernel) = match test_vm() {
+            Some(vm) => vm,
+            None => return,
+        };
+        let end = DRAM_MEM_START + (64 << 20);
+        let pattern = [0x5a, 0xa5, 0x5a, 0xa5];
+
+        vm.write_guest(GuestAddress(end - 4), &pattern).unwrap();
+        let mut read = [0; 4];
+        vm.read_guest(GuestAddress(end - 4), &mut read).unwrap();
+        assert_eq!(read, pattern);
+
+        // ranges running past the end fail
+        assert!(matches!(
+            vm.write_guest(GuestAddress(end - 2), &pattern),
+            Err(VmError::GuestMemory(_))
+        ));
+        assert!(matches!(
+            vm.read_guest(GuestAddress(end - 2), &mut read),
+            Err(VmError::GuestMemory(_))
+        ));
+    }
+
+    #[test]
+    fn test_thread_affinity() {
+        let (pinned_tx, pinned_rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            pinned_rx.recv().unwrap();
+            // SAFETY: An all zero cpu_set_t is an empty set.
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            // SAFETY: `set` is a valid cpu_set_t of the given size.
+            let ret = unsafe {
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set)
+            };
+            assert_eq!(ret, 0);
+            (0..libc::CPU_SETSIZE as usize)
+                // SAFETY: `cpu` is below `CPU_SETSIZE`.
+                .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+                .collect::<Vec<_>>()
+        });
+
+        Vm::set_thread_affinity(thread.as_pthread_t(), &[0]).unwrap();
+        pinned_tx.send(()).unwrap();
+
+        assert_eq!(thread.join().unwrap(), [0]);
+    }
+
+    #[test]
+    fn test_pause_stops_vcpu_loop() {
+        let vcpu_thread = VcpuThread::default();
+        let pause = PauseHandle::new(vcpu_thread.clone());
+        let exits = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        // stands in for `run_vcpu`, counting guest exits
+        let thread = {
+            let (pause, exits, stop) = (pause.clone(), exits.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    while pause.wait_while_paused(PAUSE_POLL_INTERVAL) {}
+                    exits.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        };
+        *vcpu_thread.lock().unwrap() = Some(thread.as_pthread_t());
+        let advances = |from: u64| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while exits.load(Ordering::SeqCst) == from {
+                assert!(Instant::now() < deadline);
+                thread::yield_now();
+            }
+        };
+        advances(0);
+
+        pause.pause().unwrap();
+        assert!(pause.is_paused());
+        let paused_at = exits.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(exits.load(Ordering::SeqCst), paused_at);
+
+        pause.resume();
+        advances(paused_at);
+
+        stop.store(true, Ordering::Release);
+        *vcpu_thread.lock().unwrap() = None;
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_kick_interrupts_busy_vcpu() {
+        let (mut vm, _kernel) = match test_vm() {
+            Some(vm) => vm,
+            None => return,
+        };
+        vm.configure().unwrap();
+        vm.cpu.set_pc(DRAM_MEM_START + 0x8_0000).unwrap();
+        register_kick_handler().unwrap();
+
+        // SAFETY: Plain syscall without arguments.
+        *vm.vcpu_thread.lock().unwrap() = Some(unsafe { libc::pthread_self() });
+        let returned = Arc::new(AtomicBool::new(false));
+        let kicker = {
+            let (vcpu_thread, returned) = (vm.vcpu_thread.clone(), returned.clone());
+            thread::spawn(move || {
+                // let the guest spin for a while first
+                thread::sleep(Duration::from_millis(100));
+                // a kick right before the vcpu enters the guest is missed
+                while !returned.load(Ordering::Acquire) {
+                    kick_vcpu(&vcpu_thread);
+                    thread::sleep(Duration::from_millis(10));
+                }
+            })
+        };
+
+        let start = Instant::now();
+        let exit = vm.cpu.run(&vm.mmio_device_manager.bus).unwrap();
+        returned.store(true, Ordering::Release);
+        *vm.vcpu_thread.lock().unwrap() = None;
+        kicker.join().unwrap();
+
+        assert!(matches!(exit, CpuExit::Interrupted));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_invalid_vcpu_affinity() {
+        let (mut vm, _kernel) = match test_vm() {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        assert!(matches!(
+            vm.set_vcpu_affinity(1, &[0]),
+            Err(VmError::InvalidVcpu(1))
+        ));
+        assert!(matches!(
+            vm.set_vcpu_affinity(0, &[]),
+            Err(VmError::InvalidCpuSet)
+        ));
+        assert!(matches!(
+            vm.set_vcpu_affinity(0, &[libc::CPU_SETSIZE as usize]),
+            Err(VmError::InvalidCpuSet)
+        ));
+        // without a running vcpu it's only applied once `run` starts
+        vm.set_vcpu_affinity(0, &[0]).unwrap();
+        assert_eq!(vm.vcpu_affinity.as_deref(), Some([0].as_slice()));
+    }
+
+    #[test]
+    fn test_gzip_kernel_inflated() {
+        let guest_memory = test_guest_memory(4 << 20);
+        let mut kernel = image_header(0x10_0000);
+        kernel.extend((0..0x1000).map(|i| i as u8));
+        let file = TempFile::new().unwrap();
+        let mut encoder = GzEncoder::new(file.as_file(), Compression::default());
+        encoder.write_all(&kernel).unwrap();
+        encoder.finish().unwrap();
+
+        let result = Vm::load_kernel(&guest_memory, file.as_path()).unwrap();
+
+        let load = GuestAddress(DRAM_MEM_START + 0x10_0000);
+        assert_eq!(result.kernel_load, load);
+        let mut loaded = vec![0; kernel.len()];
+        guest_memory.read_slice(&mut loaded, load).unwrap();
+        assert_eq!(loaded, kernel);
+    }
+
+    #[test]
+    fn test_invalid_queue_size() {
+        for queue_size in [0, 100, 65535] {
+            let mut builder = VmBuilder::new();
+            builder.add_block_config(BlockConfig {
+                id: "rootfs".to_string(),
+                path: PathBuf::from("/nonexistent/rootfs.ext4"),
+                rate_limiter: RateLimiterConfig::default(),
+                io_engine: IoEngine::default(),
+                queue_size,
+                activation_timeout: None,
+            });
+
+            assert!(matches!(
+                Vm::validate_config(builder.config()),
+                Err(VmError::InvalidQueueSize(size)) if size == queue_size
+            ));
+        }
+    }
+
+    #[test]
+    fn test_probe_host() {
+        if Kvm::new().is_err() {
+            return;
+        }
+
+        let caps = Vm::probe_host();
+
+        assert!(caps.arm_psci);
+        assert!(caps.irqfd);
+        assert!(caps.missing().is_none());
+    }
+
+    #[test]
+    fn test_missing_capability() {
+        let mut caps = HostCapabilities {
+            user_memory: true,
+            irqfd: true,
+            ioeventfd: true,
+            arm_psci: true,
+            gic: true,
+        };
+        assert!(caps.missing().is_none());
+
+        caps.irqfd = false;
+        caps.gic = false;
+        // the first missing one is reported
+        assert!(matches!(caps.missing(), Some(Cap::Irqfd)));
+    }
+
+    #[test]
+    fn test_vcpu_limit() {
+        Vm::check_vcpu_limit(4, Some(4)).unwrap();
+        Vm::check_vcpu_limit(255, None).unwrap();
+        assert!(matches!(
+            Vm::check_vcpu_limit(5, Some(4)),
+            Err(VmError::VcpuLimitExceeded(5, 4))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_vcpu_count() {
+        for vcpu_count in [0, 2] {
+            let mut builder = VmBuilder::new();
+            builder.vcpu_count(vcpu_count);
+
+            assert!(matches!(
+                Vm::validate_config(builder.config()),
+                Err(VmError::UnsupportedVcpuCount(count)) if count == vcpu_count
+            ));
+        }
+    }
+
+    #[test]
+    fn test_consoles_share_stdio() {
+        let mut builder = VmBuilder::new();
+        builder
+            .console(ConsoleBackend::Stdio)
+            .virtio_console(Some(ConsoleBackend::Stdio));
+        assert!(matches!(
+            Vm::validate_config(builder.config()),
+            Err(VmError::StdioConsoleInUse)
+        ));
+
+        builder.virtio_console(Some(ConsoleBackend::Pty));
+        Vm::validate_config(builder.config()).unwrap();
+    }
+
+    #[test]
+    fn test_memory_not_huge_page_aligned() {
+        let mut builder = VmBuilder::new();
+        builder.memory_size(129).huge_pages(HugePages::Hugetlbfs2M);
+        assert!(matches!(
+            Vm::validate_config(builder.config()),
+            Err(VmError::UnalignedMemorySize(size)) if size == 2 << 20
+        ));
+
+        builder.memory_size(512).huge_pages(HugePages::Hugetlbfs1G);
+        assert!(matches!(
+            Vm::validate_config(builder.config()),
+            Err(VmError::UnalignedMemorySize(size)) if size == 1 << 30
+        ));
+
+        builder.memory_size(1024);
+        Vm::validate_config(builder.config()).unwrap();
+    }
+
+    #[test]
+    fn test_validate_missing_serial() {
+        let (mut vm, _kernel) = match test_vm() {
+            Some(vm) => vm,
+            None => return,
+        };
+        vm.validate().unwrap();
+
+        vm.mmio_device_manager
+            .unregister(&(DeviceType::Serial, DeviceType::Serial.to_string()))
+            .unwrap();
+
+        assert!(matches!(
+            vm.validate(),
+            Err(VmError::MissingDevice(DeviceType::Serial))
+        ));
+    }
+
+    #[test]
+    fn test_layout_fdt() {
+        let memory = test_guest_memory(16 << 20);
+        let mut cmdline = Cmdline::try_from(DEFAULT_KERNEL_CMDLINE, 2048).unwrap();
+        let mut manager = MMIODeviceManager::new();
+        manager.register_mmio_rtc(Rtc::new(), None).unwrap();
+        let block_info = manager
+            .register_mmio_virtio_for_boot(
+                &NoopRegistrar,
+                "rootfs".to_string(),
+                block_transport(&memory),
+                &mut cmdline,
+            )
+            .unwrap();
+
+        let fdt = Vm::layout_fdt(false, 0, &manager, None, &cmdline, &memory).unwrap();
+
+        let bootargs = fdt.property("/chosen", "bootargs").unwrap();
+        assert_eq!(bootargs, format!("{}\0", DEFAULT_KERNEL_CMDLINE).as_bytes());
+        let reg: Vec<u64> = fdt
+            .property("/memory", "reg")
+            .unwrap()
+            .chunks(8)
+            .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(reg, [DRAM_MEM_START, 16 << 20]);
+        let node_paths = fdt.node_paths().unwrap();
+        let rtc_info = manager
+            .devices()
+            .into_iter()
+            .find(|device| device.device_type == DeviceType::Rtc)
+            .unwrap();
+        assert!(node_paths.contains(&format!("/rtc@{:x}", rtc_info.addr)));
+        assert!(node_paths.contains(&format!("/virtio_mmio@{:x}", block_info.addr)));
+    }
+
+    #[test]
+    fn test_fdt_reports_window_size() {
+        let memory = test_guest_memory(16 << 20);
+        let cmdline = Cmdline::try_from(DEFAULT_KERNEL_CMDLINE, 2048).unwrap();
+        let mut manager = MMIODeviceManager::new();
+        let device_info = manager.allocate_mmio_resources(1, 0x2000);
+        manager
+            .register_mmio_virtio(
+                &NoopRegistrar,
+                "rootfs".to_string(),
+                block_transport(&memory),
+                &device_info,
+            )
+            .unwrap();
+
+        let fdt = Vm::layout_fdt(false, 0, &manager, None, &cmdline, &memory).unwrap();
+
+        let reg = fdt
+            .property(&format!("/virtio_mmio@{:x}", device_info.addr), "reg")
+            .unwrap();
+        assert_eq!(reg[..8], device_info.addr.to_be_bytes());
+        assert_eq!(reg[8..], 0x2000u64.to_be_bytes());
+        // the next window starts past it
+        let next = manager.allocate_mmio_resources(1, MMIO_LEN);
+        assert!(next.addr >= device_info.addr + 0x2000);
     }
 }
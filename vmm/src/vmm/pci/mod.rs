@@ -0,0 +1,29 @@
+use crate::vmm::layout::DRAM_MEM_START;
+
+/// Guest physical address of the PCIe configuration space (ECAM). It sits at the top of the
+/// MMIO window, far above the virtio-mmio devices allocated from its bottom.
+pub const PCI_ECAM_BASE: u64 = 0x7000_0000;
+/// Config space of a single bus: 32 devices with 8 functions of 4 KiB each.
+pub const PCI_ECAM_SIZE: u64 = 32 * 8 * 0x1000;
+/// Window the guest maps 32 bit memory BARs of PCI devices into.
+pub const PCI_MMIO_BASE: u64 = PCI_ECAM_BASE + PCI_ECAM_SIZE;
+pub const PCI_MMIO_SIZE: u64 = DRAM_MEM_START - PCI_MMIO_BASE;
+
+/// ECAM of a PCIe host bridge with a single bus, bus 0.
+///
+/// No devices can be plugged in yet, so every config space read returns all ones, which is
+/// what the guest expects for an empty slot, and writes are ignored.
+#[derive(Debug, Default)]
+pub struct PciRoot {}
+
+impl PciRoot {
+    pub fn new() -> PciRoot {
+        PciRoot {}
+    }
+
+    pub fn read(&self, _offset: u64, data: &mut [u8]) {
+        data.fill(0xff);
+    }
+
+    pub fn write(&mut self, _offset: u64, _data: &[u8]) {}
+}
@@ -0,0 +1,144 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+use crate::vmm::timerfd::TimerFd;
+
+/// How long a blocked limiter waits before the device retries.
+const REFILL_TIMER_DELAY: Duration = Duration::from_millis(100);
+
+/// A bucket of `size` tokens that refills completely every `refill_time` milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Versionize)]
+pub struct TokenBucketConfig {
+    pub size: u64,
+    pub refill_time: u64,
+}
+
+/// Limits of a device, a missing bucket means that resource isn't limited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Versionize)]
+pub struct RateLimiterConfig {
+    /// Bytes moved between the guest and the device's backend.
+    pub bandwidth: Option<TokenBucketConfig>,
+    /// Requests handled by the device.
+    pub ops: Option<TokenBucketConfig>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    size: u64,
+    refill_time: Duration,
+    budget: u64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn new(config: TokenBucketConfig) -> TokenBucket {
+        TokenBucket {
+            size: config.size,
+            refill_time: Duration::from_millis(config.refill_time),
+            budget: config.size,
+            last_update: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_nanos();
+        let refill_time = self.refill_time.as_nanos().max(1);
+
+        let tokens = u128::from(self.size) * elapsed / refill_time;
+        if tokens > 0 {
+            self.budget = (u128::from(self.budget) + tokens).min(u128::from(self.size)) as u64;
+            self.last_update = now;
+        }
+    }
+
+    // A request bigger than the whole bucket is let through once the bucket is full,
+    // otherwise it would never fit.
+    fn has(&self, tokens: u64) -> bool {
+        self.budget >= tokens.min(self.size)
+    }
+
+    fn take(&mut self, tokens: u64) {
+        self.budget = self.budget.saturating_sub(tokens);
+    }
+}
+
+/// Token bucket limiter for a device's bandwidth and operations.
+///
+/// Once a request doesn't fit in the remaining budget the limiter blocks and arms its timer.
+/// The device is expected to stop processing requests, register `as_raw_fd` with its event
+/// loop and call `event_handler` when it becomes readable before trying again.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    timer_fd: TimerFd,
+    blocked: bool,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimiterConfig) -> io::Result<RateLimiter> {
+        Ok(RateLimiter {
+            bandwidth: config.bandwidth.map(TokenBucket::new),
+            ops: config.ops.map(TokenBucket::new),
+            timer_fd: TimerFd::new()?,
+            blocked: false,
+        })
+    }
+
+    /// Takes one operation and `bytes` bytes from the buckets. Returns false, and takes
+    /// nothing, when either bucket doesn't have enough tokens left.
+    pub fn consume(&mut self, bytes: u64) -> bool {
+        if self.blocked {
+            return false;
+        }
+
+        for bucket in self.bandwidth.iter_mut().chain(self.ops.iter_mut()) {
+            bucket.refill();
+        }
+
+        let fits = self
+            .bandwidth
+            .as_ref()
+            .is_none_or(|bucket| bucket.has(bytes))
+            && self.ops.as_ref().is_none_or(|bucket| bucket.has(1));
+        if !fits {
+            self.blocked = true;
+            if let Err(err) = self.timer_fd.set_oneshot(REFILL_TIMER_DELAY) {
+                panic!("Failed to arm the rate limiter timer: {:?}", err);
+            }
+            return false;
+        }
+
+        if let Some(bucket) = &mut self.bandwidth {
+            bucket.take(bytes);
+        }
+        if let Some(bucket) = &mut self.ops {
+            bucket.take(1);
+        }
+        true
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Consumes the timer expiration and unblocks the limiter.
+    pub fn event_handler(&mut self) -> io::Result<()> {
+        self.timer_fd.read()?;
+        self.blocked = false;
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd.as_raw_fd()
+    }
+}
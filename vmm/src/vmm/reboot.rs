@@ -0,0 +1,74 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Reboots older than this don't count towards the limit.
+pub const REBOOT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Remembers when the guest rebooted, to stop a guest that keeps crashing right after boot.
+#[derive(Debug, Default)]
+pub struct RebootTracker {
+    max_reboots: Option<u32>,
+    reboots: VecDeque<Instant>,
+}
+
+impl RebootTracker {
+    pub fn new(max_reboots: Option<u32>) -> RebootTracker {
+        RebootTracker {
+            max_reboots,
+            reboots: VecDeque::new(),
+        }
+    }
+
+    /// Records a reboot at `now`. Returns false when the guest rebooted more than
+    /// `max_reboots` times within `REBOOT_WINDOW`, in which case it shouldn't be restarted.
+    pub fn record(&mut self, now: Instant) -> bool {
+        let max_reboots = match self.max_reboots {
+            Some(value) => value as usize,
+            None => return true,
+        };
+
+        while let Some(reboot) = self.reboots.front() {
+            if now.duration_since(*reboot) < REBOOT_WINDOW {
+                break;
+            }
+            self.reboots.pop_front();
+        }
+        self.reboots.push_back(now);
+
+        self.reboots.len() <= max_reboots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reboot_loop_stopped() {
+        let mut tracker = RebootTracker::new(Some(3));
+        let start = Instant::now();
+
+        for i in 0..3 {
+            assert!(tracker.record(start + Duration::from_secs(i)));
+        }
+        assert!(!tracker.record(start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_old_reboots_expire() {
+        let mut tracker = RebootTracker::new(Some(1));
+        let start = Instant::now();
+
+        assert!(tracker.record(start));
+        assert!(tracker.record(start + REBOOT_WINDOW));
+        assert!(!tracker.record(start + REBOOT_WINDOW + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let mut tracker = RebootTracker::new(None);
+        let start = Instant::now();
+
+        assert!((0..100).all(|i| tracker.record(start + Duration::from_millis(i))));
+    }
+}
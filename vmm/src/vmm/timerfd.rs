@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::Duration;
+
+// A zero delay disarms a timerfd, so delays are rounded up to fire right away instead.
+const MIN_DELAY: Duration = Duration::from_nanos(1);
+
+/// Non blocking timerfd on the monotonic clock, meant to be registered with the
+/// `EventManager` and read when it becomes readable.
+#[derive(Debug)]
+pub struct TimerFd {
+    file: File,
+}
+
+impl TimerFd {
+    pub fn new() -> io::Result<TimerFd> {
+        // SAFETY: Plain syscall, the returned fd is checked below.
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: `fd` was just created and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        Ok(TimerFd { file })
+    }
+
+    /// Makes the fd readable every `interval`, starting one `interval` from now.
+    pub fn set_interval(&self, interval: Duration) -> io::Result<()> {
+        let interval = interval.max(MIN_DELAY);
+        self.settime(interval, interval)
+    }
+
+    /// Makes the fd readable once, `delay` from now.
+    pub fn set_oneshot(&self, delay: Duration) -> io::Result<()> {
+        self.settime(delay.max(MIN_DELAY), Duration::ZERO)
+    }
+
+    /// Stops the timer.
+    pub fn disarm(&self) -> io::Result<()> {
+        self.settime(Duration::ZERO, Duration::ZERO)
+    }
+
+    /// Returns the number of expirations since the last read, or `WouldBlock` when the
+    /// timer hasn't expired.
+    pub fn read(&self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        (&self.file).read_exact(&mut buf)?;
+
+        Ok(u64::from_ne_bytes(buf))
+    }
+
+    fn settime(&self, value: Duration, interval: Duration) -> io::Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: timespec(interval),
+            it_value: timespec(value),
+        };
+
+        // SAFETY: `spec` is a valid itimerspec and the old value isn't requested.
+        let ret =
+            unsafe { libc::timerfd_settime(self.as_raw_fd(), 0, &spec, std::ptr::null_mut()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_oneshot() {
+        let timer = TimerFd::new().unwrap();
+        timer.set_oneshot(Duration::from_millis(10)).unwrap();
+
+        let err = timer.read().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(timer.read().unwrap(), 1);
+        // a oneshot doesn't fire again
+        thread::sleep(Duration::from_millis(20));
+        assert!(timer.read().is_err());
+    }
+
+    #[test]
+    fn test_disarm() {
+        let timer = TimerFd::new().unwrap();
+        timer.set_oneshot(Duration::from_millis(10)).unwrap();
+        timer.disarm().unwrap();
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(timer.read().is_err());
+    }
+}